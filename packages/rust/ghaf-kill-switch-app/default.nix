@@ -71,6 +71,9 @@ let
           wrapProgram "$out/bin/cosmic-applet-killswitch" \
             --prefix LD_LIBRARY_PATH : ${pkgs.lib.makeLibraryPath dlopenLibraries}
         fi
+        # System bus policy for the state change announcements, see src/sync.rs
+        install -Dm644 dbus/ae.tii.KillSwitch.Sync.conf \
+          $out/share/dbus-1/system.d/ae.tii.KillSwitch.Sync.conf
         mkdir -p $out/share/applications
         cat > $out/share/applications/ae.tii.CosmicAppletKillSwitch.desktop <<EOF
         [Desktop Entry]
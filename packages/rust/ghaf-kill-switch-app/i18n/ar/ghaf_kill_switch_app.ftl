@@ -0,0 +1,47 @@
+# SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+# SPDX-License-Identifier: Apache-2.0
+
+privacy-controls = عناصر التحكم في الخصوصية
+block-enable-all = حظر / تمكين الكل
+
+microphone = الميكروفون
+camera = الكاميرا
+wifi = Wi-Fi
+bluetooth = البلوتوث
+location = الموقع
+
+## Device state
+
+enabled = مُمكّن
+disabled = معطّل
+disabled-for = معطّل، متبقٍ { $minutes } دقيقة
+hard-blocked = محظور بمفتاح الجهاز
+locked = تُديره مؤسستك
+muted = مكتوم، تسجل التطبيقات صمتًا
+in-use-by = قيد الاستخدام من قِبل { $apps }
+
+## Tooltips
+
+hard-blocked-tooltip = شغّل مفتاح الجهاز لتمكين هذا الجهاز
+locked-tooltip = تُدير مؤسستك هذا الجهاز
+enable-all = تمكين جميع الأجهزة
+block-all = حظر جميع الأجهزة
+enable-microphone = تمكين الوصول إلى الميكروفون
+disable-microphone = تعطيل الوصول إلى الميكروفون
+enable-camera = تمكين الوصول إلى الكاميرا
+disable-camera = تعطيل الوصول إلى الكاميرا
+enable-wifi = تمكين الوصول إلى Wi-Fi
+disable-wifi = تعطيل الوصول إلى Wi-Fi
+enable-bluetooth = تمكين الوصول إلى البلوتوث
+disable-bluetooth = تعطيل الوصول إلى البلوتوث
+enable-location = تمكين الوصول إلى الموقع
+disable-location = تعطيل الوصول إلى الموقع
+block-timed = حظر لمدة ساعة
+cancel-timer = إلغاء المؤقت مع إبقاء الحظر
+mute-microphone = كتم الميكروفون مع إبقاء الجهاز متاحًا للتطبيقات
+unmute-microphone = إلغاء كتم الميكروفون
+
+## Notifications
+
+device-enabled = تم تمكين { $device }
+device-blocked = تم حظر { $device }
@@ -0,0 +1,47 @@
+# SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+# SPDX-License-Identifier: Apache-2.0
+
+privacy-controls = Privacy Controls
+block-enable-all = Block / Enable All
+
+microphone = Microphone
+camera = Camera
+wifi = Wi-Fi
+bluetooth = Bluetooth
+location = Location
+
+## Device state
+
+enabled = Enabled
+disabled = Disabled
+disabled-for = Disabled, { $minutes } min left
+hard-blocked = Blocked by hardware switch
+locked = Managed by your organization
+muted = Muted, apps record silence
+in-use-by = In use by { $apps }
+
+## Tooltips
+
+hard-blocked-tooltip = Turn on the hardware switch to enable this device
+locked-tooltip = Your organization manages this device
+enable-all = Enable all devices
+block-all = Block all devices
+enable-microphone = Enable microphone access
+disable-microphone = Disable microphone access
+enable-camera = Enable camera access
+disable-camera = Disable camera access
+enable-wifi = Enable Wi-Fi access
+disable-wifi = Disable Wi-Fi access
+enable-bluetooth = Enable Bluetooth access
+disable-bluetooth = Disable Bluetooth access
+enable-location = Enable location access
+disable-location = Disable location access
+block-timed = Block for 1 hour
+cancel-timer = Cancel timer, keep blocked
+mute-microphone = Mute microphone, apps keep the device
+unmute-microphone = Unmute microphone
+
+## Notifications
+
+device-enabled = { $device } enabled
+device-blocked = { $device } blocked
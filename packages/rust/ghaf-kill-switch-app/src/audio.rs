@@ -0,0 +1,69 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Soft mute of the microphone on the audio VM's PipeWire, through `pactl`.
+//!
+//! Unlike a killswitch block, the capture device stays available: applications keep
+//! their streams open and only record silence, which conferencing apps cope with.
+//! `pactl` reaches the audio VM through the `PULSE_SERVER` of the session.
+use std::io;
+use std::process::Command;
+
+const DEFAULT_SOURCE: &str = "@DEFAULT_SOURCE@";
+
+/// Whether the default source is muted, `None` if PipeWire is not reachable.
+pub fn is_muted() -> Option<bool> {
+    let output = match Command::new("pactl")
+        .args(["get-source-mute", DEFAULT_SOURCE])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            log::debug!(
+                "pactl get-source-mute failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return None;
+        }
+        Err(e) => {
+            log::debug!("Failed to execute pactl: {e}");
+            return None;
+        }
+    };
+    // `Mute: yes` or `Mute: no`, the words are not localized
+    match String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .strip_prefix("Mute:")
+        .map(str::trim)
+    {
+        Some("yes") => Some(true),
+        Some("no") => Some(false),
+        _ => {
+            log::warn!(
+                "Unexpected pactl get-source-mute output: {}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            None
+        }
+    }
+}
+
+pub fn set_muted(muted: bool) -> io::Result<()> {
+    let output = Command::new("pactl")
+        .args([
+            "set-source-mute",
+            DEFAULT_SOURCE,
+            if muted { "1" } else { "0" },
+        ])
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to execute pactl: {e}")))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "pactl set-source-mute failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    log::info!("Microphone {}", if muted { "muted" } else { "unmuted" });
+    Ok(())
+}
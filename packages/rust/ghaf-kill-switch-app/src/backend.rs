@@ -0,0 +1,140 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Invocation of `ghaf-killswitch`, which blocks and unblocks the devices in the VMs
+//! they are passed through to, and parsing of its status. Changes are only made as far
+//! as the administrator policy permits them.
+use crate::policy::Policy;
+use crate::{Config, Device, audio, rfkill};
+use std::io;
+use std::process::Command;
+
+const KILLSWITCH: &str = "ghaf-killswitch";
+
+/// Reads the device states from `ghaf-killswitch`, the hardware switches from rfkill, and
+/// whether the microphone is muted.
+pub fn status() -> io::Result<Config> {
+    let mut config = parse_status(&run(&["status"])?);
+    // Software cannot unblock a radio held off by its switch
+    let hard_blocked = rfkill::hard_blocked();
+    for &device in &hard_blocked {
+        config.set_enabled(device, false);
+    }
+    config.hard_blocked = hard_blocked;
+    config.microphone_muted = audio::is_muted().unwrap_or_default();
+    Ok(config)
+}
+
+/// Parses `ghaf-killswitch status` output, a `<device>: <status>` line per device.
+/// Devices missing from it are reported enabled.
+pub fn parse_status(output: &str) -> Config {
+    let mut config = Config::default();
+    for line in output.lines() {
+        let Some((name, status)) = line.split_once(':') else {
+            continue;
+        };
+
+        let name = name.trim();
+        let Some(device) = Device::from_backend_name(name) else {
+            log::warn!("Unknown device in {KILLSWITCH} status output: {name}");
+            continue;
+        };
+        config.set_enabled(device, status.trim() == "unblocked");
+    }
+    config
+}
+
+/// Unblocks `device`, or blocks it if not `enabled`, unless `policy` locks it to the
+/// other state.
+pub fn set(policy: &Policy, device: Device, enabled: bool) -> io::Result<()> {
+    if !policy.allows(device, enabled) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is managed by administrator policy",
+                device.backend_name()
+            ),
+        ));
+    }
+    run(&[command(enabled), device.backend_name()]).map(drop)
+}
+
+/// Unblocks all devices, or blocks them if not `enabled`, returning the devices skipped
+/// because `policy` locks them.
+pub fn set_all(policy: &Policy, enabled: bool) -> io::Result<Vec<Device>> {
+    if !policy.any_locked() {
+        return run(&[command(enabled), "--all"]).map(|_| Vec::new());
+    }
+    // `--all` would override the administrator's locks
+    let (locked, unlocked): (Vec<_>, Vec<_>) =
+        Device::ALL.into_iter().partition(|&d| policy.is_locked(d));
+    for device in unlocked {
+        set(policy, device, enabled)?;
+    }
+    Ok(locked)
+}
+
+fn command(enabled: bool) -> &'static str {
+    if enabled { "unblock" } else { "block" }
+}
+
+/// Runs `ghaf-killswitch` with `args`, returning its output. Its error output is
+/// returned as the error if it fails.
+fn run(args: &[&str]) -> io::Result<String> {
+    let output = Command::new(KILLSWITCH)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to execute {KILLSWITCH}: {e}")))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(io::Error::other(format!(
+            "{KILLSWITCH} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let config = parse_status("mic: blocked\ncam: unblocked\nnet: blocked\n");
+        assert!(!config.is_enabled(Device::Microphone));
+        assert!(config.is_enabled(Device::Camera));
+        assert!(!config.is_enabled(Device::WiFi));
+        // Devices missing from the output are reported enabled
+        assert!(config.is_enabled(Device::Bluetooth));
+        assert!(config.is_enabled(Device::Location));
+        assert!(config.hard_blocked.is_empty());
+    }
+
+    #[test]
+    fn test_parse_status_malformed() {
+        let config = parse_status("  gps :  blocked  \nusb: blocked\nbluetooth blocked\n\nmic:\n");
+        assert!(!config.is_enabled(Device::Location));
+        assert!(config.is_enabled(Device::Bluetooth));
+        // Anything but `unblocked` counts as blocked
+        assert!(!config.is_enabled(Device::Microphone));
+        assert!(config.is_enabled(Device::Camera));
+        assert!(config.is_enabled(Device::WiFi));
+    }
+
+    #[test]
+    fn test_set_locked() {
+        let policy: Policy = serde_json::from_str(r#"{ "locked": { "cam": false } }"#).unwrap();
+        // Refused before `ghaf-killswitch` is run at all
+        let e = set(&policy, Device::Camera, true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_parse_status_empty() {
+        let config = parse_status("");
+        assert!(Device::ALL.into_iter().all(|d| config.is_enabled(d)));
+    }
+}
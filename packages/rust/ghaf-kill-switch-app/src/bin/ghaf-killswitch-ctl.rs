@@ -0,0 +1,139 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Command line companion of the kill switch applet, for devices without a graphical
+//! shell, e.g. over SSH. It drives the same backend and honours the same administrator
+//! policy as the applet.
+use ghaf_kill_switch_app::policy::Policy;
+use ghaf_kill_switch_app::{Device, audio, backend, sync};
+use std::process::ExitCode;
+use systemd_journal_logger::JournalLog;
+
+const USAGE: &str = "\
+Usage: ghaf-killswitch-ctl <command>
+
+Commands:
+  status [--json]         Show the state of every device
+  block <device>|--all    Block a device, or all devices
+  unblock <device>|--all  Unblock a device, or all devices
+  mute                    Mute the microphone, apps record silence
+  unmute                  Unmute the microphone
+
+Devices: mic, cam, net, bluetooth, gps";
+
+fn main() -> ExitCode {
+    // Changes made over SSH end up in the journal like the applet's
+    log::set_max_level(log::LevelFilter::Info);
+    match JournalLog::new() {
+        Ok(journal) => {
+            let _ = journal.install();
+        }
+        Err(e) => eprintln!("Logging to the journal unavailable: {e}"),
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["status"] => status(false),
+        ["status", "--json"] => status(true),
+        ["block", target] => set(target, false).inspect(|()| announce()),
+        ["unblock", target] => set(target, true).inspect(|()| announce()),
+        ["mute"] => mute(true).inspect(|()| announce()),
+        ["unmute"] => mute(false).inspect(|()| announce()),
+        ["--help" | "-h"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Lets running applets show a change right away rather than on their next poll.
+fn announce() {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log::warn!("Failed to announce state change: {e}");
+            return;
+        }
+    };
+    if let Err(e) = runtime.block_on(sync::announce()) {
+        log::warn!("Failed to announce state change: {e}");
+    }
+}
+
+fn load_policy() -> Policy {
+    let mut policy = Policy::default();
+    policy.reload();
+    policy
+}
+
+fn status(json: bool) -> Result<(), String> {
+    let config = backend::status().map_err(|e| e.to_string())?;
+    if json {
+        let json = serde_json::to_string(&config).map_err(|e| e.to_string())?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    let policy = load_policy();
+    for device in Device::ALL {
+        let mut state = if config.is_hard_blocked(device) {
+            "hard-blocked"
+        } else if config.is_enabled(device) {
+            "unblocked"
+        } else {
+            "blocked"
+        }
+        .to_string();
+        if device == Device::Microphone && config.microphone_muted {
+            state.push_str(", muted");
+        }
+        if policy.is_locked(device) {
+            state.push_str(", locked");
+        }
+        println!("{}: {state}", device.backend_name());
+    }
+    Ok(())
+}
+
+fn set(target: &str, enabled: bool) -> Result<(), String> {
+    let policy = load_policy();
+    if target == "--all" {
+        let skipped = backend::set_all(&policy, enabled).map_err(|e| e.to_string())?;
+        for device in skipped {
+            eprintln!(
+                "{} is managed by administrator policy, skipped",
+                device.backend_name()
+            );
+        }
+        return Ok(());
+    }
+
+    let device =
+        Device::from_backend_name(target).ok_or_else(|| format!("Unknown device '{target}'"))?;
+    backend::set(&policy, device, enabled).map_err(|e| e.to_string())
+}
+
+fn mute(muted: bool) -> Result<(), String> {
+    // A blocked microphone records nothing to mute
+    let config = backend::status().map_err(|e| e.to_string())?;
+    if !config.is_enabled(Device::Microphone) {
+        return Err("The microphone is blocked".to_string());
+    }
+    audio::set_muted(muted).map_err(|e| e.to_string())
+}
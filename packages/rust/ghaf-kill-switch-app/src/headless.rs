@@ -0,0 +1,258 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Tray-less mode for profiles without a panel (e.g. kiosk).
+//!
+//! Device states are published on the session bus as properties of
+//! `ae.tii.KillSwitch` at [`OBJECT_PATH`], and every change is announced
+//! through the notification center (`org.freedesktop.Notifications`).
+//!
+//! Desktops other than COSMIC read them from the status file instead, see
+//! [`crate::status_file`].
+//!
+//! Like the applet, it keeps locked devices in the state the administrator policy
+//! enforces and unblocks devices once their timed block runs out.
+use crate::schedule::Schedule;
+use crate::{Config, Device, ID, KillSwitch, Policy, fl, status_file, sync};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Value;
+
+pub const OBJECT_PATH: &str = "/ae/tii/KillSwitch";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const NOTIFICATION_TIMEOUT_MS: i32 = 5000;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+pub trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+/// Sends a desktop notification about a device state change.
+pub async fn notify_change(
+    proxy: &NotificationsProxy<'_>,
+    device: Device,
+    enabled: bool,
+) -> zbus::Result<u32> {
+    let summary = if enabled {
+        fl!("device-enabled", device = device.label())
+    } else {
+        fl!("device-blocked", device = device.label())
+    };
+    proxy
+        .notify(
+            &fl!("privacy-controls"),
+            0,
+            device.icon_name(),
+            &summary,
+            "",
+            &[],
+            HashMap::new(),
+            NOTIFICATION_TIMEOUT_MS,
+        )
+        .await
+}
+
+struct DeviceStates {
+    config: Config,
+}
+
+#[zbus::interface(name = "ae.tii.KillSwitch")]
+impl DeviceStates {
+    #[zbus(property)]
+    fn microphone_enabled(&self) -> bool {
+        self.config.microphone_enabled
+    }
+
+    #[zbus(property)]
+    fn camera_enabled(&self) -> bool {
+        self.config.camera_enabled
+    }
+
+    #[zbus(property)]
+    fn wifi_enabled(&self) -> bool {
+        self.config.wifi_enabled
+    }
+
+    #[zbus(property)]
+    fn bluetooth_enabled(&self) -> bool {
+        self.config.bt_enabled
+    }
+
+    #[zbus(property)]
+    fn location_enabled(&self) -> bool {
+        self.config.gps_enabled
+    }
+}
+
+impl DeviceStates {
+    async fn emit_changed(&self, emitter: &SignalEmitter<'_>, device: Device) -> zbus::Result<()> {
+        match device {
+            Device::Microphone => self.microphone_enabled_changed(emitter).await,
+            Device::Camera => self.camera_enabled_changed(emitter).await,
+            Device::WiFi => self.wifi_enabled_changed(emitter).await,
+            Device::Bluetooth => self.bluetooth_enabled_changed(emitter).await,
+            Device::Location => self.location_enabled_changed(emitter).await,
+        }
+    }
+}
+
+/// Runs without a panel icon until the process is terminated.
+pub fn run() -> zbus::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(serve())
+}
+
+async fn serve() -> zbus::Result<()> {
+    // Nothing is published before the real states are known
+    let config = loop {
+        if let Some(config) = load_config().await {
+            break config;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+    let connection = zbus::connection::Builder::session()?
+        .name(ID)?
+        .serve_at(
+            OBJECT_PATH,
+            DeviceStates {
+                config: config.clone(),
+            },
+        )?
+        .build()
+        .await?;
+    let notifications = NotificationsProxy::new(&connection).await?;
+    let states = connection
+        .object_server()
+        .interface::<_, DeviceStates>(OBJECT_PATH)
+        .await?;
+    log::info!("Kill switch running headless, state published at {ID} {OBJECT_PATH}");
+
+    // Changes announced by applets and `ghaf-killswitch-ctl` are picked up right away
+    let announced = Arc::new(Notify::new());
+    tokio::spawn({
+        let announced = announced.clone();
+        async move {
+            if let Err(e) = sync::listen(|| announced.notify_one()).await {
+                log::warn!("State changes of other instances unavailable: {e}");
+            }
+        }
+    });
+
+    let mut policy = Policy::default();
+    let mut schedule = Schedule::load();
+    let mut status = None;
+    status_file::update(&config, &mut status);
+    let mut current = config;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = announced.notified() => {}
+        }
+
+        // An unknown state is never published as enabled
+        let Some(mut config) = load_config().await else {
+            continue;
+        };
+        policy.reload();
+        if enforce(&policy, &mut schedule, &config).await {
+            let Some(enforced) = load_config().await else {
+                continue;
+            };
+            config = enforced;
+        }
+        status_file::update(&config, &mut status);
+        let changed: Vec<_> = Device::ALL
+            .into_iter()
+            .filter(|&d| config.is_enabled(d) != current.is_enabled(d))
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+
+        let mut iface = states.get_mut().await;
+        iface.config = config.clone();
+        for &device in &changed {
+            let enabled = config.is_enabled(device);
+            log::info!("{device:?} changed, enabled: {enabled}");
+            if let Err(e) = iface.emit_changed(states.signal_emitter(), device).await {
+                log::error!("Failed to publish {device:?} state: {e}");
+            }
+            if let Err(e) = notify_change(&notifications, device, enabled).await {
+                log::error!("Failed to send notification for {device:?}: {e}");
+            }
+        }
+        drop(iface);
+        current = config;
+    }
+}
+
+/// Applies the timers and the policy to the devices in `config` the way the applet does,
+/// returning whether any device was changed.
+async fn enforce(policy: &Policy, schedule: &mut Schedule, config: &Config) -> bool {
+    let cancelled = schedule.cancel_enabled(config);
+    if !cancelled.is_empty() {
+        log::info!("Timed block of {cancelled:?} cancelled, unblocked externally");
+        schedule.save();
+    }
+    let mut commands: Vec<_> = schedule
+        .expire(policy)
+        .into_iter()
+        .inspect(|device| log::info!("Timed block of {device:?} expired, unblocking"))
+        .map(|device| (device, true))
+        .collect();
+    for (device, enabled) in policy.corrections(config) {
+        log::info!("{device:?} locked by policy, enabled: {enabled}");
+        if schedule.cancel(device) {
+            schedule.save();
+        }
+        commands.push((device, enabled));
+    }
+    if commands.is_empty() {
+        return false;
+    }
+
+    let policy = policy.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        for (device, enabled) in commands {
+            KillSwitch::run_killswitch_command(&policy, device, enabled);
+        }
+    })
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to run commands in background task: {e}");
+    }
+    // Other instances would only notice on their next poll
+    if let Err(e) = sync::announce().await {
+        log::warn!("Failed to announce state change: {e}");
+    }
+    true
+}
+
+/// Reads the device states, `None` if they are unknown.
+async fn load_config() -> Option<Config> {
+    tokio::task::spawn_blocking(KillSwitch::query_config)
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("Failed to get config from background task: {e}");
+            None
+        })
+}
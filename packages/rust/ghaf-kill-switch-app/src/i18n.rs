@@ -0,0 +1,42 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Fluent translations of the applet strings, embedded from `i18n/<lang>/`.
+use i18n_embed::fluent::{FluentLanguageLoader, fluent_language_loader};
+use i18n_embed::{DefaultLocalizer, DesktopLanguageRequester, LanguageLoader, Localizer};
+use rust_embed::RustEmbed;
+use std::sync::LazyLock;
+
+#[derive(RustEmbed)]
+#[folder = "i18n/"]
+struct Localizations;
+
+pub static LANGUAGE_LOADER: LazyLock<FluentLanguageLoader> = LazyLock::new(|| {
+    let loader: FluentLanguageLoader = fluent_language_loader!();
+    loader
+        .load_fallback_language(&Localizations)
+        .expect("Error while loading fallback language");
+    loader
+});
+
+/// Looks up a translated message, e.g. `fl!("disabled-for", minutes = 5)`.
+#[macro_export]
+macro_rules! fl {
+    ($message_id:literal) => {{
+        i18n_embed_fl::fl!($crate::i18n::LANGUAGE_LOADER, $message_id)
+    }};
+
+    ($message_id:literal, $($args:expr),*) => {{
+        i18n_embed_fl::fl!($crate::i18n::LANGUAGE_LOADER, $message_id, $($args), *)
+    }};
+}
+
+/// Selects the translations matching the user's locale, falling back to English.
+pub fn init() {
+    let requested = DesktopLanguageRequester::requested_languages();
+    let localizer = DefaultLocalizer::new(&*LANGUAGE_LOADER, &Localizations);
+    if let Err(e) = localizer.select(&requested) {
+        log::error!("Failed to load translations for {requested:?}: {e}");
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Device state model, `ghaf-killswitch` backend and state synchronization, shared by
+//! the COSMIC applet and `ghaf-killswitch-ctl`.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+pub mod audio;
+pub mod backend;
+pub mod i18n;
+pub mod policy;
+pub mod rfkill;
+pub mod sync;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Device {
+    #[serde(rename = "mic")]
+    Microphone,
+    #[serde(rename = "cam")]
+    Camera,
+    #[serde(rename = "net")]
+    WiFi,
+    #[serde(rename = "bluetooth")]
+    Bluetooth,
+    #[serde(rename = "gps")]
+    Location,
+}
+
+impl Device {
+    pub const ALL: [Device; 5] = [
+        Device::Microphone,
+        Device::Camera,
+        Device::WiFi,
+        Device::Bluetooth,
+        Device::Location,
+    ];
+
+    pub fn label(self) -> String {
+        match self {
+            Device::Microphone => fl!("microphone"),
+            Device::Camera => fl!("camera"),
+            Device::WiFi => fl!("wifi"),
+            Device::Bluetooth => fl!("bluetooth"),
+            Device::Location => fl!("location"),
+        }
+    }
+
+    pub fn icon_name(self) -> &'static str {
+        match self {
+            Device::Microphone => "microphone-sensitivity-medium-symbolic",
+            Device::Camera => "camera-photo-symbolic",
+            Device::WiFi => "network-wireless-symbolic",
+            Device::Bluetooth => "bluetooth-symbolic",
+            Device::Location => "find-location-symbolic",
+        }
+    }
+
+    /// Device name as understood by `ghaf-killswitch`
+    pub fn backend_name(self) -> &'static str {
+        match self {
+            Device::Microphone => "mic",
+            Device::Camera => "cam",
+            Device::WiFi => "net",
+            Device::Bluetooth => "bluetooth",
+            Device::Location => "gps",
+        }
+    }
+
+    /// Device with the `ghaf-killswitch` name `name`
+    pub fn from_backend_name(name: &str) -> Option<Self> {
+        Device::ALL.into_iter().find(|d| d.backend_name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    pub microphone_enabled: bool,
+    pub camera_enabled: bool,
+    pub wifi_enabled: bool,
+    pub bt_enabled: bool,
+    pub gps_enabled: bool,
+    /// Microphone muted on the audio VM, which leaves the device available
+    pub microphone_muted: bool,
+    /// Radios blocked by a hardware switch, which software cannot unblock, as reported by
+    /// rfkill
+    pub hard_blocked: BTreeSet<Device>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            microphone_enabled: true,
+            camera_enabled: true,
+            wifi_enabled: true,
+            bt_enabled: true,
+            gps_enabled: true,
+            microphone_muted: false,
+            hard_blocked: BTreeSet::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn is_enabled(&self, device: Device) -> bool {
+        match device {
+            Device::Microphone => self.microphone_enabled,
+            Device::Camera => self.camera_enabled,
+            Device::WiFi => self.wifi_enabled,
+            Device::Bluetooth => self.bt_enabled,
+            Device::Location => self.gps_enabled,
+        }
+    }
+
+    pub fn all_blocked(&self) -> bool {
+        Device::ALL.into_iter().all(|d| !self.is_enabled(d))
+    }
+
+    pub fn is_hard_blocked(&self, device: Device) -> bool {
+        self.hard_blocked.contains(&device)
+    }
+
+    pub fn set_enabled(&mut self, device: Device, enabled: bool) {
+        match device {
+            Device::Microphone => self.microphone_enabled = enabled,
+            Device::Camera => self.camera_enabled = enabled,
+            Device::WiFi => self.wifi_enabled = enabled,
+            Device::Bluetooth => self.bt_enabled = enabled,
+            Device::Location => self.gps_enabled = enabled,
+        }
+    }
+}
@@ -9,50 +9,63 @@ use cosmic::iced::window;
 use cosmic::iced::{Length, Limits, Subscription};
 use cosmic::widget::{self, icon, toggler};
 use cosmic::{Application, Element};
-use serde::{Deserialize, Serialize};
-use std::process::Command;
+use ghaf_kill_switch_app::policy::Policy;
+use ghaf_kill_switch_app::{Config, Device, audio, backend, fl, i18n, sync};
+use schedule::Schedule;
+use status_file::Status;
 use std::time::Duration;
 use systemd_journal_logger::JournalLog;
+use usage::Usage;
+
+mod headless;
+mod schedule;
+mod shortcuts;
+mod status_file;
+mod usage;
 
 const ID: &str = "ae.tii.CosmicAppletKillSwitch";
 const POPUP_WIDTH: f32 = 290.0;
+const TIMED_BLOCK_DURATION: Duration = Duration::from_secs(60 * 60);
+const POLICY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Status polling with the popup closed, for changes no instance announces, e.g. by
+/// `ghaf-killswitch` run directly
+const STATUS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone)]
 pub enum Message {
     ToggleMicrophone(bool),
+    MuteMicrophone(bool),
     ToggleCamera(bool),
     ToggleWiFi(bool),
     ToggleBT(bool),
+    ToggleLocation(bool),
     ToggleAll(bool),
+    BlockTimed(Device),
+    CancelTimedBlock(Device),
+    CheckSchedule,
+    CheckPolicy,
     TogglePopup,
     RefreshStatus,
-    ConfigLoaded(Config),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[allow(clippy::struct_excessive_bools)]
-pub struct Config {
-    microphone_enabled: bool,
-    camera_enabled: bool,
-    wifi_enabled: bool,
-    bt_enabled: bool,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            microphone_enabled: true,
-            camera_enabled: true,
-            wifi_enabled: true,
-            bt_enabled: true,
-        }
-    }
+    ConfigLoaded(Config, u64),
+    CommandsDone,
+    UsageLoaded(Usage),
+    ShortcutActivated(String),
 }
 
 pub struct KillSwitch {
     core: Core,
     config: Config,
+    schedule: Schedule,
+    policy: Policy,
+    usage: Usage,
+    /// Last states written to the status file
+    status: Option<Status>,
     popup: Option<window::Id>,
+    /// `ghaf-killswitch` commands still running
+    commands_running: u32,
+    /// Bumped whenever a command starts or ends, so a status read that overlapped
+    /// one is not mistaken for a change made elsewhere
+    commands_generation: u64,
 }
 
 impl Application for KillSwitch {
@@ -76,9 +89,23 @@ impl Application for KillSwitch {
         let app = Self {
             core,
             config: Self::get_config(),
+            schedule: Schedule::load(),
+            policy: Policy::default(),
+            usage: Usage::default(),
+            status: None,
             popup: None,
+            commands_running: 0,
+            commands_generation: 0,
         };
-        (app, cosmic::Task::none())
+        // Timers that ran out while the applet was not running fire right away,
+        // and locked devices are brought to their enforced state
+        (
+            app,
+            cosmic::Task::batch([
+                cosmic::Task::done(Message::CheckSchedule.into()),
+                cosmic::Task::done(Message::CheckPolicy.into()),
+            ]),
+        )
     }
 
     fn view(&self) -> Element<'_, Message> {
@@ -88,6 +115,7 @@ impl Application for KillSwitch {
             .applet
             .icon_button("security-high-symbolic")
             .on_press(Message::TogglePopup)
+            .name(fl!("privacy-controls"))
             .into()
     }
 
@@ -101,55 +129,59 @@ impl Application for KillSwitch {
         // Check if this is our popup window
         if self.popup == Some(id) {
             let spacing = self.core.system_theme().cosmic().spacing;
-            let all_disabled = !self.config.microphone_enabled
-                && !self.config.camera_enabled
-                && !self.config.wifi_enabled
-                && !self.config.bt_enabled;
+            let all_disabled = self.config.all_blocked();
 
-            let content = widget::column::with_capacity(6)
+            let content = widget::column::with_capacity(8)
                 .push(
-                    widget::container(widget::text("Privacy Controls").size(14))
+                    widget::container(widget::text(fl!("privacy-controls")).size(14))
                         .width(Length::Fixed(POPUP_WIDTH))
                         .padding([spacing.space_xs, spacing.space_m]),
                 )
                 .push(self.create_control_row(
                     "security-high-symbolic",
-                    "Block / Enable All",
+                    fl!("block-enable-all"),
                     all_disabled,
                     Message::ToggleAll,
-                    false,
+                    None,
                 ))
                 .push(
                     cosmic::iced::widget::container(cosmic::iced::widget::Rule::horizontal(1))
                         .width(Length::Fixed(POPUP_WIDTH)),
                 )
                 .push(self.create_control_row(
-                    "microphone-sensitivity-medium-symbolic",
-                    "Microphone",
+                    Device::Microphone.icon_name(),
+                    Device::Microphone.label(),
                     self.config.microphone_enabled,
                     Message::ToggleMicrophone,
-                    true,
+                    Some(Device::Microphone),
                 ))
                 .push(self.create_control_row(
-                    "camera-photo-symbolic",
-                    "Camera",
+                    Device::Camera.icon_name(),
+                    Device::Camera.label(),
                     self.config.camera_enabled,
                     Message::ToggleCamera,
-                    true,
+                    Some(Device::Camera),
                 ))
                 .push(self.create_control_row(
-                    "network-wireless-symbolic",
-                    "Wi-Fi",
+                    Device::WiFi.icon_name(),
+                    Device::WiFi.label(),
                     self.config.wifi_enabled,
                     Message::ToggleWiFi,
-                    true,
+                    Some(Device::WiFi),
                 ))
                 .push(self.create_control_row(
-                    "bluetooth-symbolic",
-                    "Bluetooth",
+                    Device::Bluetooth.icon_name(),
+                    Device::Bluetooth.label(),
                     self.config.bt_enabled,
                     Message::ToggleBT,
-                    true,
+                    Some(Device::Bluetooth),
+                ))
+                .push(self.create_control_row(
+                    Device::Location.icon_name(),
+                    Device::Location.label(),
+                    self.config.gps_enabled,
+                    Message::ToggleLocation,
+                    Some(Device::Location),
                 ))
                 .spacing(1);
 
@@ -163,64 +195,73 @@ impl Application for KillSwitch {
     fn update(&mut self, message: Self::Message) -> cosmic::Task<cosmic::Action<Self::Message>> {
         log::debug!("Update called with message: {message:?}");
         match message {
-            Message::ToggleMicrophone(enabled) => {
-                self.config.microphone_enabled = enabled;
-                log::debug!("Microphone toggled: {enabled}");
-                cosmic::Task::future(async move {
-                    let _ = tokio::task::spawn_blocking(move || {
-                        Self::run_killswitch_command("mic", enabled);
-                    })
-                    .await;
-                    cosmic::Action::None
-                })
-            }
-            Message::ToggleCamera(enabled) => {
-                self.config.camera_enabled = enabled;
-                log::debug!("Camera toggled: {enabled}");
-                cosmic::Task::future(async move {
-                    let _ = tokio::task::spawn_blocking(move || {
-                        Self::run_killswitch_command("cam", enabled);
-                    })
-                    .await;
-                    cosmic::Action::None
-                })
-            }
-            Message::ToggleWiFi(enabled) => {
-                self.config.wifi_enabled = enabled;
-                log::debug!("WiFi toggled: {enabled}");
-                cosmic::Task::future(async move {
-                    let _ = tokio::task::spawn_blocking(move || {
-                        Self::run_killswitch_command("net", enabled);
-                    })
-                    .await;
-                    cosmic::Action::None
-                })
-            }
-            Message::ToggleBT(enabled) => {
-                self.config.bt_enabled = enabled;
-                log::debug!("Bluetooth toggled: {enabled}");
-                cosmic::Task::future(async move {
-                    let _ = tokio::task::spawn_blocking(move || {
-                        Self::run_killswitch_command("bluetooth", enabled);
-                    })
-                    .await;
-                    cosmic::Action::None
+            Message::ToggleMicrophone(enabled) => self.toggle(Device::Microphone, enabled),
+            Message::MuteMicrophone(muted) => {
+                // A blocked microphone records nothing to mute
+                if !self.config.microphone_enabled {
+                    return cosmic::Task::none();
+                }
+                self.config.microphone_muted = muted;
+                self.spawn_commands(move || {
+                    if let Err(e) = audio::set_muted(muted) {
+                        log::error!("{e}");
+                    }
                 })
             }
+            Message::ToggleCamera(enabled) => self.toggle(Device::Camera, enabled),
+            Message::ToggleWiFi(enabled) => self.toggle(Device::WiFi, enabled),
+            Message::ToggleBT(enabled) => self.toggle(Device::Bluetooth, enabled),
+            Message::ToggleLocation(enabled) => self.toggle(Device::Location, enabled),
             Message::ToggleAll(enabled_from_toggler) => {
                 let enabled = !enabled_from_toggler;
-                self.config.microphone_enabled = enabled;
-                self.config.camera_enabled = enabled;
-                self.config.wifi_enabled = enabled;
-                self.config.bt_enabled = enabled;
+                let unlocked: Vec<_> = Device::ALL
+                    .into_iter()
+                    .filter(|&d| !self.policy.is_locked(d))
+                    .collect();
+                let mut cancelled = false;
+                for &device in &unlocked {
+                    let enabled = enabled && !self.config.is_hard_blocked(device);
+                    self.config.set_enabled(device, enabled);
+                    cancelled |= self.schedule.cancel(device);
+                }
+                if cancelled {
+                    self.schedule.save();
+                }
                 log::debug!("All devices toggled: {enabled}");
-                cosmic::Task::future(async move {
-                    let _ = tokio::task::spawn_blocking(move || {
-                        Self::run_killswitch_command_all(enabled);
-                    })
-                    .await;
-                    cosmic::Action::None
-                })
+                let policy = self.policy.clone();
+                self.spawn_commands(move || Self::run_killswitch_command_all(&policy, enabled))
+            }
+            Message::BlockTimed(device) => {
+                if !self.permitted(device, false) {
+                    return cosmic::Task::none();
+                }
+                self.config.set_enabled(device, false);
+                self.schedule.set(device, TIMED_BLOCK_DURATION);
+                self.schedule.save();
+                log::info!(
+                    "{device:?} blocked for {} minutes",
+                    TIMED_BLOCK_DURATION.as_secs() / 60
+                );
+                self.run_commands(vec![(device, false)])
+            }
+            Message::CancelTimedBlock(device) => {
+                log::debug!("Timed block of {device:?} cancelled");
+                self.cancel_timed_block(device);
+                cosmic::Task::none()
+            }
+            Message::CheckSchedule => {
+                let expired = self.schedule.expire(&self.policy);
+                for &device in &expired {
+                    log::info!("Timed block of {device:?} expired, unblocking");
+                    self.config.set_enabled(device, true);
+                }
+                self.run_commands(expired.into_iter().map(|d| (d, true)).collect())
+            }
+            Message::CheckPolicy => {
+                if !self.policy.reload() {
+                    return cosmic::Task::none();
+                }
+                self.enforce_policy()
             }
             Message::TogglePopup => {
                 log::debug!("!!! Toggle popup clicked !!!");
@@ -245,171 +286,324 @@ impl Application for KillSwitch {
                         .min_width(POPUP_WIDTH)
                         .min_height(250.0)
                         .max_width(POPUP_WIDTH)
-                        .max_height(300.0);
+                        .max_height(420.0);
 
-                    get_popup(popup_settings)
+                    // Show current state and users right away instead of on the first tick
+                    cosmic::Task::batch([
+                        get_popup(popup_settings),
+                        cosmic::Task::done(Message::RefreshStatus.into()),
+                    ])
                 }
             }
             Message::RefreshStatus => {
                 log::debug!("Request to get_config");
 
-                cosmic::Task::perform(
-                    tokio::task::spawn_blocking(Self::get_config),
-                    |res| match res {
-                        Ok(config) => Message::ConfigLoaded(config).into(),
+                let generation = self.commands_generation;
+                let config = cosmic::Task::perform(
+                    tokio::task::spawn_blocking(Self::query_config),
+                    move |res| match res {
+                        Ok(Some(config)) => Message::ConfigLoaded(config, generation).into(),
+                        // Keep the last known state rather than reporting bogus changes
+                        Ok(None) => cosmic::Action::None,
                         Err(_) => {
                             log::error!("Failed to get config from background task");
                             cosmic::Action::None
                         }
                     },
-                )
+                );
+                // Nobody sees the users with the popup closed
+                if self.popup.is_none() {
+                    return config;
+                }
+                let usage = cosmic::Task::perform(
+                    tokio::task::spawn_blocking(Usage::query),
+                    |res| match res {
+                        Ok(usage) => Message::UsageLoaded(usage).into(),
+                        Err(_) => {
+                            log::error!("Failed to get device usage from background task");
+                            cosmic::Action::None
+                        }
+                    },
+                );
+                cosmic::Task::batch([config, usage])
             }
 
-            Message::ConfigLoaded(config) => {
+            Message::ConfigLoaded(config, generation) => {
+                // Our own commands already updated the state shown, anything else that
+                // differs was changed outside the applet (hardware switch, other session)
+                let external = generation == self.commands_generation && self.commands_running == 0;
+                let changed: Vec<_> = Device::ALL
+                    .into_iter()
+                    .filter(|&d| external && config.is_enabled(d) != self.config.is_enabled(d))
+                    .map(|d| (d, config.is_enabled(d)))
+                    .collect();
                 self.config = config;
+                status_file::update(&self.config, &mut self.status);
+                // A device unblocked elsewhere no longer needs its timer, which would
+                // otherwise unblock it again after it is blocked for good
+                if external {
+                    let cancelled = self.schedule.cancel_enabled(&self.config);
+                    if !cancelled.is_empty() {
+                        log::info!("Timed block of {cancelled:?} cancelled, unblocked externally");
+                        self.schedule.save();
+                    }
+                }
+                // Locked devices changed elsewhere, e.g. by `ghaf-killswitch` over SSH, are
+                // put back
+                let enforce = if external {
+                    self.enforce_policy()
+                } else {
+                    cosmic::Task::none()
+                };
+                if changed.is_empty() {
+                    return enforce;
+                }
+                for &(device, enabled) in &changed {
+                    log::info!("{device:?} changed externally, enabled: {enabled}");
+                }
+                cosmic::Task::batch([enforce, Self::notify_changes(changed)])
+            }
+
+            Message::CommandsDone => {
+                self.commands_running = self.commands_running.saturating_sub(1);
+                self.commands_generation += 1;
                 cosmic::Task::none()
             }
+
+            Message::UsageLoaded(usage) => {
+                self.usage = usage;
+                cosmic::Task::none()
+            }
+
+            Message::ShortcutActivated(id) => {
+                if id != shortcuts::TOGGLE_ALL {
+                    log::warn!("Unknown shortcut activated: {id}");
+                    return cosmic::Task::none();
+                }
+                // Same as flipping the toggle, announced since the popup is likely closed
+                let before = self.config.clone();
+                let toggle = self.update(Message::ToggleAll(!before.all_blocked()));
+                let changed: Vec<_> = Device::ALL
+                    .into_iter()
+                    .filter(|&d| before.is_enabled(d) != self.config.is_enabled(d))
+                    .map(|d| (d, self.config.is_enabled(d)))
+                    .collect();
+                cosmic::Task::batch([toggle, Self::notify_changes(changed)])
+            }
         }
     }
 
     fn subscription(&self) -> Subscription<Self::Message> {
-        // Refresh status every 2 seconds when popup is open
-        if self.popup.is_some() {
+        // Refresh status every 2 seconds when popup is open. Otherwise changes are
+        // announced by the other instances, and only rarely looked for
+        let refresh = if self.popup.is_some() {
             cosmic::iced::time::every(Duration::from_secs(2)).map(|_| Message::RefreshStatus)
         } else {
+            cosmic::iced::time::every(STATUS_CHECK_INTERVAL).map(|_| Message::RefreshStatus)
+        };
+
+        // Keep ticking while a timed block is pending, so it expires and the
+        // remaining time stays current even with the popup closed
+        let schedule = if self.schedule.is_empty() {
             Subscription::none()
-        }
+        } else {
+            cosmic::iced::time::every(Duration::from_secs(1)).map(|_| Message::CheckSchedule)
+        };
+
+        // The policy file can change at any time
+        let policy = cosmic::iced::time::every(POLICY_CHECK_INTERVAL).map(|_| Message::CheckPolicy);
+
+        // Bound once for the lifetime of the applet
+        let shortcuts = Subscription::run(|| {
+            cosmic::iced::stream::channel(1, |mut output| async move {
+                let result = shortcuts::listen(|id| {
+                    let _ = output.try_send(Message::ShortcutActivated(id.to_string()));
+                })
+                .await;
+                if let Err(e) = result {
+                    log::warn!("Global shortcuts unavailable: {e}");
+                }
+            })
+        });
+
+        // Changes made by other instances, e.g. in another session, show up right away
+        let sync = Subscription::run(|| {
+            cosmic::iced::stream::channel(1, |mut output| async move {
+                let result = sync::listen(|| {
+                    let _ = output.try_send(Message::RefreshStatus);
+                })
+                .await;
+                if let Err(e) = result {
+                    log::warn!("State changes of other instances unavailable: {e}");
+                }
+            })
+        });
+
+        Subscription::batch([refresh, schedule, policy, shortcuts, sync])
     }
 }
 
 impl KillSwitch {
-    fn run_killswitch_command_all(enabled: bool) {
-        let arg = if enabled { "unblock" } else { "block" };
-        let output = Command::new("ghaf-killswitch")
-            .arg(arg)
-            .arg("--all")
-            .output()
-            .expect("Failed to execute ghaf-killswitch command");
-
-        if output.status.success() {
-            log::info!("ghaf-killswitch {arg} --all successful");
-        } else {
-            log::error!(
-                "ghaf-killswitch {} --all failed: {}",
-                arg,
-                String::from_utf8_lossy(&output.stderr)
-            );
+    fn run_killswitch_command_all(policy: &Policy, enabled: bool) {
+        match backend::set_all(policy, enabled) {
+            Ok(skipped) if skipped.is_empty() => log::info!("All devices set, enabled: {enabled}"),
+            Ok(skipped) => {
+                log::info!("All devices but {skipped:?} locked by policy set, enabled: {enabled}")
+            }
+            Err(e) => log::error!("{e}"),
         }
     }
+
     fn get_config() -> Config {
-        let output = Command::new("ghaf-killswitch").arg("status").output();
-
-        match output {
-            Ok(output) => {
-                if output.status.success() {
-                    let stdout = String::from_utf8_lossy(&output.stdout);
-                    let mut config = Config::default();
-
-                    for line in stdout.lines() {
-                        let Some((device, status)) = line.split_once(':') else {
-                            continue;
-                        };
-
-                        let device = device.trim();
-                        let enabled = status.trim() == "unblocked";
-
-                        match device {
-                            "mic" => config.microphone_enabled = enabled,
-                            "cam" => config.camera_enabled = enabled,
-                            "net" => config.wifi_enabled = enabled,
-                            "bluetooth" => config.bt_enabled = enabled,
-                            _ => log::warn!(
-                                "Unknown device in ghaf-killswitch status output: {device}"
-                            ),
+        Self::query_config().unwrap_or_default()
+    }
+
+    /// Reads the device states from `ghaf-killswitch`, `None` if they are unknown.
+    fn query_config() -> Option<Config> {
+        backend::status()
+            .map_err(|e| log::error!("Failed to read device states: {e}"))
+            .ok()
+    }
+
+    /// Brings locked devices to the state the policy enforces.
+    fn enforce_policy(&mut self) -> cosmic::Task<cosmic::Action<Message>> {
+        let enforce = self.policy.corrections(&self.config);
+        for &(device, enabled) in &enforce {
+            log::info!("{device:?} locked by policy, enabled: {enabled}");
+            self.config.set_enabled(device, enabled);
+            self.cancel_timed_block(device);
+        }
+        self.run_commands(enforce)
+    }
+
+    /// Returns whether the policy permits setting `device` to `enabled`, logging refusals.
+    fn permitted(&self, device: Device, enabled: bool) -> bool {
+        let allowed = self.policy.allows(device, enabled);
+        if !allowed {
+            log::warn!("{device:?} is managed by administrator policy, refusing to change it");
+        }
+        allowed
+    }
+
+    fn toggle(&mut self, device: Device, enabled: bool) -> cosmic::Task<cosmic::Action<Message>> {
+        if !self.permitted(device, enabled) {
+            return cosmic::Task::none();
+        }
+        self.config.set_enabled(device, enabled);
+        self.cancel_timed_block(device);
+        log::debug!("{device:?} toggled: {enabled}");
+        self.run_commands(vec![(device, enabled)])
+    }
+
+    /// Sends the state changes to `ghaf-killswitch`, which the backend only does as far as
+    /// the policy permits.
+    fn run_commands(
+        &mut self,
+        commands: Vec<(Device, bool)>,
+    ) -> cosmic::Task<cosmic::Action<Message>> {
+        if commands.is_empty() {
+            return cosmic::Task::none();
+        }
+        let policy = self.policy.clone();
+        self.spawn_commands(move || {
+            for (device, enabled) in commands {
+                Self::run_killswitch_command(&policy, device, enabled);
+            }
+        })
+    }
+
+    /// Runs `commands` in the background, keeping track of them until they finish.
+    fn spawn_commands(
+        &mut self,
+        commands: impl FnOnce() + Send + 'static,
+    ) -> cosmic::Task<cosmic::Action<Message>> {
+        self.commands_running += 1;
+        self.commands_generation += 1;
+        cosmic::Task::future(async move {
+            let _ = tokio::task::spawn_blocking(commands).await;
+            // Other instances would only notice on their next poll
+            if let Err(e) = sync::announce().await {
+                log::warn!("Failed to announce state change: {e}");
+            }
+            Message::CommandsDone.into()
+        })
+    }
+
+    /// Tells the user about devices changed outside the applet.
+    fn notify_changes(changed: Vec<(Device, bool)>) -> cosmic::Task<cosmic::Action<Message>> {
+        cosmic::Task::future(async move {
+            let proxy = match zbus::Connection::session().await {
+                Ok(connection) => headless::NotificationsProxy::new(&connection).await,
+                Err(e) => Err(e),
+            };
+            match proxy {
+                Ok(proxy) => {
+                    for (device, enabled) in changed {
+                        if let Err(e) = headless::notify_change(&proxy, device, enabled).await {
+                            log::error!("Failed to send notification for {device:?}: {e}");
                         }
                     }
-                    config
-                } else {
-                    log::error!(
-                        "ghaf-killswitch status command failed: {}",
-                        String::from_utf8_lossy(&output.stderr)
-                    );
-                    Config::default()
                 }
+                Err(e) => log::error!("Failed to connect to the notification service: {e}"),
             }
-            Err(e) => {
-                log::error!("Failed to execute ghaf-killswitch status command: {e}");
-                Config::default()
-            }
-        }
+            cosmic::Action::None
+        })
     }
 
-    fn run_killswitch_command(device: &str, enabled: bool) {
-        let arg = if enabled { "unblock" } else { "block" };
-        let output = Command::new("ghaf-killswitch")
-            .arg(arg)
-            .arg(device)
-            .output()
-            .expect("Failed to execute ghaf-killswitch command");
+    fn cancel_timed_block(&mut self, device: Device) {
+        if self.schedule.cancel(device) {
+            self.schedule.save();
+        }
+    }
 
-        if output.status.success() {
-            log::info!("ghaf-killswitch {arg} {device} successful");
-        } else {
-            log::error!(
-                "ghaf-killswitch {} {} failed: {}",
-                arg,
-                device,
-                String::from_utf8_lossy(&output.stderr)
-            );
+    fn run_killswitch_command(policy: &Policy, device: Device, enabled: bool) {
+        match backend::set(policy, device, enabled) {
+            Ok(()) => log::info!("{device:?} set, enabled: {enabled}"),
+            Err(e) => log::error!("{e}"),
         }
     }
+
     fn create_control_row(
         &self,
         icon_name: &'static str,
-        label: &'static str,
+        label: String,
         enabled: bool,
         on_toggle: fn(bool) -> Message,
-        show_status_text: bool,
+        device: Option<Device>,
     ) -> Element<'static, Message> {
         let spacing = self.core.system_theme().cosmic().spacing;
-        let status_text = if enabled { "Enabled" } else { "Disabled" };
-        let tooltip_text = match label {
-            "Block / Enable All" => {
-                if enabled {
-                    "Enable all devices"
-                } else {
-                    "Block all devices"
-                }
-            }
-            "Microphone" => {
-                if enabled {
-                    "Disable microphone access"
-                } else {
-                    "Enable microphone access"
-                }
-            }
-            "Camera" => {
-                if enabled {
-                    "Disable camera access"
-                } else {
-                    "Enable camera access"
-                }
-            }
-            "Wi-Fi" => {
-                if enabled {
-                    "Disable Wi-Fi access"
-                } else {
-                    "Enable Wi-Fi access"
-                }
-            }
-            "Bluetooth" => {
-                if enabled {
-                    "Disable Bluetooth access"
-                } else {
-                    "Enable Bluetooth access"
-                }
-            }
-            _ => "Toggle device access",
+        // Only a blocked device counts down to being unblocked
+        let remaining = device
+            .filter(|_| !enabled)
+            .and_then(|d| self.schedule.remaining(d));
+        let hard_blocked = device.is_some_and(|d| self.config.is_hard_blocked(d));
+        let locked = device.is_some_and(|d| self.policy.is_locked(d));
+        // The microphone has a third state between enabled and blocked
+        let mutable = device == Some(Device::Microphone) && enabled;
+        let muted = mutable && self.config.microphone_muted;
+        let status_text = match remaining {
+            _ if locked => fl!("locked"),
+            _ if hard_blocked => fl!("hard-blocked"),
+            Some(remaining) => fl!("disabled-for", minutes = remaining.as_secs().div_ceil(60)),
+            None if muted => fl!("muted"),
+            None if enabled => fl!("enabled"),
+            None => fl!("disabled"),
+        };
+        let tooltip_text = match (device, enabled) {
+            _ if locked => fl!("locked-tooltip"),
+            _ if hard_blocked => fl!("hard-blocked-tooltip"),
+            (None, true) => fl!("enable-all"),
+            (None, false) => fl!("block-all"),
+            (Some(Device::Microphone), true) => fl!("disable-microphone"),
+            (Some(Device::Microphone), false) => fl!("enable-microphone"),
+            (Some(Device::Camera), true) => fl!("disable-camera"),
+            (Some(Device::Camera), false) => fl!("enable-camera"),
+            (Some(Device::WiFi), true) => fl!("disable-wifi"),
+            (Some(Device::WiFi), false) => fl!("enable-wifi"),
+            (Some(Device::Bluetooth), true) => fl!("disable-bluetooth"),
+            (Some(Device::Bluetooth), false) => fl!("enable-bluetooth"),
+            (Some(Device::Location), true) => fl!("disable-location"),
+            (Some(Device::Location), false) => fl!("enable-location"),
         };
 
         let icon_widget = widget::container(icon::from_name(icon_name).size(32))
@@ -418,18 +612,76 @@ impl KillSwitch {
             .align_x(Horizontal::Center)
             .align_y(Vertical::Center);
 
-        let text_column = widget::column::with_capacity(2)
+        // Applications that lose access when the device is blocked
+        let users = device.and_then(|d| self.usage.apps(d)).map(|apps| {
+            let names: Vec<_> = apps.iter().map(String::as_str).collect();
+            widget::text(fl!("in-use-by", apps = names.join(", "))).size(12)
+        });
+
+        // Without a handler the toggle renders as disabled. Screen readers announce it
+        // by the row's label, followed by what toggling does or why it cannot be toggled
+        let toggle = toggler(enabled)
+            .on_toggle_maybe((!hard_blocked && !locked).then_some(on_toggle))
+            .name(label.clone())
+            .description(tooltip_text.clone());
+
+        let text_column = widget::column::with_capacity(3)
             .push(widget::text(label).size(14))
-            .push_maybe(show_status_text.then(|| widget::text(status_text).size(12)))
+            .push_maybe(device.is_some().then(|| widget::text(status_text).size(12)))
+            .push_maybe(users)
             .spacing(2);
 
-        let toggle = toggler(enabled).on_toggle(on_toggle);
+        // Offer a timed block on enabled devices, and a way out of a running one
+        let timer_button = device.and_then(|device| {
+            let (message, tooltip) = if hard_blocked || locked {
+                return None;
+            } else if remaining.is_some() {
+                (Message::CancelTimedBlock(device), fl!("cancel-timer"))
+            } else if enabled {
+                (Message::BlockTimed(device), fl!("block-timed"))
+            } else {
+                return None;
+            };
+            Some(widget::tooltip(
+                widget::button::icon(icon::from_name("alarm-symbolic"))
+                    .on_press(message)
+                    .name(tooltip.clone()),
+                widget::text(tooltip).size(12),
+                widget::tooltip::Position::Bottom,
+            ))
+        });
+
+        let mute_button = mutable.then(|| {
+            let (icon_name, tooltip) = if muted {
+                (
+                    "microphone-sensitivity-muted-symbolic",
+                    fl!("unmute-microphone"),
+                )
+            } else {
+                (
+                    "microphone-sensitivity-high-symbolic",
+                    fl!("mute-microphone"),
+                )
+            };
+            widget::tooltip(
+                widget::button::icon(icon::from_name(icon_name))
+                    .on_press(Message::MuteMicrophone(!muted))
+                    .name(tooltip.clone()),
+                widget::text(tooltip).size(12),
+                widget::tooltip::Position::Bottom,
+            )
+        });
+
+        let lock_icon = locked.then(|| icon::from_name("system-lock-screen-symbolic").size(16));
 
         let content = widget::container(
-            widget::row::with_capacity(3)
+            widget::row::with_capacity(7)
                 .push(icon_widget)
                 .push(text_column)
                 .push(widget::Space::new().width(Length::Fill))
+                .push_maybe(mute_button)
+                .push_maybe(timer_button)
+                .push_maybe(lock_icon)
                 .push(toggle)
                 .spacing(spacing.space_s),
         )
@@ -449,5 +701,17 @@ fn main() -> cosmic::iced::Result {
     // Initialize systemd journal logger
     log::set_max_level(log::LevelFilter::Info);
     JournalLog::new().unwrap().install().unwrap();
+
+    i18n::init();
+
+    // Kiosk profiles have no panel, state is published on D-Bus only
+    if std::env::args().skip(1).any(|arg| arg == "--headless") {
+        if let Err(e) = headless::run() {
+            log::error!("Headless kill switch failed: {e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     cosmic::applet::run::<KillSwitch>(())
 }
@@ -0,0 +1,204 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Administrator lockdown policy.
+//!
+//! The read-only policy file pins devices to a state, e.g. to keep the camera
+//! blocked on managed machines:
+//!
+//! ```json
+//! { "locked": { "cam": false } }
+//! ```
+//!
+//! Locked devices are forced to their state, also when found changed outside
+//! the applet, can no longer be toggled from it, and no command changing them
+//! is sent. The file is re-read when its modification time changes.
+use crate::{Config, Device};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+pub const POLICY_FILE: &str = "/etc/ghaf/killswitch-policy.json";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Enforced state per locked device, `true` for enabled
+    #[serde(default)]
+    locked: BTreeMap<Device, bool>,
+    #[serde(skip)]
+    modified: Option<SystemTime>,
+}
+
+impl Policy {
+    /// Re-reads the policy if the file changed, returning whether it did.
+    ///
+    /// A missing file means no devices are locked. A malformed file keeps the
+    /// previous policy, so a botched edit does not unlock anything.
+    pub fn reload(&mut self) -> bool {
+        self.reload_from(Path::new(POLICY_FILE))
+    }
+
+    fn reload_from(&mut self, path: &Path) -> bool {
+        let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => Some(modified),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                log::error!("Failed to stat policy {}: {e}", path.display());
+                return false;
+            }
+        };
+        if modified == self.modified {
+            return false;
+        }
+
+        let Some(modified) = modified else {
+            log::info!("Policy {} removed, unlocking all devices", path.display());
+            *self = Self::default();
+            return true;
+        };
+        let policy = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_slice::<Self>(&data).map_err(|e| e.to_string()));
+        match policy {
+            Ok(policy) => {
+                log::info!("Loaded policy {}: {:?}", path.display(), policy.locked);
+                *self = Self {
+                    modified: Some(modified),
+                    ..policy
+                };
+            }
+            Err(e) => {
+                log::error!(
+                    "Keeping previous policy, {} is invalid: {e}",
+                    path.display()
+                );
+                self.modified = Some(modified);
+            }
+        }
+        true
+    }
+
+    pub fn is_locked(&self, device: Device) -> bool {
+        self.locked.contains_key(&device)
+    }
+
+    /// The state `device` is locked to, if any.
+    pub fn enforced(&self, device: Device) -> Option<bool> {
+        self.locked.get(&device).copied()
+    }
+
+    /// Whether `device` may be set to `enabled`, i.e. it is not locked to the other state.
+    pub fn allows(&self, device: Device, enabled: bool) -> bool {
+        self.enforced(device).is_none_or(|e| e == enabled)
+    }
+
+    pub fn any_locked(&self) -> bool {
+        !self.locked.is_empty()
+    }
+
+    /// Changes bringing the locked devices in `config` to their enforced state. Radios
+    /// held off by their hardware switch are left to it.
+    pub fn corrections(&self, config: &Config) -> Vec<(Device, bool)> {
+        self.locked
+            .iter()
+            .map(|(&device, &enabled)| (device, enabled))
+            .filter(|&(d, enabled)| config.is_enabled(d) != enabled)
+            .filter(|&(d, enabled)| !(enabled && config.is_hard_blocked(d)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn policy(json: &str) -> Policy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_allows_and_enforced() {
+        let policy = policy(r#"{ "locked": { "cam": false, "gps": true } }"#);
+        assert!(policy.any_locked());
+        assert!(policy.is_locked(Device::Camera));
+        assert_eq!(policy.enforced(Device::Camera), Some(false));
+        assert_eq!(policy.enforced(Device::Location), Some(true));
+        assert_eq!(policy.enforced(Device::Microphone), None);
+
+        // Locked devices may only be set to their enforced state
+        assert!(policy.allows(Device::Camera, false));
+        assert!(!policy.allows(Device::Camera, true));
+        assert!(policy.allows(Device::Location, true));
+        assert!(!policy.allows(Device::Location, false));
+        assert!(policy.allows(Device::Microphone, true));
+        assert!(policy.allows(Device::Microphone, false));
+
+        let open = Policy::default();
+        assert!(!open.any_locked());
+        assert!(Device::ALL.into_iter().all(|d| open.allows(d, false)));
+    }
+
+    #[test]
+    fn test_corrections() {
+        let policy = policy(r#"{ "locked": { "cam": false, "net": true, "gps": true } }"#);
+        let mut config = Config::default();
+        config.set_enabled(Device::WiFi, false);
+        config.set_enabled(Device::Location, false);
+        config.hard_blocked.insert(Device::Location);
+        // Only the hardware switch unblocks the location radio
+        assert_eq!(
+            policy.corrections(&config),
+            [(Device::Camera, false), (Device::WiFi, true)]
+        );
+
+        config.set_enabled(Device::Camera, false);
+        config.set_enabled(Device::WiFi, true);
+        assert!(policy.corrections(&config).is_empty());
+        assert!(Policy::default().corrections(&config).is_empty());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(!policy("{}").any_locked());
+        assert!(serde_json::from_str::<Policy>(r#"{ "locked": { "tv": false } }"#).is_err());
+        assert!(serde_json::from_str::<Policy>(r#"{ "unlocked": {} }"#).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("ks-policy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("policy.json");
+        let mut mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut write = |data: &str| {
+            std::fs::write(&path, data).unwrap();
+            mtime += Duration::from_secs(1);
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        let mut policy = Policy::default();
+
+        // No file, nothing locked
+        assert!(!policy.reload_from(&path));
+        assert!(!policy.any_locked());
+
+        write(r#"{ "locked": { "cam": false } }"#);
+        assert!(policy.reload_from(&path));
+        assert_eq!(policy.enforced(Device::Camera), Some(false));
+        // Unchanged files are not re-read
+        assert!(!policy.reload_from(&path));
+
+        // A botched edit unlocks nothing
+        write(r#"{ "locked": { "cam": "#);
+        assert!(policy.reload_from(&path));
+        assert_eq!(policy.enforced(Device::Camera), Some(false));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(policy.reload_from(&path));
+        assert!(!policy.any_locked());
+    }
+}
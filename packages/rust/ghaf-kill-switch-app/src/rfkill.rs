@@ -0,0 +1,72 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Hardware switch state of the radios, from the kernel's rfkill class.
+//!
+//! Only radios visible to the VM the applet runs in are reported, a radio passed
+//! through to another VM is reported by rfkill there.
+use crate::Device;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const RFKILL_CLASS: &str = "/sys/class/rfkill";
+
+/// Devices held off by a hardware switch.
+pub fn hard_blocked() -> BTreeSet<Device> {
+    hard_blocked_in(Path::new(RFKILL_CLASS))
+}
+
+fn hard_blocked_in(class: &Path) -> BTreeSet<Device> {
+    let entries = match std::fs::read_dir(class) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::debug!("Failed to read {}: {e}", class.display());
+            return BTreeSet::new();
+        }
+    };
+    let read = |path: &Path| std::fs::read_to_string(path).unwrap_or_default();
+    entries
+        .flatten()
+        .filter(|entry| read(&entry.path().join("hard")).trim() == "1")
+        .filter_map(|entry| device(read(&entry.path().join("type")).trim()))
+        .collect()
+}
+
+/// Device controlled by a radio of rfkill type `kind`
+fn device(kind: &str) -> Option<Device> {
+    match kind {
+        "wlan" => Some(Device::WiFi),
+        "bluetooth" => Some(Device::Bluetooth),
+        "gps" => Some(Device::Location),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hard_blocked() {
+        let class = std::env::temp_dir().join(format!("ks-rfkill-{}", std::process::id()));
+        for (name, kind, hard) in [
+            ("rfkill0", "wlan", "1"),
+            ("rfkill1", "bluetooth", "0"),
+            ("rfkill2", "gps", "1\n"),
+            ("rfkill3", "nfc", "1"),
+        ] {
+            let dir = class.join(name);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("type"), kind).unwrap();
+            std::fs::write(dir.join("hard"), hard).unwrap();
+        }
+
+        assert_eq!(
+            hard_blocked_in(&class),
+            BTreeSet::from([Device::WiFi, Device::Location])
+        );
+        std::fs::remove_dir_all(&class).unwrap();
+        assert!(hard_blocked_in(&class).is_empty());
+    }
+}
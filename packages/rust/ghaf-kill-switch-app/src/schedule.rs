@@ -0,0 +1,222 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Timed blocks ("block camera for 1 hour") persisted across applet restarts.
+use crate::{Config, Device, Policy};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const STATE_DIR: &str = "ghaf-kill-switch";
+const SCHEDULE_FILE: &str = "schedule.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Unblock deadline per device, in seconds since the Unix epoch
+    unblock_at: BTreeMap<Device, u64>,
+}
+
+impl Schedule {
+    /// Loads the persisted schedule, falling back to an empty one.
+    pub fn load() -> Self {
+        schedule_path().map_or_else(Self::default, |path| Self::load_from(&path))
+    }
+
+    fn load_from(path: &Path) -> Self {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                log::error!("Ignoring malformed schedule {}: {e}", path.display());
+                Self::default()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                log::error!("Failed to read schedule {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(path) = schedule_path() else {
+            log::warn!("No state directory available, timed blocks will not persist");
+            return;
+        };
+        self.save_to(&path);
+    }
+
+    fn save_to(&self, path: &Path) {
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(path, serde_json::to_vec_pretty(self)?));
+        if let Err(e) = result {
+            log::error!("Failed to write schedule {}: {e}", path.display());
+        }
+    }
+
+    /// Schedules `device` to be unblocked after `duration`.
+    pub fn set(&mut self, device: Device, duration: Duration) {
+        self.unblock_at
+            .insert(device, now_secs().saturating_add(duration.as_secs()));
+    }
+
+    /// Removes the timer for `device`, returning whether one was active.
+    pub fn cancel(&mut self, device: Device) -> bool {
+        self.unblock_at.remove(&device).is_some()
+    }
+
+    /// Removes and returns the timers of devices `config` shows enabled, e.g. unblocked
+    /// with `ghaf-killswitch-ctl`, which would otherwise fire against a later choice.
+    pub fn cancel_enabled(&mut self, config: &Config) -> Vec<Device> {
+        let enabled: Vec<_> = self
+            .unblock_at
+            .keys()
+            .copied()
+            .filter(|&d| config.is_enabled(d))
+            .collect();
+        for device in &enabled {
+            self.unblock_at.remove(device);
+        }
+        enabled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unblock_at.is_empty()
+    }
+
+    pub fn remaining(&self, device: Device) -> Option<Duration> {
+        self.unblock_at
+            .get(&device)
+            .map(|&deadline| Duration::from_secs(deadline.saturating_sub(now_secs())))
+    }
+
+    /// Removes the timers that ran out, saving the schedule if there were any, and returns
+    /// the devices to unblock.
+    pub fn expire(&mut self, policy: &Policy) -> Vec<Device> {
+        let scheduled = self.unblock_at.len();
+        let expired = self.expire_at(now_secs(), policy);
+        if self.unblock_at.len() != scheduled {
+            self.save();
+        }
+        expired
+    }
+
+    fn expire_at(&mut self, now: u64, policy: &Policy) -> Vec<Device> {
+        let mut expired = self.take_expired_at(now);
+        // A lock set while the timer ran takes precedence
+        expired.retain(|&d| policy.allows(d, true));
+        expired
+    }
+
+    fn take_expired_at(&mut self, now: u64) -> Vec<Device> {
+        let expired: Vec<_> = self
+            .unblock_at
+            .iter()
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(&device, _)| device)
+            .collect();
+        for device in &expired {
+            self.unblock_at.remove(device);
+        }
+        expired
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn schedule_path() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))?;
+    Some(state_home.join(STATE_DIR).join(SCHEDULE_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_and_cancel() {
+        let mut schedule = Schedule::default();
+        assert!(schedule.is_empty());
+        schedule.set(Device::Camera, Duration::from_secs(3600));
+        let remaining = schedule.remaining(Device::Camera).unwrap();
+        assert!(remaining <= Duration::from_secs(3600));
+        assert!(remaining > Duration::from_secs(3590));
+        assert_eq!(schedule.remaining(Device::Microphone), None);
+
+        assert!(schedule.cancel(Device::Camera));
+        assert!(!schedule.cancel(Device::Camera));
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn test_take_expired() {
+        let mut schedule = Schedule::default();
+        schedule.unblock_at.insert(Device::Camera, 100);
+        schedule.unblock_at.insert(Device::Microphone, 200);
+
+        assert!(schedule.take_expired_at(99).is_empty());
+        assert_eq!(schedule.take_expired_at(150), [Device::Camera]);
+        // Taken timers do not fire again
+        assert!(schedule.take_expired_at(150).is_empty());
+        assert_eq!(schedule.take_expired_at(u64::MAX), [Device::Microphone]);
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn test_expire_locked() {
+        let mut schedule = Schedule::default();
+        schedule.unblock_at.insert(Device::Camera, 100);
+        schedule.unblock_at.insert(Device::Microphone, 100);
+        let policy: Policy = serde_json::from_str(r#"{ "locked": { "cam": false } }"#).unwrap();
+
+        assert_eq!(schedule.expire_at(150, &policy), [Device::Microphone]);
+        // The locked device's timer is gone all the same
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_enabled() {
+        let mut schedule = Schedule::default();
+        schedule.unblock_at.insert(Device::Camera, 100);
+        schedule.unblock_at.insert(Device::Microphone, 200);
+        let mut config = Config::default();
+        config.set_enabled(Device::Microphone, false);
+
+        assert_eq!(schedule.cancel_enabled(&config), [Device::Camera]);
+        assert!(schedule.remaining(Device::Microphone).is_some());
+        assert!(schedule.cancel_enabled(&config).is_empty());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("ks-schedule-{}", std::process::id()));
+        let path = dir.join(STATE_DIR).join(SCHEDULE_FILE);
+
+        // Nothing saved yet
+        assert!(Schedule::load_from(&path).is_empty());
+
+        let mut schedule = Schedule::default();
+        schedule.unblock_at.insert(Device::Camera, 100);
+        schedule.unblock_at.insert(Device::Location, u64::MAX);
+        schedule.save_to(&path);
+
+        // A timer that ran out while the applet was not running fires after loading
+        let mut loaded = Schedule::load_from(&path);
+        assert_eq!(loaded.unblock_at, schedule.unblock_at);
+        assert_eq!(loaded.take_expired_at(now_secs()), [Device::Camera]);
+        assert!(loaded.remaining(Device::Location).is_some());
+
+        std::fs::write(&path, b"{ not json").unwrap();
+        assert!(Schedule::load_from(&path).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
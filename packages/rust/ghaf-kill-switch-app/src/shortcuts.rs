@@ -0,0 +1,140 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Global keyboard shortcuts through the desktop portal
+//! (`org.freedesktop.portal.GlobalShortcuts`), served by `xdg-desktop-portal-cosmic`.
+//!
+//! The portal lets the user confirm or change the suggested trigger, so the key
+//! combination is only a preference. Without the portal the applet works as before.
+use crate::fl;
+use cosmic::iced::futures::StreamExt;
+use std::collections::HashMap;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+/// Blocks all devices, or enables them again if all are blocked
+pub const TOGGLE_ALL: &str = "toggle-all";
+const TOGGLE_ALL_TRIGGER: &str = "CTRL+ALT+p";
+const REQUEST_PATH: &str = "/org/freedesktop/portal/desktop/request";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.portal.GlobalShortcuts",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait GlobalShortcuts {
+    fn create_session(&self, options: HashMap<&str, Value<'_>>) -> zbus::Result<OwnedObjectPath>;
+
+    fn bind_shortcuts(
+        &self,
+        session_handle: &ObjectPath<'_>,
+        shortcuts: &[(&str, HashMap<&str, Value<'_>>)],
+        parent_window: &str,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    #[zbus(signal)]
+    fn activated(
+        &self,
+        session_handle: ObjectPath<'_>,
+        shortcut_id: &str,
+        timestamp: u64,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.portal.Request",
+    default_service = "org.freedesktop.portal.Desktop"
+)]
+trait Request {
+    #[zbus(signal)]
+    fn response(&self, response: u32, results: HashMap<String, OwnedValue>) -> zbus::Result<()>;
+}
+
+/// Binds the applet's shortcuts, then calls `activated` with the id of every shortcut
+/// the user triggers. Only returns on errors, e.g. without a portal.
+pub async fn listen(mut activated: impl FnMut(&str)) -> zbus::Result<()> {
+    let connection = zbus::Connection::session().await?;
+    let portal = GlobalShortcutsProxy::new(&connection).await?;
+    let mut activations = portal.receive_activated().await?;
+
+    let token = "ghaf_kill_switch_session";
+    let mut results = call(&connection, token, || {
+        let options = HashMap::from([
+            ("handle_token", Value::from(token)),
+            ("session_handle_token", Value::from(token)),
+        ]);
+        portal.create_session(options)
+    })
+    .await?;
+    let session = results
+        .remove("session_handle")
+        .and_then(|handle| String::try_from(handle).ok())
+        .ok_or_else(|| zbus::Error::Failure("No session handle returned".to_string()))?;
+    let session = ObjectPath::try_from(session.as_str())?;
+
+    let description = fl!("block-enable-all");
+    let shortcuts = [(
+        TOGGLE_ALL,
+        HashMap::from([
+            ("description", Value::from(description.as_str())),
+            ("preferred_trigger", Value::from(TOGGLE_ALL_TRIGGER)),
+        ]),
+    )];
+    let token = "ghaf_kill_switch_bind";
+    call(&connection, token, || {
+        let options = HashMap::from([("handle_token", Value::from(token))]);
+        portal.bind_shortcuts(&session, &shortcuts, "", options)
+    })
+    .await?;
+    log::info!("Global shortcuts bound in portal session {session}");
+
+    while let Some(signal) = activations.next().await {
+        let args = signal.args()?;
+        if *args.session_handle() == session {
+            log::debug!("Shortcut {} activated", args.shortcut_id());
+            activated(args.shortcut_id());
+        }
+    }
+    Err(zbus::Error::Failure(
+        "Portal stopped sending shortcuts".to_string(),
+    ))
+}
+
+/// Runs a portal method answering through the request object named by its
+/// `handle_token`, returning the results.
+///
+/// The request path is known up front, so the response is subscribed to before the
+/// call and cannot be missed.
+async fn call<F>(
+    connection: &zbus::Connection,
+    token: &str,
+    method: impl FnOnce() -> F,
+) -> zbus::Result<HashMap<String, OwnedValue>>
+where
+    F: Future<Output = zbus::Result<OwnedObjectPath>>,
+{
+    let sender = connection
+        .unique_name()
+        .map(|name| name.trim_start_matches(':').replace('.', "_"))
+        .unwrap_or_default();
+    let request = RequestProxy::builder(connection)
+        .path(format!("{REQUEST_PATH}/{sender}/{token}"))?
+        .build()
+        .await?;
+    let mut responses = request.receive_response().await?;
+
+    method().await?;
+    let response = responses
+        .next()
+        .await
+        .ok_or_else(|| zbus::Error::Failure(format!("No response to {token}")))?;
+    let args = response.args()?;
+    match args.response {
+        0 => Ok(args.results),
+        code => Err(zbus::Error::Failure(format!(
+            "Portal refused {token} with code {code}"
+        ))),
+    }
+}
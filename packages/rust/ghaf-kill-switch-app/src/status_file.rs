@@ -0,0 +1,208 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Device states for status bars other than the COSMIC panel, e.g. waybar modules or
+//! i3status scripts.
+//!
+//! Both the applet and the headless mode write the states to
+//! `$XDG_RUNTIME_DIR/ghaf-kill-switch/status.json` on every change, and waybar is sent
+//! `SIGRTMIN+8` so that a custom module re-reads it:
+//!
+//! ```json
+//! "custom/kill-switch": {
+//!     "exec": "cat $XDG_RUNTIME_DIR/ghaf-kill-switch/status.json",
+//!     "return-type": "json",
+//!     "interval": "once",
+//!     "signal": 8
+//! }
+//! ```
+//!
+//! Besides waybar's `text` (blocked devices out of all), `alt`, `class` (`enabled`,
+//! `partial` or `blocked`) and `tooltip`, the file lists the state of each device under
+//! `devices`, by its `ghaf-killswitch` name.
+use crate::{Config, Device, fl};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const STATUS_DIR: &str = "ghaf-kill-switch";
+const STATUS_FILE: &str = "status.json";
+/// Offset from `SIGRTMIN` of the signal making waybar re-read the file
+const WAYBAR_SIGNAL: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum State {
+    Enabled,
+    /// Microphone muted on the audio VM, still available to apps
+    Muted,
+    Blocked,
+    HardBlocked,
+}
+
+impl State {
+    fn of(config: &Config, device: Device) -> Self {
+        if config.is_hard_blocked(device) {
+            State::HardBlocked
+        } else if !config.is_enabled(device) {
+            State::Blocked
+        } else if device == Device::Microphone && config.microphone_muted {
+            State::Muted
+        } else {
+            State::Enabled
+        }
+    }
+
+    fn label(self) -> String {
+        match self {
+            State::Enabled => fl!("enabled"),
+            State::Muted => fl!("muted"),
+            State::Blocked => fl!("disabled"),
+            State::HardBlocked => fl!("hard-blocked"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Status {
+    text: String,
+    alt: &'static str,
+    tooltip: String,
+    class: &'static str,
+    devices: BTreeMap<Device, State>,
+}
+
+impl Status {
+    pub fn new(config: &Config) -> Self {
+        let devices: BTreeMap<_, _> = Device::ALL
+            .into_iter()
+            .map(|device| (device, State::of(config, device)))
+            .collect();
+        let blocked = devices
+            .values()
+            .filter(|&&state| matches!(state, State::Blocked | State::HardBlocked))
+            .count();
+        let class = match blocked {
+            0 => "enabled",
+            n if n == devices.len() => "blocked",
+            _ => "partial",
+        };
+        let tooltip = devices
+            .iter()
+            .map(|(device, state)| format!("{}: {}", device.label(), state.label()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self {
+            text: format!("{blocked}/{}", devices.len()),
+            alt: class,
+            tooltip,
+            class,
+            devices,
+        }
+    }
+
+    /// Replaces the status file, then tells waybar to re-read it.
+    pub fn publish(&self) -> io::Result<()> {
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(|runtime| PathBuf::from(runtime).join(STATUS_DIR))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "XDG_RUNTIME_DIR not set"))?;
+        self.write_in(&dir)?;
+
+        // pkill fails when no waybar is running, which is fine
+        if let Err(e) = Command::new("pkill")
+            .arg(format!("-RTMIN+{WAYBAR_SIGNAL}"))
+            .args(["-x", "waybar"])
+            .status()
+        {
+            log::debug!("Failed to execute pkill to signal waybar: {e}");
+        }
+        Ok(())
+    }
+
+    fn write_in(&self, dir: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        // Readers never see a partly written file
+        let path = dir.join(STATUS_FILE);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+/// Writes the status file for `config`, unless it already shows the `last` status.
+pub fn update(config: &Config, last: &mut Option<Status>) {
+    let status = Status::new(config);
+    if last.as_ref() == Some(&status) {
+        return;
+    }
+    if let Err(e) = status.publish() {
+        log::error!("Failed to write status file: {e}");
+    }
+    *last = Some(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let mut config = Config::default();
+        assert_eq!(State::of(&config, Device::Microphone), State::Enabled);
+        config.microphone_muted = true;
+        assert_eq!(State::of(&config, Device::Microphone), State::Muted);
+        config.set_enabled(Device::Microphone, false);
+        assert_eq!(State::of(&config, Device::Microphone), State::Blocked);
+        // Muting only applies to the microphone
+        assert_eq!(State::of(&config, Device::Camera), State::Enabled);
+        config.set_enabled(Device::WiFi, false);
+        config.hard_blocked.insert(Device::WiFi);
+        assert_eq!(State::of(&config, Device::WiFi), State::HardBlocked);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut config = Config {
+            microphone_muted: true,
+            ..Config::default()
+        };
+        let status = Status::new(&config);
+        assert_eq!(status.text, "0/5");
+        assert_eq!(status.class, "enabled");
+        assert_eq!(status.tooltip.lines().count(), Device::ALL.len());
+
+        config.set_enabled(Device::Camera, false);
+        config.set_enabled(Device::Location, false);
+        config.hard_blocked.insert(Device::Location);
+        let status = Status::new(&config);
+        assert_eq!(status.text, "2/5");
+        assert_eq!((status.alt, status.class), ("partial", "partial"));
+        assert_eq!(status.devices[&Device::Microphone], State::Muted);
+        assert_eq!(status.devices[&Device::Location], State::HardBlocked);
+
+        for device in Device::ALL {
+            config.set_enabled(device, false);
+        }
+        assert_eq!(Status::new(&config).class, "blocked");
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("ks-status-{}", std::process::id()));
+        let mut config = Config::default();
+        config.set_enabled(Device::WiFi, false);
+        Status::new(&config).write_in(&dir).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join(STATUS_FILE)).unwrap()).unwrap();
+        assert_eq!(written["text"], "1/5");
+        assert_eq!(written["class"], "partial");
+        assert_eq!(written["devices"]["net"], "blocked");
+        assert_eq!(written["devices"]["mic"], "enabled");
+        assert!(!dir.join("status.json.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
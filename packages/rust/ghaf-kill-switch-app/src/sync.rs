@@ -0,0 +1,69 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! State synchronization between kill switch instances: applets in several panels,
+//! sessions or seats, the headless service and `ghaf-killswitch-ctl`.
+//!
+//! `ghaf-killswitch` does not announce changes, so whichever instance changes a device
+//! broadcasts `ae.tii.KillSwitch.Sync.Changed` on the system bus, which reaches the
+//! instances of every user. They re-read the states right away instead of on their
+//! next poll. The signal carries no state, a stray one only costs a status read.
+//!
+//! Polling stays in place for changes made around the instances, e.g. by a hardware
+//! switch.
+//!
+//! Announcing and listening share one connection per process, so that an instance can
+//! tell its own announcements apart by their sender. The policy allowing them on the
+//! system bus is `dbus/ae.tii.KillSwitch.Sync.conf`, installed to
+//! `share/dbus-1/system.d`.
+use tokio::sync::OnceCell;
+use zbus::export::ordered_stream::OrderedStreamExt;
+use zbus::message::Type;
+use zbus::{Connection, MatchRule, MessageStream};
+
+const PATH: &str = "/ae/tii/KillSwitch";
+const INTERFACE: &str = "ae.tii.KillSwitch.Sync";
+const CHANGED: &str = "Changed";
+
+static CONNECTION: OnceCell<Connection> = OnceCell::const_new();
+
+/// System bus connection of this process, opened on first use.
+async fn connection() -> zbus::Result<&'static Connection> {
+    CONNECTION.get_or_try_init(Connection::system).await
+}
+
+/// Tells the other instances that the device states changed.
+pub async fn announce() -> zbus::Result<()> {
+    connection()
+        .await?
+        .emit_signal(None::<&str>, PATH, INTERFACE, CHANGED, &())
+        .await
+}
+
+/// Calls `changed` whenever another instance announces a change. Only returns on
+/// errors, e.g. without a system bus.
+pub async fn listen(mut changed: impl FnMut()) -> zbus::Result<()> {
+    let connection = connection().await?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .path(PATH)?
+        .interface(INTERFACE)?
+        .member(CHANGED)?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, connection, None).await?;
+    log::info!("Listening for state changes of other instances");
+
+    while let Some(signal) = signals.next().await {
+        let signal = signal?;
+        // Our own changes are already shown
+        let own = signal.header().sender() == connection.unique_name().map(|n| n.inner());
+        if !own {
+            log::debug!("State change announced by {:?}", signal.header().sender());
+            changed();
+        }
+    }
+    Err(zbus::Error::Failure(
+        "System bus stopped sending state changes".to_string(),
+    ))
+}
@@ -0,0 +1,140 @@
+/*
+ * SPDX-FileCopyrightText: 2025-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Applications currently capturing from the microphone or camera, from `pw-dump`.
+use crate::Device;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::process::Command;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    microphone: BTreeSet<String>,
+    camera: BTreeSet<String>,
+}
+
+impl Usage {
+    /// Queries PipeWire for active capture streams; empty if it is not reachable.
+    pub fn query() -> Self {
+        match Command::new("pw-dump").output() {
+            Ok(output) if output.status.success() => Self::parse(&output.stdout),
+            Ok(output) => {
+                log::debug!(
+                    "pw-dump failed: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                Self::default()
+            }
+            Err(e) => {
+                log::debug!("Failed to execute pw-dump: {e}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Applications holding a capture stream of `device`, if any.
+    pub fn apps(&self, device: Device) -> Option<&BTreeSet<String>> {
+        let apps = match device {
+            Device::Microphone => &self.microphone,
+            Device::Camera => &self.camera,
+            _ => return None,
+        };
+        (!apps.is_empty()).then_some(apps)
+    }
+
+    fn parse(dump: &[u8]) -> Self {
+        let objects: Vec<Value> = serde_json::from_slice(dump).unwrap_or_else(|e| {
+            log::warn!("Ignoring malformed pw-dump output: {e}");
+            Vec::new()
+        });
+
+        let mut usage = Self::default();
+        for object in &objects {
+            if object["type"] != "PipeWire:Interface:Node" {
+                continue;
+            }
+            let info = &object["info"];
+            // Idle and suspended streams hold no device
+            if info["state"] != "running" {
+                continue;
+            }
+            let props = &info["props"];
+            let apps = match props["media.class"].as_str() {
+                Some("Stream/Input/Audio") => &mut usage.microphone,
+                Some("Stream/Input/Video") => &mut usage.camera,
+                _ => continue,
+            };
+            if let Some(name) = [
+                "application.name",
+                "application.process.binary",
+                "node.name",
+            ]
+            .iter()
+            .find_map(|key| props[*key].as_str())
+            {
+                apps.insert(name.to_string());
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(class: &str, state: &str, props: &str) -> String {
+        format!(
+            r#"{{"type": "PipeWire:Interface:Node", "info": {{"state": "{state}",
+                "props": {{"media.class": "{class}", {props}}}}}}}"#
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        let dump = format!(
+            "[{}, {}, {}, {}, {}, {}]",
+            node(
+                "Stream/Input/Audio",
+                "running",
+                r#""application.name": "Firefox""#
+            ),
+            node(
+                "Stream/Input/Video",
+                "running",
+                r#""application.process.binary": "chromium""#
+            ),
+            node(
+                "Stream/Input/Audio",
+                "running",
+                r#""node.name": "recorder""#
+            ),
+            // Idle streams and playback hold no capture device
+            node(
+                "Stream/Input/Audio",
+                "idle",
+                r#""application.name": "Idle""#
+            ),
+            node(
+                "Stream/Output/Audio",
+                "running",
+                r#""application.name": "Player""#
+            ),
+            r#"{"type": "PipeWire:Interface:Client", "info": {}}"#,
+        );
+        let usage = Usage::parse(dump.as_bytes());
+
+        let mic: Vec<_> = usage.apps(Device::Microphone).unwrap().iter().collect();
+        assert_eq!(mic, ["Firefox", "recorder"]);
+        let cam: Vec<_> = usage.apps(Device::Camera).unwrap().iter().collect();
+        assert_eq!(cam, ["chromium"]);
+        assert_eq!(usage.apps(Device::WiFi), None);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert_eq!(Usage::parse(b"not json"), Usage::default());
+        assert_eq!(Usage::parse(b"[]").apps(Device::Camera), None);
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+/// Host-wide memory budget for one monitoring round.
+///
+/// While the host has more than `reserve` bytes available, the surplus is
+/// shared between growing guests in proportion to their pressure. Once it
+/// drops below `reserve`, the shortfall is reclaimed from all guests in
+/// proportion to their slack (`100 - pressure`), so idle guests give up the
+/// most.
+#[derive(Debug)]
+pub struct HostBudget {
+    headroom: usize,
+    deficit: usize,
+    pressure_sum: u64,
+    slack_sum: u64,
+}
+
+impl HostBudget {
+    /// Budget of a host with `available` bytes, for guests at `pressures` percent.
+    pub fn new(available: usize, reserve: usize, pressures: impl IntoIterator<Item = u8>) -> Self {
+        let (pressure_sum, slack_sum) = pressures.into_iter().fold((0, 0), |(p, s), pressure| {
+            let pressure = u64::from(pressure.min(100));
+            (p + pressure, s + 100 - pressure)
+        });
+        Self {
+            headroom: available.saturating_sub(reserve),
+            deficit: reserve.saturating_sub(available),
+            pressure_sum,
+            slack_sum,
+        }
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.deficit > 0
+    }
+
+    /// Limits the balloon `target` of a guest currently at `current` bytes
+    /// and `pressure` percent to its share of the budget.
+    ///
+    /// When reclaiming, the guest is not shrunk below `floor` (the size that
+    /// keeps it at its high pressure mark), nor grown at all.
+    pub fn limit(&self, current: usize, target: usize, pressure: u8, floor: usize) -> usize {
+        let pressure = pressure.min(100);
+        if self.is_short() {
+            let share = proportional(self.deficit, 100 - pressure, self.slack_sum);
+            let shrunk = current.saturating_sub(share).max(floor.min(current));
+            return target.min(shrunk);
+        }
+
+        if target > current {
+            let share = proportional(self.headroom, pressure, self.pressure_sum);
+            return target.min(current.saturating_add(share));
+        }
+        target
+    }
+}
+
+/// `amount * weight / sum`, treating a `sum` below `weight` (a guest without
+/// a previous sample) as `weight`.
+fn proportional(amount: usize, weight: u8, sum: u64) -> usize {
+    let weight = u128::from(weight);
+    let sum = u128::from(sum).max(weight);
+    (amount as u128 * weight)
+        .checked_div(sum)
+        .and_then(|s| usize::try_from(s).ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    #[test]
+    fn test_growth_shared_by_pressure() {
+        let budget = HostBudget::new(1500 * MIB, 500 * MIB, [75, 25]);
+        assert!(!budget.is_short());
+        // 1000 MiB headroom, 3/4 of it to the guest at 75%
+        assert_eq!(budget.limit(1000 * MIB, 4000 * MIB, 75, 0), 1750 * MIB);
+        assert_eq!(budget.limit(1000 * MIB, 4000 * MIB, 25, 0), 1250 * MIB);
+        // Small requests and shrinking are left alone
+        assert_eq!(budget.limit(1000 * MIB, 1100 * MIB, 75, 0), 1100 * MIB);
+        assert_eq!(budget.limit(1000 * MIB, 800 * MIB, 75, 0), 800 * MIB);
+    }
+
+    #[test]
+    fn test_reclaim_shared_by_slack() {
+        let budget = HostBudget::new(100 * MIB, 500 * MIB, [80, 40]);
+        assert!(budget.is_short());
+        // 400 MiB deficit, slack 20 + 60
+        assert_eq!(budget.limit(2000 * MIB, 2000 * MIB, 80, 0), 1900 * MIB);
+        assert_eq!(budget.limit(2000 * MIB, 2000 * MIB, 40, 0), 1700 * MIB);
+        // No growth while short, and never below the floor
+        assert_eq!(budget.limit(2000 * MIB, 3000 * MIB, 40, 0), 1700 * MIB);
+        assert_eq!(
+            budget.limit(2000 * MIB, 2000 * MIB, 40, 1800 * MIB),
+            1800 * MIB
+        );
+        assert_eq!(
+            budget.limit(2000 * MIB, 2000 * MIB, 40, 2500 * MIB),
+            2000 * MIB
+        );
+        // A saturated guest gives nothing up
+        assert_eq!(budget.limit(2000 * MIB, 2000 * MIB, 100, 0), 2000 * MIB);
+    }
+
+    #[test]
+    fn test_unknown_pressures() {
+        let budget = HostBudget::new(1500 * MIB, 500 * MIB, []);
+        assert_eq!(budget.limit(1000 * MIB, 4000 * MIB, 50, 0), 2000 * MIB);
+        let budget = HostBudget::new(0, 500 * MIB, [100]);
+        assert_eq!(budget.limit(1000 * MIB, 1000 * MIB, 100, 0), 1000 * MIB);
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Period over which the events of one type are rate-limited
+pub const EVENT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Which QMP events are logged, and how many of each type per window
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    allow: Vec<String>,
+    rate: u32,
+}
+
+impl EventFilter {
+    /// Logs the event types in `allow`, all when empty, at most `rate` of each type per
+    /// [`EVENT_WINDOW`], all when 0.
+    pub fn new(allow: Vec<String>, rate: u32) -> Self {
+        Self { allow, rate }
+    }
+
+    /// Whether events of type `name` are logged at all.
+    pub fn allows(&self, name: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|a| a == name)
+    }
+}
+
+/// Logged and suppressed events of one type
+#[derive(Debug, Default)]
+struct Counter {
+    logged: u32,
+    suppressed: u64,
+    total_suppressed: u64,
+}
+
+/// Rate limiting state of the events of one connection
+#[derive(Debug)]
+pub struct EventLog {
+    filter: EventFilter,
+    start: Instant,
+    counters: HashMap<String, Counter>,
+}
+
+/// Events of one type suppressed in a window that ended
+#[derive(Debug, PartialEq)]
+pub struct Suppressed {
+    pub name: String,
+    pub count: u64,
+    pub total: u64,
+}
+
+impl EventLog {
+    pub fn new(filter: EventFilter, now: Instant) -> Self {
+        Self {
+            filter,
+            start: now,
+            counters: HashMap::new(),
+        }
+    }
+
+    pub fn filter(&self) -> &EventFilter {
+        &self.filter
+    }
+
+    /// Counts an event of type `name`, returning whether to log it, along with the
+    /// events suppressed in the window that ended before it, if any.
+    pub fn record(&mut self, name: &str, now: Instant) -> (bool, Vec<Suppressed>) {
+        let ended = self.flush(now);
+        let counter = self.counters.entry(name.to_string()).or_default();
+        let log = self.filter.rate == 0 || counter.logged < self.filter.rate;
+        if log {
+            counter.logged += 1;
+        } else {
+            counter.suppressed += 1;
+            counter.total_suppressed += 1;
+        }
+        (log, ended)
+    }
+
+    /// Ends the window once it is over, returning the events suppressed in it, so they
+    /// are reported even when no further event comes.
+    pub fn flush(&mut self, now: Instant) -> Vec<Suppressed> {
+        let mut ended = Vec::new();
+        if now.duration_since(self.start) >= EVENT_WINDOW {
+            self.start = now;
+            for (name, counter) in &mut self.counters {
+                if counter.suppressed > 0 {
+                    ended.push(Suppressed {
+                        name: name.clone(),
+                        count: counter.suppressed,
+                        total: counter.total_suppressed,
+                    });
+                }
+                counter.logged = 0;
+                counter.suppressed = 0;
+            }
+            ended.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        ended
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let all = EventFilter::default();
+        assert!(all.allows("RTC_CHANGE"));
+
+        let filter = EventFilter::new(vec!["BALLOON_CHANGE".to_string()], 0);
+        assert!(filter.allows("BALLOON_CHANGE"));
+        assert!(!filter.allows("RTC_CHANGE"));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut log = EventLog::new(EventFilter::new(Vec::new(), 2), start);
+        assert_eq!(log.record("RTC_CHANGE", start), (true, vec![]));
+        assert_eq!(log.record("RTC_CHANGE", start), (true, vec![]));
+        assert_eq!(log.record("RTC_CHANGE", start), (false, vec![]));
+        assert_eq!(log.record("RTC_CHANGE", start), (false, vec![]));
+        // Other types have their own limit
+        assert_eq!(log.record("NIC_RX_FILTER_CHANGED", start), (true, vec![]));
+
+        let next = start + EVENT_WINDOW;
+        let (logged, ended) = log.record("RTC_CHANGE", next);
+        assert!(logged);
+        assert_eq!(
+            ended,
+            vec![Suppressed {
+                name: "RTC_CHANGE".to_string(),
+                count: 2,
+                total: 2
+            }]
+        );
+        assert_eq!(log.record("RTC_CHANGE", next), (true, vec![]));
+        assert_eq!(log.record("RTC_CHANGE", next), (false, vec![]));
+
+        let (_, ended) = log.record("RTC_CHANGE", next + EVENT_WINDOW);
+        assert_eq!(ended[0].count, 1);
+        assert_eq!(ended[0].total, 3);
+    }
+
+    #[test]
+    fn test_flush() {
+        let start = Instant::now();
+        let mut log = EventLog::new(EventFilter::new(Vec::new(), 1), start);
+        log.record("RTC_CHANGE", start);
+        log.record("RTC_CHANGE", start);
+        assert_eq!(log.flush(start + EVENT_WINDOW / 2), vec![]);
+
+        let ended = log.flush(start + EVENT_WINDOW);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].count, 1);
+        // Reported once only
+        assert_eq!(log.flush(start + EVENT_WINDOW * 2), vec![]);
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let start = Instant::now();
+        let mut log = EventLog::new(EventFilter::default(), start);
+        for _ in 0..100 {
+            assert!(log.record("RTC_CHANGE", start).0);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use crate::qmp::QmpCommand;
+use anyhow::{Context, Result, anyhow, bail};
+use ghaf_mem_manager::socket::parse_size;
+use serde::Deserialize;
+use std::{path::Path, str::FromStr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
+    net::UnixStream,
+    time::{sleep, timeout},
+};
+
+/// Longest time an action may run in the guest
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+const EXEC_POLL: Duration = Duration::from_millis(200);
+
+/// Memory freeing action run in a guest through its qemu-guest-agent, for when
+/// ballooning alone cannot free memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAction {
+    /// Drops the guest's page cache and reclaimable slab objects
+    DropCaches,
+    /// Raises the limit of compressed memory of the guest's zram swap to the given size,
+    /// so more of its memory can be swapped out
+    Zram(usize),
+}
+
+impl GuestAction {
+    fn script(self) -> String {
+        match self {
+            GuestAction::DropCaches => "sync && echo 3 > /proc/sys/vm/drop_caches".to_string(),
+            GuestAction::Zram(size) => format!("echo {size} > /sys/block/zram0/mem_limit"),
+        }
+    }
+}
+
+impl std::fmt::Display for GuestAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestAction::DropCaches => write!(f, "drop-caches"),
+            GuestAction::Zram(size) => write!(f, "zram={size}"),
+        }
+    }
+}
+
+impl FromStr for GuestAction {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        match spec.split_once('=') {
+            None if spec == "drop-caches" => Ok(GuestAction::DropCaches),
+            Some(("zram", size)) => Ok(GuestAction::Zram(parse_size(size)?)),
+            _ => bail!("Unknown guest action `{spec}`, expected `drop-caches` or `zram=SIZE`"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExecStarted {
+    pid: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExecStatus {
+    exited: bool,
+    exitcode: Option<i64>,
+}
+
+/// Runs `action` through the guest agent listening on `socket`.
+pub async fn run(socket: &Path, action: GuestAction) -> Result<()> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .context("Failed to connect to guest agent socket")?;
+    timeout(EXEC_TIMEOUT, exec(stream, &action.script()))
+        .await
+        .map_err(|_| anyhow!("Guest agent timed out"))?
+}
+
+async fn exec<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
+    stream: S,
+    script: &str,
+) -> Result<()> {
+    let mut stream = BufStream::new(stream);
+    // Replies to commands of an earlier, interrupted client may still be queued
+    let id = sync_id();
+    send(&mut stream, QmpCommand::new("guest-sync").arg("id", id)).await?;
+    while receive(&mut stream).await? != id {}
+
+    let cmd = QmpCommand::new("guest-exec")
+        .arg("path", "/bin/sh")
+        .arg("arg", vec!["-c", script])
+        .arg("capture-output", true);
+    send(&mut stream, cmd).await?;
+    let started: ExecStarted = serde_json::from_value(receive(&mut stream).await?)?;
+
+    loop {
+        send(
+            &mut stream,
+            QmpCommand::new("guest-exec-status").arg("pid", started.pid),
+        )
+        .await?;
+        let status: ExecStatus = serde_json::from_value(receive(&mut stream).await?)?;
+        if status.exited {
+            return match status.exitcode {
+                Some(0) => Ok(()),
+                code => bail!("`{script}` failed in guest with exit code {code:?}"),
+            };
+        }
+        sleep(EXEC_POLL).await;
+    }
+}
+
+async fn send<S: AsyncWrite + std::marker::Unpin>(stream: &mut S, cmd: QmpCommand) -> Result<()> {
+    stream.write_all(&serde_json::to_vec(&cmd)?).await?;
+    stream.write_all(b"\n").await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn receive<S: AsyncRead + AsyncWrite + std::marker::Unpin>(
+    stream: &mut BufStream<S>,
+) -> Result<serde_json::Value> {
+    let mut buf = vec![];
+    if stream.read_until(b'\n', &mut buf).await? == 0 {
+        bail!("Guest agent closed the connection");
+    }
+    let serde_json::Value::Object(mut reply) = serde_json::from_slice(&buf)? else {
+        bail!("Unexpected reply type");
+    };
+    if let Some(error) = reply.remove("error") {
+        bail!("Guest agent error: {error}");
+    }
+    reply.remove("return").context("Reply without return value")
+}
+
+/// Sync ids only need to differ between clients, not be unpredictable
+fn sync_id() -> u32 {
+    use std::hash::{BuildHasher, RandomState};
+    RandomState::new().hash_one(std::process::id()) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn agent(
+        mut stream: BufStream<tokio::io::DuplexStream>,
+        exitcode: i64,
+    ) -> Result<Vec<serde_json::Value>> {
+        let mut commands = vec![];
+        let mut polls = 0;
+        loop {
+            let mut buf = vec![];
+            if stream.read_until(b'\n', &mut buf).await? == 0 {
+                return Ok(commands);
+            }
+            let cmd: serde_json::Value = serde_json::from_slice(&buf)?;
+            let reply = match cmd["execute"].as_str() {
+                // A stale reply first, then the synchronization
+                Some("guest-sync") => format!(
+                    "{{\"return\": {{\"pid\": 1}}}}\n{{\"return\": {}}}\n",
+                    cmd["arguments"]["id"]
+                ),
+                Some("guest-exec") => "{\"return\": {\"pid\": 42}}\n".to_string(),
+                Some("guest-exec-status") => {
+                    polls += 1;
+                    if polls < 2 {
+                        "{\"return\": {\"exited\": false}}\n".to_string()
+                    } else {
+                        format!("{{\"return\": {{\"exited\": true, \"exitcode\": {exitcode}}}}}\n")
+                    }
+                }
+                _ => "{\"error\": {\"class\": \"CommandNotFound\"}}\n".to_string(),
+            };
+            commands.push(cmd);
+            stream.write_all(reply.as_bytes()).await?;
+            stream.flush().await?;
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_exec() -> Result<()> {
+        for (exitcode, ok) in [(0, true), (1, false)] {
+            let (client, server) = tokio::io::duplex(4096);
+            let server = tokio::spawn(agent(BufStream::new(server), exitcode));
+            assert_eq!(exec(client, "true").await.is_ok(), ok);
+
+            let commands = server.await??;
+            let names: Vec<_> = commands.iter().map(|c| c["execute"].clone()).collect();
+            assert_eq!(
+                names,
+                [
+                    "guest-sync",
+                    "guest-exec",
+                    "guest-exec-status",
+                    "guest-exec-status"
+                ]
+            );
+            assert_eq!(commands[1]["arguments"]["arg"][1], "true");
+            assert_eq!(commands[2]["arguments"]["pid"], 42);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(
+            "drop-caches".parse::<GuestAction>().unwrap(),
+            GuestAction::DropCaches
+        );
+        assert_eq!(
+            "zram=1G".parse::<GuestAction>().unwrap(),
+            GuestAction::Zram(1 << 30)
+        );
+        assert!("zram".parse::<GuestAction>().is_err());
+        assert!("reboot".parse::<GuestAction>().is_err());
+        assert_eq!(
+            GuestAction::Zram(512).script(),
+            "echo 512 > /sys/block/zram0/mem_limit"
+        );
+    }
+}
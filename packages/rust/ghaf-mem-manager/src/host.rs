@@ -0,0 +1,82 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use anyhow::{Context, Result};
+
+const MEMINFO: &str = "/proc/meminfo";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostMemory {
+    pub total: usize,
+    pub available: usize,
+}
+
+impl HostMemory {
+    pub async fn read() -> Result<Self> {
+        let meminfo = tokio::fs::read_to_string(MEMINFO)
+            .await
+            .with_context(|| format!("Failed to read {MEMINFO}"))?;
+        Self::parse(&meminfo)
+    }
+
+    pub fn parse(meminfo: &str) -> Result<Self> {
+        let field = |name: &str| -> Result<usize> {
+            parse_kib(meminfo, name)
+                .with_context(|| format!("{name} missing or invalid in meminfo"))
+        };
+
+        Ok(Self {
+            total: field("MemTotal")?,
+            available: field("MemAvailable")?,
+        })
+    }
+}
+
+/// Returns in bytes the `name:   1234 kB` field of a `/proc` file such as `meminfo` or
+/// `status`.
+pub fn parse_kib(text: &str, name: &str) -> Option<usize> {
+    let kib: usize = text
+        .lines()
+        .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim_end()
+        .parse()
+        .ok()?;
+    Some(kib.saturating_mul(1024))
+}
+
+impl std::fmt::Display for HostMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} MiB available of {} MiB",
+            self.available / 1024 / 1024,
+            self.total / 1024 / 1024
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16305500 kB\n\
+                       MemFree:         1021312 kB\n\
+                       MemAvailable:    8150812 kB\n\
+                       Buffers:          331436 kB\n";
+        let host = HostMemory::parse(meminfo).unwrap();
+        assert_eq!(host.total, 16_305_500 * 1024);
+        assert_eq!(host.available, 8_150_812 * 1024);
+
+        assert!(HostMemory::parse("MemTotal: 1024 kB\n").is_err());
+        assert!(HostMemory::parse("MemTotal: x kB\nMemAvailable: 1 kB\n").is_err());
+
+        let status = "Name:\tghaf-mem-manager\nVmRSS:\t    4096 kB\nThreads:\t1\n";
+        assert_eq!(parse_kib(status, "VmRSS"), Some(4096 * 1024));
+        assert_eq!(parse_kib("Name:\tkthreadd\n", "VmRSS"), None);
+    }
+}
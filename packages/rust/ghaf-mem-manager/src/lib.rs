@@ -0,0 +1,10 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Balloon policy of the memory manager, shared by the daemon and tooling replaying
+//! recorded guest stats against it.
+pub mod budget;
+pub mod schedule;
+pub mod socket;
+pub mod stats;
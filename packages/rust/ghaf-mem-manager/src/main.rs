@@ -2,41 +2,102 @@
  * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
  * SPDX-License-Identifier: Apache-2.0
  */
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use std::{
-    collections::HashMap,
-    path::PathBuf,
-    time::{Duration, Instant},
-};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 
+mod events;
+mod guest;
+mod host;
+mod overhead;
+mod psi;
 mod qmp;
-use qmp::QmpEndpoint;
+mod reconnect;
+mod stagger;
+use events::{EventFilter, EventLog, Suppressed};
+use ghaf_mem_manager::budget::HostBudget;
+use ghaf_mem_manager::schedule::{self, Policy, WeekTime, Window};
+use ghaf_mem_manager::socket::{self, VmSocket};
+use ghaf_mem_manager::stats::{MemoryStats, Tuning};
+use guest::GuestAction;
+use host::HostMemory;
+use overhead::Load;
+use qmp::{QmpConnection, QmpEndpoint};
+use reconnect::Reconnect;
+use stagger::Stagger;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to QMP socket
+    /// Path to QMP socket, optionally followed by memory bounds for this VM overriding
+    /// `--minimum` and `--maximum`, its guest agent socket and the socket an in-guest
+    /// agent pushes `/proc/pressure/memory` to, e.g.
+    /// `/run/qmp/gui-vm.sock:min=2G,max=8G,agent=/run/qga/gui-vm.sock,psi=/run/psi/gui-vm.sock`
     #[arg(short, long)]
-    socket: Vec<PathBuf>,
+    socket: Vec<VmSocket>,
 
-    /// Monitoring interval in seconds
+    /// Monitoring interval in seconds, also used right after memory events
     #[arg(short, long, default_value_t = 1)]
     interval: u64,
 
+    /// Longest monitoring interval in seconds, reached by doubling the interval while
+    /// guests are settled
+    #[arg(long, default_value_t = 8)]
+    max_interval: u64,
+
+    /// Seconds after which a VM whose pressure stayed stable is considered idle; 0
+    /// keeps all VMs on the common interval
+    #[arg(long, default_value_t = 300)]
+    idle_after: u64,
+
+    /// Monitoring and guest stats interval in seconds of idle VMs, until their pressure
+    /// moves again
+    #[arg(long, default_value_t = 60)]
+    idle_interval: u64,
+
+    /// CPU time the daemon may use, in percent of one CPU; the interval is raised while
+    /// this is exceeded or the host CPU is under pressure
+    #[arg(long, default_value_t = 1.0)]
+    cpu_budget: f64,
+
+    /// Resident memory the daemon may use; the interval is raised while this is exceeded
+    #[arg(long, value_parser = socket::parse_size)]
+    rss_budget: Option<usize>,
+
+    /// Longest monitoring interval in seconds while over the CPU or memory budget
+    #[arg(long, default_value_t = 60)]
+    max_throttle_interval: u64,
+
+    /// Random delay of each VM's polls, in percent of the gap between two VMs; the VMs
+    /// are polled one after another over the interval rather than all at once
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u8).range(0..=100))]
+    jitter: u8,
+
     /// Minimum ballooning interval
     #[arg(short, long, default_value_t = 3)]
     balloon_interval: u64,
 
-    /// Minimum memory size
-    #[arg(short, long, default_value_t = usize::MIN)]
+    /// Minimum memory size, in bytes or with a K, M, G or T suffix
+    #[arg(short, long, default_value_t = usize::MIN, value_parser = socket::parse_size)]
     minimum: usize,
 
-    /// Maximum memory size
-    #[arg(short = 'M', long, default_value_t = usize::MAX)]
+    /// Maximum memory size, in bytes or with a K, M, G or T suffix
+    #[arg(short = 'M', long, default_value_t = usize::MAX, value_parser = socket::parse_size)]
     maximum: usize,
 
+    /// Minimum memory size in percent of the guest total, the stricter of this and
+    /// `--minimum` applies
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    min_percent: Option<u8>,
+
+    /// Maximum memory size in percent of the guest total, the stricter of this and
+    /// `--maximum` applies
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_percent: Option<u8>,
+
     /// Low memory presure
     #[arg(short, long, default_value_t = 70)]
     low: u8,
@@ -44,137 +105,646 @@ struct Args {
     /// High memory pressure
     #[arg(short, long, default_value_t = 80)]
     high: u8,
+
+    /// Margin, in percent, by which the pressure must leave the window before the
+    /// balloon is adjusted
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    hysteresis: u8,
+
+    /// Weight, in percent, of the latest guest stats in the moving average the balloon
+    /// follows; lower values ride out brief spikes, 100 follows every sample
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=100))]
+    smoothing: u8,
+
+    /// Largest balloon change per adjustment, in percent of the guest total; 0 for no
+    /// limit
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    max_step: u8,
+
+    /// Host memory to keep available; enables host-wide balancing of guests
+    #[arg(short = 'r', long, value_parser = socket::parse_size)]
+    host_reserve: Option<usize>,
+
+    /// Time-based policy override, e.g.
+    /// `socket=/run/media.qmp,days=mon-fri,time=22:00-07:00,low=85,high=95,min=1G,max=4G`;
+    /// the first matching window applies
+    #[arg(short, long)]
+    window: Vec<Window>,
+
+    /// Actions run in a guest through its agent when ballooning cannot free memory,
+    /// `drop-caches` or `zram=SIZE` to raise the zram swap limit; none by default
+    #[arg(long, value_delimiter = ',')]
+    guest_actions: Vec<GuestAction>,
+
+    /// Minimum interval in seconds between guest actions in one VM
+    #[arg(long, default_value_t = 300)]
+    guest_action_interval: u64,
+
+    /// Guest memory pressure (PSI `some avg10`, in percent) at which a VM reporting it
+    /// gets memory right away, without waiting for its balloon stats
+    #[arg(long, default_value_t = 10.0)]
+    psi_threshold: f32,
+
+    /// QMP event type to log, e.g. `BALLOON_CHANGE`; all when none is given. Memory
+    /// events trigger an evaluation whether they are logged or not
+    #[arg(long)]
+    event: Vec<String>,
+
+    /// Events of one type logged per minute and VM, further ones are only counted; 0 to
+    /// log all
+    #[arg(long, default_value_t = 10)]
+    event_rate: u32,
+
+    /// Seconds a VM's QMP socket may stay unreachable before an error is logged; 0 to
+    /// only log warnings
+    #[arg(long, default_value_t = 300)]
+    connect_alert: u64,
+}
+
+/// QMP events that change a guest's memory between stats updates
+const MEMORY_EVENTS: [&str; 2] = ["BALLOON_CHANGE", "MEMORY_DEVICE_SIZE_CHANGE"];
+/// Pressure change, in percent, that counts as guest activity
+const PRESSURE_SETTLE: u8 = 5;
+/// Interval for checking whether a balloon device was added to a VM without one
+const BALLOON_REPROBE: Duration = Duration::from_secs(60);
+/// Age after which a guest pressure report no longer counts
+const PSI_MAX_AGE: Duration = Duration::from_secs(5);
+
+/// Per-VM state carried between monitoring rounds
+#[derive(Default)]
+struct VmState {
+    /// Whether the VM has a balloon device, `None` until probed on the connection
+    balloon: Option<Balloon>,
+    last_update: Option<usize>,
+    last_balloon: Option<Instant>,
+    last_pressure: Option<u8>,
+    last_guest_action: Option<Instant>,
+    /// Latest guest pressure report and when it arrived
+    psi: Option<(f32, Instant)>,
+    /// Moving average of the memory used by the guest
+    reserved: Option<f64>,
+    window: Option<usize>,
+    /// Since when the guest has not been active
+    settled_since: Option<Instant>,
+    idle: bool,
+    /// Guest stats interval last set on the connection
+    stats_interval: Option<Duration>,
+    /// Open connection, with its id
+    conn: Option<(QmpConnection, u64)>,
+    /// Failed connection attempts since the last connection
+    reconnect: Reconnect,
 }
 
-#[derive(Debug)]
-struct MemoryStats {
-    balloon_size: usize,
-    base_memory: usize,
-    plugged_memory: usize,
-    total_memory: usize,
-    free_memory: usize,
-    available_memory: usize,
+/// Polling interval, growing while guests are settled and reset on activity.
+///
+/// While the daemon is over its overhead budget, the interval is throttled: it never
+/// drops below `floor`, which doubles up to `ceiling`.
+struct PollInterval {
+    base: Duration,
+    max: Duration,
+    floor: Duration,
+    ceiling: Duration,
+    current: Duration,
 }
 
-impl MemoryStats {
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn pressure(&self) -> u8 {
-        ((201 * self.balloon_size - 200 * self.available_memory) / self.balloon_size / 2) as u8
+impl PollInterval {
+    fn new(base: Duration, max: Duration, ceiling: Duration) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            floor: base,
+            ceiling: ceiling.max(base),
+            current: base,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.floor;
+    }
+
+    fn relax(&mut self) {
+        self.current = (self.current * 2).min(self.max.max(self.floor));
     }
 
-    pub fn reserved(&self) -> usize {
-        self.balloon_size - self.available_memory
+    /// Doubles the floor, returns whether it changed.
+    fn throttle(&mut self) -> bool {
+        let floor = (self.floor * 2).min(self.ceiling);
+        let changed = floor != self.floor;
+        self.floor = floor;
+        self.current = self.current.max(floor);
+        changed
     }
 
-    pub fn adjusted(&self, target: u8) -> usize {
-        self.reserved() * 100 / target as usize
+    /// Halves the floor, returns whether it changed.
+    fn unthrottle(&mut self) -> bool {
+        let floor = (self.floor / 2).max(self.base);
+        let changed = floor != self.floor;
+        self.floor = floor;
+        changed
     }
+}
+
+/// Balloon device presence of a VM
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Balloon {
+    Present,
+    /// Missing, probed again at the given time in case it is hot-added
+    Missing(Instant),
+}
 
-    pub fn window(&self, min: u8, max: u8) -> Option<usize> {
-        let p = self.pressure();
-        if p < min {
-            Some(self.adjusted(min))
-        } else if p > max {
-            Some(self.adjusted(max - 2))
-        } else {
-            None
+/// Reason the monitoring loop woke up for a VM
+enum Wakeup {
+    /// A memory event was received on the connection with the given id
+    Event(usize, u64),
+    /// The connection with the given id was closed
+    Closed(usize, u64, Result<()>),
+    /// The guest reported its memory pressure
+    Pressure(usize, f32),
+}
+
+/// Connects to `qmp` and forwards its memory events as wakeups for `vm`, logging the
+/// events `filter` allows.
+async fn connect(
+    qmp: &QmpEndpoint,
+    vm: usize,
+    id: u64,
+    wakeups: &mpsc::Sender<Wakeup>,
+    filter: &EventFilter,
+) -> Result<QmpConnection> {
+    let (conn, task, mut receiver) = qmp.connect().await?;
+    let events = wakeups.clone();
+    let closed = wakeups.clone();
+    let source = qmp.to_string();
+    let mut log = EventLog::new(filter.clone(), Instant::now());
+    tokio::spawn(async move {
+        let result = tokio::select! {
+            r = task => r,
+            () = async move {
+                // Suppressed events are reported when their window ends, even without
+                // a further event
+                let mut flush = tokio::time::interval(events::EVENT_WINDOW);
+                loop {
+                    let e = tokio::select! {
+                        e = receiver.recv() => match e {
+                            Some(e) => e,
+                            None => break,
+                        },
+                        _ = flush.tick() => {
+                            log_suppressed(&source, log.flush(Instant::now()));
+                            continue;
+                        }
+                    };
+                    let name = e.get("event").and_then(serde_json::Value::as_str).unwrap_or_default();
+                    // Memory events wake the VM up whether they are logged or not. A full
+                    // queue already holds a wakeup, and blocking here would stall the
+                    // connection
+                    if MEMORY_EVENTS.contains(&name) {
+                        if let Err(mpsc::error::TrySendError::Closed(_)) =
+                            events.try_send(Wakeup::Event(vm, id))
+                        {
+                            break;
+                        }
+                    }
+                    if !log.filter().allows(name) {
+                        continue;
+                    }
+                    let (logged, suppressed) = log.record(name, Instant::now());
+                    log_suppressed(&source, suppressed);
+                    if logged {
+                        info!("Got event from {source}: {e:?}");
+                    } else {
+                        debug!("Got event from {source}: {e:?}");
+                    }
+                }
+            } => Ok(()),
+        };
+        let _ = closed.send(Wakeup::Closed(vm, id, result)).await;
+    });
+    Ok(conn)
+}
+
+/// Logs the events from `source` suppressed in a window that ended.
+fn log_suppressed(source: &str, suppressed: Vec<Suppressed>) {
+    for s in suppressed {
+        info!(
+            "Suppressed {} {} events from {source} in the last {}s, {} in total",
+            s.count,
+            s.name,
+            events::EVENT_WINDOW.as_secs(),
+            s.total
+        );
+    }
+}
+
+/// Returns whether the VM connected through `conn` has a balloon device to manage, probing
+/// it once per connection and then every [`BALLOON_REPROBE`] while it is missing.
+async fn probe_balloon(
+    qmp: &QmpEndpoint,
+    balloon: &mut Option<Balloon>,
+    conn: &QmpConnection,
+) -> Result<bool> {
+    match *balloon {
+        Some(Balloon::Present) => return Ok(true),
+        Some(Balloon::Missing(next_probe)) if next_probe > Instant::now() => return Ok(false),
+        _ => {}
+    }
+
+    let present = conn.has_balloon().await?;
+    match (*balloon, present) {
+        (Some(Balloon::Missing(_)), true) => info!("Balloon device added to {qmp}, managing it"),
+        (None, false) => warn!(
+            "{qmp} has no balloon device, leaving it unmanaged and checking again every {}s",
+            BALLOON_REPROBE.as_secs()
+        ),
+        _ => {}
+    }
+    *balloon = Some(if present {
+        Balloon::Present
+    } else {
+        Balloon::Missing(Instant::now() + BALLOON_REPROBE)
+    });
+    Ok(present)
+}
+
+/// Evaluates a VM's latest stats and adjusts its balloon if needed, returning whether
+/// the guest is active, i.e. ballooned or its pressure moved or left the window. When
+/// the limits keep the balloon from relieving the pressure, the guest actions are run
+/// through the VM's `agent`.
+///
+/// A guest reporting pressure stalls above `--psi-threshold` is grown right away, to
+/// the low mark of its balloon stats, and not shrunk until its pressure settles.
+async fn evaluate(
+    args: &Args,
+    qmp: &QmpEndpoint,
+    state: &mut VmState,
+    policy: Policy,
+    budget: Option<&HostBudget>,
+    ival: Duration,
+    agent: Option<&Path>,
+) -> Result<bool> {
+    let conn = state
+        .conn
+        .as_ref()
+        .map(|(conn, _)| conn)
+        .context("Not connected")?;
+    if !probe_balloon(qmp, &mut state.balloon, conn).await? {
+        return Ok(false);
+    }
+    if state.stats_interval != Some(ival) {
+        conn.set_stats_interval(ival).await?;
+        state.stats_interval = Some(ival);
+    }
+    let balloon = conn.query_balloon().await?;
+    let memory = conn.query_memory().await?;
+    let guest_stats = conn.query_stats().await?;
+
+    let spike = state
+        .psi
+        .is_some_and(|(avg10, at)| avg10 >= args.psi_threshold && at.elapsed() < PSI_MAX_AGE);
+    let fresh = state.last_update.replace(guest_stats.last_update) != Some(guest_stats.last_update);
+    // The stats lag behind a spike, so act on the last ones
+    if !fresh && !spike {
+        return Ok(false);
+    }
+    let mut stats = MemoryStats {
+        balloon_size: balloon.actual,
+        base_memory: memory.base_memory,
+        plugged_memory: memory.plugged_memory,
+        total_memory: memory.base_memory.saturating_add(memory.plugged_memory),
+        free_memory: guest_stats.stats.stat_free_memory,
+        available_memory: guest_stats.stats.stat_available_memory,
+    };
+
+    debug!("Stats for {qmp}: {stats}, pressure: {}%", stats.pressure());
+    if !stats.is_valid() {
+        debug!("Skipping inconsistent stats sample for {qmp}");
+        return Ok(false);
+    }
+    if fresh {
+        let reserved = stats.average_reserved(&mut state.reserved, args.smoothing);
+        // A spike is acted on as measured, the average only catches up
+        if !spike {
+            stats.available_memory = stats.balloon_size.saturating_sub(reserved);
         }
     }
+    let pressure = stats.pressure();
+    if args.smoothing < 100 {
+        debug!("Smoothed pressure for {qmp}: {pressure}%");
+    }
+
+    let mut active = spike
+        || stats
+            .window(policy.low, policy.high, args.hysteresis)
+            .is_some()
+        || state
+            .last_pressure
+            .is_none_or(|p| p.abs_diff(pressure) >= PRESSURE_SETTLE);
+    state.last_pressure.replace(pressure);
+
+    let tuning = Tuning {
+        hysteresis: args.hysteresis,
+        min_percent: args.min_percent,
+        max_percent: args.max_percent,
+        max_step: args.max_step,
+    };
+    let target = stats.target(&policy, tuning, spike, budget);
+    if target.is_some_and(|t| t.stuck) {
+        run_guest_actions(args, qmp, &mut state.last_guest_action, agent);
+    }
+
+    let target = target.map(|t| t.size);
+    if let Some(target) = target.filter(|&t| t != stats.balloon_size).filter(|&t| {
+        (spike && t > stats.balloon_size)
+            || state
+                .last_balloon
+                .is_none_or(|l| l.elapsed() >= Duration::from_secs(args.balloon_interval))
+    }) {
+        info!(
+            "Adjusting {qmp} balloon size from {} to {target}",
+            stats.balloon_size
+        );
+        state.last_balloon.replace(Instant::now());
+        conn.balloon(target).await?;
+        active = true;
+    }
+    Ok(active)
 }
 
-impl std::fmt::Display for MemoryStats {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(
-            f,
-            "Memory stats:\n\
-             Balloon size: {} MiB\n\
-             Base memory: {} MiB\n\
-             Plugged memory: {} MiB\n\
-             Total memory: {} MiB\n\
-             Free memory: {} MiB\n\
-             Available memory: {} MiB",
-            self.balloon_size / 1024 / 1024,
-            self.base_memory / 1024 / 1024,
-            self.plugged_memory / 1024 / 1024,
-            self.total_memory / 1024 / 1024,
-            self.free_memory / 1024 / 1024,
-            self.available_memory / 1024 / 1024
-        )
+/// Runs the guest actions in the background, unless they ran recently.
+fn run_guest_actions(
+    args: &Args,
+    qmp: &QmpEndpoint,
+    last: &mut Option<Instant>,
+    agent: Option<&Path>,
+) {
+    let Some(agent) = agent.filter(|_| !args.guest_actions.is_empty()) else {
+        return;
+    };
+    let interval = Duration::from_secs(args.guest_action_interval);
+    if last.is_some_and(|l| l.elapsed() < interval) {
+        return;
     }
+    *last = Some(Instant::now());
+
+    let actions = args.guest_actions.clone();
+    let agent = agent.to_path_buf();
+    let source = qmp.to_string();
+    tokio::spawn(async move {
+        for action in actions {
+            match guest::run(&agent, action).await {
+                Ok(()) => info!("Ran guest action {action} in {source}"),
+                Err(e) => warn!("Guest action {action} in {source} failed: {e}"),
+            }
+        }
+    });
 }
 
 async fn monitor_memory(args: Args) -> Result<()> {
-    let mut qmps: HashMap<_, (_, Option<Instant>)> = args
+    let mut qmps: Vec<_> = args
         .socket
         .iter()
-        .map(|p| (QmpEndpoint::new(p), (None, None)))
+        .map(|s| (QmpEndpoint::new(&s.path), VmState::default()))
         .collect();
-    let dur = Duration::from_secs(args.interval);
-    let bival = Duration::from_secs(args.balloon_interval);
-    let mut ival = tokio::time::interval(dur);
+    let base = Policy {
+        low: args.low,
+        high: args.high,
+        minimum: args.minimum,
+        maximum: args.maximum,
+    };
+    // A window setting one mark only must still fit the other global one
+    if let Some(window) = args.window.iter().find(|w| {
+        let policy = w.apply(base);
+        policy.low > policy.high
+    }) {
+        anyhow::bail!("Window `{window}` puts the low mark above the high one");
+    }
+    let mut ival = PollInterval::new(
+        Duration::from_secs(args.interval),
+        Duration::from_secs(args.max_interval),
+        Duration::from_secs(args.max_throttle_interval),
+    );
+    let event_filter = EventFilter::new(args.event.clone(), args.event_rate);
+    let mut overhead = overhead::Budget::new(args.cpu_budget, args.rss_budget);
+    let (wakeup_tx, mut wakeups) = mpsc::channel(16);
+    let mut next_id = 0;
+    let mut stagger = Stagger::new(qmps.len(), ival.current, args.jitter, Instant::now());
+    let mut round_active = false;
     let mut errors = 0;
-    ival.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    for (vm, socket) in args.socket.iter().enumerate() {
+        let Some(path) = socket.psi.clone() else {
+            continue;
+        };
+        let wakeups = wakeup_tx.clone();
+        tokio::spawn(async move {
+            psi::follow(&path, |avg10| {
+                !matches!(
+                    wakeups.try_send(Wakeup::Pressure(vm, avg10)),
+                    Err(mpsc::error::TrySendError::Closed(_))
+                )
+            })
+            .await;
+        });
+    }
 
     loop {
-        ival.tick().await;
-        for (qmp, (last, last_balloon)) in &mut qmps {
-            let (conn, task, mut receiver) = match qmp.connect().await {
-                Ok(ctr) => ctr,
+        // Evaluate the VMs whose turn it is to be polled, and those with memory events
+        let next_poll = stagger.next();
+        let mut wakeup = tokio::select! {
+            () = async {
+                match next_poll {
+                    Some(next) => tokio::time::sleep_until(next.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => None,
+            w = wakeups.recv() => w,
+        };
+        let scheduled = stagger.due(Instant::now());
+        let mut due = scheduled.clone();
+        while let Some(w) = wakeup {
+            match w {
+                Wakeup::Event(vm, id) => {
+                    if qmps[vm].1.conn.as_ref().is_some_and(|(_, c)| *c == id) {
+                        due[vm] = true;
+                    }
+                }
+                Wakeup::Closed(vm, id, result) => {
+                    let (qmp, state) = &mut qmps[vm];
+                    if state.conn.as_ref().is_some_and(|(_, c)| *c == id) {
+                        state.conn = None;
+                        match result {
+                            Ok(()) => warn!("Connection to {qmp} closed, reconnecting later"),
+                            Err(e) => warn!("Connection to {qmp} lost: {e}, reconnecting later"),
+                        }
+                    }
+                }
+                Wakeup::Pressure(vm, avg10) => {
+                    let (qmp, state) = &mut qmps[vm];
+                    // Only a rising edge is urgent, the polls follow up on the rest
+                    let rising = avg10 >= args.psi_threshold
+                        && state.psi.is_none_or(|(p, _)| p < args.psi_threshold);
+                    state.psi = Some((avg10, Instant::now()));
+                    if rising {
+                        info!("Memory pressure spike in {qmp}: {avg10}% stalled, reacting now");
+                        due[vm] = true;
+                    }
+                }
+            }
+            wakeup = wakeups.try_recv().ok();
+        }
+        if !due.contains(&true) {
+            continue;
+        }
+
+        let budget = match args.host_reserve {
+            Some(reserve) => match HostMemory::read().await {
+                Ok(host) => {
+                    let budget = HostBudget::new(
+                        host.available,
+                        reserve,
+                        qmps.iter().filter_map(|(_, q)| q.last_pressure),
+                    );
+                    debug!("Host memory: {host}, {budget:?}");
+                    Some(budget)
+                }
                 Err(e) => {
-                    warn!("Connection to {qmp} failed: {e}, trying again later",);
-                    continue;
+                    warn!("Host memory unavailable: {e}, balancing guests independently");
+                    None
                 }
+            },
+            None => None,
+        };
+
+        // Memory events count as activity
+        let mut active = due.iter().zip(&scheduled).any(|(&d, &s)| d && !s);
+        let now = WeekTime::now();
+        let idle_after = Duration::from_secs(args.idle_after);
+        let connect_alert = Some(Duration::from_secs(args.connect_alert)).filter(|d| !d.is_zero());
+        for (vm, (qmp, state)) in qmps.iter_mut().enumerate() {
+            if !due[vm] {
+                continue;
+            }
+            let vm_ival = if state.idle {
+                ival.current.max(Duration::from_secs(args.idle_interval))
+            } else {
+                ival.current
             };
-            if let Err(e) = tokio::select! {
-                e = async {
-                    conn.set_stats_interval(dur).await?;
-                    let balloon = conn.query_balloon().await?;
-                    let memory = conn.query_memory().await?;
-                    let guest_stats = conn.query_stats().await?;
-
-                    if last.replace(guest_stats.last_update) != Some(guest_stats.last_update) {
-                        let stats = MemoryStats {
-                            balloon_size: balloon.actual,
-                            base_memory: memory.base_memory,
-                            plugged_memory: memory.plugged_memory,
-                            total_memory: memory.base_memory + memory.plugged_memory,
-                            free_memory: guest_stats.stats.stat_free_memory,
-                            available_memory: guest_stats.stats.stat_available_memory,
-                        };
-
-                        debug!("Stats for {qmp}: {stats}, pressure: {}%", stats.pressure());
-                        if let Some(target) = stats
-                            .window(args.low, args.high)
-                            .map(|t| t.clamp(args.minimum, args.maximum))
-                            .filter(|&t| t != stats.balloon_size)
-                            .filter(|_| last_balloon.is_none_or(|l| l.elapsed() >= bival))
-                        {
-                            info!("Adjusting {qmp} balloon size from {} to {target}",
-                                stats.balloon_size);
-                            last_balloon.replace(Instant::now());
-                            conn.balloon(target).await?;
+            if scheduled[vm] {
+                stagger.polled(vm, vm_ival, Instant::now());
+            }
+            let window = schedule::active(&args.window, qmp.path(), now);
+            if window.map(|(i, _)| i) != state.window {
+                match window {
+                    Some((_, window)) => info!("Entering policy window `{window}` for {qmp}"),
+                    None => info!("Leaving policy windows for {qmp}, back to default policy"),
+                }
+                state.window = window.map(|(i, _)| i);
+            }
+            let vm_base = args.socket[vm].apply(base);
+            let policy = window.map_or(vm_base, |(_, w)| w.apply(vm_base));
+
+            if state.conn.is_none() {
+                // Retried on a schedule depending on why the last attempt failed
+                let now = Instant::now();
+                if !state.reconnect.is_due(now) {
+                    continue;
+                }
+                match connect(qmp, vm, next_id, &wakeup_tx, &event_filter).await {
+                    Ok(conn) => {
+                        state.reconnect.connected(qmp.path(), now);
+                        state.conn = Some((conn, next_id));
+                        // The VM may have been restarted with different devices
+                        // and workload
+                        state.balloon = None;
+                        state.reserved = None;
+                        state.stats_interval = None;
+                        state.settled_since = None;
+                        if std::mem::take(&mut state.idle) {
+                            stagger.set_idle(vm, false);
                         }
                     }
-                    Ok(())
-                } => e,
-                e = task => e,
-                () = {
-                    async move {
-                        while let Some(e) = receiver.recv().await {
-                            info!("Got event: {e:?}");
+                    Err(e) => {
+                        state.reconnect.failed(qmp.path(), &e, connect_alert, now);
+                        continue;
+                    }
+                }
+                next_id += 1;
+            }
+
+            let agent = args.socket[vm].agent.as_deref();
+            match evaluate(&args, qmp, state, policy, budget.as_ref(), vm_ival, agent).await {
+                Ok(vm_active) => {
+                    active |= vm_active;
+                    errors = 0;
+                    // Memory events and pressure reports count as activity too
+                    if vm_active || !scheduled[vm] {
+                        state.settled_since = Some(Instant::now());
+                        if std::mem::take(&mut state.idle) {
+                            info!(
+                                "{qmp} is active again, polling every {}s",
+                                ival.current.as_secs()
+                            );
+                            stagger.set_idle(vm, false);
                         }
+                    } else if !state.idle
+                        && !idle_after.is_zero()
+                        && state
+                            .settled_since
+                            .get_or_insert_with(Instant::now)
+                            .elapsed()
+                            >= idle_after
+                    {
+                        info!(
+                            "{qmp} settled for {}s, polling every {}s",
+                            idle_after.as_secs(),
+                            args.idle_interval.max(ival.current.as_secs())
+                        );
+                        state.idle = true;
+                        stagger.set_idle(vm, true);
                     }
-                } => Ok(()),
-            } {
-                errors += 1;
-                if errors >= 5 {
-                    Err(e)?;
-                } else {
-                    warn!("Got error {e} with {qmp} for the {errors}th time");
                 }
-            } else {
-                errors = 0;
+                Err(e) => {
+                    state.conn = None;
+                    errors += 1;
+                    if errors >= 5 {
+                        Err(e)?;
+                    } else {
+                        warn!("Got error {e} with {qmp} for the {errors}th time");
+                    }
+                }
+            }
+        }
+
+        round_active |= active;
+        if active {
+            ival.reset();
+            stagger.reset(ival.current, Instant::now());
+        }
+        if stagger.round_complete() {
+            match overhead.check().await {
+                Ok(Load::Over(excess)) => {
+                    if ival.throttle() {
+                        info!(
+                            "Over overhead budget with {excess}, polling at most every {}s",
+                            ival.floor.as_secs()
+                        );
+                    }
+                }
+                Ok(Load::Low) => {
+                    if ival.unthrottle() {
+                        info!(
+                            "Overhead within budget, polling at most every {}s",
+                            ival.floor.as_secs()
+                        );
+                    }
+                }
+                Ok(Load::Within) => (),
+                Err(e) => debug!("Overhead unavailable: {e}"),
+            }
+            if !std::mem::take(&mut round_active) {
+                ival.relax();
             }
+            debug!("Polling every {}s", ival.current.as_secs());
         }
     }
 }
@@ -185,3 +755,102 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     monitor_memory(args).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_memory_event_wakeup() -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+
+        let tmpd = tempfile::tempdir()?;
+        let sockpath = tmpd.path().join("socket");
+        let listener = tokio::net::UnixListener::bind(&sockpath)?;
+        let (wakeup_tx, mut wakeups) = mpsc::channel(16);
+
+        tokio::select! {
+            e = async move {
+                let (server, _) = listener.accept().await?;
+                let mut server = BufStream::new(server);
+                server.write_all(b"{}\n").await?;
+                server.flush().await?;
+                server.read_line(&mut String::new()).await?;
+                server.write_all(b"{}\n{\"event\":\"RESET\"}\n").await?;
+                server.write_all(b"{\"event\":\"BALLOON_CHANGE\",\"data\":{}}\n").await?;
+                server.flush().await?;
+                std::future::pending::<()>().await;
+                unreachable!();
+            } => e,
+            e = async move {
+                let _conn = connect(
+                    &QmpEndpoint::new(sockpath),
+                    3,
+                    7,
+                    &wakeup_tx,
+                    &EventFilter::default(),
+                )
+                .await?;
+                match wakeups.recv().await {
+                    Some(Wakeup::Event(3, 7)) => Ok(()),
+                    _ => anyhow::bail!("Expected a memory event wakeup"),
+                }
+            } => e,
+            () = tokio::time::sleep(Duration::from_secs(5)) => anyhow::bail!("Timed out"),
+        }
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let mut ival = PollInterval::new(
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        );
+        ival.relax();
+        ival.relax();
+        assert_eq!(ival.current, Duration::from_secs(4));
+        ival.relax();
+        assert_eq!(ival.current, Duration::from_secs(5));
+        ival.reset();
+        assert_eq!(ival.current, Duration::from_secs(1));
+
+        // A maximum below the base interval disables backing off
+        let mut ival = PollInterval::new(
+            Duration::from_secs(2),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+        ival.relax();
+        assert_eq!(ival.current, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_poll_interval_throttle() {
+        let mut ival = PollInterval::new(
+            Duration::from_secs(1),
+            Duration::from_secs(4),
+            Duration::from_secs(16),
+        );
+        assert!(ival.throttle());
+        assert!(ival.throttle());
+        assert_eq!(ival.current, Duration::from_secs(4));
+        assert!(ival.throttle());
+        assert!(ival.throttle());
+        assert!(!ival.throttle());
+        assert_eq!(ival.current, Duration::from_secs(16));
+
+        // Activity resets to the throttled floor, not the base interval
+        ival.reset();
+        assert_eq!(ival.current, Duration::from_secs(16));
+        ival.relax();
+        assert_eq!(ival.current, Duration::from_secs(16));
+
+        assert!(ival.unthrottle());
+        ival.reset();
+        assert_eq!(ival.current, Duration::from_secs(8));
+        while ival.unthrottle() {}
+        ival.reset();
+        assert_eq!(ival.current, Duration::from_secs(1));
+    }
+}
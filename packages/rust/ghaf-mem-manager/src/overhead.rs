@@ -0,0 +1,180 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use crate::{host, psi};
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+
+const SCHEDSTAT: &str = "/proc/self/schedstat";
+const STATUS: &str = "/proc/self/status";
+const CPU_PRESSURE: &str = "/proc/pressure/cpu";
+
+/// Host CPU pressure (`some avg10`, in percent) above which polling backs off
+const HOST_CPU_PRESSURE: f64 = 80.0;
+
+/// Resource usage of the daemon itself at one point in time
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    cpu: Duration,
+    rss: usize,
+    at: Instant,
+}
+
+impl Usage {
+    pub async fn read() -> Result<Self> {
+        let schedstat = tokio::fs::read_to_string(SCHEDSTAT)
+            .await
+            .with_context(|| format!("Failed to read {SCHEDSTAT}"))?;
+        let cpu = schedstat
+            .split_whitespace()
+            .next()
+            .and_then(|ns| ns.parse().ok())
+            .with_context(|| format!("Invalid {SCHEDSTAT}"))?;
+        let status = tokio::fs::read_to_string(STATUS)
+            .await
+            .with_context(|| format!("Failed to read {STATUS}"))?;
+        Ok(Self {
+            cpu: Duration::from_nanos(cpu),
+            rss: host::parse_kib(&status, "VmRSS")
+                .with_context(|| format!("VmRSS missing from {STATUS}"))?,
+            at: Instant::now(),
+        })
+    }
+}
+
+/// Why the daemon should poll less often
+#[derive(Debug, Clone, PartialEq)]
+pub enum Excess {
+    /// Own CPU use, in percent of one CPU
+    Cpu(f64),
+    /// Own resident memory, in bytes
+    Memory(usize),
+    /// Host CPU pressure, in percent
+    HostPressure(f64),
+}
+
+impl std::fmt::Display for Excess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cpu(percent) => write!(f, "daemon CPU use at {percent:.2}%"),
+            Self::Memory(rss) => write!(f, "daemon memory use at {} KiB", rss / 1024),
+            Self::HostPressure(avg10) => write!(f, "host CPU pressure at {avg10:.1}%"),
+        }
+    }
+}
+
+/// Daemon overhead relative to its budget
+#[derive(Debug, Clone, PartialEq)]
+pub enum Load {
+    /// Over budget, polling should back off
+    Over(Excess),
+    /// Within budget
+    Within,
+    /// Within half of the budget, so polling twice as often would still fit
+    Low,
+}
+
+/// Budget for the daemon's own resource use
+pub struct Budget {
+    /// Percent of one CPU
+    pub cpu: f64,
+    /// Resident memory in bytes
+    pub rss: Option<usize>,
+    last: Option<Usage>,
+}
+
+impl Budget {
+    pub fn new(cpu: f64, rss: Option<usize>) -> Self {
+        Self {
+            cpu,
+            rss,
+            last: None,
+        }
+    }
+
+    /// Samples usage since the previous call and compares it against the budget.
+    pub async fn check(&mut self) -> Result<Load> {
+        let usage = Usage::read().await?;
+        let host_pressure = read_cpu_pressure().await;
+        Ok(self.assess(usage, host_pressure))
+    }
+
+    fn assess(&mut self, usage: Usage, host_pressure: Option<f64>) -> Load {
+        let Some(last) = self.last.replace(usage) else {
+            return Load::Within;
+        };
+        let wall = usage.at.duration_since(last.at).as_secs_f64();
+        if wall <= 0.0 {
+            return Load::Within;
+        }
+        let cpu = usage.cpu.saturating_sub(last.cpu).as_secs_f64() * 100.0 / wall;
+
+        if cpu > self.cpu {
+            Load::Over(Excess::Cpu(cpu))
+        } else if self.rss.is_some_and(|rss| usage.rss > rss) {
+            Load::Over(Excess::Memory(usage.rss))
+        } else if let Some(p) = host_pressure.filter(|&p| p > HOST_CPU_PRESSURE) {
+            Load::Over(Excess::HostPressure(p))
+        } else if cpu * 2.0 <= self.cpu {
+            // Memory use hardly depends on the polling interval
+            Load::Low
+        } else {
+            Load::Within
+        }
+    }
+}
+
+/// Reads `some avg10` from the host CPU pressure, `None` without PSI support.
+async fn read_cpu_pressure() -> Option<f64> {
+    let pressure = tokio::fs::read_to_string(CPU_PRESSURE).await.ok()?;
+    pressure.lines().find_map(psi::parse).map(f64::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn usage(cpu_ms: u64, rss: usize, at: Instant) -> Usage {
+        Usage {
+            cpu: Duration::from_millis(cpu_ms),
+            rss,
+            at,
+        }
+    }
+
+    #[test]
+    fn test_assess() {
+        let mut budget = Budget::new(1.0, Some(8 << 20));
+        let start = Instant::now();
+        // The first sample only sets the baseline
+        assert_eq!(budget.assess(usage(0, 1 << 20, start), None), Load::Within);
+
+        // 3ms of CPU in one second is 0.3%
+        let at = start + Duration::from_secs(1);
+        assert_eq!(budget.assess(usage(3, 1 << 20, at), Some(10.0)), Load::Low);
+
+        // 8ms in the next second is 0.8%
+        let at = at + Duration::from_secs(1);
+        assert_eq!(budget.assess(usage(11, 1 << 20, at), None), Load::Within);
+
+        // 50ms in the next second is 5%
+        let at = at + Duration::from_secs(1);
+        assert_eq!(
+            budget.assess(usage(61, 1 << 20, at), None),
+            Load::Over(Excess::Cpu(5.0))
+        );
+
+        let at = at + Duration::from_secs(1);
+        assert_eq!(
+            budget.assess(usage(61, 16 << 20, at), None),
+            Load::Over(Excess::Memory(16 << 20))
+        );
+
+        let at = at + Duration::from_secs(1);
+        assert_eq!(
+            budget.assess(usage(61, 1 << 20, at), Some(90.0)),
+            Load::Over(Excess::HostPressure(90.0))
+        );
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::path::Path;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::UnixStream,
+    time::sleep,
+};
+use tracing::{debug, info};
+
+/// Delay before connecting again to an agent that is gone
+const RECONNECT: Duration = Duration::from_secs(5);
+
+/// Returns the `some avg10` share of a `/proc/pressure/*` line, the percentage of the
+/// last 10 seconds in which at least one task stalled on the resource.
+pub fn parse(line: &str) -> Option<f32> {
+    line.trim()
+        .strip_prefix("some ")?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+        .filter(|avg10: &f32| (0.0..=100.0).contains(avg10))
+}
+
+/// Follows the pressure reports an in-guest agent pushes over the virtio-serial port or
+/// vsock proxy at `path`, calling `report` with each `some avg10` until it returns
+/// false. The agent only has to copy `/proc/pressure/memory` to its port periodically;
+/// other lines are ignored.
+pub async fn follow(path: &Path, mut report: impl FnMut(f32) -> bool) {
+    let mut connected = false;
+    loop {
+        match UnixStream::connect(path).await {
+            Ok(stream) => {
+                if !connected {
+                    info!("Reading guest memory pressure from {}", path.display());
+                    connected = true;
+                }
+                let mut lines = BufReader::new(stream).lines();
+                loop {
+                    match lines.next_line().await {
+                        Ok(Some(line)) => {
+                            if let Some(avg10) = parse(&line) {
+                                if !report(avg10) {
+                                    return;
+                                }
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            debug!("Reading pressure from {} failed: {e}", path.display());
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => debug!("Pressure agent at {} unavailable: {e}", path.display()),
+        }
+        sleep(RECONNECT).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("some avg10=12.50 avg60=3.10 avg300=0.70 total=123456\n"),
+            Some(12.5)
+        );
+        assert_eq!(
+            parse("full avg10=4.00 avg60=1.00 avg300=0.20 total=4567"),
+            None
+        );
+        assert_eq!(parse("some avg60=3.10"), None);
+        assert_eq!(parse("some avg10=abc"), None);
+        assert_eq!(parse("some avg10=250.00"), None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_follow() -> anyhow::Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let sockpath = tmpd.path().join("psi");
+        let listener = tokio::net::UnixListener::bind(&sockpath)?;
+        let agent = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            stream
+                .write_all(
+                    b"some avg10=1.00 avg60=0.00 avg300=0.00 total=1\n\
+                      full avg10=0.50 avg60=0.00 avg300=0.00 total=1\n\
+                      some avg10=30.00 avg60=5.00 avg300=1.00 total=2\n",
+                )
+                .await?;
+            std::future::pending::<()>().await;
+            anyhow::Ok(())
+        });
+
+        let mut reports = vec![];
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            follow(&sockpath, |avg10| {
+                reports.push(avg10);
+                reports.len() < 2
+            }),
+        )
+        .await?;
+        assert_eq!(reports, [1.0, 30.0]);
+        agent.abort();
+        Ok(())
+    }
+}
@@ -4,7 +4,12 @@
 */
 use anyhow::{Context, anyhow, bail};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, result::Result as StdResult, time::Duration};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    result::Result as StdResult,
+    time::Duration,
+};
 use tokio::{
     io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
     net::UnixStream,
@@ -66,6 +71,11 @@ pub struct GuestMemoryInfo {
     pub stats: GuestMemoryStats,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ObjectProperty {
+    pub name: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct Empty {}
 
@@ -125,6 +135,10 @@ impl QmpEndpoint {
         Self { path: path.into() }
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub async fn connect(
         &self,
     ) -> Result<(
@@ -200,11 +214,14 @@ impl QmpConnection {
                             tx.replace((newtx, sleep(TIMEOUT)));
                         },
                         res = async {
-                            while let Ok(resp) = stream.get_json().await {
-                                let QmpResponse::Event(e) = resp else { continue; };
-                                evsender.send(e).await?;
+                            loop {
+                                match stream.get_json().await {
+                                    Ok(QmpResponse::Event(e)) => evsender.send(e).await?,
+                                    Ok(_) => continue,
+                                    // Including the peer hanging up, which ends the connection
+                                    Err(e) => break Err(e),
+                                }
                             }
-                            Result::Ok(())
                         } => res?,
                     }
                 }
@@ -221,7 +238,7 @@ impl QmpConnection {
             rx.recv()
                 .await
                 .context("Invalid response")?
-                .map_err(|e| anyhow!("{}", e.to_string()))?,
+                .map_err(|e| anyhow!("{e}"))?,
         )?)
     }
 
@@ -248,6 +265,13 @@ impl QmpConnection {
         self.send_command::<Empty>(cmd).await.map(|_| ())
     }
 
+    /// Returns whether the VM has the `balloon0` device all other balloon commands use.
+    pub async fn has_balloon(&self) -> Result<bool> {
+        let cmd = QmpCommand::new("qom-list").arg("path", "/machine/peripheral");
+        let devices: Vec<ObjectProperty> = self.send_command(cmd).await?;
+        Ok(devices.iter().any(|d| d.name == "balloon0"))
+    }
+
     pub async fn query_stats(&self) -> Result<GuestMemoryInfo> {
         let cmd = QmpCommand::new("qom-get")
             .arg("path", "/machine/peripheral/balloon0")
@@ -267,6 +291,8 @@ mod test {
     const EMPTY_JSON: &[u8] = b"{}\n";
     const ERROR_JSON: &[u8] = b"{\"error\":\"something\"}\n";
     const BALLOON_RETURN_JSON: &[u8] = b"{\"return\":{\"actual\":123}}\n";
+    const PERIPHERALS_JSON: &[u8] = b"{\"return\":[{\"name\":\"type\",\"type\":\"string\"},\
+        {\"name\":\"balloon0\",\"type\":\"child<virtio-balloon-pci>\"}]}\n";
 
     async fn read_json_line<S: AsyncRead + std::marker::Unpin>(
         stream: &mut S,
@@ -299,7 +325,7 @@ mod test {
     }
 
     async fn harness(
-        fs: impl AsyncFnOnce(tokio::io::DuplexStream) -> anyhow::Result<()>,
+        fs: impl AsyncFnOnce(&mut tokio::io::DuplexStream) -> anyhow::Result<()>,
         fc: impl AsyncFnOnce(QmpConnection, mpsc::Receiver<serde_json::Value>) -> anyhow::Result<()>,
         timeout: Duration,
     ) -> anyhow::Result<()> {
@@ -315,7 +341,8 @@ mod test {
 
         tokio::select! {
             e = async move {
-                fs(server).await?;
+                // The server stays connected, a hangup ends the connection
+                fs(&mut server).await?;
                 std::future::pending::<()>().await;
                 unreachable!();
             } => e,
@@ -426,6 +453,22 @@ mod test {
         }
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_peer_hangup() -> anyhow::Result<()> {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let (handshake, connection) =
+            tokio::join!(handshake(&mut server), QmpConnection::new(client));
+        handshake?;
+        let (_client, task, _ev) = connection?;
+        drop(server);
+
+        match tokio::time::timeout(TIMEOUT_SLOW, task).await {
+            Err(_) => bail!("Hangup not noticed"),
+            Ok(Ok(())) => bail!("Connection ended without an error"),
+            Ok(Err(_)) => Ok(()),
+        }
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_query_command() -> anyhow::Result<()> {
         harness(
@@ -458,6 +501,39 @@ mod test {
         .await
     }
 
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_has_balloon() -> anyhow::Result<()> {
+        for (reply, expected) in [
+            (PERIPHERALS_JSON, true),
+            (
+                b"{\"return\":[{\"name\":\"type\",\"type\":\"string\"}]}\n".as_slice(),
+                false,
+            ),
+        ] {
+            harness(
+                async move |mut server| {
+                    let cmd = read_json_line(&mut server).await?;
+                    if cmd["execute"] != "qom-list"
+                        || cmd["arguments"]["path"] != "/machine/peripheral"
+                    {
+                        bail!("Missing or unexpected command");
+                    }
+                    server.write_all(reply).await?;
+                    Ok(())
+                },
+                async move |client, _| {
+                    if client.has_balloon().await? != expected {
+                        bail!("Unexpected balloon presence");
+                    }
+                    Ok(())
+                },
+                TIMEOUT_SLOW,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
     #[tokio::test(flavor = "current_thread")]
     async fn test_command_error() -> anyhow::Result<()> {
         harness(
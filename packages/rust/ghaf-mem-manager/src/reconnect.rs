@@ -0,0 +1,256 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::fmt;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Why connecting to a QMP socket failed, each cause being retried on its own schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// No socket, e.g. QEMU not started yet
+    Missing,
+    /// Socket not accessible to the daemon, which only a reconfiguration fixes
+    Permission,
+    /// Nobody listening, e.g. QEMU starting up or a stale socket
+    Refused,
+    Other,
+}
+
+impl Failure {
+    pub fn of(e: &anyhow::Error) -> Self {
+        let kind = e
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .map(io::Error::kind);
+        match kind {
+            Some(io::ErrorKind::NotFound) => Failure::Missing,
+            Some(io::ErrorKind::PermissionDenied) => Failure::Permission,
+            Some(io::ErrorKind::ConnectionRefused) => Failure::Refused,
+            _ => Failure::Other,
+        }
+    }
+
+    /// First and longest delay between attempts
+    fn backoff(self) -> (Duration, Duration) {
+        match self {
+            Failure::Missing => (Duration::from_secs(1), Duration::from_secs(10)),
+            Failure::Refused => (Duration::from_secs(1), Duration::from_secs(30)),
+            Failure::Permission => (Duration::from_secs(10), Duration::from_secs(300)),
+            Failure::Other => (Duration::from_secs(2), Duration::from_secs(60)),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::Missing => "socket missing",
+            Failure::Permission => "permission denied",
+            Failure::Refused => "connection refused",
+            Failure::Other => "connection failed",
+        })
+    }
+}
+
+/// Connection attempts to a QMP socket since it was last connected.
+///
+/// Failures back off per cause, and only the first one of a cause is logged as a
+/// warning. An outage lasting longer than the alert period is logged as an error, once.
+#[derive(Debug, Default)]
+pub struct Reconnect {
+    failure: Option<Failure>,
+    /// Failed attempts in a row with the current cause
+    attempts: u32,
+    /// First failure of the outage
+    since: Option<Instant>,
+    retry_at: Option<Instant>,
+    alerted: bool,
+}
+
+impl Reconnect {
+    /// Whether to try connecting again at `now`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    /// Records the failed attempt to connect to `path` at `now`, escalating an outage
+    /// longer than `alert_after`.
+    pub fn failed(
+        &mut self,
+        path: &Path,
+        e: &anyhow::Error,
+        alert_after: Option<Duration>,
+        now: Instant,
+    ) {
+        let failure = Failure::of(e);
+        let changed = self.failure != Some(failure);
+        if changed {
+            self.failure = Some(failure);
+            self.attempts = 0;
+        }
+        self.attempts = self.attempts.saturating_add(1);
+        let since = *self.since.get_or_insert(now);
+        let delay = self.delay();
+        self.retry_at = Some(now + delay);
+
+        let qmp = path.display();
+        let retry = delay.as_secs();
+        if changed && failure == Failure::Permission {
+            warn!(
+                "Connection to {qmp} failed: {e}, {}, retrying in {retry}s",
+                permission_hint(path)
+            );
+        } else if changed {
+            warn!("Connection to {qmp} failed: {e}, retrying in {retry}s");
+        } else {
+            debug!("Connection to {qmp} failed again: {e}, retrying in {retry}s");
+        }
+
+        let down = now.duration_since(since);
+        if !self.alerted && alert_after.is_some_and(|after| down >= after) {
+            error!(
+                "{qmp} unreachable for {}s ({failure}), its memory is not managed",
+                down.as_secs()
+            );
+            self.alerted = true;
+        }
+    }
+
+    /// Records a connection to `path`, ending the outage.
+    pub fn connected(&mut self, path: &Path, now: Instant) {
+        if let Some(since) = self.since {
+            info!(
+                "Connected to {} after being unreachable for {}s",
+                path.display(),
+                now.duration_since(since).as_secs()
+            );
+        }
+        *self = Self::default();
+    }
+
+    fn delay(&self) -> Duration {
+        let (first, max) = self.failure.map_or_else(Default::default, Failure::backoff);
+        let doublings = self.attempts.saturating_sub(1).min(16);
+        (first * 2u32.pow(doublings)).min(max)
+    }
+}
+
+/// Owner and mode of the socket at `path`, against the user the daemon runs as.
+fn permission_hint(path: &Path) -> String {
+    // `/proc/self` belongs to the effective user and group of the process
+    let (uid, gid) =
+        std::fs::metadata("/proc/self").map_or((None, None), |m| (Some(m.uid()), Some(m.gid())));
+    let daemon = format!(
+        "daemon running as uid {} gid {}",
+        uid.map_or_else(|| "?".to_string(), |u| u.to_string()),
+        gid.map_or_else(|| "?".to_string(), |g| g.to_string())
+    );
+    match std::fs::metadata(path) {
+        Ok(m) => format!(
+            "socket owned by uid {} gid {} with mode {:o}, {daemon}; make the socket \
+             accessible to that user or group",
+            m.uid(),
+            m.gid(),
+            m.mode() & 0o777
+        ),
+        Err(e) => format!(
+            "socket not inspectable ({e}), {daemon}; check the permissions of {}",
+            path.parent().unwrap_or(path).display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        Err::<(), _>(io::Error::from(kind))
+            .context("Failed to connect to QMP socket")
+            .unwrap_err()
+    }
+
+    #[test]
+    fn test_failure_classification() {
+        assert_eq!(
+            Failure::of(&io_error(io::ErrorKind::NotFound)),
+            Failure::Missing
+        );
+        assert_eq!(
+            Failure::of(&io_error(io::ErrorKind::PermissionDenied)),
+            Failure::Permission
+        );
+        assert_eq!(
+            Failure::of(&io_error(io::ErrorKind::ConnectionRefused)),
+            Failure::Refused
+        );
+        assert_eq!(
+            Failure::of(&anyhow::anyhow!("QMP connection timed out")),
+            Failure::Other
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let path = Path::new("/nonexistent/qmp.sock");
+        let start = Instant::now();
+        let mut reconnect = Reconnect::default();
+        assert!(reconnect.is_due(start));
+
+        let missing = io_error(io::ErrorKind::NotFound);
+        let mut delays = Vec::new();
+        for _ in 0..6 {
+            reconnect.failed(path, &missing, None, start);
+            delays.push(reconnect.delay().as_secs());
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert!(!reconnect.is_due(start));
+        assert!(reconnect.is_due(start + Duration::from_secs(10)));
+
+        // Another cause starts over on its own schedule
+        reconnect.failed(
+            path,
+            &io_error(io::ErrorKind::PermissionDenied),
+            None,
+            start,
+        );
+        assert_eq!(reconnect.delay(), Duration::from_secs(10));
+
+        reconnect.connected(path, start);
+        assert!(reconnect.is_due(start));
+        assert_eq!(reconnect.attempts, 0);
+    }
+
+    #[test]
+    fn test_alert_once() {
+        let path = Path::new("/nonexistent/qmp.sock");
+        let start = Instant::now();
+        let alert = Some(Duration::from_secs(60));
+        let refused = io_error(io::ErrorKind::ConnectionRefused);
+        let mut reconnect = Reconnect::default();
+
+        reconnect.failed(path, &refused, alert, start);
+        assert!(!reconnect.alerted);
+        // The outage counts from its first failure, whatever the causes since
+        let missing = io_error(io::ErrorKind::NotFound);
+        reconnect.failed(path, &missing, alert, start + Duration::from_secs(60));
+        assert!(reconnect.alerted);
+
+        // Recovering ends the outage
+        reconnect.connected(path, start + Duration::from_secs(61));
+        reconnect.failed(path, &refused, alert, start + Duration::from_secs(62));
+        assert!(!reconnect.alerted);
+
+        // No alert when disabled
+        let mut reconnect = Reconnect::default();
+        reconnect.failed(path, &refused, None, start);
+        reconnect.failed(path, &refused, None, start + Duration::from_secs(3600));
+        assert!(!reconnect.alerted);
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use crate::socket::parse_size;
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{Datelike, Local, Timelike};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Balloon policy of a VM, either the global one or a window override.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub low: u8,
+    pub high: u8,
+    pub minimum: usize,
+    pub maximum: usize,
+}
+
+/// Local time of the week, as (day since Monday, minute of the day)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeekTime {
+    day: u8,
+    minute: u16,
+}
+
+impl WeekTime {
+    pub fn now() -> Self {
+        let now = Local::now();
+        Self {
+            day: u8::try_from(now.weekday().num_days_from_monday()).unwrap_or(0),
+            minute: u16::try_from(now.hour() * 60 + now.minute()).unwrap_or(0),
+        }
+    }
+}
+
+/// Time-based policy override, e.g.
+/// `socket=/run/media-vm.qmp,days=mon-fri,time=22:00-07:00,low=85,high=95`.
+///
+/// All keys are optional: without `socket` the window applies to every VM,
+/// without `days` to every day and without `time` to the whole day. A `time`
+/// range ending before it starts spans midnight and belongs to the day it
+/// starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    spec: String,
+    socket: Option<PathBuf>,
+    /// Bit per day, Monday first
+    days: u8,
+    start: u16,
+    end: u16,
+    low: Option<u8>,
+    high: Option<u8>,
+    minimum: Option<usize>,
+    maximum: Option<usize>,
+}
+
+impl Window {
+    pub fn matches(&self, socket: &Path, at: WeekTime) -> bool {
+        if self.socket.as_deref().is_some_and(|s| s != socket) {
+            return false;
+        }
+
+        let on = |day: u8| self.days & (1 << day) != 0;
+        if self.start < self.end {
+            on(at.day) && (self.start..self.end).contains(&at.minute)
+        } else {
+            // Spans midnight, or the whole day when start == end
+            let yesterday = (at.day + 6) % 7;
+            (on(at.day) && at.minute >= self.start) || (on(yesterday) && at.minute < self.end)
+        }
+    }
+
+    pub fn apply(&self, base: Policy) -> Policy {
+        Policy {
+            low: self.low.unwrap_or(base.low),
+            high: self.high.unwrap_or(base.high),
+            minimum: self.minimum.unwrap_or(base.minimum),
+            maximum: self.maximum.unwrap_or(base.maximum),
+        }
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut window = Self {
+            spec: spec.to_string(),
+            socket: None,
+            days: 0x7f,
+            start: 0,
+            end: 0,
+            low: None,
+            high: None,
+            minimum: None,
+            maximum: None,
+        };
+
+        for item in spec.split(',') {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("Expected key=value in window, got `{item}`"))?;
+            let invalid = || format!("Invalid window {key} `{value}`");
+            match key {
+                "socket" => window.socket = Some(value.into()),
+                "days" => window.days = parse_days(value).with_context(invalid)?,
+                "time" => {
+                    let (start, end) = value.split_once('-').with_context(invalid)?;
+                    window.start = parse_time(start).with_context(invalid)?;
+                    window.end = parse_time(end).with_context(invalid)?;
+                }
+                "low" => window.low = Some(value.parse().with_context(invalid)?),
+                "high" => window.high = Some(value.parse().with_context(invalid)?),
+                "min" => window.minimum = Some(parse_size(value).with_context(invalid)?),
+                "max" => window.maximum = Some(parse_size(value).with_context(invalid)?),
+                _ => bail!("Unknown window key `{key}`"),
+            }
+        }
+        let (low, high) = (window.low.unwrap_or(0), window.high.unwrap_or(100));
+        if low > high || high > 100 {
+            bail!("Window needs low <= high <= 100, got low={low} high={high}");
+        }
+        Ok(window)
+    }
+}
+
+/// Returns the first window active for `socket` at `at`, with its index.
+pub fn active<'a>(
+    windows: &'a [Window],
+    socket: &Path,
+    at: WeekTime,
+) -> Option<(usize, &'a Window)> {
+    windows
+        .iter()
+        .enumerate()
+        .find(|(_, w)| w.matches(socket, at))
+}
+
+fn parse_day(day: &str) -> Result<u8> {
+    DAYS.iter()
+        .position(|&d| d.eq_ignore_ascii_case(day))
+        .and_then(|d| u8::try_from(d).ok())
+        .ok_or_else(|| anyhow!("Unknown day `{day}`"))
+}
+
+/// Parses a day (`sat`) or a range of days (`mon-fri`, `fri-mon`) to a bitmask.
+fn parse_days(days: &str) -> Result<u8> {
+    let (first, last) = match days.split_once('-') {
+        Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+        None => (parse_day(days)?, parse_day(days)?),
+    };
+    let mut mask = 0;
+    let mut day = first;
+    loop {
+        mask |= 1 << day;
+        if day == last {
+            break Ok(mask);
+        }
+        day = (day + 1) % 7;
+    }
+}
+
+/// Parses `HH:MM` to minutes since midnight.
+fn parse_time(time: &str) -> Result<u16> {
+    let (hours, minutes) = time.split_once(':').context("Expected HH:MM")?;
+    let hours = hours.parse::<u16>().ok().filter(|h| (..24).contains(h));
+    let minutes = minutes.parse::<u16>().ok().filter(|m| (..60).contains(m));
+    hours
+        .zip(minutes)
+        .and_then(|(hours, minutes)| hours.checked_mul(60)?.checked_add(minutes))
+        .context("Time out of range")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: Policy = Policy {
+        low: 70,
+        high: 80,
+        minimum: 0,
+        maximum: usize::MAX,
+    };
+
+    fn at(day: &str, time: &str) -> WeekTime {
+        WeekTime {
+            day: parse_day(day).unwrap(),
+            minute: parse_time(time).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let w: Window = "socket=/run/media.qmp,days=mon-fri,time=09:00-17:30,low=50,max=1024"
+            .parse()
+            .unwrap();
+        assert_eq!(w.socket.as_deref(), Some(Path::new("/run/media.qmp")));
+        assert_eq!(w.days, 0x1f);
+        assert_eq!((w.start, w.end), (9 * 60, 17 * 60 + 30));
+        assert_eq!(
+            w.apply(BASE),
+            Policy {
+                low: 50,
+                maximum: 1024,
+                ..BASE
+            }
+        );
+
+        assert_eq!(parse_days("fri-mon").unwrap(), 0b111_0001);
+        assert_eq!(parse_days("sun").unwrap(), 0b100_0000);
+        assert!("days=someday".parse::<Window>().is_err());
+        assert!("time=25:00-26:00".parse::<Window>().is_err());
+        assert!("time=24:00-07:00".parse::<Window>().is_err());
+        assert!("time=07:99-08:00".parse::<Window>().is_err());
+        assert!("time=9999:00-08:00".parse::<Window>().is_err());
+        assert!("time=07:00-08:-1".parse::<Window>().is_err());
+        assert!("low=150".parse::<Window>().is_err());
+        assert!("high=101".parse::<Window>().is_err());
+        assert!("low=90,high=80".parse::<Window>().is_err());
+        assert!("low=80,high=80".parse::<Window>().is_ok());
+        assert!("time=09:00".parse::<Window>().is_err());
+        assert!("color=red".parse::<Window>().is_err());
+        assert!("low".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let vm = Path::new("/run/media.qmp");
+        let w: Window = "socket=/run/media.qmp,days=mon-fri,time=09:00-17:00"
+            .parse()
+            .unwrap();
+        assert!(w.matches(vm, at("mon", "09:00")));
+        assert!(w.matches(vm, at("fri", "16:59")));
+        assert!(!w.matches(vm, at("fri", "17:00")));
+        assert!(!w.matches(vm, at("sat", "12:00")));
+        assert!(!w.matches(Path::new("/run/other.qmp"), at("mon", "12:00")));
+
+        let all_day: Window = "days=sat-sun".parse().unwrap();
+        assert!(all_day.matches(vm, at("sun", "00:00")));
+        assert!(all_day.matches(vm, at("sun", "23:59")));
+        assert!(!all_day.matches(vm, at("mon", "00:00")));
+    }
+
+    #[test]
+    fn test_matches_over_midnight() {
+        let vm = Path::new("/run/media.qmp");
+        let night: Window = "days=fri,time=22:00-07:00".parse().unwrap();
+        assert!(!night.matches(vm, at("fri", "06:00")));
+        assert!(night.matches(vm, at("fri", "22:00")));
+        assert!(night.matches(vm, at("sat", "06:59")));
+        assert!(!night.matches(vm, at("sat", "07:00")));
+        assert!(!night.matches(vm, at("sat", "22:00")));
+    }
+
+    #[test]
+    fn test_first_window_wins() {
+        let vm = Path::new("/run/media.qmp");
+        let windows: Vec<Window> = ["time=22:00-07:00,low=85", "days=sat-sun,low=60"]
+            .iter()
+            .map(|w| w.parse().unwrap())
+            .collect();
+        assert_eq!(active(&windows, vm, at("sat", "23:00")).unwrap().0, 0);
+        assert_eq!(active(&windows, vm, at("sat", "12:00")).unwrap().0, 1);
+        assert!(active(&windows, vm, at("mon", "12:00")).is_none());
+    }
+}
@@ -0,0 +1,139 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use crate::schedule::Policy;
+use anyhow::{Context, Result, bail};
+use std::{path::PathBuf, str::FromStr};
+
+/// QMP socket of a VM, optionally with memory bounds overriding the global ones, the
+/// socket of its guest agent and the socket its memory pressure is reported on, e.g.
+/// `/run/qmp/gui-vm.sock:min=2G,max=8G,agent=/run/qga/gui-vm.sock,psi=/run/psi/gui-vm.sock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSocket {
+    pub path: PathBuf,
+    minimum: Option<usize>,
+    maximum: Option<usize>,
+    pub agent: Option<PathBuf>,
+    pub psi: Option<PathBuf>,
+}
+
+impl VmSocket {
+    pub fn apply(&self, base: Policy) -> Policy {
+        Policy {
+            minimum: self.minimum.unwrap_or(base.minimum),
+            maximum: self.maximum.unwrap_or(base.maximum),
+            ..base
+        }
+    }
+}
+
+impl FromStr for VmSocket {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        // Socket paths may contain colons, options always contain `=`
+        let (path, options) = match spec.rsplit_once(':') {
+            Some((path, options)) if options.contains('=') => (path, options),
+            _ => (spec, ""),
+        };
+        let mut socket = Self {
+            path: path.into(),
+            minimum: None,
+            maximum: None,
+            agent: None,
+            psi: None,
+        };
+
+        for item in options.split(',').filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("Expected key=value in socket options, got `{item}`"))?;
+            match key {
+                "min" => socket.minimum = Some(parse_size(value)?),
+                "max" => socket.maximum = Some(parse_size(value)?),
+                "agent" => socket.agent = Some(value.into()),
+                "psi" => socket.psi = Some(value.into()),
+                _ => bail!("Unknown socket option `{key}`"),
+            }
+        }
+        Ok(socket)
+    }
+}
+
+/// Parses a size in bytes, with an optional binary `K`, `M`, `G` or `T` suffix, e.g. `512M`.
+pub fn parse_size(size: &str) -> Result<usize> {
+    let (number, shift) = [('K', 10), ('M', 20), ('G', 30), ('T', 40)]
+        .iter()
+        .find_map(|&(unit, shift)| {
+            size.strip_suffix([unit, unit.to_ascii_lowercase()])
+                .map(|number| (number, shift))
+        })
+        .unwrap_or((size, 0));
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid size `{size}`"))?;
+    number
+        .checked_mul(1 << shift)
+        .and_then(|bytes| usize::try_from(bytes).ok())
+        .with_context(|| format!("Size `{size}` out of range"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    const BASE: Policy = Policy {
+        low: 70,
+        high: 80,
+        minimum: 0,
+        maximum: usize::MAX,
+    };
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("8m").unwrap(), 8 << 20);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_socket() {
+        let s: VmSocket = "/run/qmp/gui-vm.sock:min=2G,max=8G".parse().unwrap();
+        assert_eq!(s.path, Path::new("/run/qmp/gui-vm.sock"));
+        assert_eq!(
+            s.apply(BASE),
+            Policy {
+                minimum: 2 << 30,
+                maximum: 8 << 30,
+                ..BASE
+            }
+        );
+
+        let s: VmSocket = "/run/qmp/net:vm.sock".parse().unwrap();
+        assert_eq!(s.path, Path::new("/run/qmp/net:vm.sock"));
+        assert_eq!(s.apply(BASE), BASE);
+        assert_eq!(s.agent, None);
+
+        let s: VmSocket = "/run/qmp/gui-vm.sock:agent=/run/qga/gui-vm.sock"
+            .parse()
+            .unwrap();
+        assert_eq!(s.agent.as_deref(), Some(Path::new("/run/qga/gui-vm.sock")));
+        assert_eq!(s.psi, None);
+        assert_eq!(s.apply(BASE), BASE);
+
+        let s: VmSocket = "/run/qmp/gui-vm.sock:psi=/run/psi/gui-vm.sock"
+            .parse()
+            .unwrap();
+        assert_eq!(s.psi.as_deref(), Some(Path::new("/run/psi/gui-vm.sock")));
+
+        assert!("/run/qmp/gui-vm.sock:min=2Q".parse::<VmSocket>().is_err());
+        assert!("/run/qmp/gui-vm.sock:low=50".parse::<VmSocket>().is_err());
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+/// Poll deadlines of the VMs, spread over the interval so their QMP queries don't all
+/// fire at once.
+///
+/// VM `i` of `n` is polled at `i / n` of the interval, delayed by a random jitter of up
+/// to `jitter` percent of the gap between two VMs. Each VM is still polled once per
+/// interval.
+///
+/// Idle VMs are polled on a longer interval of their own: they neither hold up the
+/// rounds of the others nor are brought forward by their activity.
+#[derive(Debug)]
+pub struct Stagger {
+    vms: u32,
+    jitter: u8,
+    /// Poll time of each VM before the jitter
+    slots: Vec<Instant>,
+    deadlines: Vec<Instant>,
+    /// VMs polled in the current round
+    polled: Vec<bool>,
+    idle: Vec<bool>,
+    random: RandomState,
+    draws: u64,
+}
+
+impl Stagger {
+    pub fn new(vms: usize, interval: Duration, jitter: u8, now: Instant) -> Self {
+        let mut stagger = Self {
+            vms: u32::try_from(vms).unwrap_or(u32::MAX),
+            jitter: jitter.min(100),
+            slots: Vec::with_capacity(vms),
+            deadlines: Vec::with_capacity(vms),
+            polled: vec![false; vms],
+            idle: vec![false; vms],
+            random: RandomState::new(),
+            draws: 0,
+        };
+        for vm in 0..vms {
+            let slot = now + stagger.offset(vm, interval);
+            stagger.slots.push(slot);
+            let deadline = slot + stagger.draw_jitter(interval);
+            stagger.deadlines.push(deadline);
+        }
+        stagger
+    }
+
+    /// Earliest deadline, `None` without VMs.
+    pub fn next(&self) -> Option<Instant> {
+        self.deadlines.iter().min().copied()
+    }
+
+    /// Which VMs are due for polling at `now`.
+    pub fn due(&self, now: Instant) -> Vec<bool> {
+        self.deadlines.iter().map(|&d| d <= now).collect()
+    }
+
+    /// Schedules the next poll of `vm` one `interval` after the last, or after `now`
+    /// if that has passed already.
+    pub fn polled(&mut self, vm: usize, interval: Duration, now: Instant) {
+        let next = self.slots[vm] + interval;
+        self.slots[vm] = if next > now { next } else { now + interval };
+        self.deadlines[vm] = self.slots[vm] + self.draw_jitter(interval);
+        self.polled[vm] = true;
+    }
+
+    /// Returns whether every VM has been polled since the last complete round, starting
+    /// a new one if so.
+    pub fn round_complete(&mut self) -> bool {
+        let complete = self.polled.iter().zip(&self.idle).all(|(&p, &i)| p || i);
+        if complete {
+            self.polled.fill(false);
+        }
+        complete
+    }
+
+    /// Marks `vm` as idle or back in the regular rounds.
+    pub fn set_idle(&mut self, vm: usize, idle: bool) {
+        self.idle[vm] = idle;
+    }
+
+    /// Brings the polls scheduled for a longer interval forward to `interval`, except
+    /// for idle VMs.
+    pub fn reset(&mut self, interval: Duration, now: Instant) {
+        for vm in 0..self.slots.len() {
+            if self.idle[vm] {
+                continue;
+            }
+            let slot = now + interval + self.offset(vm, interval);
+            if slot < self.slots[vm] {
+                self.slots[vm] = slot;
+                self.deadlines[vm] = slot + self.draw_jitter(interval);
+            }
+        }
+    }
+
+    fn gap(&self, interval: Duration) -> Duration {
+        interval / self.vms.max(1)
+    }
+
+    fn offset(&self, vm: usize, interval: Duration) -> Duration {
+        self.gap(interval) * u32::try_from(vm).unwrap_or(u32::MAX)
+    }
+
+    fn draw_jitter(&mut self, interval: Duration) -> Duration {
+        let max = self.gap(interval) * u32::from(self.jitter) / 100;
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        self.draws += 1;
+        let random = self.random.hash_one(self.draws);
+        Duration::from_nanos(random % u64::try_from(max.as_nanos()).unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_phase_offsets() {
+        let start = Instant::now();
+        let stagger = Stagger::new(4, SECOND, 0, start);
+        let offsets: Vec<_> = stagger.deadlines.iter().map(|&d| d - start).collect();
+        assert_eq!(
+            offsets,
+            [0, 250, 500, 750].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(stagger.next(), Some(start));
+        assert_eq!(stagger.due(start), vec![true, false, false, false]);
+        assert_eq!(
+            stagger.due(start + Duration::from_millis(500)),
+            vec![true, true, true, false]
+        );
+        assert_eq!(Stagger::new(0, SECOND, 20, start).next(), None);
+    }
+
+    #[test]
+    fn test_cadence_with_jitter() {
+        let start = Instant::now();
+        let mut stagger = Stagger::new(2, SECOND, 50, start);
+        for round in 1..=100 {
+            for vm in 0..2 {
+                let deadline = stagger.deadlines[vm];
+                stagger.polled(vm, SECOND, deadline);
+                let slot = start + SECOND * round + Duration::from_millis(500) * vm as u32;
+                // The jitter never accumulates, and stays within half the gap
+                assert_eq!(stagger.slots[vm], slot);
+                assert!(stagger.deadlines[vm] - slot < Duration::from_millis(250));
+            }
+            assert!(stagger.round_complete());
+        }
+    }
+
+    #[test]
+    fn test_round_and_reset() {
+        let start = Instant::now();
+        let mut stagger = Stagger::new(2, 8 * SECOND, 0, start);
+        stagger.polled(0, 8 * SECOND, start);
+        assert!(!stagger.round_complete());
+        stagger.polled(1, 8 * SECOND, start + 4 * SECOND);
+        assert!(stagger.round_complete());
+        assert!(!stagger.round_complete());
+        assert_eq!(stagger.slots, vec![start + 8 * SECOND, start + 12 * SECOND]);
+
+        // Activity brings both forward to the shorter interval, keeping their phases
+        let now = start + 5 * SECOND;
+        stagger.reset(SECOND, now);
+        assert_eq!(
+            stagger.slots,
+            vec![now + SECOND, now + SECOND + Duration::from_millis(500)]
+        );
+
+        // A VM that fell behind is polled a full interval after it was served
+        let late = now + 10 * SECOND;
+        stagger.polled(0, SECOND, late);
+        assert_eq!(stagger.slots[0], late + SECOND);
+    }
+
+    #[test]
+    fn test_idle_vm() {
+        let start = Instant::now();
+        let mut stagger = Stagger::new(2, SECOND, 0, start);
+        stagger.set_idle(1, true);
+
+        // Rounds go on without the idle VM
+        stagger.polled(0, SECOND, start);
+        assert!(stagger.round_complete());
+        stagger.polled(1, 60 * SECOND, start + SECOND / 2);
+        stagger.reset(SECOND, start + SECOND);
+        assert_eq!(stagger.slots[1], start + SECOND / 2 + 60 * SECOND);
+
+        // Back in the rounds, it is brought forward by activity again
+        stagger.set_idle(1, false);
+        assert!(!stagger.round_complete());
+        stagger.reset(SECOND, start + SECOND);
+        assert_eq!(stagger.slots[1], start + 2 * SECOND + SECOND / 2);
+    }
+}
@@ -0,0 +1,519 @@
+/*
+ * SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use crate::{budget::HostBudget, schedule::Policy};
+
+/// How the balloon target is shaped beyond the pressure window of the policy
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tuning {
+    /// Percent by which the pressure may leave the window before the balloon is resized
+    pub hysteresis: u8,
+    /// Smallest balloon in percent of the guest total, on top of the policy minimum
+    pub min_percent: Option<u8>,
+    /// Largest balloon in percent of the guest total, on top of the policy maximum
+    pub max_percent: Option<u8>,
+    /// Largest resize in percent of the guest total, 0 for any
+    pub max_step: u8,
+}
+
+/// Balloon size decided for one sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub size: usize,
+    /// Neither can the host get memory back nor the guest more of it
+    pub stuck: bool,
+}
+
+/// Memory of a guest as seen through its balloon, in bytes
+#[derive(Debug)]
+pub struct MemoryStats {
+    pub balloon_size: usize,
+    pub base_memory: usize,
+    pub plugged_memory: usize,
+    pub total_memory: usize,
+    pub free_memory: usize,
+    pub available_memory: usize,
+}
+
+impl MemoryStats {
+    /// Whether the sample is consistent enough to base a balloon decision on.
+    ///
+    /// During balloon transitions the guest may briefly report more available
+    /// memory than the balloon currently holds.
+    pub fn is_valid(&self) -> bool {
+        self.balloon_size > 0 && self.available_memory <= self.balloon_size
+    }
+
+    /// Memory pressure in percent, rounded; samples with `available > balloon`
+    /// are clamped to 0% and an empty balloon reads as 100%.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn pressure(&self) -> u8 {
+        if self.balloon_size == 0 {
+            return 100;
+        }
+        let balloon = self.balloon_size as u128;
+        ((self.reserved() as u128 * 200 + balloon) / (balloon * 2)) as u8
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.balloon_size.saturating_sub(self.available_memory)
+    }
+
+    /// Feeds the used memory into its exponential moving `average`, `weight` percent of
+    /// which is this sample, and returns the new average. Unlike the available memory,
+    /// the used memory does not jump when the balloon is resized.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn average_reserved(&self, average: &mut Option<f64>, weight: u8) -> usize {
+        let sample = self.reserved() as f64;
+        let avg = average.map_or(sample, |a| a + (sample - a) * f64::from(weight) / 100.0);
+        *average = Some(avg);
+        avg.round() as usize
+    }
+
+    /// `percent` of the guest total, saturating at `usize::MAX`.
+    pub fn share(&self, percent: u8) -> usize {
+        usize::try_from(self.total_memory as u128 * u128::from(percent) / 100).unwrap_or(usize::MAX)
+    }
+
+    /// Balloon size that would put the guest at `target` percent pressure,
+    /// saturating at `usize::MAX` (e.g. for a 0% target).
+    pub fn adjusted(&self, target: u8) -> usize {
+        (self.reserved() as u128 * 100)
+            .checked_div(u128::from(target))
+            .and_then(|t| usize::try_from(t).ok())
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Balloon size limits of `policy`, narrowed by the percentages of total memory.
+    /// A minimum above the maximum is lowered to it.
+    pub fn limits(
+        &self,
+        policy: &Policy,
+        min_percent: Option<u8>,
+        max_percent: Option<u8>,
+    ) -> (usize, usize) {
+        let maximum = max_percent.map_or(policy.maximum, |p| policy.maximum.min(self.share(p)));
+        let minimum = min_percent.map_or(policy.minimum, |p| policy.minimum.max(self.share(p)));
+        (minimum.min(maximum), maximum)
+    }
+
+    /// Moves `target` at most `max_step` percent of the guest total away from the current
+    /// balloon size; 0 leaves it as is.
+    pub fn step(&self, target: usize, max_step: u8) -> usize {
+        if max_step == 0 {
+            return target;
+        }
+        let step = self.share(max_step);
+        target.clamp(
+            self.balloon_size.saturating_sub(step),
+            self.balloon_size.saturating_add(step),
+        )
+    }
+
+    /// Balloon size for this sample under `policy`, `None` while the pressure stays in
+    /// the window and the host has memory to spare.
+    ///
+    /// A pressure `spike` grows the balloon at least to the low mark. The host `budget`
+    /// limits growth and, once the host runs short, reclaims even from guests within
+    /// the window.
+    pub fn target(
+        &self,
+        policy: &Policy,
+        tuning: Tuning,
+        spike: bool,
+        budget: Option<&HostBudget>,
+    ) -> Option<Target> {
+        let wanted = self.window(policy.low, policy.high, tuning.hysteresis);
+        let wanted = if spike {
+            let grown = self.adjusted(policy.low).max(self.balloon_size);
+            Some(wanted.map_or(grown, |w| w.max(grown)))
+        } else {
+            wanted
+        };
+        let short = budget.is_some_and(HostBudget::is_short);
+        let size = wanted.or_else(|| short.then_some(self.balloon_size))?;
+        let size = budget.map_or(size, |b| {
+            let floor = self.adjusted(policy.high);
+            b.limit(self.balloon_size, size, self.pressure(), floor)
+        });
+        let (min, max) = self.limits(policy, tuning.min_percent, tuning.max_percent);
+        let size = self.step(size.clamp(min, max), tuning.max_step);
+        let stuck = (short && size >= self.balloon_size)
+            || wanted.is_some_and(|w| w > self.balloon_size && size <= self.balloon_size);
+        Some(Target { size, stuck })
+    }
+
+    /// Balloon size bringing the pressure back into the window, once it left it by more
+    /// than `margin` percent.
+    pub fn window(&self, min: u8, max: u8, margin: u8) -> Option<usize> {
+        let p = self.pressure();
+        if p < min.saturating_sub(margin) {
+            Some(self.adjusted(min))
+        } else if p > max.saturating_add(margin) {
+            Some(self.adjusted(max.saturating_sub(2)))
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Memory stats:\n\
+             Balloon size: {} MiB\n\
+             Base memory: {} MiB\n\
+             Plugged memory: {} MiB\n\
+             Total memory: {} MiB\n\
+             Free memory: {} MiB\n\
+             Available memory: {} MiB",
+            self.balloon_size / 1024 / 1024,
+            self.base_memory / 1024 / 1024,
+            self.plugged_memory / 1024 / 1024,
+            self.total_memory / 1024 / 1024,
+            self.free_memory / 1024 / 1024,
+            self.available_memory / 1024 / 1024
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MIB: usize = 1024 * 1024;
+    /// Largest guest in the property tests, small enough for the moving average to be exact
+    const MAX_SIZE: usize = 1 << 48;
+
+    fn stats(balloon_size: usize, available_memory: usize) -> MemoryStats {
+        MemoryStats {
+            balloon_size,
+            base_memory: balloon_size,
+            plugged_memory: 0,
+            total_memory: balloon_size,
+            free_memory: available_memory,
+            available_memory,
+        }
+    }
+
+    /// Valid samples: a non-empty balloon and at most as much memory available
+    fn valid_stats() -> impl Strategy<Value = MemoryStats> {
+        (1..MAX_SIZE)
+            .prop_flat_map(|balloon| (Just(balloon), 0..=balloon))
+            .prop_map(|(balloon, available)| stats(balloon, available))
+    }
+
+    #[test]
+    fn test_pressure() {
+        assert_eq!(stats(1000 * MIB, 1000 * MIB).pressure(), 0);
+        assert_eq!(stats(1000 * MIB, 250 * MIB).pressure(), 75);
+        assert_eq!(stats(1000 * MIB, 0).pressure(), 100);
+        // Rounded to the nearest percent
+        assert_eq!(stats(1000, 254).pressure(), 75);
+        assert_eq!(stats(1000, 255).pressure(), 75);
+        assert_eq!(stats(1000, 256).pressure(), 74);
+    }
+
+    #[test]
+    fn test_available_exceeds_balloon() {
+        let s = stats(1000 * MIB, 1200 * MIB);
+        assert!(!s.is_valid());
+        assert_eq!(s.pressure(), 0);
+        assert_eq!(s.reserved(), 0);
+        assert_eq!(s.adjusted(70), 0);
+    }
+
+    #[test]
+    fn test_empty_balloon() {
+        let s = stats(0, 0);
+        assert!(!s.is_valid());
+        assert_eq!(s.pressure(), 100);
+        assert_eq!(s.reserved(), 0);
+    }
+
+    #[test]
+    fn test_extreme_sizes() {
+        let s = stats(usize::MAX, 0);
+        assert!(s.is_valid());
+        assert_eq!(s.pressure(), 100);
+        assert_eq!(s.reserved(), usize::MAX);
+        assert_eq!(s.adjusted(100), usize::MAX);
+        assert_eq!(s.adjusted(50), usize::MAX);
+
+        let s = stats(usize::MAX, usize::MAX);
+        assert_eq!(s.pressure(), 0);
+    }
+
+    #[test]
+    fn test_window() {
+        let s = stats(1000 * MIB, 500 * MIB);
+        // Within the window, no adjustment
+        assert_eq!(s.window(40, 60, 0), None);
+        // Too little pressure: shrink towards the low mark
+        assert_eq!(s.window(70, 80, 0), Some(500 * MIB * 100 / 70));
+        // Too much pressure: grow to slightly below the high mark
+        assert_eq!(s.window(20, 30, 0), Some(500 * MIB * 100 / 28));
+    }
+
+    #[test]
+    fn test_window_hysteresis() {
+        let s = stats(1000 * MIB, 500 * MIB);
+        // Within the margin around the window, no adjustment
+        assert_eq!(s.window(55, 60, 5), None);
+        assert_eq!(s.window(40, 45, 5), None);
+        // Beyond it, back to the same targets as without a margin
+        assert_eq!(s.window(60, 80, 5), Some(500 * MIB * 100 / 60));
+        assert_eq!(s.window(20, 40, 5), Some(500 * MIB * 100 / 38));
+    }
+
+    #[test]
+    fn test_average_reserved() {
+        let mut average = None;
+        // The first sample starts the average
+        assert_eq!(stats(1000, 600).average_reserved(&mut average, 25), 400);
+        // A spike only moves it by its weight
+        assert_eq!(stats(1000, 200).average_reserved(&mut average, 25), 500);
+        // Resizing the balloon alone does not
+        assert_eq!(stats(2000, 1500).average_reserved(&mut average, 25), 500);
+        assert_eq!(stats(1000, 200).average_reserved(&mut average, 100), 800);
+    }
+
+    #[test]
+    fn test_step() {
+        let s = stats(1000 * MIB, 0);
+        assert_eq!(s.step(2000 * MIB, 0), 2000 * MIB);
+        assert_eq!(s.step(2000 * MIB, 10), 1100 * MIB);
+        assert_eq!(s.step(500 * MIB, 10), 900 * MIB);
+        assert_eq!(s.step(950 * MIB, 10), 950 * MIB);
+        assert_eq!(stats(usize::MAX, 0).step(0, 100), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let s = stats(4096 * MIB, 0);
+        let policy = Policy {
+            low: 70,
+            high: 80,
+            minimum: 1024 * MIB,
+            maximum: 3072 * MIB,
+        };
+        assert_eq!(s.limits(&policy, None, None), (1024 * MIB, 3072 * MIB));
+        // The stricter of the absolute and relative limit applies
+        assert_eq!(
+            s.limits(&policy, Some(50), Some(50)),
+            (2048 * MIB, 2048 * MIB)
+        );
+        assert_eq!(
+            s.limits(&policy, Some(10), Some(90)),
+            (1024 * MIB, 3072 * MIB)
+        );
+        // Conflicting limits are resolved towards the maximum
+        assert_eq!(
+            s.limits(&policy, Some(90), Some(50)),
+            (2048 * MIB, 2048 * MIB)
+        );
+        // No overflow on huge guests
+        let unbounded = Policy {
+            minimum: 0,
+            maximum: usize::MAX,
+            ..policy
+        };
+        let s = stats(usize::MAX, 0);
+        assert_eq!(
+            s.limits(&unbounded, Some(100), None),
+            (usize::MAX, usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_target() {
+        let policy = Policy {
+            low: 40,
+            high: 60,
+            minimum: 0,
+            maximum: usize::MAX,
+        };
+        let tuning = Tuning::default();
+        let s = stats(1000 * MIB, 500 * MIB);
+        // Within the window, nothing to do
+        assert_eq!(s.target(&policy, tuning, false, None), None);
+        // A spike grows towards the low mark
+        let target = s.target(&policy, tuning, true, None).unwrap();
+        assert_eq!(target.size, 500 * MIB * 100 / 40);
+        assert!(!target.stuck);
+        // Stepped and limited
+        let tuning = Tuning {
+            max_step: 10,
+            ..tuning
+        };
+        assert_eq!(
+            s.target(&policy, tuning, true, None).map(|t| t.size),
+            Some(1100 * MIB)
+        );
+        let capped = Policy {
+            maximum: 1000 * MIB,
+            ..policy
+        };
+        assert_eq!(
+            s.target(&capped, tuning, true, None),
+            Some(Target {
+                size: 1000 * MIB,
+                stuck: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_target_host_budget() {
+        let policy = Policy {
+            low: 40,
+            high: 60,
+            minimum: 0,
+            maximum: usize::MAX,
+        };
+        let s = stats(2000 * MIB, 1000 * MIB);
+        // A short host reclaims from a guest within the window, down to its high mark
+        let short = HostBudget::new(0, 4000 * MIB, [50]);
+        assert_eq!(
+            s.target(&policy, Tuning::default(), false, Some(&short)),
+            Some(Target {
+                size: 1000 * MIB * 100 / 60,
+                stuck: false
+            })
+        );
+        // Growth is limited to the headroom, leaving the guest stuck once it is gone
+        let spare = HostBudget::new(600 * MIB, 500 * MIB, [50]);
+        assert_eq!(
+            s.target(&policy, Tuning::default(), true, Some(&spare)),
+            Some(Target {
+                size: 2100 * MIB,
+                stuck: false
+            })
+        );
+        let full = HostBudget::new(500 * MIB, 500 * MIB, [50]);
+        assert_eq!(
+            s.target(&policy, Tuning::default(), true, Some(&full)),
+            Some(Target {
+                size: 2000 * MIB,
+                stuck: true
+            })
+        );
+        // A guest over its high mark gives nothing up to a short host
+        let s = stats(2000 * MIB, 100 * MIB);
+        assert_eq!(
+            s.target(&policy, Tuning::default(), false, Some(&short))
+                .map(|t| t.stuck),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_window_degenerate_bounds() {
+        let s = stats(1000 * MIB, 0);
+        assert_eq!(s.window(0, 0, 0), Some(usize::MAX));
+        assert_eq!(s.window(0, 1, 0), Some(usize::MAX));
+        assert_eq!(stats(1000 * MIB, 1000 * MIB).window(0, 0, 0), None);
+        // A full margin never grows the balloon
+        assert_eq!(s.window(0, 0, 100), None);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_pressure_bounded(balloon in any::<usize>(), available in any::<usize>()) {
+            prop_assert!(stats(balloon, available).pressure() <= 100);
+        }
+
+        #[test]
+        fn prop_pressure_monotonic(s in valid_stats(), less in any::<usize>()) {
+            // Less available memory never reads as less pressure
+            let tighter = stats(s.balloon_size, s.available_memory.saturating_sub(less));
+            prop_assert!(tighter.pressure() >= s.pressure());
+        }
+
+        #[test]
+        fn prop_adjusted_monotonic(s in valid_stats(), target in 1..=100u8, lower in 1..=100u8) {
+            // A lower pressure target never asks for a smaller balloon
+            let lower = lower.min(target);
+            prop_assert!(s.adjusted(lower) >= s.adjusted(target));
+            prop_assert!(s.adjusted(100) == s.reserved());
+        }
+
+        #[test]
+        fn prop_window_direction(
+            s in valid_stats(),
+            min in 0..=100u8,
+            max in 0..=100u8,
+            margin in 0..=100u8,
+        ) {
+            let p = s.pressure();
+            match s.window(min, max, margin) {
+                None => prop_assert!(p >= min.saturating_sub(margin) && p <= max.saturating_add(margin)),
+                // Too little pressure shrinks the balloon, too much grows it
+                Some(size) if p < min.saturating_sub(margin) => prop_assert!(size <= s.balloon_size),
+                Some(size) => prop_assert!(size >= s.balloon_size),
+            }
+        }
+
+        #[test]
+        fn prop_step_clamped(s in valid_stats(), target in any::<usize>(), max_step in 0..=100u8) {
+            let stepped = s.step(target, max_step);
+            if max_step == 0 {
+                prop_assert_eq!(stepped, target);
+            } else {
+                let step = s.share(max_step);
+                prop_assert!(stepped.abs_diff(s.balloon_size) <= step);
+                // Targets within reach are left alone, others are moved towards
+                prop_assert!(stepped.abs_diff(target) <= s.balloon_size.abs_diff(target));
+                if target.abs_diff(s.balloon_size) <= step {
+                    prop_assert_eq!(stepped, target);
+                }
+            }
+        }
+
+        #[test]
+        fn prop_limits_ordered(
+            s in valid_stats(),
+            minimum in any::<usize>(),
+            maximum in any::<usize>(),
+            min_percent in proptest::option::of(0..=100u8),
+            max_percent in proptest::option::of(0..=100u8),
+        ) {
+            let policy = Policy { low: 70, high: 80, minimum, maximum };
+            let (lo, hi) = s.limits(&policy, min_percent, max_percent);
+            prop_assert!(lo <= hi);
+            prop_assert!(hi <= maximum);
+            if let Some(p) = max_percent {
+                prop_assert!(hi <= s.share(p));
+            }
+            // The minimum only gives way to the maximum
+            if lo < hi {
+                prop_assert!(lo >= minimum);
+                if let Some(p) = min_percent {
+                    prop_assert!(lo >= s.share(p));
+                }
+            }
+        }
+
+        #[test]
+        fn prop_average_between(
+            previous in 0..MAX_SIZE,
+            s in valid_stats(),
+            weight in 1..=100u8,
+        ) {
+            let mut average = Some(previous as f64);
+            let avg = s.average_reserved(&mut average, weight);
+            let sample = s.reserved();
+            prop_assert!(avg >= previous.min(sample) && avg <= previous.max(sample));
+            if weight == 100 {
+                prop_assert_eq!(avg, sample);
+            }
+        }
+    }
+}
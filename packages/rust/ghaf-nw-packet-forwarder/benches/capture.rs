@@ -0,0 +1,75 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! Packets per second through the capture path, from a receiver that always has a frame
+//! ready: a `spawn_blocking` call per packet, as done before the capture threads, against
+//! a capture thread feeding a queue. Run with `cargo bench`.
+use nw_pckt_fwd::capture;
+use pnet::datalink::DataLinkReceiver;
+use std::hint::black_box;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+const PACKETS: u32 = 200_000;
+const FRAME_SIZE: usize = 128;
+
+struct Flood([u8; FRAME_SIZE]);
+
+impl DataLinkReceiver for Flood {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        Ok(&self.0)
+    }
+}
+
+fn receiver() -> Arc<Mutex<Box<dyn DataLinkReceiver>>> {
+    Arc::new(Mutex::new(Box::new(Flood([0xa5; FRAME_SIZE]))))
+}
+
+async fn spawn_blocking_per_packet() -> Duration {
+    let rx = receiver();
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        let rx = Arc::clone(&rx);
+        let frame =
+            tokio::task::spawn_blocking(move || rx.blocking_lock().next().map(<[u8]>::to_vec))
+                .await
+                .expect("Capture task panicked")
+                .expect("Capture failed");
+        black_box(frame);
+    }
+    start.elapsed()
+}
+
+async fn capture_thread() -> Duration {
+    let cancel_token = CancellationToken::new();
+    let mut frames = capture::spawn("bench", receiver(), cancel_token.clone())
+        .expect("Failed to start capture thread");
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        let frame = frames.recv().await.expect("Capture thread stopped");
+        black_box(frame.expect("Capture failed"));
+    }
+    let elapsed = start.elapsed();
+    cancel_token.cancel();
+    elapsed
+}
+
+fn report(name: &str, elapsed: Duration) -> f64 {
+    let pps = f64::from(PACKETS) / elapsed.as_secs_f64();
+    println!("{name:<28} {pps:>12.0} packets/s");
+    pps
+}
+
+#[tokio::main]
+async fn main() {
+    let before = report(
+        "spawn_blocking per packet",
+        spawn_blocking_per_packet().await,
+    );
+    let after = report("capture thread", capture_thread().await);
+    println!("{:<28} {:>12.1}x", "speedup", after / before);
+}
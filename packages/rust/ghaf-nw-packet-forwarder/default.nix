@@ -57,6 +57,14 @@ let
     // {
       inherit cargoArtifacts;
 
+      nativeBuildInputs = commonArgs.nativeBuildInputs ++ [ pkgs.makeWrapper ];
+
+      # nft closes the port translation range to the host's own stack
+      postInstall = ''
+        wrapProgram $out/bin/nw-pckt-fwd \
+          --prefix PATH : ${lib.makeBinPath [ pkgs.nftables ]}
+      '';
+
       passthru.tests = {
         inherit cargoTest cargoClippy;
       };
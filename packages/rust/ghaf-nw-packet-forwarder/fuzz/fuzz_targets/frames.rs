@@ -0,0 +1,191 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! Feeds arbitrary frames through the whole pipeline of the forwarder, classification,
+//! rewriting and bookkeeping, as if captured on either interface. The first input byte
+//! picks the interface, the rest is the frame. Run with `cargo fuzz run frames`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use log::{LevelFilter, Log, Metadata, Record};
+use nw_pckt_fwd::drop_log::DropLog;
+use nw_pckt_fwd::filter::balancer::{Strategy, Target};
+use nw_pckt_fwd::filter::chromecast::{MDNS_FILTER, SSDP_FILTER};
+use nw_pckt_fwd::filter::{Chromecast, Conntrack, DevicePins, MdnsReflector};
+use nw_pckt_fwd::forward_impl::dhcp_relay::DhcpRelay;
+use nw_pckt_fwd::forward_impl::forward;
+use nw_pckt_fwd::forward_impl::napt::Napt;
+use nw_pckt_fwd::forward_impl::neighbors::Neighbors;
+use nw_pckt_fwd::forward_impl::retransmit::Retransmitter;
+use nw_pckt_fwd::pcap_dump::PcapDump;
+use nw_pckt_fwd::pipeline;
+use nw_pckt_fwd::traffic_stats::TrafficStats;
+use pnet::datalink::{DataLinkSender, NetworkInterface};
+use pnet::ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use std::io;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+
+/// Formats every message, so that the packet dumps in the logs are exercised too
+struct FormatLogger;
+
+impl Log for FormatLogger {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        std::hint::black_box(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Discards what the pipeline sends
+struct Sink;
+
+impl DataLinkSender for Sink {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, _: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(Ok(()))
+    }
+}
+
+struct Pipeline {
+    runtime: Runtime,
+    chromecast: Chromecast,
+    reflector: Arc<MdnsReflector>,
+    conntrack: Arc<Conntrack>,
+    pins: Arc<DevicePins>,
+    dhcp_relay: Arc<DhcpRelay>,
+    napt: Napt,
+    neighbors: Neighbors,
+    retransmitter: Retransmitter,
+    traffic: TrafficStats,
+    pcap: PcapDump,
+    drop_log: DropLog,
+    tx: Arc<Mutex<Box<dyn DataLinkSender>>>,
+    external_iface: NetworkInterface,
+    internal_iface: NetworkInterface,
+}
+
+fn iface(name: &str, mac: MacAddr, ip: &str) -> NetworkInterface {
+    NetworkInterface {
+        name: name.to_string(),
+        description: String::new(),
+        index: 0,
+        mac: Some(mac),
+        ips: vec![ip.parse().unwrap()],
+        flags: 0,
+    }
+}
+
+static PIPELINE: LazyLock<Pipeline> = LazyLock::new(|| {
+    let _ = log::set_logger(&FormatLogger);
+    log::set_max_level(LevelFilter::Trace);
+
+    let external_iface = iface("ext", MacAddr(2, 0, 0, 0, 1, 1), "192.168.1.3/24");
+    let internal_iface = iface("int", MacAddr(2, 0, 0, 0, 2, 1), "192.168.100.1/24");
+    forward::assign_ifaces(&external_iface, &internal_iface, None, None).unwrap();
+
+    let target = Target {
+        ip: "192.168.100.2/24".parse::<IpNetwork>().unwrap(),
+        mac: MacAddr(2, 0, 0, 0, 2, 2),
+    };
+    let chromecast = Chromecast::with_balancer(nw_pckt_fwd::filter::Balancer::new(
+        vec![target],
+        Strategy::Hash,
+        Duration::from_secs(60),
+    ));
+    chromecast.set_filter(SSDP_FILTER, true);
+    chromecast.set_filter(MDNS_FILTER, true);
+    let services = ["_airplay._tcp", "_ipp._tcp", "ssdp"].map(|s| s.parse().unwrap());
+
+    Pipeline {
+        runtime: tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap(),
+        chromecast,
+        reflector: Arc::new(MdnsReflector::new(&services)),
+        conntrack: Arc::new(Conntrack::new(Duration::from_secs(60), 64)),
+        pins: Arc::new(DevicePins::new(Duration::from_secs(60))),
+        dhcp_relay: Arc::new(DhcpRelay::new(true)),
+        napt: Napt::new(
+            Some(61000..=61015),
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            64,
+        ),
+        neighbors: Neighbors::new(Duration::from_secs(60)),
+        retransmitter: Retransmitter::new(1, Duration::ZERO),
+        traffic: TrafficStats::new(),
+        pcap: PcapDump::new(None, 0, 0),
+        drop_log: DropLog::new(0, None, None),
+        tx: Arc::new(Mutex::new(Box::new(Sink))),
+        external_iface,
+        internal_iface,
+    }
+});
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&side, frame)) = data.split_first() else {
+        return;
+    };
+    let p = &*PIPELINE;
+    let mut frame = frame.to_vec();
+    p.runtime.block_on(async {
+        if side & 1 == 0 {
+            pipeline::process_internal_packets(
+                &p.chromecast.get_internal_ops(),
+                &p.reflector,
+                &p.conntrack,
+                &p.dhcp_relay,
+                &p.napt,
+                &p.neighbors,
+                &p.traffic,
+                &p.pcap,
+                &p.tx,
+                &mut frame,
+                &p.internal_iface,
+                &forward::get_ifaces(),
+            )
+            .await;
+        } else {
+            pipeline::process_external_packets(
+                &p.chromecast.get_external_ops(),
+                &p.reflector,
+                &p.conntrack,
+                &p.pins,
+                &p.dhcp_relay,
+                &p.napt,
+                &p.neighbors,
+                &p.retransmitter,
+                &p.traffic,
+                &p.pcap,
+                &p.drop_log,
+                &p.tx,
+                &mut frame,
+                &p.external_iface,
+                &p.internal_iface,
+            )
+            .await;
+        }
+    });
+});
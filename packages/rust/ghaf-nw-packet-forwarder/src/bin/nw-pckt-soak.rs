@@ -0,0 +1,608 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Soak test
+//!
+//! Drives a running forwarder with synthetic cast traffic for hours and reports drops,
+//! latency and the forwarder's memory growth, to catch regressions of the datapath before
+//! a release. The tool plays both the chromecast VM, on the peer of the forwarder's
+//! internal interface, and a cast device on the external network, on the peer of its
+//! external interface:
+//!
+//! - mDNS queries and SSDP searches from the chromecast VM, expected on the external side
+//! - mDNS responses from the cast device, expected on the internal side
+//! - a UDP media stream from the cast device to the port of the SSDP searches, expected
+//!   on the internal side
+//!
+//! Every packet carries a sequence number and its send time, so both sides are matched
+//! on the same clock. A loopback setup with two veth pairs:
+//!
+//! ```text
+//! ip link add soak-ext type veth peer name fwd-ext
+//! ip link add soak-int type veth peer name fwd-int
+//! ip addr add 192.168.1.3/24 dev fwd-ext && ip addr add 192.168.100.1/24 dev fwd-int
+//! for i in soak-ext fwd-ext soak-int fwd-int; do ip link set $i up; done
+//! nw-pckt-fwd --external-iface fwd-ext --internal-iface fwd-int \
+//!     --ccastvm-ip 192.168.100.2/24 --ccastvm-mac 02:00:00:00:00:02 --rate-limiting 0 &
+//! nw-pckt-soak --ext-iface soak-ext --int-iface soak-int --forwarder-ip 192.168.1.3 \
+//!     --forwarder-mac $(cat /sys/class/net/fwd-ext/address) \
+//!     --ccastvm-ip 192.168.100.2 --ccastvm-mac 02:00:00:00:00:02 --pid $!
+//! ```
+//!
+//! Keep the forwarder's rate limiting off, or the stream rate within it, unless its drops
+//! are what is being measured.
+use clap::Parser;
+use log::{error, info, warn};
+use pnet::datalink::{self, Channel::Ethernet, Config, DataLinkReceiver, DataLinkSender};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MARKER: &[u8; 8] = b"GHAFSOAK";
+/// Marker, pattern, sequence number and send time in nanoseconds since the start
+const TAG_LEN: usize = MARKER.len() + 1 + 8 + 8;
+const MDNS_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SSDP_IP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const CAST_PORT: u16 = 8009;
+const SSDP_SEARCH: &[u8] = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\
+MAN: \"ssdp:discover\"\r\nMX: 1\r\nST: urn:dial-multicast:com.google.cast:1\r\n\r\n";
+/// Time given to packets in flight before the final report
+const DRAIN: Duration = Duration::from_secs(2);
+/// Latency samples kept per pattern and report interval
+const MAX_SAMPLES: usize = 100_000;
+
+/// Soak test for the network packet forwarder
+#[derive(Parser, Debug)]
+#[command(about = "Soak test driving a running nw-pckt-fwd with synthetic cast traffic.")]
+struct Args {
+    /// Peer of the forwarder's external interface
+    #[arg(long)]
+    ext_iface: String,
+
+    /// Peer of the forwarder's internal interface
+    #[arg(long)]
+    int_iface: String,
+
+    /// Address of the forwarder's external interface
+    #[arg(long)]
+    forwarder_ip: Ipv4Addr,
+
+    /// MAC address of the forwarder's external interface
+    #[arg(long)]
+    forwarder_mac: MacAddr,
+
+    /// Chromecast VM address configured on the forwarder
+    #[arg(long)]
+    ccastvm_ip: Ipv4Addr,
+
+    /// Chromecast VM MAC address configured on the forwarder
+    #[arg(long)]
+    ccastvm_mac: MacAddr,
+
+    /// Address of the simulated cast device on the external network
+    #[arg(long, default_value = "192.168.1.50")]
+    device_ip: Ipv4Addr,
+
+    /// Source port of the chromecast VM's SSDP searches, receiving the media stream
+    #[arg(long, default_value_t = 40000)]
+    search_port: u16,
+
+    /// Discovery packets per second, for each discovery pattern
+    #[arg(long, default_value_t = 1)]
+    discovery_rate: u32,
+
+    /// Media stream packets per second
+    #[arg(long, default_value_t = 200)]
+    stream_rate: u32,
+
+    /// Media stream payload size in bytes
+    #[arg(long, default_value_t = 1200)]
+    stream_size: usize,
+
+    /// Test duration in seconds
+    #[arg(long, default_value_t = 4 * 3600)]
+    duration: u64,
+
+    /// Interval in seconds between reports
+    #[arg(long, default_value_t = 60)]
+    report_interval: u64,
+
+    /// Forwarder instance whose resident memory is tracked, repeat for several
+    #[arg(long)]
+    pid: Vec<u32>,
+
+    /// Loss in percent of a pattern failing the test
+    #[arg(long, default_value_t = 1.0)]
+    max_loss: f64,
+
+    /// Growth of a forwarder's resident memory, in KiB, failing the test
+    #[arg(long, default_value_t = 10 * 1024)]
+    max_rss_growth: u64,
+}
+
+/// Traffic pattern sent through the forwarder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Pattern {
+    MdnsQuery,
+    SsdpSearch,
+    MdnsResponse,
+    Stream,
+}
+
+impl Pattern {
+    const ALL: [Pattern; 4] = [
+        Pattern::MdnsQuery,
+        Pattern::SsdpSearch,
+        Pattern::MdnsResponse,
+        Pattern::Stream,
+    ];
+
+    /// Whether the pattern goes from the internal to the external network
+    fn outbound(self) -> bool {
+        matches!(self, Pattern::MdnsQuery | Pattern::SsdpSearch)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Pattern::MdnsQuery => "mdns-query",
+            Pattern::SsdpSearch => "ssdp-search",
+            Pattern::MdnsResponse => "mdns-response",
+            Pattern::Stream => "stream",
+        }
+    }
+
+    fn rate(self, args: &Args) -> u32 {
+        match self {
+            Pattern::Stream => args.stream_rate,
+            _ => args.discovery_rate,
+        }
+    }
+}
+
+/// Identifies a test packet, carried at the end of its UDP payload
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Tag {
+    pattern: Pattern,
+    seq: u64,
+    sent: Duration,
+}
+
+impl Tag {
+    fn encode(&self) -> [u8; TAG_LEN] {
+        let mut tag = [0; TAG_LEN];
+        tag[..8].copy_from_slice(MARKER);
+        tag[8] = self.pattern as u8;
+        tag[9..17].copy_from_slice(&self.seq.to_be_bytes());
+        let sent = u64::try_from(self.sent.as_nanos()).unwrap_or(u64::MAX);
+        tag[17..].copy_from_slice(&sent.to_be_bytes());
+        tag
+    }
+
+    /// Finds the tag in a UDP payload.
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let start = payload.windows(MARKER.len()).rposition(|w| w == MARKER)?;
+        let tag = payload.get(start..start + TAG_LEN)?;
+        Some(Self {
+            pattern: *Pattern::ALL.get(usize::from(tag[8]))?,
+            seq: u64::from_be_bytes(tag[9..17].try_into().ok()?),
+            sent: Duration::from_nanos(u64::from_be_bytes(tag[17..].try_into().ok()?)),
+        })
+    }
+}
+
+/// Counters of one pattern
+#[derive(Debug, Default)]
+struct PatternStats {
+    sent: u64,
+    received: u64,
+    max_latency: Duration,
+    /// Latencies since the last report
+    samples: Vec<Duration>,
+}
+
+impl PatternStats {
+    fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        100.0 * self.sent.saturating_sub(self.received) as f64 / self.sent as f64
+    }
+
+    /// Latency at `quantile` of the samples since the last report.
+    fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let mut samples = self.samples.clone();
+        samples.sort_unstable();
+        let index = ((samples.len() as f64 - 1.0) * quantile).round() as usize;
+        samples.get(index).copied()
+    }
+}
+
+type Stats = Mutex<BTreeMap<Pattern, PatternStats>>;
+type Channel = (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>);
+
+/// Identity of one side of the forwarder
+struct Endpoint {
+    mac: MacAddr,
+    ip: Ipv4Addr,
+}
+
+fn main() -> ExitCode {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let args = Args::parse();
+
+    let (ext_tx, ext_rx) = match open(&args.ext_iface) {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let (int_tx, int_rx) = match open(&args.int_iface) {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let device_mac = datalink::interfaces()
+        .into_iter()
+        .find(|i| i.name == args.ext_iface)
+        .and_then(|i| i.mac)
+        .unwrap_or(MacAddr(2, 0, 0, 0, 0, 0x50));
+
+    let start = Instant::now();
+    let stats: Arc<Stats> = Arc::new(Mutex::new(
+        Pattern::ALL
+            .iter()
+            .map(|&p| (p, PatternStats::default()))
+            .collect(),
+    ));
+    let running = Arc::new(AtomicBool::new(true));
+    for (rx, outbound) in [(ext_rx, true), (int_rx, false)] {
+        let stats = Arc::clone(&stats);
+        let running = Arc::clone(&running);
+        thread::spawn(move || receive(rx, outbound, start, &stats, &running));
+    }
+
+    let device = Endpoint {
+        mac: device_mac,
+        ip: args.device_ip,
+    };
+    let ccastvm = Endpoint {
+        mac: args.ccastvm_mac,
+        ip: args.ccastvm_ip,
+    };
+    let rss_start: Vec<_> = args.pid.iter().map(|&pid| (pid, read_rss(pid))).collect();
+    let end = start + Duration::from_secs(args.duration);
+    let report_interval = Duration::from_secs(args.report_interval.max(1));
+    let mut next_report = start + report_interval;
+    let mut senders = [ext_tx, int_tx];
+    let mut next_send: BTreeMap<Pattern, Instant> =
+        Pattern::ALL.iter().map(|&p| (p, start)).collect();
+    let mut seq = 0;
+
+    info!("Soak test running for {}s", args.duration);
+    while Instant::now() < end {
+        let now = Instant::now();
+        for (&pattern, due) in &mut next_send {
+            let rate = pattern.rate(&args);
+            if rate == 0 || *due > now {
+                continue;
+            }
+            let tag = Tag {
+                pattern,
+                seq,
+                sent: now.duration_since(start),
+            };
+            seq += 1;
+            let frame = build(pattern, &tag, &args, &device, &ccastvm);
+            let tx = &mut senders[usize::from(pattern.outbound())];
+            match tx.send_to(&frame, None) {
+                Some(Ok(())) => stats.lock().unwrap().entry(pattern).or_default().sent += 1,
+                Some(Err(e)) => warn!("Failed to send {} packet: {e}", pattern.name()),
+                None => warn!("Failed to send {} packet", pattern.name()),
+            }
+            *due += Duration::from_secs(1) / rate;
+        }
+        if now >= next_report {
+            report(&stats, &rss_start, now.duration_since(start));
+            next_report += report_interval;
+        }
+        let next = next_send.values().min().copied().unwrap_or(end);
+        thread::sleep(
+            next.min(next_report)
+                .saturating_duration_since(Instant::now()),
+        );
+    }
+
+    thread::sleep(DRAIN);
+    running.store(false, Ordering::Relaxed);
+    let failed = report(&stats, &rss_start, start.elapsed());
+    let stats = stats.lock().unwrap();
+    let lossy: Vec<_> = stats
+        .iter()
+        .filter(|(_, s)| s.loss() > args.max_loss)
+        .map(|(p, _)| p.name())
+        .collect();
+    let growing: Vec<_> = failed
+        .iter()
+        .filter(|(_, growth)| *growth > args.max_rss_growth)
+        .map(|(pid, _)| pid.to_string())
+        .collect();
+    if lossy.is_empty() && growing.is_empty() {
+        info!("Soak test passed");
+        ExitCode::SUCCESS
+    } else {
+        error!(
+            "Soak test failed, loss over {}% for [{}], memory growth over {} KiB for pids [{}]",
+            args.max_loss,
+            lossy.join(", "),
+            args.max_rss_growth,
+            growing.join(", ")
+        );
+        ExitCode::FAILURE
+    }
+}
+
+fn open(iface: &str) -> Result<Channel, String> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|i| i.name == iface)
+        .ok_or_else(|| format!("No interface {iface}"))?;
+    let config = Config {
+        read_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    match datalink::channel(&interface, config) {
+        Ok(Ethernet(tx, rx)) => Ok((tx, rx)),
+        Ok(_) => Err(format!("Unhandled channel type on {iface}")),
+        Err(e) => Err(format!("Failed to open {iface}: {e}")),
+    }
+}
+
+/// Builds the frame of a test packet.
+fn build(
+    pattern: Pattern,
+    tag: &Tag,
+    args: &Args,
+    device: &Endpoint,
+    ccastvm: &Endpoint,
+) -> Vec<u8> {
+    let tag = tag.encode();
+    // DNS header with no records, as query or response
+    let dns = |response: bool| {
+        let mut header = [0u8; 12];
+        header[2] = if response { 0x84 } else { 0 };
+        header
+    };
+    match pattern {
+        Pattern::MdnsQuery => udp_frame(
+            ccastvm,
+            (multicast_mac(MDNS_IP), MDNS_IP),
+            (MDNS_PORT, MDNS_PORT),
+            &[&dns(false)[..], &tag].concat(),
+        ),
+        Pattern::SsdpSearch => udp_frame(
+            ccastvm,
+            (multicast_mac(SSDP_IP), SSDP_IP),
+            (args.search_port, SSDP_PORT),
+            &[SSDP_SEARCH, &tag].concat(),
+        ),
+        Pattern::MdnsResponse => udp_frame(
+            device,
+            (multicast_mac(MDNS_IP), MDNS_IP),
+            (MDNS_PORT, MDNS_PORT),
+            &[&dns(true)[..], &tag].concat(),
+        ),
+        Pattern::Stream => {
+            let mut payload = vec![0u8; args.stream_size.max(TAG_LEN) - TAG_LEN];
+            payload.extend_from_slice(&tag);
+            udp_frame(
+                device,
+                (args.forwarder_mac, args.forwarder_ip),
+                (CAST_PORT, args.search_port),
+                &payload,
+            )
+        }
+    }
+}
+
+fn multicast_mac(ip: Ipv4Addr) -> MacAddr {
+    let [_, b, c, d] = ip.octets();
+    MacAddr(0x01, 0x00, 0x5e, b & 0x7f, c, d)
+}
+
+fn udp_frame(
+    src: &Endpoint,
+    (dest_mac, dest_ip): (MacAddr, Ipv4Addr),
+    (src_port, dest_port): (u16, u16),
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut frame = vec![0u8; 14 + 20 + udp_len];
+    let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+    eth.set_source(src.mac);
+    eth.set_destination(dest_mac);
+    eth.set_ethertype(EtherTypes::Ipv4);
+    let mut ip = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + udp_len) as u16);
+    ip.set_ttl(if dest_ip.is_multicast() { 1 } else { 64 });
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ip.set_source(src.ip);
+    ip.set_destination(dest_ip);
+    ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+    let mut udp = MutableUdpPacket::new(&mut frame[34..]).unwrap();
+    udp.set_source(src_port);
+    udp.set_destination(dest_port);
+    udp.set_length(udp_len as u16);
+    udp.set_payload(payload);
+    udp.set_checksum(udp::ipv4_checksum(&udp.to_immutable(), &src.ip, &dest_ip));
+    frame
+}
+
+/// Counts the test packets arriving on one side, those of `outbound` patterns on the
+/// external side, the others on the internal side.
+fn receive(
+    mut rx: Box<dyn DataLinkReceiver>,
+    outbound: bool,
+    start: Instant,
+    stats: &Stats,
+    running: &AtomicBool,
+) {
+    while running.load(Ordering::Relaxed) {
+        let Ok(frame) = rx.next() else {
+            continue;
+        };
+        let Some(tag) = parse(frame) else {
+            continue;
+        };
+        // The side a pattern is sent from sees its own frames too
+        if tag.pattern.outbound() != outbound {
+            continue;
+        }
+        let latency = start.elapsed().saturating_sub(tag.sent);
+        let mut stats = stats.lock().unwrap();
+        let pattern = stats.entry(tag.pattern).or_default();
+        pattern.received += 1;
+        pattern.max_latency = pattern.max_latency.max(latency);
+        if pattern.samples.len() < MAX_SAMPLES {
+            pattern.samples.push(latency);
+        }
+    }
+}
+
+fn parse(frame: &[u8]) -> Option<Tag> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new(eth.payload())?;
+    if ip.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    Tag::decode(UdpPacket::new(ip.payload())?.payload())
+}
+
+/// Resident memory of `pid` in KiB
+fn read_rss(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Logs the counters and resets the latency samples, returning the memory growth of
+/// each tracked forwarder in KiB.
+fn report(stats: &Stats, rss_start: &[(u32, Option<u64>)], elapsed: Duration) -> Vec<(u32, u64)> {
+    let mut stats = stats.lock().unwrap();
+    for (pattern, s) in stats.iter_mut() {
+        let ms = |d: Option<Duration>| d.map_or(f64::NAN, |d| d.as_secs_f64() * 1000.0);
+        info!(
+            "[{}s] {:<13} sent {:>9} received {:>9} loss {:>6.2}% latency p50 {:.3}ms p99 {:.3}ms max {:.3}ms",
+            elapsed.as_secs(),
+            pattern.name(),
+            s.sent,
+            s.received,
+            s.loss(),
+            ms(s.percentile(0.5)),
+            ms(s.percentile(0.99)),
+            ms(Some(s.max_latency)),
+        );
+        s.samples.clear();
+    }
+    rss_start
+        .iter()
+        .map(|&(pid, start)| {
+            let now = read_rss(pid);
+            let growth = now
+                .zip(start)
+                .map_or(0, |(now, start)| now.saturating_sub(start));
+            match now {
+                Some(now) => info!(
+                    "[{}s] forwarder {pid} rss {now} KiB, grew {growth} KiB",
+                    elapsed.as_secs()
+                ),
+                None => warn!("[{}s] forwarder {pid} is gone", elapsed.as_secs()),
+            }
+            (pid, growth)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_roundtrip() {
+        let tag = Tag {
+            pattern: Pattern::Stream,
+            seq: 42,
+            sent: Duration::from_micros(1234),
+        };
+        let payload = [&b"M-SEARCH * HTTP/1.1\r\n\r\n"[..], &tag.encode()].concat();
+        assert_eq!(Tag::decode(&payload), Some(tag));
+        assert_eq!(Tag::decode(&payload[..payload.len() - 1]), None);
+        assert_eq!(Tag::decode(b"no tag here"), None);
+    }
+
+    #[test]
+    fn test_frame_is_parsed() {
+        let endpoint = Endpoint {
+            mac: MacAddr(2, 0, 0, 0, 0, 2),
+            ip: Ipv4Addr::new(192, 168, 100, 2),
+        };
+        let tag = Tag {
+            pattern: Pattern::MdnsQuery,
+            seq: 7,
+            sent: Duration::from_millis(5),
+        };
+        let frame = udp_frame(
+            &endpoint,
+            (multicast_mac(MDNS_IP), MDNS_IP),
+            (MDNS_PORT, MDNS_PORT),
+            &tag.encode(),
+        );
+        assert_eq!(parse(&frame), Some(tag));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_destination(), MacAddr(0x01, 0, 0x5e, 0, 0, 0xfb));
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let stats = PatternStats {
+            sent: 10,
+            received: 9,
+            samples: (1..=100).map(Duration::from_millis).collect(),
+            ..Default::default()
+        };
+        assert_eq!(stats.percentile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(stats.percentile(0.99), Some(Duration::from_millis(99)));
+        assert!((stats.loss() - 10.0).abs() < f64::EPSILON);
+        assert_eq!(PatternStats::default().percentile(0.5), None);
+    }
+}
@@ -0,0 +1,108 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Capture threads
+//!
+//! Each capture channel is read by a dedicated OS thread, which hands the frames to the
+//! async processing task over a bounded queue. This replaces a `spawn_blocking` call per
+//! packet. When processing falls behind, the queue fills up and the thread stops reading,
+//! so excess frames are dropped by the kernel and show up in its capture statistics.
+use pnet::datalink::DataLinkReceiver;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use tokio_util::sync::CancellationToken;
+
+/// A captured frame, or the error the read failed with
+pub type Frame = Result<Vec<u8>, String>;
+
+/// Frames queued between a capture thread and its processing task
+pub const QUEUE_DEPTH: usize = 1024;
+/// Upper bound for a blocking read, so the receiver can be swapped under it and the
+/// thread notices cancellation
+pub const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Pause after a failed read, e.g. while the interface is down
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Starts a capture thread reading `rx` until `cancel_token` is cancelled or the
+/// returned queue is dropped.
+pub fn spawn(
+    name: &str,
+    rx: Arc<Mutex<Box<dyn DataLinkReceiver>>>,
+    cancel_token: CancellationToken,
+) -> std::io::Result<mpsc::Receiver<Frame>> {
+    let (tx, frames) = mpsc::channel(QUEUE_DEPTH);
+    thread::Builder::new()
+        .name(format!("capture-{name}"))
+        .spawn(move || capture_loop(&rx, &tx, &cancel_token))?;
+    Ok(frames)
+}
+
+fn capture_loop(
+    rx: &Mutex<Box<dyn DataLinkReceiver>>,
+    tx: &mpsc::Sender<Frame>,
+    cancel_token: &CancellationToken,
+) {
+    while !cancel_token.is_cancelled() {
+        // Only contended while the receiver is being swapped
+        let frame = match rx.blocking_lock().next().map(<[u8]>::to_vec) {
+            Ok(frame) => Ok(frame),
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => Err(format!("Error receiving packet: {e}")),
+        };
+        let failed = frame.is_err();
+        if tx.blocking_send(frame).is_err() {
+            // Processing task is gone
+            break;
+        }
+        if failed {
+            thread::sleep(ERROR_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Yields `frames` numbered frames, then times out
+    struct Numbered {
+        next: u8,
+        frames: u8,
+        buffer: [u8; 1],
+    }
+
+    impl DataLinkReceiver for Numbered {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            if self.next == self.frames {
+                thread::sleep(Duration::from_millis(10));
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            self.buffer[0] = self.next;
+            self.next += 1;
+            Ok(&self.buffer)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_delivered_in_order() {
+        let rx: Box<dyn DataLinkReceiver> = Box::new(Numbered {
+            next: 0,
+            frames: 3,
+            buffer: [0],
+        });
+        let cancel_token = CancellationToken::new();
+        let mut frames = spawn("test", Arc::new(Mutex::new(rx)), cancel_token.clone()).unwrap();
+        for i in 0..3 {
+            assert_eq!(frames.recv().await, Some(Ok(vec![i])));
+        }
+
+        // The thread stops on cancellation and closes the queue
+        cancel_token.cancel();
+        assert_eq!(frames.recv().await, None);
+    }
+}
@@ -0,0 +1,251 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Kernel capture statistics
+//!
+//! Frames the kernel drops because the capture socket's receive queue overflowed never
+//! reach the forwarder, so they are invisible to the filters' own drop accounting. The
+//! capture sockets are therefore opened here and handed to pnet via `Config::socket_fd`,
+//! which lets `PACKET_STATISTICS` be polled on them to tell ring overruns apart from
+//! application-level drops.
+use crate::traffic_stats::TrafficStats;
+use log::{debug, warn};
+use std::fmt::Write as _;
+use std::io;
+use std::os::fd::RawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, interval};
+use tokio_util::sync::CancellationToken;
+
+/// Opens a raw `AF_PACKET` socket capturing all protocols, for use as `Config::socket_fd`.
+///
+/// pnet takes ownership of the descriptor and closes it with the channel.
+pub fn open_socket() -> io::Result<RawFd> {
+    // SAFETY: plain socket(2) call, the result is checked below
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_RAW,
+            (libc::ETH_P_ALL as u16).to_be().into(),
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// Frames seen by the socket, including the dropped ones
+    pub received: u64,
+    /// Frames dropped by the kernel before userspace read them
+    pub dropped: u64,
+}
+
+/// Cumulative capture statistics of one side of the forwarder.
+///
+/// The kernel resets the counters on every read, so they are accumulated here and keep
+/// counting when the underlying socket is replaced (e.g. on an external link switch).
+pub struct CaptureStats {
+    side: &'static str,
+    fd: Mutex<Option<RawFd>>,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl CaptureStats {
+    pub fn new(side: &'static str) -> Self {
+        Self {
+            side,
+            fd: Mutex::new(None),
+            received: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts tracking `fd`, collecting what is left on the previous socket first.
+    ///
+    /// Must be called while the previous socket is still open.
+    pub fn track(&self, fd: RawFd) {
+        let mut current = self.fd.lock().expect("Failed to lock capture stats");
+        if let Some(old) = current.replace(fd) {
+            match read_kernel_stats(old) {
+                Ok(delta) => self.add(delta),
+                Err(e) => debug!("Failed to read final {} capture stats: {e}", self.side),
+            }
+        }
+    }
+
+    /// Collects the counters since the last poll and adds them to the totals.
+    pub fn poll(&self) -> io::Result<Counters> {
+        let current = self.fd.lock().expect("Failed to lock capture stats");
+        let Some(fd) = *current else {
+            return Ok(Counters::default());
+        };
+        let delta = read_kernel_stats(fd)?;
+        self.add(delta);
+        Ok(delta)
+    }
+
+    pub fn totals(&self) -> Counters {
+        Counters {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn side(&self) -> &'static str {
+        self.side
+    }
+
+    fn add(&self, delta: Counters) {
+        self.received.fetch_add(delta.received, Ordering::Relaxed);
+        self.dropped.fetch_add(delta.dropped, Ordering::Relaxed);
+    }
+}
+
+fn read_kernel_stats(fd: RawFd) -> io::Result<Counters> {
+    let mut stats = libc::tpacket_stats {
+        tp_packets: 0,
+        tp_drops: 0,
+    };
+    let mut len = size_of::<libc::tpacket_stats>() as libc::socklen_t;
+    // SAFETY: `stats` and `len` describe a valid, writable `tpacket_stats`
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_PACKET,
+            libc::PACKET_STATISTICS,
+            (&raw mut stats).cast(),
+            &raw mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Counters {
+        received: stats.tp_packets.into(),
+        dropped: stats.tp_drops.into(),
+    })
+}
+
+/// Polls `stats` every `period`, logging kernel drops and exporting the totals along with
+/// the `traffic` counters to `export_path` (if set) in the Prometheus text format, e.g.
+/// for the node exporter textfile collector.
+pub async fn monitor(
+    stats: Vec<Arc<CaptureStats>>,
+    traffic: Arc<TrafficStats>,
+    period: Duration,
+    export_path: Option<&Path>,
+    cancel_token: CancellationToken,
+) {
+    let mut interval = interval(period);
+    loop {
+        tokio::select! {
+            () = cancel_token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        for side in &stats {
+            match side.poll() {
+                Ok(delta) if delta.dropped > 0 => warn!(
+                    "Kernel dropped {} of {} frames on the {} capture socket (ring overrun)",
+                    delta.dropped,
+                    delta.received,
+                    side.side()
+                ),
+                Ok(delta) => debug!(
+                    "Captured {} frames on the {} side",
+                    delta.received,
+                    side.side()
+                ),
+                Err(e) => debug!("Failed to read {} capture stats: {e}", side.side()),
+            }
+        }
+
+        if let Some(path) = export_path
+            && let Err(e) = export(path, &stats, &traffic).await
+        {
+            warn!("Failed to export capture stats to {}: {e}", path.display());
+        }
+    }
+}
+
+async fn export(
+    path: &Path,
+    stats: &[Arc<CaptureStats>],
+    traffic: &TrafficStats,
+) -> io::Result<()> {
+    let totals: Vec<_> = stats.iter().map(|s| (s.side(), s.totals())).collect();
+    let text = render(&totals) + &traffic.render();
+    // Write and rename so readers never see a partial file
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, text).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+fn render(totals: &[(&str, Counters)]) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP nw_pckt_fwd_capture_received_total Frames seen by the capture socket.\n");
+    out.push_str("# TYPE nw_pckt_fwd_capture_received_total counter\n");
+    for (side, counters) in totals {
+        let _ = writeln!(
+            out,
+            "nw_pckt_fwd_capture_received_total{{side=\"{side}\"}} {}",
+            counters.received
+        );
+    }
+    out.push_str(
+        "# HELP nw_pckt_fwd_capture_kernel_dropped_total Frames dropped by the kernel before capture.\n",
+    );
+    out.push_str("# TYPE nw_pckt_fwd_capture_kernel_dropped_total counter\n");
+    for (side, counters) in totals {
+        let _ = writeln!(
+            out,
+            "nw_pckt_fwd_capture_kernel_dropped_total{{side=\"{side}\"}} {}",
+            counters.dropped
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untracked_stats_are_zero() {
+        let stats = CaptureStats::new("internal");
+        assert_eq!(stats.poll().unwrap(), Counters::default());
+        assert_eq!(stats.totals(), Counters::default());
+    }
+
+    #[test]
+    fn test_render() {
+        let totals = [
+            (
+                "internal",
+                Counters {
+                    received: 10,
+                    dropped: 0,
+                },
+            ),
+            (
+                "external",
+                Counters {
+                    received: 200,
+                    dropped: 3,
+                },
+            ),
+        ];
+        let text = render(&totals);
+        assert!(text.contains("nw_pckt_fwd_capture_received_total{side=\"internal\"} 10\n"));
+        assert!(text.contains("nw_pckt_fwd_capture_received_total{side=\"external\"} 200\n"));
+        assert!(text.contains("nw_pckt_fwd_capture_kernel_dropped_total{side=\"external\"} 3\n"));
+        assert_eq!(text.matches("# TYPE").count(), 2);
+    }
+}
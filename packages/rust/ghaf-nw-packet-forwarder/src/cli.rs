@@ -3,15 +3,28 @@
     SPDX-License-Identifier: Apache-2.0
 */
 use clap::Parser;
+use clap::Subcommand;
 use clap::ValueEnum;
 use lazy_static::lazy_static;
 use pnet::ipnetwork::IpNetwork;
 use pnet::util::MacAddr;
 use std::error::Error;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::time::Duration;
 
+use crate::drop_log::DropLog;
+use crate::filter::balancer::{Strategy, Target};
+use crate::filter::mdns_reflector::ServiceSpec;
 use crate::filter::security::RateLimiter;
+use crate::filter::{Balancer, Conntrack, DevicePins};
+use crate::forward_impl::dhcp_relay::DhcpRelay;
+use crate::forward_impl::napt::{self, Napt};
+use crate::forward_impl::neighbors::Neighbors;
+use crate::forward_impl::retransmit::Retransmitter;
+use crate::inject::Probe;
+use crate::pcap_dump::PcapDump;
 
 lazy_static! {
     static ref CLI_ARGS: Args = {
@@ -38,9 +51,10 @@ pub enum LogOutput {
 #[command(about = "Packet forwarder between two network interfaces for Ghaf.")]
 #[command(long_about =None /* ,version =VERSION*/)]
 struct Args {
-    /// Name of the external network interface
-    #[arg(long)]
-    external_iface: String,
+    /// Name of the external network interface, or a comma-separated list in
+    /// order of preference (e.g. `eth0,wlan0`) to follow the best available link
+    #[arg(long, required = true, value_delimiter = ',')]
+    external_iface: Vec<String>,
 
     /// Name of the internal network interface
     #[arg(long)]
@@ -70,13 +84,140 @@ struct Args {
     #[arg(long, default_value_t = 50)]
     rate_limiting_max_routes: usize,
 
-    /// Chromecast VM Ip address
+    /// Chromecast VM Ip address, or a comma-separated list of addresses of VMs running
+    /// the service active/active
+    #[arg(long, value_delimiter = ',')]
+    ccastvm_ip: Vec<IpNetwork>,
+
+    /// Chromecast VM Mac address, or a comma-separated list in the order of `--ccastvm-ip`
+    #[arg(long, value_delimiter = ',')]
+    ccastvm_mac: Vec<MacAddr>,
+
+    /// How flows are spread over several chromecast VMs
+    #[arg(long, value_enum, default_value_t = Default::default())]
+    ccastvm_balance: Strategy,
+
+    /// Seconds a flow stays with the chromecast VM it was spread to, or that opened it,
+    /// after its last packet
+    #[arg(long, default_value_t = 30)]
+    ccastvm_flow_timeout: u64,
+
+    /// TCP port health-checked on each of several chromecast VMs
+    #[arg(long, default_value_t = 8009)]
+    ccastvm_health_port: u16,
+
+    /// Interval in seconds for health-checking several chromecast VMs, 0 to disable
+    #[arg(long, default_value_t = 5)]
+    ccastvm_health_interval: u64,
+
+    /// Relay DHCP between internal VMs and the server on the external network
+    #[arg(long)]
+    dhcp_relay: bool,
+
+    /// Extra copies of each discovery response (mDNS answers, SSDP search replies) sent
+    /// to the internal guest, for lossy external links; 0 to disable
+    #[arg(long, default_value_t = 0)]
+    discovery_retransmit: u32,
+
+    /// Milliseconds between the copies of a discovery response
+    #[arg(long, default_value_t = 100)]
+    discovery_retransmit_spacing: u64,
+
+    /// Discovery services to reflect between interfaces, e.g. `_airplay._tcp,_ipp._tcp,ssdp`.
+    /// Append `=off` to configure a service with reflection initially disabled
+    #[arg(long, value_delimiter = ',')]
+    reflect_service: Vec<ServiceSpec>,
+
+    /// Seconds a tracked flow admits return traffic after its last packet
+    #[arg(long, default_value_t = 30)]
+    conntrack_timeout: u64,
+
+    /// Maximum number of flows in the connection tracking table
+    #[arg(long, default_value_t = 256)]
+    conntrack_max_flows: usize,
+
+    /// External ports translating the flows internal VMs open to the outside themselves,
+    /// e.g. `61000-65535`; only the filtered services are forwarded without. Keep the
+    /// range clear of the ephemeral ports of the VMs and of this host. Needs `nft`, the
+    /// range is closed to this host's own stack while running
+    #[arg(long, value_parser = napt::parse_ports)]
+    napt_ports: Option<RangeInclusive<u16>>,
+
+    /// Seconds a translated UDP flow stays mapped after its last packet
+    #[arg(long, default_value_t = 60)]
+    napt_udp_timeout: u64,
+
+    /// Seconds a translated TCP connection stays mapped after its last packet
+    #[arg(long, default_value_t = 3600)]
+    napt_tcp_timeout: u64,
+
+    /// Maximum number of flows in the port translation table
+    #[arg(long, default_value_t = 1024)]
+    napt_max_flows: usize,
+
+    /// Seconds an internal client stays reachable at the MAC it was last seen with, if the
+    /// DHCP relay leased its address to that MAC, 0 to always use the configured MACs
+    #[arg(long, default_value_t = 0)]
+    neighbor_ttl: u64,
+
+    /// Seconds a device answering discovery stays pinned after it was last seen, 0 to
+    /// disable pinning. The pins are listed and cleared through the control API, or
+    /// cleared with SIGUSR1 (`systemctl kill -s USR1 <unit>`)
+    #[arg(long, default_value_t = 3600)]
+    pin_ttl: u64,
+
+    /// File keeping pinned devices across restarts, revalidated when loaded
+    #[arg(long)]
+    pin_state_file: Option<PathBuf>,
+
+    /// Interval in seconds for polling kernel capture statistics, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    capture_stats_interval: u64,
+
+    /// File to export capture and traffic statistics to, in Prometheus text format
     #[arg(long)]
-    ccastvm_ip: Option<IpNetwork>,
+    capture_stats_file: Option<PathBuf>,
 
-    /// Chromecast VM Mac address
+    /// Unix socket serving per-direction, per-protocol and per-client traffic statistics
+    /// as JSON to every client connecting
     #[arg(long)]
-    ccastvm_mac: Option<MacAddr>,
+    stats_socket: Option<PathBuf>,
+
+    /// Vsock port serving the control API, which switches filters, reads statistics and
+    /// moves the chromecast VMs at runtime
+    #[arg(long, requires = "control_vsock_peer")]
+    control_vsock_port: Option<u32>,
+
+    /// Vsock context id of the only VM allowed to use the control API, e.g. the admin VM,
+    /// required with --control-vsock-port
+    #[arg(long)]
+    control_vsock_peer: Option<u32>,
+
+    /// Maximum number of blocked external connection attempts logged per second, 0 to
+    /// disable the log
+    #[arg(long, default_value_t = 0)]
+    drop_log_rate: u32,
+
+    /// MaxMind country database (e.g. GeoLite2-Country.mmdb) tagging logged sources
+    #[arg(long)]
+    geoip_country_db: Option<PathBuf>,
+
+    /// MaxMind ASN database (e.g. GeoLite2-ASN.mmdb) tagging logged sources
+    #[arg(long)]
+    geoip_asn_db: Option<PathBuf>,
+
+    /// Pcap file receiving every forwarded and dropped frame for debugging, with the
+    /// verdict of each frame in `<path>.idx`
+    #[arg(long)]
+    pcap_dump: Option<PathBuf>,
+
+    /// Size in MiB at which the pcap dump moves to `<path>.1` and starts over
+    #[arg(long, default_value_t = 16)]
+    pcap_dump_size: u64,
+
+    /// Number of previous pcap dumps kept
+    #[arg(long, default_value_t = 3)]
+    pcap_dump_files: u32,
 
     /// Log severity
     #[arg(long, default_value_t = log::Level::Info)]
@@ -85,6 +226,16 @@ struct Args {
     /// Log output
     #[arg(long, value_enum, default_value_t = Default::default())]
     pub log_output: LogOutput,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run one crafted packet through the forwarding rules and exit, forwarding it if
+    /// they let it through
+    Inject(Probe),
 }
 
 fn handling_args() -> Result<Args, Box<dyn Error>> {
@@ -95,14 +246,14 @@ fn handling_args() -> Result<Args, Box<dyn Error>> {
 
 impl Args {
     fn validate(&self) {
-        if self.ccastvm_ip.is_none() != self.ccastvm_mac.is_none() {
-            panic!("Error: --ccastvm-ip and --ccastvm-mac must be either both set or both unset.");
+        if self.ccastvm_ip.len() != self.ccastvm_mac.len() {
+            panic!("Error: --ccastvm-ip and --ccastvm-mac must list the same number of addresses.");
         }
     }
 }
 
-pub fn get_ext_iface_name() -> &'static str {
-    CLI_ARGS.external_iface.as_str()
+pub fn get_ext_iface_names() -> &'static [String] {
+    &CLI_ARGS.external_iface
 }
 pub fn get_int_iface_name() -> &'static str {
     CLI_ARGS.internal_iface.as_str()
@@ -116,16 +267,109 @@ pub fn get_int_ip() -> Option<IpNetwork> {
     CLI_ARGS.internal_ip
 }
 
-pub fn get_chromecast() -> bool {
-    CLI_ARGS.ccastvm_ip.is_some() && CLI_ARGS.ccastvm_mac.is_some()
+pub fn get_chromecast_balancer() -> Balancer {
+    let targets = CLI_ARGS
+        .ccastvm_ip
+        .iter()
+        .zip(&CLI_ARGS.ccastvm_mac)
+        .map(|(&ip, &mac)| Target { ip, mac })
+        .collect();
+    Balancer::new(
+        targets,
+        CLI_ARGS.ccastvm_balance,
+        Duration::from_secs(CLI_ARGS.ccastvm_flow_timeout),
+    )
+}
+
+/// Port and interval of chromecast VM health checks, only run with several VMs
+pub fn get_chromecast_health_check() -> Option<(u16, Duration)> {
+    Some(Duration::from_secs(CLI_ARGS.ccastvm_health_interval))
+        .filter(|d| !d.is_zero() && CLI_ARGS.ccastvm_ip.len() > 1)
+        .map(|d| (CLI_ARGS.ccastvm_health_port, d))
+}
+
+pub fn get_dhcp_relay() -> DhcpRelay {
+    DhcpRelay::new(CLI_ARGS.dhcp_relay)
+}
+
+pub fn get_retransmitter() -> Retransmitter {
+    Retransmitter::new(
+        CLI_ARGS.discovery_retransmit,
+        Duration::from_millis(CLI_ARGS.discovery_retransmit_spacing),
+    )
 }
 
-pub fn get_chromecastvm_ip() -> IpNetwork {
-    CLI_ARGS.ccastvm_ip.unwrap()
+pub fn get_reflect_services() -> &'static [ServiceSpec] {
+    &CLI_ARGS.reflect_service
 }
 
-pub fn get_chromecastvm_mac() -> MacAddr {
-    CLI_ARGS.ccastvm_mac.unwrap()
+pub fn get_conntrack() -> Conntrack {
+    Conntrack::new(
+        Duration::from_secs(CLI_ARGS.conntrack_timeout),
+        CLI_ARGS.conntrack_max_flows,
+    )
+}
+
+pub fn get_napt() -> Napt {
+    Napt::new(
+        CLI_ARGS.napt_ports.clone(),
+        Duration::from_secs(CLI_ARGS.napt_udp_timeout),
+        Duration::from_secs(CLI_ARGS.napt_tcp_timeout),
+        CLI_ARGS.napt_max_flows,
+    )
+}
+
+pub fn get_neighbors() -> Neighbors {
+    Neighbors::new(Duration::from_secs(CLI_ARGS.neighbor_ttl))
+}
+
+pub fn get_device_pins() -> DevicePins {
+    DevicePins::new(Duration::from_secs(CLI_ARGS.pin_ttl))
+}
+
+pub fn get_pin_state_file() -> Option<&'static Path> {
+    CLI_ARGS.pin_state_file.as_deref()
+}
+
+pub fn get_capture_stats_interval() -> Option<Duration> {
+    Some(Duration::from_secs(CLI_ARGS.capture_stats_interval)).filter(|d| !d.is_zero())
+}
+
+pub fn get_capture_stats_file() -> Option<&'static Path> {
+    CLI_ARGS.capture_stats_file.as_deref()
+}
+
+pub fn get_stats_socket() -> Option<&'static Path> {
+    CLI_ARGS.stats_socket.as_deref()
+}
+
+/// Port of the control API and the context id of the only peer allowed
+pub fn get_control_vsock() -> Option<(u32, u32)> {
+    CLI_ARGS.control_vsock_port.zip(CLI_ARGS.control_vsock_peer)
+}
+
+pub fn get_drop_log() -> DropLog {
+    DropLog::new(
+        CLI_ARGS.drop_log_rate,
+        CLI_ARGS.geoip_country_db.as_deref(),
+        CLI_ARGS.geoip_asn_db.as_deref(),
+    )
+}
+
+pub fn get_pcap_dump() -> PcapDump {
+    PcapDump::new(
+        CLI_ARGS.pcap_dump.as_deref(),
+        CLI_ARGS.pcap_dump_size << 20,
+        CLI_ARGS.pcap_dump_files,
+    )
+}
+
+/// Test packet to inject instead of forwarding captured traffic
+pub fn get_inject() -> Option<&'static Probe> {
+    match &CLI_ARGS.command {
+        Some(Command::Inject(probe)) => Some(probe),
+        None => None,
+    }
 }
 
 pub fn get_log_level() -> &'static log::Level {
@@ -0,0 +1,373 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Control API
+//!
+//! Lets the Ghaf admin VM manage the forwarder at runtime over vsock, without a shell
+//! on net-vm: switch the discovery filters, read the traffic statistics, move the
+//! chromecast service to another VM and manage the pinned cast devices.
+//!
+//! A client sends one JSON request per line and gets one JSON reply per line:
+//!
+//! - `{"command": "filters"}` lists the filters and whether they are enabled
+//! - `{"command": "set-filter", "name": "cast-ssdp", "enabled": true}`
+//! - `{"command": "stats"}` returns the same snapshot as the statistics socket
+//! - `{"command": "targets"}` lists the chromecast VMs
+//! - `{"command": "set-target", "index": 0, "ip": "192.168.100.5/24", "mac": "02:..."}`
+//! - `{"command": "pins"}` lists the pinned devices and the seconds until each expires
+//! - `{"command": "clear-pins"}` forgets all pinned devices
+//!
+//! Failed requests are answered with `{"error": "..."}`. A client sending a request
+//! longer than `MAX_REQUEST_LEN` bytes is disconnected. Only the VM with the configured
+//! context id may connect. Changes are not persisted, the command line configuration
+//! applies again after a restart.
+use crate::filter::balancer::Target;
+use crate::filter::{Chromecast, DevicePins, MdnsReflector};
+use crate::traffic_stats::TrafficStats;
+use log::{debug, info, warn};
+use pnet::ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+const LISTEN_BACKLOG: i32 = 4;
+/// Longest request line accepted, the largest valid request is well below this
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Request {
+    Filters,
+    SetFilter {
+        name: String,
+        enabled: bool,
+    },
+    Stats,
+    Targets,
+    SetTarget {
+        index: usize,
+        ip: String,
+        mac: String,
+    },
+    Pins,
+    ClearPins,
+}
+
+pub struct Control {
+    pub chromecast: Arc<Mutex<Chromecast>>,
+    pub reflector: Arc<MdnsReflector>,
+    pub pins: Arc<DevicePins>,
+    pub traffic: Arc<TrafficStats>,
+}
+
+impl Control {
+    /// Answers a single request line.
+    async fn handle(&self, line: &str) -> Value {
+        let request = match serde_json::from_str::<Request>(line) {
+            Ok(request) => request,
+            Err(e) => return json!({ "error": format!("Invalid request: {e}") }),
+        };
+        debug!("Control - {request:?}");
+        match request {
+            Request::Filters => {
+                let mut filters: Vec<_> = self
+                    .chromecast
+                    .lock()
+                    .await
+                    .filters()
+                    .into_iter()
+                    .map(|(name, enabled)| json!({ "name": name, "enabled": enabled }))
+                    .collect();
+                filters.extend(
+                    self.reflector
+                        .services()
+                        .into_iter()
+                        .map(|s| json!({ "name": s.name, "enabled": s.enabled })),
+                );
+                json!({ "filters": filters })
+            }
+            Request::SetFilter { name, enabled } => {
+                let found = self.chromecast.lock().await.set_filter(&name, enabled)
+                    || self.reflector.set_service_enabled(&name, enabled);
+                if found {
+                    json!({ "ok": true })
+                } else {
+                    json!({ "error": format!("Unknown filter '{name}'") })
+                }
+            }
+            Request::Stats => json!({ "stats": self.traffic.snapshot() }),
+            Request::Targets => {
+                let balancer = self.chromecast.lock().await.get_balancer();
+                let targets: Vec<_> = balancer
+                    .targets()
+                    .iter()
+                    .map(|t| json!({ "ip": t.ip.to_string(), "mac": t.mac.to_string() }))
+                    .collect();
+                json!({ "targets": targets })
+            }
+            Request::SetTarget { index, ip, mac } => {
+                let target = match parse_target(&ip, &mac) {
+                    Ok(target) => target,
+                    Err(e) => return json!({ "error": e }),
+                };
+                let balancer = self.chromecast.lock().await.get_balancer();
+                if balancer.set_target(index, target).await {
+                    json!({ "ok": true })
+                } else {
+                    json!({ "error": format!("No target at index {index}") })
+                }
+            }
+            Request::Pins => {
+                let pins: Vec<_> = self
+                    .pins
+                    .list()
+                    .await
+                    .into_iter()
+                    .map(|(ip, mac, expires)| {
+                        json!({
+                            "ip": ip.to_string(),
+                            "mac": mac.to_string(),
+                            "expires": expires.as_secs(),
+                        })
+                    })
+                    .collect();
+                json!({ "pins": pins })
+            }
+            Request::ClearPins => {
+                self.pins.clear().await;
+                json!({ "ok": true })
+            }
+        }
+    }
+
+    async fn serve_client(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        while read_request(&mut reader, &mut line).await? {
+            let request = String::from_utf8_lossy(&line);
+            let mut reply = serde_json::to_vec(&self.handle(request.trim_end()).await)?;
+            reply.push(b'\n');
+            writer.write_all(&reply).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next request line into `line`, returning false at the end of the stream.
+///
+/// Fails once the line grows past `MAX_REQUEST_LEN`, so a client cannot make the
+/// forwarder buffer without bound.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<bool> {
+    line.clear();
+    let limit = MAX_REQUEST_LEN as u64 + 1;
+    if reader.take(limit).read_until(b'\n', line).await? == 0 {
+        return Ok(false);
+    }
+    if line.last() != Some(&b'\n') && line.len() > MAX_REQUEST_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("request longer than {MAX_REQUEST_LEN} bytes"),
+        ));
+    }
+    Ok(true)
+}
+
+fn parse_target(ip: &str, mac: &str) -> Result<Target, String> {
+    let ip: IpNetwork = ip.parse().map_err(|e| format!("Invalid ip '{ip}': {e}"))?;
+    if !ip.is_ipv4() {
+        return Err(format!("Invalid ip '{ip}': chromecast VMs are IPv4 only"));
+    }
+    let mac: MacAddr = mac
+        .parse()
+        .map_err(|e| format!("Invalid mac '{mac}': {e}"))?;
+    Ok(Target { ip, mac })
+}
+
+/// Serves the control API on vsock `port`, for the VM with context id `peer` only,
+/// until `cancel_token` is cancelled.
+pub async fn serve(
+    control: Arc<Control>,
+    port: u32,
+    peer: u32,
+    cancel_token: CancellationToken,
+) -> io::Result<()> {
+    let listener = AsyncFd::new(listen(port)?)?;
+    info!("Serving the control API on vsock port {port}");
+
+    loop {
+        let (stream, cid) = tokio::select! {
+            () = cancel_token.cancelled() => break,
+            accepted = accept(&listener) => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept control client: {e}");
+                    continue;
+                }
+            },
+        };
+        if cid != peer {
+            warn!("Control - refused connection from vsock cid {cid}");
+            continue;
+        }
+        info!("Control - client connected from vsock cid {cid}");
+        tokio::task::spawn({
+            let control = Arc::clone(&control);
+            async move {
+                if let Err(e) = control.serve_client(stream).await {
+                    debug!("Control - client {cid} failed: {e}");
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Opens a non-blocking vsock socket listening on `port` of any local context id.
+fn listen(port: u32) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket(2) call, the result is checked below
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_VSOCK,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just opened and is owned by nothing else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_vm is plain data, all zeroes is a valid value
+    let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_port = port;
+    addr.svm_cid = libc::VMADDR_CID_ANY;
+    // SAFETY: addr is a sockaddr_vm of the given length
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&raw const addr).cast(),
+            size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: plain listen(2) call on the bound socket
+    if unsafe { libc::listen(fd.as_raw_fd(), LISTEN_BACKLOG) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Accepts a client, returning its stream and context id.
+///
+/// The stream is driven as a `UnixStream`, which only reads and writes the descriptor
+/// and so works the same on a connected vsock socket.
+async fn accept(listener: &AsyncFd<OwnedFd>) -> io::Result<(UnixStream, u32)> {
+    loop {
+        let mut guard = listener.readable().await?;
+        // SAFETY: sockaddr_vm is plain data, all zeroes is a valid value
+        let mut addr: libc::sockaddr_vm = unsafe { std::mem::zeroed() };
+        let mut len = size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        let accepted = guard.try_io(|listener| {
+            // SAFETY: addr and len describe a writable sockaddr_vm
+            let fd = unsafe {
+                libc::accept4(
+                    listener.as_raw_fd(),
+                    (&raw mut addr).cast(),
+                    &mut len,
+                    libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: accept4 returned a new descriptor owned by nothing else
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        });
+        match accepted {
+            Ok(fd) => {
+                let stream = std::os::unix::net::UnixStream::from(fd?);
+                return Ok((UnixStream::from_std(stream)?, addr.svm_cid));
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_requests() {
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command": "filters"}"#).unwrap(),
+            Request::Filters
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(
+                r#"{"command": "set-filter", "name": "cast-ssdp", "enabled": true}"#
+            )
+            .unwrap(),
+            Request::SetFilter {
+                name: "cast-ssdp".to_string(),
+                enabled: true
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command": "clear-pins"}"#).unwrap(),
+            Request::ClearPins
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"command": "reboot"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"command": "set-filter"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let target = parse_target("192.168.100.5/24", "02:00:00:00:00:05").unwrap();
+        assert_eq!(target.ip.to_string(), "192.168.100.5/24");
+        assert_eq!(target.mac, MacAddr(2, 0, 0, 0, 0, 5));
+        assert!(parse_target("fd00::5/64", "02:00:00:00:00:05").is_err());
+        assert!(parse_target("192.168.100.5/24", "02:00").is_err());
+        assert!(parse_target("chromecast", "02:00:00:00:00:05").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let input = b"{\"command\": \"stats\"}\n{\"command\": \"filters\"}".to_vec();
+        let mut reader = input.as_slice();
+        let mut line = Vec::new();
+        assert!(read_request(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"{\"command\": \"stats\"}\n");
+        assert!(read_request(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"{\"command\": \"filters\"}");
+        assert!(!read_request(&mut reader, &mut line).await.unwrap());
+
+        let mut input = vec![b' '; MAX_REQUEST_LEN - 1];
+        input.push(b'\n');
+        let mut reader = input.as_slice();
+        assert!(read_request(&mut reader, &mut line).await.unwrap());
+
+        let input = vec![b' '; MAX_REQUEST_LEN + 1];
+        let mut reader = input.as_slice();
+        let err = read_request(&mut reader, &mut line).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
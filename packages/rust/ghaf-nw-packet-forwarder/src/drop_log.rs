@@ -0,0 +1,232 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Blocked connection log
+//!
+//! Logs unicast packets from the external network that the forwarder refuses, so what
+//! is probing the opened ports can be reviewed. Entries go to the `drop_log` target as
+//! `key=value` pairs and, when offline MaxMind databases (e.g. GeoLite2-Country and
+//! GeoLite2-ASN) are given, carry the country and autonomous system of the source.
+//!
+//! Entries are rate-limited per second. The number of entries suppressed is reported
+//! with the next one logged.
+use log::{error, info};
+use maxminddb::{Reader, geoip2};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Why an external packet was not forwarded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    /// Addressed to no forwarded service
+    NoService,
+    /// Not part of a flow opened from the internal network
+    Untracked,
+    /// From a device other than the one pinned for the flow
+    NotPinned,
+    /// Failed the safety checks or could not be sent
+    Refused,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::NoService => "no-service",
+            DropReason::Untracked => "untracked",
+            DropReason::NotPinned => "not-pinned",
+            DropReason::Refused => "refused",
+        })
+    }
+}
+
+/// Entries logged in the current window, and suppressed since the last one logged
+struct Window {
+    start: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl Window {
+    /// Returns the number of entries suppressed before this one if it may be logged.
+    fn admit(&mut self, now: Instant, rate: u32) -> Option<u64> {
+        if now.duration_since(self.start) >= WINDOW {
+            self.start = now;
+            self.logged = 0;
+        }
+        if self.logged >= rate {
+            self.suppressed += 1;
+            return None;
+        }
+        self.logged += 1;
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+pub struct DropLog {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    rate: u32,
+    window: Mutex<Window>,
+}
+
+impl DropLog {
+    /// Creates a log of at most `rate` entries per second, 0 to disable it, tagging
+    /// sources from the given databases. A database that fails to open is left out.
+    pub fn new(rate: u32, country_db: Option<&Path>, asn_db: Option<&Path>) -> Self {
+        let open = |path: Option<&Path>| {
+            let path = path.filter(|_| rate > 0)?;
+            Reader::open_readfile(path)
+                .inspect_err(|e| error!("Failed to open GeoIP database {}: {e}", path.display()))
+                .ok()
+        };
+        Self {
+            country: open(country_db),
+            asn: open(asn_db),
+            rate,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Logs `eth_packet`, dropped for `reason`, unless the rate limit was reached.
+    pub fn record(&self, reason: DropReason, eth_packet: &EthernetPacket) {
+        if self.rate == 0 {
+            return;
+        }
+        let Some(entry) = self.entry(reason, eth_packet) else {
+            return;
+        };
+        let admitted = self.window.lock().unwrap().admit(Instant::now(), self.rate);
+        match admitted {
+            Some(0) => info!(target: "drop_log", "{entry}"),
+            Some(suppressed) => info!(target: "drop_log", "{entry} suppressed={suppressed}"),
+            None => {}
+        }
+    }
+
+    /// Formats the entry for `eth_packet`, `None` unless it is unicast IPv4.
+    fn entry(&self, reason: DropReason, eth_packet: &EthernetPacket) -> Option<String> {
+        if eth_packet.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+        let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+        let (src, dst) = (ipv4_packet.get_source(), ipv4_packet.get_destination());
+        if dst.is_multicast() || dst.is_broadcast() {
+            return None;
+        }
+        let protocol = ipv4_packet.get_next_level_protocol();
+        let ports = match protocol {
+            IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4_packet.payload())
+                .map(|tcp| (tcp.get_source(), tcp.get_destination())),
+            IpNextHeaderProtocols::Udp => UdpPacket::new(ipv4_packet.payload())
+                .map(|udp| (udp.get_source(), udp.get_destination())),
+            _ => None,
+        };
+        let (sport, dport) = ports.unwrap_or_default();
+        Some(format!(
+            "blocked reason={reason} proto={} src={src} sport={sport} dst={dst} dport={dport} {}",
+            protocol.to_string().to_lowercase(),
+            self.tags(src)
+        ))
+    }
+
+    /// Country and autonomous system of `ip`, `-` when unknown.
+    fn tags(&self, ip: Ipv4Addr) -> String {
+        let ip = IpAddr::V4(ip);
+        let country = self
+            .country
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Country>(ip).ok())
+            .and_then(|c| c.country.and_then(|c| c.iso_code).map(str::to_owned));
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Asn>(ip).ok());
+        let number = asn.as_ref().and_then(|a| a.autonomous_system_number);
+        let org = asn.as_ref().and_then(|a| a.autonomous_system_organization);
+        format!(
+            "country={} asn={} org=\"{}\"",
+            country.as_deref().unwrap_or("-"),
+            number.map_or_else(|| "-".to_owned(), |n| format!("AS{n}")),
+            org.unwrap_or("-").replace('"', "'")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+
+    fn tcp_frame(dst: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 20];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ip = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(40);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip.set_source(Ipv4Addr::new(203, 0, 113, 7));
+        ip.set_destination(dst);
+        let mut tcp = MutableTcpPacket::new(ip.payload_mut()).unwrap();
+        tcp.set_source(51515);
+        tcp.set_destination(8009);
+        frame
+    }
+
+    #[test]
+    fn test_entry_without_databases() {
+        let log = DropLog::new(10, None, None);
+        let frame = tcp_frame(Ipv4Addr::new(192, 168, 1, 20));
+        let entry = log
+            .entry(DropReason::Untracked, &EthernetPacket::new(&frame).unwrap())
+            .unwrap();
+        assert_eq!(
+            entry,
+            "blocked reason=untracked proto=tcp src=203.0.113.7 sport=51515 \
+             dst=192.168.1.20 dport=8009 country=- asn=- org=\"-\""
+        );
+
+        // Discovery traffic is no connection attempt
+        let frame = tcp_frame(Ipv4Addr::new(239, 255, 255, 250));
+        assert!(
+            log.entry(DropReason::NoService, &EthernetPacket::new(&frame).unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_reports_suppressed() {
+        let start = Instant::now();
+        let mut window = Window {
+            start,
+            logged: 0,
+            suppressed: 0,
+        };
+        assert_eq!(window.admit(start, 2), Some(0));
+        assert_eq!(window.admit(start, 2), Some(0));
+        assert_eq!(window.admit(start, 2), None);
+        assert_eq!(window.admit(start + Duration::from_millis(500), 2), None);
+        assert_eq!(window.admit(start + WINDOW, 2), Some(2));
+        assert_eq!(window.admit(start + WINDOW, 2), Some(0));
+    }
+}
@@ -0,0 +1,222 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Prioritized external interfaces
+//!
+//! The external side of the forwarder can be given several interfaces in order of
+//! preference (e.g. `eth0,wlan0`). A monitor task re-binds the capture channel to the
+//! best available link whenever link state changes, so docking or undocking does not
+//! require a restart. Learned forwarding state (SSDP ports, rate limiter routes) is
+//! kept in the filters and survives the switch untouched.
+use crate::capture;
+use crate::capture_stats::{self, CaptureStats};
+use crate::cli;
+use crate::forward_impl::forward;
+use log::{error, info};
+use pnet::datalink::{
+    self, Channel::Ethernet, Config, DataLinkReceiver, DataLinkSender, NetworkInterface,
+};
+use std::os::fd::RawFd;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, interval};
+use tokio_util::sync::CancellationToken;
+
+pub type Sender = Arc<Mutex<Box<dyn DataLinkSender>>>;
+pub type Receiver = Arc<Mutex<Box<dyn DataLinkReceiver>>>;
+type Channel = (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>, RawFd);
+
+/// How often link state of the candidate interfaces is checked
+const LINK_CHECK_PERIOD: Duration = Duration::from_secs(2);
+
+pub struct ExternalLink {
+    candidates: Vec<String>,
+    current: RwLock<NetworkInterface>,
+    pub tx: Sender,
+    pub rx: Receiver,
+    /// Kernel capture statistics, carried over across interface switches
+    pub stats: Arc<CaptureStats>,
+}
+
+impl ExternalLink {
+    /// Opens a capture channel on the best available candidate interface.
+    ///
+    /// If none of the candidates is up yet, the first existing one is used and the
+    /// monitor switches over once a link comes up.
+    pub fn open(candidates: &[String]) -> Result<Self, String> {
+        let interfaces = datalink::interfaces();
+        let iface = pick_external(candidates, &interfaces)
+            .or_else(|| {
+                candidates.iter().find_map(|name| {
+                    interfaces
+                        .iter()
+                        .find(|iface| &iface.name == name && !iface.is_loopback())
+                })
+            })
+            .cloned()
+            .ok_or_else(|| format!("No matching external interface found in {candidates:?}"))?;
+
+        let (tx, rx, fd) = open_channel(&iface)?;
+        let stats = CaptureStats::new("external");
+        stats.track(fd);
+        Ok(Self {
+            candidates: candidates.to_vec(),
+            current: RwLock::new(iface),
+            tx: Arc::new(Mutex::new(tx)),
+            rx: Arc::new(Mutex::new(rx)),
+            stats: Arc::new(stats),
+        })
+    }
+
+    /// Returns the external interface currently in use.
+    pub fn current(&self) -> NetworkInterface {
+        self.current
+            .read()
+            .expect("Failed to acquire read lock on external interface")
+            .clone()
+    }
+
+    /// Periodically re-evaluates the candidates and migrates to the preferred one.
+    pub async fn monitor(&self, cancel_token: CancellationToken) {
+        if self.candidates.len() < 2 {
+            return;
+        }
+
+        let mut interval = interval(LINK_CHECK_PERIOD);
+        loop {
+            tokio::select! {
+                () = cancel_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            let Some(best) = pick_external(&self.candidates, &datalink::interfaces()).cloned()
+            else {
+                continue;
+            };
+
+            if best.name != self.current().name
+                && let Err(e) = self.switch_to(best).await
+            {
+                error!("Failed to switch external interface: {e}");
+            }
+        }
+    }
+
+    async fn switch_to(&self, iface: NetworkInterface) -> Result<(), String> {
+        let (tx, rx, fd) = open_channel(&iface)?;
+        let ext_ip = cli::get_ext_ip().filter(|ip| iface.ips.iter().any(|i| i.ip() == ip.ip()));
+        forward::assign_ext_iface(&iface, ext_ip)?;
+
+        // Collect the old socket's statistics before it is closed by the swap
+        self.stats.track(fd);
+        *self.tx.lock().await = tx;
+        *self.rx.lock().await = rx;
+
+        let mut current = self
+            .current
+            .write()
+            .expect("Failed to acquire write lock on external interface");
+        info!(
+            "External interface switched from {} to {}, ip:{:?}",
+            current.name, iface.name, iface.ips
+        );
+        *current = iface;
+        Ok(())
+    }
+}
+
+/// Picks the first candidate, in order of preference, that is up, running and has an IPv4 address.
+fn pick_external<'a>(
+    candidates: &[String],
+    interfaces: &'a [NetworkInterface],
+) -> Option<&'a NetworkInterface> {
+    candidates.iter().find_map(|name| {
+        interfaces.iter().find(|iface| {
+            &iface.name == name
+                && !iface.is_loopback()
+                && iface.is_up()
+                && iface.is_running()
+                && iface.ips.iter().any(|ip| ip.is_ipv4())
+        })
+    })
+}
+
+fn open_channel(iface: &NetworkInterface) -> Result<Channel, String> {
+    let fd = capture_stats::open_socket()
+        .map_err(|e| format!("Failed to open capture socket for {}: {e}", iface.name))?;
+    let config = Config {
+        read_timeout: Some(capture::READ_TIMEOUT),
+        socket_fd: Some(fd),
+        ..Default::default()
+    };
+    match datalink::channel(iface, config) {
+        Ok(Ethernet(tx, rx)) => Ok((tx, rx, fd)),
+        Ok(_) => Err(format!("Unhandled channel type for {}", iface.name)),
+        Err(e) => Err(format!(
+            "Failed to create datalink channel for {}: {e}",
+            iface.name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::ipnetwork::IpNetwork;
+
+    const UP_RUNNING: u32 = 0x1 | 0x40;
+
+    fn iface(name: &str, flags: u32, ips: Vec<IpNetwork>) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 0,
+            mac: None,
+            ips,
+            flags,
+        }
+    }
+
+    fn candidates() -> Vec<String> {
+        vec!["eth0".to_string(), "wlan0".to_string()]
+    }
+
+    #[test]
+    fn test_pick_external_prefers_first_candidate() {
+        let ip: IpNetwork = "192.168.1.10/24".parse().unwrap();
+        let interfaces = vec![
+            iface("wlan0", UP_RUNNING, vec![ip]),
+            iface("eth0", UP_RUNNING, vec![ip]),
+        ];
+        let picked = pick_external(&candidates(), &interfaces).unwrap();
+        assert_eq!(picked.name, "eth0");
+    }
+
+    #[test]
+    fn test_pick_external_skips_down_or_unaddressed() {
+        let ip: IpNetwork = "192.168.1.10/24".parse().unwrap();
+        let v6: IpNetwork = "fe80::1/64".parse().unwrap();
+
+        let interfaces = vec![
+            iface("eth0", 0, vec![ip]),
+            iface("wlan0", UP_RUNNING, vec![ip]),
+        ];
+        assert_eq!(
+            pick_external(&candidates(), &interfaces).unwrap().name,
+            "wlan0"
+        );
+
+        let interfaces = vec![
+            iface("eth0", UP_RUNNING, vec![v6]),
+            iface("wlan0", UP_RUNNING, vec![ip]),
+        ];
+        assert_eq!(
+            pick_external(&candidates(), &interfaces).unwrap().name,
+            "wlan0"
+        );
+
+        let interfaces = vec![iface("eth0", 0, vec![ip]), iface("wlan0", 0, vec![ip])];
+        assert!(pick_external(&candidates(), &interfaces).is_none());
+    }
+}
@@ -0,0 +1,352 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Internal destination load-balancing
+//!
+//! Spreads external to internal flows over several instances of a guest service, e.g.
+//! two media renderer VMs running active/active. Each flow keeps its target for as long
+//! as it stays active and the target stays healthy, so all its packets reach the same
+//! instance.
+//!
+//! Targets are health-checked with a TCP connect to the service port. When no target is
+//! healthy, all of them are used, so a single instance behaves as without balancing.
+//!
+//! The address of a target can be changed at runtime, e.g. when the control API moves
+//! the service to another VM; the number of targets stays fixed.
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use pnet::ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant, interval, timeout};
+use tokio_util::sync::CancellationToken;
+
+/// Upper bound for a single health check connection attempt
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+/// Flows remembered for stickiness
+const MAX_FLOWS: usize = 1024;
+
+/// How a new flow picks its target
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Healthy targets in turn
+    RoundRobin,
+    /// Hash of the flow's endpoints, stable across restarts
+    #[default]
+    Hash,
+}
+
+/// An instance of the internal destination service
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub ip: IpNetwork,
+    pub mac: MacAddr,
+}
+
+/// Remote address, remote port and local port of an external to internal flow
+pub type FlowKey = (Ipv4Addr, u16, u16);
+
+pub struct Balancer {
+    targets: RwLock<Vec<Target>>,
+    healthy: Vec<AtomicBool>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    flows: Mutex<HashMap<FlowKey, (usize, Instant)>>,
+    timeout: Duration,
+}
+
+impl Balancer {
+    /// Creates a balancer over `targets`, all initially healthy, keeping a flow on its
+    /// target until it was idle for `timeout`.
+    pub fn new(targets: Vec<Target>, strategy: Strategy, timeout: Duration) -> Self {
+        Self {
+            healthy: targets.iter().map(|_| AtomicBool::new(true)).collect(),
+            targets: RwLock::new(targets),
+            strategy,
+            next: AtomicUsize::new(0),
+            flows: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.healthy.is_empty()
+    }
+
+    /// Returns whether `ip` is the address of one of the targets.
+    pub fn is_target(&self, ip: Ipv4Addr) -> bool {
+        self.targets().iter().any(|t| t.ip.ip() == ip)
+    }
+
+    /// Returns the current targets, in their configured order.
+    pub fn targets(&self) -> Vec<Target> {
+        self.targets.read().unwrap().clone()
+    }
+
+    fn target(&self, index: usize) -> Target {
+        self.targets.read().unwrap()[index]
+    }
+
+    /// Replaces the target at `index`, returning `false` if there is none. Flows on the
+    /// old address are forgotten and the new one starts out healthy.
+    pub async fn set_target(&self, index: usize, target: Target) -> bool {
+        let mut flows = self.flows.lock().await;
+        {
+            let mut targets = self.targets.write().unwrap();
+            let Some(slot) = targets.get_mut(index) else {
+                return false;
+            };
+            *slot = target;
+        }
+        flows.retain(|_, &mut (i, _)| i != index);
+        self.healthy[index].store(true, Ordering::Relaxed);
+        info!(
+            "Balancer - target {index} set to {} ({})",
+            target.ip, target.mac
+        );
+        true
+    }
+
+    /// Returns the target for `flow`, the one it used before if still healthy.
+    pub async fn select(&self, flow: FlowKey) -> Option<Target> {
+        let mut candidates: Vec<_> = (0..self.healthy.len())
+            .filter(|&i| self.healthy[i].load(Ordering::Relaxed))
+            .collect();
+        if candidates.is_empty() {
+            candidates.extend(0..self.healthy.len());
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut flows = self.flows.lock().await;
+        if let Some((index, expires)) = flows.get_mut(&flow)
+            && *expires > now
+            && candidates.contains(index)
+        {
+            *expires = now + self.timeout;
+            return Some(self.target(*index));
+        }
+
+        let index = match self.strategy {
+            Strategy::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Strategy::Hash => {
+                let mut hasher = DefaultHasher::new();
+                flow.hash(&mut hasher);
+                candidates[(hasher.finish() % candidates.len() as u64) as usize]
+            }
+        };
+        self.record(&mut flows, flow, index, now);
+        let target = self.target(index);
+        debug!("Balancer - flow {flow:?} assigned to {}", target.ip);
+        Some(target)
+    }
+
+    /// Records that the target at `ip` opened `flow` itself, so the answers go back to it.
+    pub async fn assign(&self, flow: FlowKey, ip: Ipv4Addr) {
+        let Some(index) = self.targets().iter().position(|t| t.ip.ip() == ip) else {
+            return;
+        };
+        let mut flows = self.flows.lock().await;
+        self.record(&mut flows, flow, index, Instant::now());
+    }
+
+    /// Keeps `flow` on the target at `index`, making room in a full table by dropping
+    /// the expired flows, or else the one closest to expiring.
+    fn record(
+        &self,
+        flows: &mut HashMap<FlowKey, (usize, Instant)>,
+        flow: FlowKey,
+        index: usize,
+        now: Instant,
+    ) {
+        if !flows.contains_key(&flow) && flows.len() >= MAX_FLOWS {
+            flows.retain(|_, &mut (_, expires)| expires > now);
+            if flows.len() >= MAX_FLOWS
+                && let Some(oldest) = flows
+                    .iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(key, _)| *key)
+            {
+                debug!("Balancer - flow table full, evicting flow {oldest:?}");
+                flows.remove(&oldest);
+            }
+        }
+        flows.insert(flow, (index, now + self.timeout));
+    }
+
+    /// Returns the target `flow` was assigned to, healthy or not: only it holds the
+    /// connection.
+    pub async fn assigned(&self, flow: FlowKey) -> Option<Target> {
+        let now = Instant::now();
+        let mut flows = self.flows.lock().await;
+        let (index, expires) = flows.get_mut(&flow).filter(|(_, e)| *e > now)?;
+        *expires = now + self.timeout;
+        Some(self.target(*index))
+    }
+
+    /// Marks the target at `index` as (un)healthy, returning whether that changed.
+    fn set_healthy(&self, index: usize, healthy: bool) -> bool {
+        self.healthy[index].swap(healthy, Ordering::Relaxed) != healthy
+    }
+
+    /// Checks every `period` whether the targets accept connections on `port`, until
+    /// `cancel_token` is cancelled.
+    pub async fn health_check(&self, port: u16, period: Duration, cancel_token: CancellationToken) {
+        let mut ticker = interval(period);
+        loop {
+            tokio::select! {
+                () = cancel_token.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            for (index, target) in self.targets().into_iter().enumerate() {
+                let addr = SocketAddr::new(target.ip.ip(), port);
+                let healthy = matches!(
+                    timeout(HEALTH_CHECK_TIMEOUT, TcpStream::connect(addr)).await,
+                    Ok(Ok(_))
+                );
+                if self.set_healthy(index, healthy) {
+                    if healthy {
+                        info!("Balancer - target {addr} is healthy again");
+                    } else {
+                        warn!("Balancer - target {addr} failed its health check");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+
+    fn targets(count: u8) -> Vec<Target> {
+        (1..=count)
+            .map(|i| Target {
+                ip: IpNetwork::new(Ipv4Addr::new(192, 168, 100, i).into(), 24).unwrap(),
+                mac: MacAddr(2, 0, 0, 0, 0, i),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_flows_stick_to_their_target() {
+        for strategy in [Strategy::RoundRobin, Strategy::Hash] {
+            let balancer = Balancer::new(targets(3), strategy, Duration::from_secs(30));
+            for port in 40000..40010 {
+                let first = balancer.select((REMOTE, 8009, port)).await;
+                assert!(first.is_some());
+                assert_eq!(balancer.select((REMOTE, 8009, port)).await, first);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_spreads_flows() {
+        let balancer = Balancer::new(targets(2), Strategy::RoundRobin, Duration::from_secs(30));
+        let first = balancer.select((REMOTE, 8009, 40000)).await.unwrap();
+        let second = balancer.select((REMOTE, 8009, 40001)).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(balancer.select((REMOTE, 8009, 40002)).await, Some(first));
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_target_is_avoided() {
+        let all = targets(2);
+        let balancer = Balancer::new(all.clone(), Strategy::Hash, Duration::from_secs(30));
+        let flow = (REMOTE, 8009, 40000);
+        let first = balancer.select(flow).await.unwrap();
+        let index = all.iter().position(|t| *t == first).unwrap();
+
+        // The flow moves over when its target goes down, and stays there
+        assert!(balancer.set_healthy(index, false));
+        let moved = balancer.select(flow).await.unwrap();
+        assert_ne!(moved, first);
+        assert!(balancer.set_healthy(index, true));
+        assert_eq!(balancer.select(flow).await, Some(moved));
+
+        // Without a healthy target, all are used
+        balancer.set_healthy(0, false);
+        balancer.set_healthy(1, false);
+        assert!(balancer.select((REMOTE, 8009, 40001)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_assigned_flow_returns_to_its_origin() {
+        let all = targets(2);
+        let balancer = Balancer::new(all.clone(), Strategy::RoundRobin, Duration::from_secs(30));
+        let flow = (REMOTE, 8009, 40000);
+        assert_eq!(balancer.assigned(flow).await, None);
+
+        balancer.assign(flow, Ipv4Addr::new(192, 168, 100, 2)).await;
+        balancer.set_healthy(1, false);
+        assert_eq!(balancer.assigned(flow).await, Some(all[1]));
+
+        // Addresses of no target are ignored
+        let other = (REMOTE, 8009, 40001);
+        balancer.assign(other, REMOTE).await;
+        assert_eq!(balancer.assigned(other).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_target_update_moves_its_flows() {
+        let all = targets(2);
+        let balancer = Balancer::new(all.clone(), Strategy::RoundRobin, Duration::from_secs(30));
+        let flow = (REMOTE, 8009, 40000);
+        let first = balancer.select(flow).await.unwrap();
+        let index = all.iter().position(|t| *t == first).unwrap();
+        balancer.set_healthy(index, false);
+
+        let moved = targets(3)[2];
+        assert!(balancer.set_target(index, moved).await);
+        assert_eq!(balancer.targets()[index], moved);
+        assert!(balancer.is_target(Ipv4Addr::new(192, 168, 100, 3)));
+        assert!(!balancer.is_target(Ipv4Addr::new(192, 168, 100, index as u8 + 1)));
+        assert_eq!(balancer.assigned(flow).await, None);
+        assert!(!balancer.set_target(2, moved).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_table_evicts_oldest() {
+        let balancer = Balancer::new(targets(1), Strategy::Hash, Duration::from_secs(30));
+        let origin = Ipv4Addr::new(192, 168, 100, 1);
+        for port in 0..MAX_FLOWS as u16 {
+            balancer.assign((REMOTE, 8009, port), origin).await;
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        let flow = (REMOTE, 8009, 50000);
+        balancer.assign(flow, origin).await;
+        assert!(balancer.assigned(flow).await.is_some());
+        assert_eq!(balancer.assigned((REMOTE, 8009, 0)).await, None);
+        assert!(balancer.assigned((REMOTE, 8009, 1)).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_flow_is_rebalanced() {
+        let balancer = Balancer::new(targets(2), Strategy::RoundRobin, Duration::from_secs(30));
+        let flow = (REMOTE, 8009, 40000);
+        let first = balancer.select(flow).await;
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert_ne!(balancer.select(flow).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_without_targets() {
+        let balancer = Balancer::new(Vec::new(), Strategy::Hash, Duration::from_secs(30));
+        assert!(!balancer.is_enabled());
+        assert_eq!(balancer.select((REMOTE, 8009, 40000)).await, None);
+    }
+}
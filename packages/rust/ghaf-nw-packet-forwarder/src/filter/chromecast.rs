@@ -3,6 +3,8 @@
     SPDX-License-Identifier: Apache-2.0
 */
 use crate::cli;
+use crate::filter::Balancer;
+use crate::filter::mdns_reflector::{is_service_name, parse_dns_names};
 use crate::forward_impl::forward::Ifaces;
 use log::{debug, info};
 use pnet::ipnetwork::IpNetwork;
@@ -11,23 +13,37 @@ use pnet::packet::dns::DnsPacket;
 use pnet::packet::ethernet::EthernetPacket;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
 use pnet::packet::udp::UdpPacket;
 use pnet::util::MacAddr;
 use std::collections::VecDeque;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, SystemTime};
 use tokio::sync::Mutex;
-const SSDP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
-const SSDP_PORT: u16 = 1900;
+pub(crate) const SSDP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub(crate) const SSDP_PORT: u16 = 1900;
 const MAX_SSDP_PORTS: usize = 3;
-const MAX_DURATION: Duration = Duration::new(5, 0); // 3 seconds
+pub(crate) const MAX_DURATION: Duration = Duration::new(5, 0); // 3 seconds
 
-const MDNS_PORT: u16 = 5353;
-const MDNS_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
-const MDNS_MAC: MacAddr = MacAddr(0x01, 0x0, 0x5E, 0x0, 0x0, 0xFB);
+pub(crate) const MDNS_PORT: u16 = 5353;
+pub(crate) const MDNS_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub(crate) const MDNS_MAC: MacAddr = MacAddr(0x01, 0x0, 0x5E, 0x0, 0x0, 0xFB);
 
-const SSDP_MAC: MacAddr = MacAddr(0x01, 0x0, 0x5E, 0x7F, 0xFF, 0xFA);
+pub(crate) const SSDP_MAC: MacAddr = MacAddr(0x01, 0x0, 0x5E, 0x7F, 0xFF, 0xFA);
+
+/// DNS-SD service type of cast devices
+const CAST_SERVICE: &str = "_googlecast._tcp";
+/// Status line of a successful SSDP search response
+const SSDP_RESPONSE: &[u8] = b"HTTP/1.1 200";
+
+/// Cast devices accept session control connections (CASTV2) on this TCP port
+pub(crate) const CAST_CONTROL_PORT: u16 = 8009;
+
+/// Names of the discovery filters, as used by the control API
+pub const SSDP_FILTER: &str = "cast-ssdp";
+pub const MDNS_FILTER: &str = "cast-mdns";
 
 pub struct Chromecast {
     //shared_data: Arc<SharedData>,
@@ -47,13 +63,15 @@ impl Chromecast {
     /// Returns a new `Chromecast` instance that is initialized with the provided
     /// interface information and the necessary operations for interacting with it.
     pub fn new(_ifaces: Ifaces) -> Self {
-        let shared_data = Arc::new(SharedData::new(
-            cli::get_chromecast(),
-            cli::get_chromecastvm_ip(),
-            cli::get_chromecastvm_mac(),
-            false,
-            true,
-        )); // Ensure shared_data is wrapped in Arc
+        Self::with_balancer(cli::get_chromecast_balancer())
+    }
+
+    /// Creates a `Chromecast` instance spreading flows over the targets of `balancer`,
+    /// regardless of the command line.
+    pub fn with_balancer(balancer: Balancer) -> Self {
+        // Without a chromecast VM the filter stays disabled
+        let balancer = Arc::new(balancer);
+        let shared_data = Arc::new(SharedData::new(balancer, false, true)); // Ensure shared_data is wrapped in Arc
 
         let external_ops = Arc::new(ExternalOps::new(shared_data.clone()));
         let internal_ops = Arc::new(InternalOps::new(shared_data.clone()));
@@ -78,73 +96,133 @@ impl Chromecast {
     pub fn get_internal_ops(&self) -> Arc<InternalOps> {
         self.internal_ops.clone() // No need to lock here, just return Arc for safe sharing
     }
-}
 
-struct SharedData {
-    enabled: bool,
-    ssdp_ports: Mutex<VecDeque<(u16, SystemTime)>>, // Thread-safe vector of ports
-    ip: IpNetwork,
-    mac: MacAddr,
-    ssdp_enabled: bool,
-    mdns_enabled: bool,
-}
-impl SharedData {
-    fn new(
-        enabled: bool,
-        ip: IpNetwork,
-        mac: MacAddr,
-        ssdp_enabled: bool,
-        mdns_enabled: bool,
-    ) -> Self {
-        SharedData {
-            enabled,
-            ssdp_ports: Mutex::new(VecDeque::with_capacity(MAX_SSDP_PORTS)),
-            ip,
-            mac,
-            ssdp_enabled,
-            mdns_enabled,
-        }
+    /// Returns the balancer spreading flows over the chromecast VMs, for health checks.
+    pub fn get_balancer(&self) -> Arc<Balancer> {
+        self.internal_ops.shared_data.balancer.clone()
     }
 
-    fn get_enabled(&self) -> bool {
-        self.enabled
+    /// Returns the discovery protocols forwarded for the chromecast VMs, by name, and
+    /// whether they are enabled.
+    pub fn filters(&self) -> Vec<(&'static str, bool)> {
+        let shared_data = &self.internal_ops.shared_data;
+        vec![
+            (
+                SSDP_FILTER,
+                shared_data.ssdp_enabled.load(Ordering::Relaxed),
+            ),
+            (
+                MDNS_FILTER,
+                shared_data.mdns_enabled.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+
+    /// Enables or disables forwarding of a discovery protocol, returning `false` for an
+    /// unknown name.
+    pub fn set_filter(&self, name: &str, enabled: bool) -> bool {
+        let shared_data = &self.internal_ops.shared_data;
+        let flag = match name {
+            SSDP_FILTER => &shared_data.ssdp_enabled,
+            MDNS_FILTER => &shared_data.mdns_enabled,
+            _ => return false,
+        };
+        flag.store(enabled, Ordering::Relaxed);
+        info!(
+            "Chromecast - {name} forwarding {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        true
     }
+}
 
-    async fn add_ssdp_port(&self, port: u16) {
-        let mut ports_lock = self.ssdp_ports.lock().await;
+/// Internal client that sent an SSDP search, answered by unicast replies to its port
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SsdpSearch {
+    pub port: u16,
+    pub mac: MacAddr,
+    pub ip: IpNetwork,
+    timestamp: SystemTime,
+}
 
-        // Remove the port if it already exists
-        ports_lock.retain(|&(stored_port, _)| stored_port != port);
+/// Recent SSDP searches of internal clients, the oldest forgotten beyond `capacity`
+pub(crate) struct SsdpSearches {
+    searches: Mutex<VecDeque<SsdpSearch>>,
+    capacity: usize,
+}
 
-        // Add the new port
-        if ports_lock.len() >= MAX_SSDP_PORTS {
-            ports_lock.pop_front();
+impl SsdpSearches {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            searches: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
         }
-        ports_lock.push_back((port, SystemTime::now()));
+    }
 
-        info!("SSDP Port map: {ports_lock:?}");
+    /// Records a search sent from `port` by the client at `mac` and `ip`.
+    pub(crate) async fn add(&self, port: u16, mac: MacAddr, ip: Ipv4Addr) {
+        let mut searches = self.searches.lock().await;
+        searches.retain(|search| search.port != port);
+        if searches.len() >= self.capacity {
+            searches.pop_front();
+        }
+        searches.push_back(SsdpSearch {
+            port,
+            mac,
+            ip: IpNetwork::new(ip.into(), 32).unwrap(),
+            timestamp: SystemTime::now(),
+        });
+        debug!("SSDP search from {ip} port {port}");
     }
 
-    async fn is_ssdp_port_available(&self, port: u16) -> bool {
-        let ports_lock = self.ssdp_ports.lock().await;
+    /// Returns the search sent from `port`, if it is recent enough to be answered.
+    pub(crate) async fn find(&self, port: u16) -> Option<SsdpSearch> {
         let now = SystemTime::now();
+        self.searches
+            .lock()
+            .await
+            .iter()
+            .find(|search| search.port == port)
+            .filter(|search| {
+                now.duration_since(search.timestamp)
+                    .is_ok_and(|d| d <= MAX_DURATION)
+            })
+            .copied()
+    }
+}
 
-        for &(stored_port, timestamp) in ports_lock.iter() {
-            if stored_port == port
-                && let Ok(duration) = now.duration_since(timestamp)
-            {
-                return duration <= MAX_DURATION;
-            }
+struct SharedData {
+    ssdp_searches: SsdpSearches,
+    cast_query: Mutex<Option<SystemTime>>, // Last mDNS query of a chromecast VM for cast devices
+    balancer: Arc<Balancer>,               // Chromecast VMs, enabled when there is at least one
+    ssdp_enabled: AtomicBool,
+    mdns_enabled: AtomicBool,
+}
+impl SharedData {
+    fn new(balancer: Arc<Balancer>, ssdp_enabled: bool, mdns_enabled: bool) -> Self {
+        SharedData {
+            ssdp_searches: SsdpSearches::new(MAX_SSDP_PORTS),
+            cast_query: Mutex::new(None),
+            balancer,
+            ssdp_enabled: AtomicBool::new(ssdp_enabled),
+            mdns_enabled: AtomicBool::new(mdns_enabled),
         }
+    }
 
-        false
+    fn get_enabled(&self) -> bool {
+        self.balancer.is_enabled()
     }
 
-    fn get_ip(&self) -> IpNetwork {
-        self.ip
+    async fn is_ssdp_port_available(&self, port: u16) -> bool {
+        self.ssdp_searches.find(port).await.is_some()
     }
-    fn get_mac(&self) -> MacAddr {
-        self.mac
+
+    async fn is_cast_queried(&self) -> bool {
+        let now = SystemTime::now();
+        self.cast_query
+            .lock()
+            .await
+            .is_some_and(|t| now.duration_since(t).is_ok_and(|d| d <= MAX_DURATION))
     }
 }
 
@@ -165,10 +243,11 @@ impl ExternalOps {
     /// # Returns
     ///
     /// Returns `Some((MacAddr, IpNetwork))` of `chrome-VM` in ghaf if the packet matches external-to-internal criteria, otherwise `None`.
+    /// With several chromecast VMs, each flow is sent to the one the balancer picks for it.
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let eth_packet = EthernetPacket::new(&packet_data).unwrap();
     /// let result = external_ops.is_ext_to_int_packet(&eth_packet).await;
     /// assert_eq!(result, Some((mac_address, ip_network)));
@@ -181,11 +260,9 @@ impl ExternalOps {
         if !enabled {
             return None;
         }
-        let ip = self.shared_data.get_ip();
-        let mac = self.shared_data.get_mac();
 
-        let ssdp_enabled = self.shared_data.ssdp_enabled;
-        let mdns_enabled = self.shared_data.mdns_enabled;
+        let ssdp_enabled = self.shared_data.ssdp_enabled.load(Ordering::Relaxed);
+        let mdns_enabled = self.shared_data.mdns_enabled.load(Ordering::Relaxed);
         if let Some(ipv4_packet) = Ipv4Packet::new(eth_packet.payload())
             && ipv4_packet.get_next_level_protocol() == IpNextHeaderProtocols::Udp
             && let Some(udp_packet) = UdpPacket::new(ipv4_packet.payload())
@@ -195,7 +272,13 @@ impl ExternalOps {
             let src_ip = ipv4_packet.get_source();
             if self.shared_data.is_ssdp_port_available(dest_port).await {
                 info!("Ext to Int - Chromecast udp packet detected,port num: {dest_port}");
-                return Some((mac, ip));
+                let flow = (src_ip, udp_packet.get_source(), dest_port);
+                return self
+                    .shared_data
+                    .balancer
+                    .select(flow)
+                    .await
+                    .map(|target| (target.mac, target.ip));
             } else if mdns_enabled && dest_port == MDNS_PORT && dest_ip == MDNS_IP {
                 let is_mdns_response = self.is_mdns_response(udp_packet.payload());
                 debug!(
@@ -216,9 +299,55 @@ impl ExternalOps {
             }
         }
 
+        // Session control answers go to the VM that opened the connection
+        if let Some(ipv4_packet) = Ipv4Packet::new(eth_packet.payload())
+            && ipv4_packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp
+            && let Some(tcp_packet) = TcpPacket::new(ipv4_packet.payload())
+            && tcp_packet.get_source() == CAST_CONTROL_PORT
+        {
+            let flow = (
+                ipv4_packet.get_source(),
+                CAST_CONTROL_PORT,
+                tcp_packet.get_destination(),
+            );
+            return self
+                .shared_data
+                .balancer
+                .assigned(flow)
+                .await
+                .map(|target| (target.mac, target.ip));
+        }
+
         None
     }
 
+    /// Checks whether a packet answers a discovery search of the chromecast VMs: an SSDP
+    /// search response to the port a search was sent from, or an mDNS answer naming the
+    /// cast service shortly after they queried for it. Announcements and answers nobody
+    /// asked for are not.
+    pub async fn is_discovery_response(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        let Some(ipv4_packet) = Ipv4Packet::new(eth_packet.payload()) else {
+            return false;
+        };
+        if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+            return false;
+        }
+        let Some(udp_packet) = UdpPacket::new(ipv4_packet.payload()) else {
+            return false;
+        };
+        let dest_ip = ipv4_packet.get_destination();
+        let dest_port = udp_packet.get_destination();
+        if dest_ip == MDNS_IP && dest_port == MDNS_PORT {
+            return self.shared_data.is_cast_queried().await
+                && parse_dns_names(udp_packet.payload()).is_some_and(|(is_response, names)| {
+                    is_response && names.iter().any(|name| is_service_name(CAST_SERVICE, name))
+                });
+        }
+        !dest_ip.is_multicast()
+            && udp_packet.payload().starts_with(SSDP_RESPONSE)
+            && self.shared_data.is_ssdp_port_available(dest_port).await
+    }
+
     fn is_mdns_response(&self, udp_payload: &[u8]) -> bool {
         // Parse the UDP payload as an mDNS message
         if let Some(dns_message) = DnsPacket::new(udp_payload) {
@@ -256,13 +385,14 @@ impl InternalOps {
     /// # Notes
     ///
     /// This function checks for the following conditions:
-    /// - The packet's source IP must match the internal IP address of the `chrome VM`.
-    /// - The packet must be a UDP packet with either an SSDP or mDNS destination port.
+    /// - The packet's source IP must match the internal IP address of a `chrome VM`.
+    /// - The packet must be a UDP packet with either an SSDP or mDNS destination port, or
+    ///   a TCP packet to the cast session control port of a device.
     /// - It supports filtering based on mDNS queries and responses and SSDP packets.
     ///
     /// # Example
     ///
-    /// ```
+    /// ```ignore
     /// let eth_packet = EthernetPacket::new(&packet_data).unwrap();
     /// let result = internal_ops.int_to_ext_filter_packets(&eth_packet).await;
     /// assert!(result);
@@ -272,14 +402,13 @@ impl InternalOps {
         if !enabled {
             return false;
         }
-        let ssdp_enabled = self.shared_data.ssdp_enabled;
-        let mdns_enabled = self.shared_data.mdns_enabled;
+        let ssdp_enabled = self.shared_data.ssdp_enabled.load(Ordering::Relaxed);
+        let mdns_enabled = self.shared_data.mdns_enabled.load(Ordering::Relaxed);
 
         if let Some(ipv4_packet) = Ipv4Packet::new(eth_packet.payload()) {
             let src_ip = ipv4_packet.get_source();
-            let chrome_vm_ip = self.shared_data.get_ip();
 
-            if src_ip != self.shared_data.get_ip().ip() {
+            if !self.shared_data.balancer.is_target(src_ip) {
                 return false;
             }
             if ipv4_packet.get_next_level_protocol() == IpNextHeaderProtocols::Udp
@@ -289,20 +418,38 @@ impl InternalOps {
                 let dest_port = udp_packet.get_destination();
                 if dest_ip == SSDP_MULTICAST_ADDR && dest_port == SSDP_PORT {
                     let src_port = udp_packet.get_source();
-                    self.shared_data.add_ssdp_port(src_port).await;
-                    debug!("Added SSDP port {src_port} to the list of ports");
+                    self.shared_data
+                        .ssdp_searches
+                        .add(src_port, eth_packet.get_source(), src_ip)
+                        .await;
                     return ssdp_enabled;
-                } else if mdns_enabled
-                    && src_ip == chrome_vm_ip.ip()
-                    && dest_port == MDNS_PORT
-                    && dest_ip == MDNS_IP
-                {
+                } else if mdns_enabled && dest_port == MDNS_PORT && dest_ip == MDNS_IP {
                     let is_mdns_query = self.is_mdns_query(udp_packet.payload());
                     debug!(
                         "Int to Ext - mdns packet detected, src ip: {src_ip}, query:{is_mdns_query}"
                     );
+                    // Answers to it are from cast devices the guest looks for
+                    if is_mdns_query
+                        && parse_dns_names(udp_packet.payload()).is_some_and(|(_, names)| {
+                            names.iter().any(|name| is_service_name(CAST_SERVICE, name))
+                        })
+                    {
+                        *self.shared_data.cast_query.lock().await = Some(SystemTime::now());
+                    }
                     return is_mdns_query;
                 }
+            } else if ipv4_packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp
+                && let Some(tcp_packet) = TcpPacket::new(ipv4_packet.payload())
+                && tcp_packet.get_destination() == CAST_CONTROL_PORT
+            {
+                let dest_ip = ipv4_packet.get_destination();
+                if dest_ip.is_multicast() || dest_ip.is_broadcast() {
+                    return false;
+                }
+                let flow = (dest_ip, CAST_CONTROL_PORT, tcp_packet.get_source());
+                self.shared_data.balancer.assign(flow, src_ip).await;
+                debug!("Int to Ext - cast session control packet, flow: {flow:?}");
+                return true;
             }
         }
         false
@@ -321,3 +468,143 @@ impl InternalOps {
 
     // Add more external operations here as needed
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::balancer::{Strategy, Target};
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::udp::MutableUdpPacket;
+
+    const CAST_VM: Ipv4Addr = Ipv4Addr::new(192, 168, 100, 5);
+    const DEVICE: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+    const GUEST_PORT: u16 = 40000;
+
+    fn udp_frame(src: Ipv4Addr, dest: (Ipv4Addr, u16), payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 8 + payload.len()];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length((20 + 8 + payload.len()) as u16);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source(src);
+        ipv4.set_destination(dest.0);
+        let mut udp = MutableUdpPacket::new(ipv4.payload_mut()).unwrap();
+        udp.set_source(GUEST_PORT);
+        udp.set_destination(dest.1);
+        udp.set_length((8 + payload.len()) as u16);
+        udp.set_payload(payload);
+        frame
+    }
+
+    fn mdns(response: bool, name: &str) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            if response { 0x84 } else { 0 },
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.extend_from_slice(&[0, 0, 12, 0, 1]);
+        msg
+    }
+
+    fn chromecast() -> Chromecast {
+        let target = Target {
+            ip: IpNetwork::new(CAST_VM.into(), 24).unwrap(),
+            mac: MacAddr(2, 0, 0, 0, 0, 5),
+        };
+        let balancer = Balancer::new(vec![target], Strategy::Hash, Duration::from_secs(60));
+        let chromecast = Chromecast::with_balancer(balancer);
+        assert!(chromecast.set_filter(SSDP_FILTER, true));
+        chromecast
+    }
+
+    async fn sent(chromecast: &Chromecast, frame: &[u8]) {
+        let eth_packet = EthernetPacket::new(frame).unwrap();
+        assert!(
+            chromecast
+                .get_internal_ops()
+                .int_to_ext_filter_packets(&eth_packet)
+                .await
+        );
+    }
+
+    async fn is_response(chromecast: &Chromecast, frame: &[u8]) -> bool {
+        let eth_packet = EthernetPacket::new(frame).unwrap();
+        chromecast
+            .get_external_ops()
+            .is_discovery_response(&eth_packet)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_ssdp_search_response() {
+        let chromecast = chromecast();
+        let reply = udp_frame(DEVICE, (CAST_VM, GUEST_PORT), b"HTTP/1.1 200 OK\r\n\r\n");
+        assert!(!is_response(&chromecast, &reply).await);
+
+        let search = b"M-SEARCH * HTTP/1.1\r\n\r\n";
+        sent(
+            &chromecast,
+            &udp_frame(CAST_VM, (SSDP_MULTICAST_ADDR, SSDP_PORT), search),
+        )
+        .await;
+        assert!(is_response(&chromecast, &reply).await);
+
+        // Announcements answer no search
+        let notify = b"NOTIFY * HTTP/1.1\r\n\r\n";
+        let announcement = udp_frame(DEVICE, (SSDP_MULTICAST_ADDR, SSDP_PORT), notify);
+        assert!(!is_response(&chromecast, &announcement).await);
+        let unicast_notify = udp_frame(DEVICE, (CAST_VM, GUEST_PORT), notify);
+        assert!(!is_response(&chromecast, &unicast_notify).await);
+    }
+
+    #[tokio::test]
+    async fn test_mdns_cast_answer() {
+        let chromecast = chromecast();
+        let answer = udp_frame(
+            DEVICE,
+            (MDNS_IP, MDNS_PORT),
+            &mdns(true, "_googlecast._tcp.local"),
+        );
+        assert!(!is_response(&chromecast, &answer).await);
+
+        // A query for another service does not let cast answers pin
+        let query = mdns(false, "_ipp._tcp.local");
+        sent(
+            &chromecast,
+            &udp_frame(CAST_VM, (MDNS_IP, MDNS_PORT), &query),
+        )
+        .await;
+        assert!(!is_response(&chromecast, &answer).await);
+
+        let query = mdns(false, "_googlecast._tcp.local");
+        sent(
+            &chromecast,
+            &udp_frame(CAST_VM, (MDNS_IP, MDNS_PORT), &query),
+        )
+        .await;
+        assert!(is_response(&chromecast, &answer).await);
+
+        let printer = udp_frame(DEVICE, (MDNS_IP, MDNS_PORT), &mdns(true, "_ipp._tcp.local"));
+        assert!(!is_response(&chromecast, &printer).await);
+        let question = udp_frame(DEVICE, (MDNS_IP, MDNS_PORT), &query);
+        assert!(!is_response(&chromecast, &question).await);
+    }
+}
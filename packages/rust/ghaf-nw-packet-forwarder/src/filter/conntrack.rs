@@ -0,0 +1,257 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Connection tracking
+//!
+//! Records flows forwarded from the internal to the external network, so unicast traffic
+//! coming back from the external network is only admitted when it answers one of them.
+//! Multicast and broadcast traffic is not tracked here, it stays subject to the service
+//! filters (chromecast, discovery reflector) alone.
+//!
+//! Flows sent to a multicast or broadcast group (e.g. an SSDP `M-SEARCH`) are answered by
+//! whoever is listening, so they admit replies from any source to the originating port.
+use log::{debug, trace};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Transport endpoint pair of an IPv4 packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Endpoints {
+    pub proto: IpNextHeaderProtocol,
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub dest: Ipv4Addr,
+    pub dest_port: u16,
+}
+
+impl Endpoints {
+    pub(crate) fn parse(eth_packet: &EthernetPacket<'_>) -> Option<Self> {
+        if eth_packet.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+        let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+        let proto = ipv4_packet.get_next_level_protocol();
+        let (src_port, dest_port) = match proto {
+            IpNextHeaderProtocols::Udp => {
+                let udp = UdpPacket::new(ipv4_packet.payload())?;
+                (udp.get_source(), udp.get_destination())
+            }
+            IpNextHeaderProtocols::Tcp => {
+                let tcp = TcpPacket::new(ipv4_packet.payload())?;
+                (tcp.get_source(), tcp.get_destination())
+            }
+            _ => return None,
+        };
+        Some(Self {
+            proto,
+            src: ipv4_packet.get_source(),
+            src_port,
+            dest: ipv4_packet.get_destination(),
+            dest_port,
+        })
+    }
+}
+
+/// Key of a tracked flow: local port plus the remote endpoint, which is `None` for flows
+/// sent to a group address
+type FlowKey = (IpNextHeaderProtocol, u16, Option<(Ipv4Addr, u16)>);
+
+/// How an admitted external to internal packet relates to the tracked flows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inbound {
+    /// Addressed to a multicast or broadcast group
+    Group,
+    /// Unicast answer to a flow sent to a group, e.g. an SSDP search response
+    GroupReply,
+    /// Unicast answer to a flow with a specific remote
+    Established,
+}
+
+pub struct Conntrack {
+    flows: Mutex<HashMap<FlowKey, Instant>>,
+    timeout: Duration,
+    max_flows: usize,
+}
+
+impl Conntrack {
+    /// Creates a tracking table holding up to `max_flows` flows, each expiring `timeout`
+    /// after its last packet.
+    pub fn new(timeout: Duration, max_flows: usize) -> Self {
+        Self {
+            flows: Mutex::new(HashMap::new()),
+            timeout,
+            max_flows: max_flows.max(1),
+        }
+    }
+
+    /// Records an internal to external packet that is being forwarded.
+    pub async fn track_outbound(&self, eth_packet: &EthernetPacket<'_>) {
+        let Some(ep) = Endpoints::parse(eth_packet) else {
+            return;
+        };
+        let remote = (!is_group(ep.dest)).then_some((ep.dest, ep.dest_port));
+        let key = (ep.proto, ep.src_port, remote);
+        let now = Instant::now();
+
+        let mut flows = self.flows.lock().await;
+        if !flows.contains_key(&key) && flows.len() >= self.max_flows {
+            flows.retain(|_, &mut expires| expires > now);
+            if flows.len() >= self.max_flows
+                && let Some(oldest) = flows.iter().min_by_key(|(_, e)| **e).map(|(k, _)| *k)
+            {
+                debug!("Conntrack table full, evicting flow {oldest:?}");
+                flows.remove(&oldest);
+            }
+        }
+        if flows.insert(key, now + self.timeout).is_none() {
+            trace!("Conntrack - new flow {key:?}");
+        }
+    }
+
+    /// Classifies an external to internal packet, `None` meaning it answers no tracked
+    /// flow and must be dropped. Traffic addressed to a group is left to the service
+    /// filters.
+    pub async fn classify_inbound(&self, eth_packet: &EthernetPacket<'_>) -> Option<Inbound> {
+        let ep = Endpoints::parse(eth_packet)?;
+        if is_group(ep.dest) {
+            return Some(Inbound::Group);
+        }
+
+        let now = Instant::now();
+        let mut flows = self.flows.lock().await;
+        let exact = (ep.proto, ep.dest_port, Some((ep.src, ep.src_port)));
+        let group = (ep.proto, ep.dest_port, None);
+        for (key, kind) in [(exact, Inbound::Established), (group, Inbound::GroupReply)] {
+            if let Some(expires) = flows.get_mut(&key)
+                && *expires > now
+            {
+                *expires = now + self.timeout;
+                return Some(kind);
+            }
+        }
+
+        debug!(
+            "Conntrack - no flow for {}:{} -> port {}, dropping",
+            ep.src, ep.src_port, ep.dest_port
+        );
+        None
+    }
+}
+
+fn is_group(ip: Ipv4Addr) -> bool {
+    ip.is_multicast() || ip.is_broadcast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::udp::MutableUdpPacket;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+    const OTHER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 66);
+    const SSDP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+    fn udp_frame(src: Ipv4Addr, src_port: u16, dest: Ipv4Addr, dest_port: u16) -> Vec<u8> {
+        let mut buffer = vec![0u8; 14 + 20 + 8];
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buffer[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(28);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source(src);
+        ipv4.set_destination(dest);
+        let mut udp = MutableUdpPacket::new(&mut buffer[34..]).unwrap();
+        udp.set_source(src_port);
+        udp.set_destination(dest_port);
+        udp.set_length(8);
+        buffer
+    }
+
+    async fn outbound(ct: &Conntrack, frame: &[u8]) {
+        ct.track_outbound(&EthernetPacket::new(frame).unwrap())
+            .await;
+    }
+
+    async fn inbound(ct: &Conntrack, frame: &[u8]) -> bool {
+        classify(ct, frame).await.is_some()
+    }
+
+    async fn classify(ct: &Conntrack, frame: &[u8]) -> Option<Inbound> {
+        ct.classify_inbound(&EthernetPacket::new(frame).unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_unicast_reply_matches_flow() {
+        let ct = Conntrack::new(Duration::from_secs(30), 16);
+        assert!(!inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await);
+
+        outbound(&ct, &udp_frame(LOCAL, 40000, REMOTE, 8009)).await;
+        assert_eq!(
+            classify(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await,
+            Some(Inbound::Established)
+        );
+        // Other hosts, source ports and local ports stay blocked
+        assert!(!inbound(&ct, &udp_frame(OTHER, 8009, LOCAL, 40000)).await);
+        assert!(!inbound(&ct, &udp_frame(REMOTE, 8010, LOCAL, 40000)).await);
+        assert!(!inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40001)).await);
+    }
+
+    #[tokio::test]
+    async fn test_group_flow_admits_any_responder() {
+        let ct = Conntrack::new(Duration::from_secs(30), 16);
+        outbound(&ct, &udp_frame(LOCAL, 50000, SSDP, 1900)).await;
+        assert_eq!(
+            classify(&ct, &udp_frame(REMOTE, 1900, LOCAL, 50000)).await,
+            Some(Inbound::GroupReply)
+        );
+        assert!(inbound(&ct, &udp_frame(OTHER, 32768, LOCAL, 50000)).await);
+        assert!(!inbound(&ct, &udp_frame(OTHER, 32768, LOCAL, 50001)).await);
+        // Group traffic is left to the service filters
+        assert_eq!(
+            classify(&ct, &udp_frame(OTHER, 1900, SSDP, 1900)).await,
+            Some(Inbound::Group)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flow_expiry() {
+        let ct = Conntrack::new(Duration::from_secs(30), 16);
+        outbound(&ct, &udp_frame(LOCAL, 40000, REMOTE, 8009)).await;
+        tokio::time::advance(Duration::from_secs(20)).await;
+        // Replies keep the flow alive
+        assert!(inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await);
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await);
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(!inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_table_evicts_oldest() {
+        let ct = Conntrack::new(Duration::from_secs(30), 2);
+        outbound(&ct, &udp_frame(LOCAL, 40000, REMOTE, 8009)).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        outbound(&ct, &udp_frame(LOCAL, 40001, REMOTE, 8009)).await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        outbound(&ct, &udp_frame(LOCAL, 40002, REMOTE, 8009)).await;
+
+        assert!(!inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40000)).await);
+        assert!(inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40001)).await);
+        assert!(inbound(&ct, &udp_frame(REMOTE, 8009, LOCAL, 40002)).await);
+    }
+}
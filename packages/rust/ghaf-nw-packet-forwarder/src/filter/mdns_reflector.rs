@@ -0,0 +1,447 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Multicast service discovery reflector
+//!
+//! Generic counterpart of the Chromecast filter: reflects discovery traffic for a
+//! configured list of service types (e.g. `_airplay._tcp`, `_ipp._tcp`, `ssdp`), so
+//! internal VMs can find services on the external network.
+//!
+//! - mDNS queries from internal clients that ask for a configured service type are
+//!   forwarded to the external network, and mDNS responses that mention one are
+//!   forwarded back to the internal multicast group.
+//! - SSDP `M-SEARCH` requests are forwarded out and the unicast replies are
+//!   returned to the internal client that searched; `NOTIFY` announcements are
+//!   forwarded in.
+//!
+//! Each service carries its own enable flag, so a service can be configured but
+//! left disabled (`_ipp._tcp=off`) and switched on later through the control API.
+use super::chromecast::{
+    MDNS_IP, MDNS_MAC, MDNS_PORT, SSDP_MAC, SSDP_MULTICAST_ADDR, SSDP_PORT, SsdpSearches,
+};
+use log::{debug, info};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::Packet;
+use pnet::packet::dns::DnsPacket;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::util::MacAddr;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Service name selecting SSDP reflection
+pub const SSDP_SERVICE: &str = "ssdp";
+const MAX_SSDP_CLIENTS: usize = 8;
+
+/// A service type to reflect, as given on the command line (`_ipp._tcp` or `_ipp._tcp=off`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl FromStr for ServiceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, enabled) = match s.split_once('=') {
+            Some((name, "on")) => (name, true),
+            Some((name, "off")) => (name, false),
+            Some((_, flag)) => {
+                return Err(format!("Invalid service flag '{flag}', expected on/off"));
+            }
+            None => (s, true),
+        };
+
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let name = name.strip_suffix(".local").unwrap_or(&name).to_string();
+        let is_dns_sd = name.ends_with("._tcp") || name.ends_with("._udp");
+        if name != SSDP_SERVICE && !(name.starts_with('_') && is_dns_sd) {
+            return Err(format!(
+                "Invalid service '{name}', expected '{SSDP_SERVICE}' or a DNS-SD type like _ipp._tcp"
+            ));
+        }
+        Ok(Self { name, enabled })
+    }
+}
+
+pub struct MdnsReflector {
+    services: Vec<ServiceSpec>,
+    /// Runtime state of each service, initially as configured
+    enabled: Vec<AtomicBool>,
+    ssdp_clients: SsdpSearches,
+}
+
+impl MdnsReflector {
+    /// Creates a reflector for the given service types.
+    pub fn new(services: &[ServiceSpec]) -> Self {
+        Self {
+            services: services.to_vec(),
+            enabled: services
+                .iter()
+                .map(|s| AtomicBool::new(s.enabled))
+                .collect(),
+            ssdp_clients: SsdpSearches::new(MAX_SSDP_CLIENTS),
+        }
+    }
+
+    /// Returns the configured services and whether they are currently enabled.
+    pub fn services(&self) -> Vec<ServiceSpec> {
+        self.services
+            .iter()
+            .zip(&self.enabled)
+            .map(|(s, e)| ServiceSpec {
+                name: s.name.clone(),
+                enabled: e.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Enables or disables reflection of a configured service, returning `false` if
+    /// `name` is not one.
+    pub fn set_service_enabled(&self, name: &str, enabled: bool) -> bool {
+        let Some(index) = self.services.iter().position(|s| s.name == name) else {
+            return false;
+        };
+        self.enabled[index].store(enabled, Ordering::Relaxed);
+        info!(
+            "Reflection of {name} {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        true
+    }
+
+    fn enabled_services(&self) -> impl Iterator<Item = &ServiceSpec> {
+        self.services
+            .iter()
+            .zip(&self.enabled)
+            .filter(|(_, e)| e.load(Ordering::Relaxed))
+            .map(|(s, _)| s)
+    }
+
+    fn is_service_enabled(&self, name: &str) -> bool {
+        self.enabled_services().any(|s| s.name == name)
+    }
+
+    /// Returns `true` if any of the DNS names belongs to an enabled service type.
+    fn matches_dns_names(&self, names: &[String]) -> bool {
+        self.enabled_services()
+            .filter(|s| s.name != SSDP_SERVICE)
+            .any(|s| names.iter().any(|n| is_service_name(&s.name, n)))
+    }
+
+    /// Filters packets from the internal network that should be reflected to the external one.
+    ///
+    /// # Arguments
+    ///
+    /// * `eth_packet` - The Ethernet packet received on the internal interface.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the packet is a discovery request for an enabled service.
+    pub async fn int_to_ext_filter_packets(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        let Some(ipv4_packet) = Ipv4Packet::new(eth_packet.payload()) else {
+            return false;
+        };
+        if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+            return false;
+        }
+        let Some(udp_packet) = UdpPacket::new(ipv4_packet.payload()) else {
+            return false;
+        };
+
+        let dest_ip = ipv4_packet.get_destination();
+        let dest_port = udp_packet.get_destination();
+        if dest_ip == MDNS_IP && dest_port == MDNS_PORT {
+            let Some((false, names)) = parse_dns_names(udp_packet.payload()) else {
+                return false;
+            };
+            let reflect = self.matches_dns_names(&names);
+            debug!("Int to Ext - mdns query for {names:?}, reflected: {reflect}");
+            reflect
+        } else if dest_ip == SSDP_MULTICAST_ADDR
+            && dest_port == SSDP_PORT
+            && self.is_service_enabled(SSDP_SERVICE)
+            && udp_packet.payload().starts_with(b"M-SEARCH")
+        {
+            self.ssdp_clients
+                .add(
+                    udp_packet.get_source(),
+                    eth_packet.get_source(),
+                    ipv4_packet.get_source(),
+                )
+                .await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Determines whether an external packet answers or announces an enabled service.
+    ///
+    /// # Arguments
+    ///
+    /// * `eth_packet` - The Ethernet packet received on the external interface.
+    ///
+    /// # Returns
+    ///
+    /// `Some((MacAddr, IpNetwork))` of the internal destination if the packet should
+    /// be reflected, otherwise `None`.
+    pub async fn is_ext_to_int_packet(
+        &self,
+        eth_packet: &EthernetPacket<'_>,
+    ) -> Option<(MacAddr, IpNetwork)> {
+        let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+        if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+        let udp_packet = UdpPacket::new(ipv4_packet.payload())?;
+
+        let dest_ip = ipv4_packet.get_destination();
+        let dest_port = udp_packet.get_destination();
+        if dest_ip == MDNS_IP && dest_port == MDNS_PORT {
+            let (true, names) = parse_dns_names(udp_packet.payload())? else {
+                return None;
+            };
+            if !self.matches_dns_names(&names) {
+                return None;
+            }
+            debug!("Ext to Int - mdns response for {names:?} reflected");
+            Some((MDNS_MAC, IpNetwork::new(IpAddr::V4(MDNS_IP), 32).unwrap()))
+        } else if !self.is_service_enabled(SSDP_SERVICE) {
+            None
+        } else if dest_ip == SSDP_MULTICAST_ADDR && dest_port == SSDP_PORT {
+            udp_packet.payload().starts_with(b"NOTIFY").then(|| {
+                debug!("Ext to Int - ssdp announcement reflected");
+                (
+                    SSDP_MAC,
+                    IpNetwork::new(IpAddr::V4(SSDP_MULTICAST_ADDR), 32).unwrap(),
+                )
+            })
+        } else {
+            let client = self.ssdp_clients.find(dest_port).await?;
+            Some((client.mac, client.ip))
+        }
+    }
+}
+
+/// Checks whether `name` (e.g. `Living Room._airplay._tcp.local`) belongs to `service`.
+pub(crate) fn is_service_name(service: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    name.strip_suffix(".local")
+        .and_then(|n| n.strip_suffix(service))
+        .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+}
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_MAX_NAME_LEN: usize = 255;
+const DNS_MAX_POINTERS: usize = 16;
+
+/// Extracts the names of all questions, records and PTR targets from a DNS message.
+///
+/// The header is read with pnet's `DnsPacket`, as in the chromecast filter. Its question
+/// and record lists are not used: they do not follow name compression, which mDNS
+/// responders use throughout, and records are counted by the number of questions, which
+/// mDNS responses leave at zero. The names are walked here instead.
+///
+/// # Returns
+/// `Some((is_response, names))`, or `None` if the message is malformed.
+pub(crate) fn parse_dns_names(msg: &[u8]) -> Option<(bool, Vec<String>)> {
+    let dns = DnsPacket::new(msg)?;
+    let is_response = dns.get_is_response() == 1;
+    let questions = usize::from(dns.get_query_count());
+    let records = [
+        dns.get_response_count(),
+        dns.get_authority_rr_count(),
+        dns.get_additional_rr_count(),
+    ]
+    .into_iter()
+    .map(usize::from)
+    .sum::<usize>();
+
+    let mut names = Vec::new();
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        let (name, next) = read_dns_name(msg, offset)?;
+        names.push(name);
+        // QTYPE and QCLASS
+        offset = next.checked_add(4).filter(|&o| o <= msg.len())?;
+    }
+    for _ in 0..records {
+        let (name, next) = read_dns_name(msg, offset)?;
+        names.push(name);
+        // TYPE, CLASS, TTL and RDLENGTH
+        let fixed: &[u8; 10] = msg.get(next..next.checked_add(10)?)?.try_into().ok()?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlen = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let rdata = next + 10;
+        offset = rdata.checked_add(rdlen).filter(|&o| o <= msg.len())?;
+        if rtype == DNS_TYPE_PTR {
+            names.push(read_dns_name(msg, rdata)?.0);
+        }
+    }
+    Some((is_response, names))
+}
+
+/// Decodes a possibly compressed DNS name starting at `offset`.
+///
+/// # Returns
+/// The dotted name and the offset just past it in the original position.
+fn read_dns_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(offset)?;
+        match len & 0xC0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = msg.get(offset + 1..offset + 1 + usize::from(len))?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                if name.len() > DNS_MAX_NAME_LEN {
+                    return None;
+                }
+                offset += 1 + usize::from(len);
+            }
+            0xC0 => {
+                pointers += 1;
+                if pointers > DNS_MAX_POINTERS {
+                    return None;
+                }
+                let low = *msg.get(offset + 1)?;
+                end.get_or_insert(offset + 2);
+                offset = usize::from(u16::from_be_bytes([len & 0x3F, low]));
+            }
+            _ => return None,
+        }
+    }
+    Some((name, end.unwrap_or(offset + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_name(buf: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.push(0);
+    }
+
+    fn mdns_query(name: &str) -> Vec<u8> {
+        let mut msg = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(&mut msg, name);
+        msg.extend_from_slice(&[0, 12, 0, 1]);
+        msg
+    }
+
+    /// PTR answer `_ipp._tcp.local -> Printer._ipp._tcp.local` using name compression.
+    fn mdns_ptr_response() -> Vec<u8> {
+        let mut msg = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        encode_name(&mut msg, "_ipp._tcp.local");
+        msg.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120]);
+        let rdata = [7, b'P', b'r', b'i', b'n', b't', b'e', b'r', 0xC0, 12];
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&rdata);
+        msg
+    }
+
+    #[test]
+    fn test_service_spec_parsing() {
+        assert_eq!(
+            "_AirPlay._tcp.local.".parse(),
+            Ok(ServiceSpec {
+                name: "_airplay._tcp".to_string(),
+                enabled: true
+            })
+        );
+        assert_eq!(
+            "ssdp=off".parse(),
+            Ok(ServiceSpec {
+                name: "ssdp".to_string(),
+                enabled: false
+            })
+        );
+        assert!("airplay".parse::<ServiceSpec>().is_err());
+        assert!("_ipp._tcp=maybe".parse::<ServiceSpec>().is_err());
+    }
+
+    #[test]
+    fn test_is_service_name() {
+        assert!(is_service_name("_ipp._tcp", "_ipp._tcp.local"));
+        assert!(is_service_name(
+            "_ipp._tcp",
+            "Office Printer._IPP._tcp.local."
+        ));
+        assert!(is_service_name(
+            "_ipp._tcp",
+            "_universal._sub._ipp._tcp.local"
+        ));
+        assert!(!is_service_name("_ipp._tcp", "x_ipp._tcp.local"));
+        assert!(!is_service_name("_ipp._tcp", "_ipps._tcp.local"));
+        assert!(!is_service_name("_ipp._tcp", "_ipp._tcp.example.com"));
+    }
+
+    #[test]
+    fn test_parse_dns_query() {
+        let (is_response, names) = parse_dns_names(&mdns_query("_airplay._tcp.local")).unwrap();
+        assert!(!is_response);
+        assert_eq!(names, vec!["_airplay._tcp.local"]);
+    }
+
+    #[test]
+    fn test_parse_dns_compressed_response() {
+        let (is_response, names) = parse_dns_names(&mdns_ptr_response()).unwrap();
+        assert!(is_response);
+        assert_eq!(names, vec!["_ipp._tcp.local", "Printer._ipp._tcp.local"]);
+    }
+
+    #[test]
+    fn test_parse_dns_malformed() {
+        // Truncated header
+        assert!(parse_dns_names(&[0; 5]).is_none());
+        // Record count without records
+        assert!(parse_dns_names(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0]).is_none());
+        // Label running past the end
+        let mut msg = mdns_query("_ipp._tcp.local");
+        msg.truncate(16);
+        assert!(parse_dns_names(&msg).is_none());
+        // Pointer loop
+        let mut msg = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.extend_from_slice(&[0xC0, 12]);
+        assert!(parse_dns_names(&msg).is_none());
+    }
+
+    #[test]
+    fn test_service_enable_flags() {
+        let names = vec!["Printer._ipp._tcp.local".to_string()];
+        let reflector = MdnsReflector::new(&["_ipp._tcp".parse().unwrap()]);
+        assert!(reflector.matches_dns_names(&names));
+        let reflector = MdnsReflector::new(&["_ipp._tcp=off".parse().unwrap()]);
+        assert!(!reflector.matches_dns_names(&names));
+        let reflector = MdnsReflector::new(&["_airplay._tcp".parse().unwrap()]);
+        assert!(!reflector.matches_dns_names(&names));
+    }
+
+    #[test]
+    fn test_service_runtime_toggle() {
+        let names = vec!["Printer._ipp._tcp.local".to_string()];
+        let reflector = MdnsReflector::new(&["_ipp._tcp=off".parse().unwrap()]);
+        assert!(reflector.set_service_enabled("_ipp._tcp", true));
+        assert!(reflector.matches_dns_names(&names));
+        assert!(reflector.services()[0].enabled);
+        assert!(reflector.set_service_enabled("_ipp._tcp", false));
+        assert!(!reflector.matches_dns_names(&names));
+        assert!(!reflector.set_service_enabled("_airplay._tcp", true));
+    }
+}
@@ -3,10 +3,26 @@
     SPDX-License-Identifier: Apache-2.0
 */
 //! # module include file
+pub mod balancer;
+
+pub use balancer::Balancer;
+
 pub mod chromecast;
 
 pub use chromecast::Chromecast;
 
+pub mod conntrack;
+
+pub use conntrack::Conntrack;
+
+pub mod mdns_reflector;
+
+pub use mdns_reflector::MdnsReflector;
+
+pub mod pinning;
+
+pub use pinning::DevicePins;
+
 pub mod security;
 
 pub use security::Security;
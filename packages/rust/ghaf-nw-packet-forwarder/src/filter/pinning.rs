@@ -0,0 +1,468 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Device identity pinning
+//!
+//! Devices answering a discovery search of the chromecast VMs (an SSDP search response,
+//! or an mDNS answer for the cast service after the VMs queried for it) are pinned by
+//! their IP and MAC address. Announcements and other group traffic pin nobody, so any LAN
+//! host sending SSDP NOTIFY or mDNS does not get a pin. Unicast traffic on established
+//! flows is then only forwarded to the internal network from a pinned device, so an
+//! arbitrary LAN host cannot reach the guest through ports opened by a cast session, and
+//! a pinned address seen from another MAC is treated as spoofed.
+//!
+//! Cast session control (CASTV2, TCP port 8009) is forwarded from the external network
+//! too, and is held to the same pins. Its TLS handshake passes through here, but the
+//! device certificate is not pinned: with TLS 1.3 it is encrypted, and with TLS 1.2 it
+//! spans several segments that would need reassembling per flow. The address and MAC
+//! pins gate the TLS flows like any other.
+//!
+//! Pins can be kept in a state file, so a restarted forwarder (e.g. along with the
+//! chromecast VM) lets cast devices through right away. Restored pins, and all pins once
+//! the internal interface comes back up, are only kept for [`REVALIDATE_WINDOW`] unless
+//! their device is seen again from the same MAC. A device answering discovery from a new
+//! MAC in the meantime replaces them.
+//!
+//! The pins can be listed and cleared through the control API, or cleared with SIGUSR1.
+use crate::forward_impl::forward;
+use log::{error, info, warn};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant, interval};
+use tokio_util::sync::CancellationToken;
+
+/// Maximum number of pinned devices
+const MAX_PINS: usize = 64;
+/// How long a pin awaiting revalidation is kept without seeing its device
+pub const REVALIDATE_WINDOW: Duration = Duration::from_secs(300);
+/// How often pins are written to the state file
+const SAVE_PERIOD: Duration = Duration::from_secs(60);
+/// How often the internal interface is checked for coming back up
+const LINK_CHECK_PERIOD: Duration = Duration::from_secs(2);
+
+struct Pin {
+    mac: MacAddr,
+    expires: Instant,
+    /// Seen from `mac` since the pin was restored or the internal interface came back
+    confirmed: bool,
+}
+
+pub struct DevicePins {
+    enabled: bool,
+    pins: Mutex<HashMap<Ipv4Addr, Pin>>,
+    ttl: Duration,
+}
+
+impl DevicePins {
+    /// Creates an empty pin table, pins expiring `ttl` after the device was last seen.
+    /// A zero `ttl` disables pinning, letting every packet through.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            enabled: !ttl.is_zero(),
+            pins: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Pins the sender of a discovery packet, returning `false` if its address is
+    /// already pinned to a different MAC.
+    pub async fn learn(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let Some((ip, mac)) = sender(eth_packet) else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut pins = self.pins.lock().await;
+
+        match pins.get_mut(&ip) {
+            Some(pin) if pin.expires > now && (pin.confirmed || pin.mac == mac) => {
+                if pin.mac != mac {
+                    warn!(
+                        "Device {ip} pinned to {} seen from {mac}, dropping",
+                        pin.mac
+                    );
+                    return false;
+                }
+                pin.expires = now + self.ttl;
+                pin.confirmed = true;
+            }
+            _ => {
+                if pins.len() >= MAX_PINS {
+                    pins.retain(|_, pin| pin.expires > now);
+                    if pins.len() >= MAX_PINS {
+                        warn!("Device pin table full, not pinning {ip} ({mac})");
+                        return true;
+                    }
+                }
+                info!("Pinned device {ip} ({mac})");
+                pins.insert(
+                    ip,
+                    Pin {
+                        mac,
+                        expires: now + self.ttl,
+                        confirmed: true,
+                    },
+                );
+            }
+        }
+        true
+    }
+
+    /// Checks that a packet was sent by a pinned device, refreshing its pin.
+    pub async fn is_pinned(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let Some((ip, mac)) = sender(eth_packet) else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut pins = self.pins.lock().await;
+        match pins.get_mut(&ip) {
+            Some(pin) if pin.mac == mac && pin.expires > now => {
+                pin.expires = now + self.ttl;
+                pin.confirmed = true;
+                true
+            }
+            _ => {
+                warn!("Ext to Int - {ip} ({mac}) is not a pinned device, dropping");
+                false
+            }
+        }
+    }
+
+    /// Checks whether a packet comes from the address of a pinned device, but from
+    /// another MAC.
+    pub async fn is_spoofed(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let Some((ip, mac)) = sender(eth_packet) else {
+            return false;
+        };
+        match self.pins.lock().await.get(&ip) {
+            Some(pin) if pin.mac != mac && pin.expires > Instant::now() => {
+                warn!(
+                    "Device {ip} pinned to {} seen from {mac}, dropping",
+                    pin.mac
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the active pins with the time left until each expires.
+    pub async fn list(&self) -> Vec<(Ipv4Addr, MacAddr, Duration)> {
+        let now = Instant::now();
+        let mut pins: Vec<_> = self
+            .pins
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, pin)| pin.expires > now)
+            .map(|(ip, pin)| (*ip, pin.mac, pin.expires - now))
+            .collect();
+        pins.sort_unstable_by_key(|(ip, _, _)| *ip);
+        pins
+    }
+
+    /// Forgets all pinned devices.
+    pub async fn clear(&self) {
+        let mut pins = self.pins.lock().await;
+        info!("Clearing {} pinned devices", pins.len());
+        pins.clear();
+    }
+
+    /// Requires every pin to be confirmed by its device within [`REVALIDATE_WINDOW`].
+    pub async fn revalidate(&self) {
+        let deadline = Instant::now() + REVALIDATE_WINDOW;
+        let mut pins = self.pins.lock().await;
+        for pin in pins.values_mut() {
+            pin.expires = pin.expires.min(deadline);
+            pin.confirmed = false;
+        }
+        info!("Revalidating {} pinned devices", pins.len());
+    }
+
+    /// Writes the active pins to `path`, one `<ip> <mac> <expiry in unix seconds>` per line.
+    pub async fn save(&self, path: &Path) -> std::io::Result<()> {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut content = String::new();
+        for (ip, pin) in self.pins.lock().await.iter() {
+            if pin.expires <= now {
+                continue;
+            }
+            let expires = wall_now + (pin.expires - now);
+            let secs = expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            content.push_str(&format!("{ip} {} {secs}\n", pin.mac));
+        }
+
+        // Replace atomically so a crash never leaves a truncated file behind
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+
+    /// Restores the pins saved to `path` for revalidation, returning how many were restored.
+    pub async fn load(&self, path: &Path) -> std::io::Result<usize> {
+        let content = tokio::fs::read_to_string(path).await?;
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut pins = self.pins.lock().await;
+        let mut restored = 0;
+        for line in content.lines() {
+            let Some((ip, mac, expires)) = parse_pin(line) else {
+                warn!("Ignoring malformed pin `{line}` in {}", path.display());
+                continue;
+            };
+            let Ok(remaining) = expires.duration_since(wall_now) else {
+                continue;
+            };
+            if pins.len() >= MAX_PINS {
+                break;
+            }
+            pins.entry(ip).or_insert(Pin {
+                mac,
+                expires: now + remaining.min(REVALIDATE_WINDOW),
+                confirmed: false,
+            });
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    /// Keeps the pins up to date until cancelled: clears them on SIGUSR1
+    /// (`systemctl kill -s USR1 <unit>`), revalidates them whenever the internal
+    /// interface comes back up and mirrors them to `state_file`.
+    pub async fn maintain(
+        &self,
+        state_file: Option<&Path>,
+        internal_iface: &str,
+        cancel_token: CancellationToken,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(path) = state_file {
+            match self.load(path).await {
+                Ok(restored) => info!("Restored {restored} pinned devices from {}", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => error!(
+                    "Failed to restore pinned devices from {}: {e}",
+                    path.display()
+                ),
+            }
+        }
+        let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to install SIGUSR1 handler: {e}");
+                return;
+            }
+        };
+
+        let mut save = interval(SAVE_PERIOD);
+        let mut link_check = interval(LINK_CHECK_PERIOD);
+        let mut was_up = true;
+        loop {
+            tokio::select! {
+                () = cancel_token.cancelled() => break,
+                _ = sigusr1.recv() => self.clear().await,
+                _ = link_check.tick() => {
+                    let up = forward::is_iface_running_up(internal_iface);
+                    if up && !was_up {
+                        info!("{internal_iface} is back up");
+                        self.revalidate().await;
+                    }
+                    was_up = up;
+                    continue;
+                }
+                _ = save.tick() => {}
+            }
+            self.persist(state_file).await;
+        }
+        self.persist(state_file).await;
+    }
+
+    async fn persist(&self, state_file: Option<&Path>) {
+        if let Some(path) = state_file
+            && let Err(e) = self.save(path).await
+        {
+            error!("Failed to save pinned devices to {}: {e}", path.display());
+        }
+    }
+}
+
+fn parse_pin(line: &str) -> Option<(Ipv4Addr, MacAddr, SystemTime)> {
+    let mut fields = line.split_whitespace();
+    let ip = fields.next()?.parse().ok()?;
+    let mac = fields.next()?.parse().ok()?;
+    let secs = fields.next()?.parse().ok()?;
+    Some((ip, mac, UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+fn sender(eth_packet: &EthernetPacket<'_>) -> Option<(Ipv4Addr, MacAddr)> {
+    if eth_packet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+    Some((ipv4_packet.get_source(), eth_packet.get_source()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    const CAST: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+    const CAST_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x50);
+    const ROGUE_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x66);
+
+    fn frame(src: Ipv4Addr, mac: MacAddr) -> Vec<u8> {
+        let mut buffer = vec![0u8; 14 + 20];
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        eth.set_source(mac);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buffer[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_source(src);
+        buffer
+    }
+
+    async fn learn(pins: &DevicePins, frame: &[u8]) -> bool {
+        pins.learn(&EthernetPacket::new(frame).unwrap()).await
+    }
+
+    async fn pinned(pins: &DevicePins, frame: &[u8]) -> bool {
+        pins.is_pinned(&EthernetPacket::new(frame).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn test_only_pinned_devices_pass() {
+        let pins = DevicePins::new(Duration::from_secs(60));
+        assert!(!pinned(&pins, &frame(CAST, CAST_MAC)).await);
+
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        assert!(pinned(&pins, &frame(CAST, CAST_MAC)).await);
+        assert!(!pinned(&pins, &frame(CAST, ROGUE_MAC)).await);
+        assert!(!pinned(&pins, &frame(Ipv4Addr::new(192, 168, 1, 66), CAST_MAC)).await);
+
+        // A spoofed announcement does not move the pin
+        assert!(!learn(&pins, &frame(CAST, ROGUE_MAC)).await);
+        assert!(pinned(&pins, &frame(CAST, CAST_MAC)).await);
+
+        pins.clear().await;
+        assert!(!pinned(&pins, &frame(CAST, CAST_MAC)).await);
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_sender() {
+        let pins = DevicePins::new(Duration::from_secs(60));
+        assert!(
+            !pins
+                .is_spoofed(&EthernetPacket::new(&frame(CAST, ROGUE_MAC)).unwrap())
+                .await
+        );
+
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        assert!(
+            pins.is_spoofed(&EthernetPacket::new(&frame(CAST, ROGUE_MAC)).unwrap())
+                .await
+        );
+        assert!(
+            !pins
+                .is_spoofed(&EthernetPacket::new(&frame(CAST, CAST_MAC)).unwrap())
+                .await
+        );
+
+        let listed = pins.list().await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].0, listed[0].1), (CAST, CAST_MAC));
+    }
+
+    #[tokio::test]
+    async fn test_disabled_pinning() {
+        let pins = DevicePins::new(Duration::ZERO);
+        assert!(pinned(&pins, &frame(CAST, ROGUE_MAC)).await);
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        assert!(learn(&pins, &frame(CAST, ROGUE_MAC)).await);
+    }
+
+    #[tokio::test]
+    async fn test_restored_pins_revalidate() {
+        let path = std::env::temp_dir().join(format!("pins-{}", std::process::id()));
+        let pins = DevicePins::new(Duration::from_secs(3600));
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        pins.save(&path).await.unwrap();
+
+        let restored = DevicePins::new(Duration::from_secs(3600));
+        assert_eq!(restored.load(&path).await.unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(pinned(&restored, &frame(CAST, CAST_MAC)).await);
+        assert!(!pinned(&restored, &frame(CAST, ROGUE_MAC)).await);
+
+        // Once confirmed, the pin is protected again
+        assert!(!learn(&restored, &frame(CAST, ROGUE_MAC)).await);
+
+        // An unconfirmed pin gives way to the device now answering discovery
+        restored.revalidate().await;
+        assert!(learn(&restored, &frame(CAST, ROGUE_MAC)).await);
+        assert!(pinned(&restored, &frame(CAST, ROGUE_MAC)).await);
+        assert!(!pinned(&restored, &frame(CAST, CAST_MAC)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_revalidation_window() {
+        let pins = DevicePins::new(Duration::from_secs(3600));
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        pins.revalidate().await;
+        tokio::time::advance(REVALIDATE_WINDOW + Duration::from_secs(1)).await;
+        assert!(!pinned(&pins, &frame(CAST, CAST_MAC)).await);
+
+        // Seeing the device again restores the full lifetime
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        pins.revalidate().await;
+        assert!(pinned(&pins, &frame(CAST, CAST_MAC)).await);
+        tokio::time::advance(REVALIDATE_WINDOW + Duration::from_secs(1)).await;
+        assert!(pinned(&pins, &frame(CAST, CAST_MAC)).await);
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        let (ip, mac, expires) = parse_pin("192.168.1.50 02:00:00:00:00:50 1700000000").unwrap();
+        assert_eq!((ip, mac), (CAST, CAST_MAC));
+        assert_eq!(expires, UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert!(parse_pin("192.168.1.50 02:00:00:00:00:50").is_none());
+        assert!(parse_pin("cast 02:00:00:00:00:50 1700000000").is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pin_expiry() {
+        let pins = DevicePins::new(Duration::from_secs(60));
+        assert!(learn(&pins, &frame(CAST, CAST_MAC)).await);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!pinned(&pins, &frame(CAST, CAST_MAC)).await);
+
+        // Once expired, the address may be pinned to a new device
+        assert!(learn(&pins, &frame(CAST, ROGUE_MAC)).await);
+        assert!(pinned(&pins, &frame(CAST, ROGUE_MAC)).await);
+    }
+}
@@ -209,6 +209,7 @@ impl Default for RateLimiter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[allow(unused_imports)]
     use std::thread::sleep;
     use std::time::Duration;
 
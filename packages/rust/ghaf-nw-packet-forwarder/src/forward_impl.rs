@@ -3,6 +3,11 @@
     SPDX-License-Identifier: Apache-2.0
 */
 
+pub mod dhcp_relay;
+pub mod napt;
+pub mod neighbors;
+pub mod retransmit;
+
 // forward.rs
 pub mod forward {
 
@@ -32,7 +37,7 @@ pub mod forward {
     use pnet::packet::ipv4::MutableIpv4Packet;
     use pnet::packet::ipv6::Ipv6Packet;
     use pnet::packet::tcp;
-    use pnet::packet::tcp::{MutableTcpPacket, TcpPacket};
+    use pnet::packet::tcp::{MutableTcpPacket, TcpFlags, TcpPacket};
     use pnet::packet::udp;
     use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
     use pnet::util::MacAddr;
@@ -88,6 +93,26 @@ pub mod forward {
         Ok(())
     }
 
+    /// Replaces the external interface, leaving the internal one untouched.
+    ///
+    /// # Arguments
+    /// * `ext_iface` - The new external network interface.
+    /// * `ext_iface_ip` - The external IP address to assign (optional).
+    ///
+    /// # Returns
+    /// A `Result` indicating success or failure of the assignment.
+    pub fn assign_ext_iface(
+        ext_iface: &NetworkInterface,
+        ext_iface_ip: Option<IpNetwork>,
+    ) -> Result<(), String> {
+        let ext_ip = select_ip(ext_iface, ext_iface_ip)?;
+
+        let mut ifaces = IFACES.write().unwrap();
+        ifaces.ext_ip = ext_ip;
+        ifaces.ext_mac = ext_iface.mac.unwrap_or_default();
+        Ok(())
+    }
+
     fn select_ip(
         iface: &NetworkInterface,
         iface_ip: Option<IpNetwork>,
@@ -130,9 +155,28 @@ pub mod forward {
     }
 
     pub fn is_iface_running_up(iface_name: &str) -> bool {
+        running_ipv4(iface_name).is_some()
+    }
+
+    /// Takes over a new address of the external interface `iface_name`, e.g. after a
+    /// DHCP renewal, while it is up and running.
+    pub fn refresh_ext_ip(iface_name: &str) {
+        let Some((mac, ip)) = running_ipv4(iface_name) else {
+            return;
+        };
+        let current_ifaces = get_ifaces();
+        if current_ifaces.ext_mac == mac && current_ifaces.ext_ip.ip() != ip.ip() {
+            let mut ifaces = IFACES.write().unwrap();
+            ifaces.ext_ip = ip;
+            info!("external interface has new ip:{}", ifaces.ext_ip);
+        }
+    }
+
+    /// MAC and first IPv4 address of the interface `iface_name`, if it is up and running
+    fn running_ipv4(iface_name: &str) -> Option<(MacAddr, IpNetwork)> {
         // Get the network interfaces
         let interfaces = datalink::interfaces();
-        if let Some((mac, ip)) = interfaces
+        interfaces
             .iter()
             .filter(|iface| iface.name == iface_name && iface.is_up() && iface.is_running())
             .filter_map(|iface| iface.mac.map(|mac| (iface, mac)))
@@ -140,19 +184,8 @@ pub mod forward {
                 iface
                     .ips
                     .iter()
-                    .find_map(|ip| ip.is_ipv4().then_some((mac, ip)))
+                    .find_map(|ip| ip.is_ipv4().then_some((mac, *ip)))
             })
-        {
-            let current_ifaces = get_ifaces();
-            if current_ifaces.ext_mac == mac && current_ifaces.ext_ip.ip() != ip.ip() {
-                let mut ifaces = IFACES.write().unwrap();
-                ifaces.ext_ip = *ip;
-                info!("external interface has new ip:{}", ifaces.ext_ip);
-            }
-            true
-        } else {
-            false
-        }
     }
 
     pub async fn set_sec_params(rate_limiter: &RateLimiter, cancel_token: CancellationToken) {
@@ -170,6 +203,9 @@ pub mod forward {
     /// * `src_mac` - The source MAC address.
     /// * `dest_mac` - The destination MAC address.
     /// * `dest_ip` - The destination IP address.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the packet was sent to the internal network.
     pub async fn external_to_internal_process_packet(
         tx: Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
         eth_packet: &mut MutableEthernetPacket<'_>,
@@ -177,21 +213,62 @@ pub mod forward {
         src_mac: MacAddr,
         dest_mac: MacAddr,
         dest_ip: IpNetwork,
-    ) {
-        let mut tx = tx.lock().await; // Acquire lock asynchronously
+    ) -> bool {
+        is_ext_to_int_packet_allowed(eth_packet, src_ips).await
+            && external_to_internal_send_packet(tx, eth_packet, src_mac, dest_mac, dest_ip).await
+    }
 
-        /*
-        1) src_ip -> should remain as it is
-        2) dest_ip,dest mac -> modified with chrome-vm ip
-        3) calculate crc and checksums again
-        */
+    /// Checks a packet coming from the external interface before it is modified in any
+    /// way: it must be IPv4, not sent from our own address and pass the safety checks.
+    ///
+    /// # Arguments
+    /// * `eth_packet` - The Ethernet packet to check.
+    /// * `src_ips` - A vector of source IP addresses to check.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the packet may be forwarded to the internal network.
+    pub async fn is_ext_to_int_packet_allowed(
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        src_ips: &Vec<pnet::ipnetwork::IpNetwork>,
+    ) -> bool {
         let is_ipv6: bool = eth_packet.get_ethertype() == EtherTypes::Ipv6;
         if is_ipv6
             || is_it_own_packet(eth_packet, src_ips)
             || !ext_to_int_is_packet_safe(eth_packet).await
         {
             debug!("Ext to Int - packet dropped {}", parse_packet(eth_packet));
-        } else if modify_ext_to_int_packet(eth_packet, src_mac, dest_mac, dest_ip) {
+            return false;
+        }
+        true
+    }
+
+    /// Sends a packet coming from the external interface, which passed
+    /// [`is_ext_to_int_packet_allowed`], to the internal network.
+    ///
+    /// # Arguments
+    /// * `tx` - The data link sender used to transmit the packet.
+    /// * `eth_packet` - The Ethernet packet to forward.
+    /// * `src_mac` - The source MAC address.
+    /// * `dest_mac` - The destination MAC address.
+    /// * `dest_ip` - The destination IP address.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the packet was sent to the internal network.
+    pub async fn external_to_internal_send_packet(
+        tx: Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        src_mac: MacAddr,
+        dest_mac: MacAddr,
+        dest_ip: IpNetwork,
+    ) -> bool {
+        let mut tx = tx.lock().await; // Acquire lock asynchronously
+
+        /*
+        1) src_ip -> should remain as it is
+        2) dest_ip,dest mac -> modified with chrome-vm ip
+        3) calculate crc and checksums again
+        */
+        if modify_ext_to_int_packet(eth_packet, src_mac, dest_mac, dest_ip) {
             // println!(
             //     "forwarded_packet:{:?}, len:{}",
             //     forwarded_packet,
@@ -209,6 +286,7 @@ pub mod forward {
                         parse_packet(eth_packet)
                     );
                     trace!("Ext to Int - Forwarded packet: {eth_packet:?}");
+                    return true;
                 }
                 Some(Err(e)) => {
                     error!("Error sending packet: {e}");
@@ -216,6 +294,7 @@ pub mod forward {
                 None => error!("Error: Send failed, no destination address."),
             }
         }
+        false
     }
     /// Determines if the given Ethernet packet belongs to our own interface's ip.
     ///
@@ -286,63 +365,62 @@ pub mod forward {
         eth_packet.set_source(src_mac);
         if eth_packet.get_ethertype() == EtherTypes::Ipv4 {
             // Parse the IPv4 packet
-            if let Some(mut ipv4_packet) =
-                MutableIpv4Packet::new(&mut eth_packet.packet_mut()[14..])
-            {
-                // Extract source and destination IPs before modifying the packet
-                let src_ip = ipv4_packet.get_source();
-
-                // Modify destination IP
-                let IpAddr::V4(dest_ipv4) = dest_ip.ip() else {
-                    error!("Not an IPv4 address");
+            let mut ipv4_packet = match ipv4_packet_mut(eth_packet) {
+                Ok(ipv4_packet) => ipv4_packet,
+                Err(e) => {
+                    debug!("Ext to Int - {e}");
                     return false;
-                };
-                ipv4_packet.set_destination(dest_ipv4);
-
-                if ipv4_packet.get_destination().is_multicast() {
-                    ipv4_packet.set_ttl(1);
                 }
+            };
+            // Extract source and destination IPs before modifying the packet
+            let src_ip = ipv4_packet.get_source();
 
-                match ipv4_packet.get_next_level_protocol() {
-                    IpNextHeaderProtocols::Tcp => {
-                        if let Some(mut tcp_packet) =
-                            MutableTcpPacket::new(ipv4_packet.payload_mut())
-                        {
-                            // Recalculate TCP checksum
-                            let checksum =
-                                tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dest_ipv4);
-                            tcp_packet.set_checksum(checksum);
-                        }
+            // Modify destination IP
+            let IpAddr::V4(dest_ipv4) = dest_ip.ip() else {
+                error!("Not an IPv4 address");
+                return false;
+            };
+            ipv4_packet.set_destination(dest_ipv4);
+
+            if ipv4_packet.get_destination().is_multicast() {
+                ipv4_packet.set_ttl(1);
+            }
+
+            match ipv4_packet.get_next_level_protocol() {
+                IpNextHeaderProtocols::Tcp => {
+                    if let Some(mut tcp_packet) = MutableTcpPacket::new(ipv4_packet.payload_mut()) {
+                        // Recalculate TCP checksum
+                        let checksum =
+                            tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dest_ipv4);
+                        tcp_packet.set_checksum(checksum);
                     }
-                    IpNextHeaderProtocols::Udp => {
-                        if let Some(mut udp_packet) =
-                            MutableUdpPacket::new(ipv4_packet.payload_mut())
-                        {
-                            // Recalculate UDP checksum
-                            udp_packet.set_checksum(0);
-
-                            let checksum =
-                                udp::ipv4_checksum(&udp_packet.to_immutable(), &src_ip, &dest_ipv4);
-                            udp_packet.set_checksum(checksum);
-                        }
+                }
+                IpNextHeaderProtocols::Udp => {
+                    if let Some(mut udp_packet) = MutableUdpPacket::new(ipv4_packet.payload_mut()) {
+                        // Recalculate UDP checksum
+                        udp_packet.set_checksum(0);
+
+                        let checksum =
+                            udp::ipv4_checksum(&udp_packet.to_immutable(), &src_ip, &dest_ipv4);
+                        udp_packet.set_checksum(checksum);
                     }
-
-                    _ => return false,
                 }
-                // println!("ipv4_packet:{:?}", ipv4_packet.packet());
 
-                // Recalculate IPv4 checksum
-                ipv4_packet.set_checksum(0); // Clear existing checksum
+                _ => return false,
+            }
+            // println!("ipv4_packet:{:?}", ipv4_packet.packet());
 
-                match calculate_ipv4_checksum(ipv4_packet.packet()) {
-                    Ok(checksum) => {
-                        ipv4_packet.set_checksum(checksum);
-                        debug!("Ext to Int - ipv4_packet: {ipv4_packet:?}, checksum:{checksum:?}");
-                    }
-                    Err(e) => {
-                        error!("{e}");
-                        return false;
-                    }
+            // Recalculate IPv4 checksum
+            ipv4_packet.set_checksum(0); // Clear existing checksum
+
+            match calculate_ipv4_checksum(ipv4_packet.packet()) {
+                Ok(checksum) => {
+                    ipv4_packet.set_checksum(checksum);
+                    debug!("Ext to Int - ipv4_packet: {ipv4_packet:?}, checksum:{checksum:?}");
+                }
+                Err(e) => {
+                    error!("{e}");
+                    return false;
                 }
             }
         } else {
@@ -355,18 +433,45 @@ pub mod forward {
         true
     }
 
-    fn calculate_ipv4_checksum(header: &[u8]) -> Result<u16, Box<dyn Error>> {
-        if header.len() < 20 {
-            return Err("IPv4 header must be at least 20 bytes long!".into());
+    /// The IPv4 packet carried by `eth_packet`, refused if its header or total length
+    /// runs past the end of the frame, as it does in truncated or crafted frames.
+    pub(crate) fn ipv4_packet_mut<'p>(
+        eth_packet: &'p mut MutableEthernetPacket<'_>,
+    ) -> Result<MutableIpv4Packet<'p>, String> {
+        let payload = eth_packet.payload_mut();
+        let available = payload.len();
+        let ipv4_packet = MutableIpv4Packet::new(payload)
+            .ok_or_else(|| format!("IPv4 packet truncated to {available} bytes"))?;
+        let header_len = usize::from(ipv4_packet.get_header_length()) * 4;
+        let total_len = usize::from(ipv4_packet.get_total_length());
+        if header_len < Ipv4Packet::minimum_packet_size() || header_len > total_len {
+            return Err(format!(
+                "IPv4 header length {header_len} invalid for total length {total_len}"
+            ));
         }
+        // Frames may be padded past the packet, never cut short of it
+        if total_len > available {
+            return Err(format!(
+                "IPv4 packet of {total_len} bytes truncated to {available}"
+            ));
+        }
+        Ok(ipv4_packet)
+    }
 
-        // Only process the first 20 bytes (IPv4 header length)
-        let header = &header[0..20];
+    fn calculate_ipv4_checksum(packet: &[u8]) -> Result<u16, Box<dyn Error>> {
+        // The header length field counts 32-bit words, options included
+        let header_len = packet.first().map_or(0, |b| usize::from(b & 0x0f) * 4);
+        if header_len < 20 {
+            return Err("IPv4 header must be at least 20 bytes long!".into());
+        }
+        let header = packet
+            .get(..header_len)
+            .ok_or("IPv4 header runs past the end of the packet!")?;
 
         let mut sum: u32 = 0;
 
         // Iterate over 16-bit words
-        for chunk in header.chunks(2) {
+        for chunk in header.chunks_exact(2) {
             // Convert two bytes into a single 16-bit word
             let word = u16::from_be_bytes([chunk[0], chunk[1]]);
             sum += word as u32;
@@ -469,11 +574,14 @@ pub mod forward {
     /// * `tx` - An `Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>` used to send the modified packet to the external interface.
     /// * `eth_packet` - A reference to an `EthernetPacket` which represents the packet to be forwarded.
     /// * `ifaces` - A reference to the `Ifaces` struct containing the network interfaces' details, including external IP and MAC addresses.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the packet was sent to the external network.
     pub async fn internal_to_external_process_packet(
         tx: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
         eth_packet: &mut MutableEthernetPacket<'_>,
         ifaces: &Ifaces,
-    ) {
+    ) -> bool {
         let mut tx = tx.lock().await; // Acquire lock asynchronously
         let ext_mac = ifaces.ext_mac;
         let ext_ip = ifaces.ext_ip;
@@ -498,6 +606,7 @@ pub mod forward {
                         parse_packet(eth_packet)
                     );
                     trace!("Int to ext - Forwarded packet(raw): {eth_packet:?}");
+                    return true;
                 }
                 Some(Err(e)) => {
                     error!("Int to Ext - Error sending packet: {e}");
@@ -505,6 +614,7 @@ pub mod forward {
                 None => error!("Int to Ext - Send failed, no destination address."),
             }
         }
+        false
     }
     /// Checks whether the given Ethernet packet should be propagated to external network
     ///
@@ -570,57 +680,56 @@ pub mod forward {
 
         if eth_packet.get_ethertype() == EtherTypes::Ipv4 {
             // Parse the IPv4 packet
-            if let Some(mut ipv4_packet) =
-                MutableIpv4Packet::new(&mut eth_packet.packet_mut()[14..])
-            {
-                // Modify source IP
-                let IpNetwork::V4(ipv4) = ext_iface_ip else {
-                    error!("Not an IPv4 address");
+            let mut ipv4_packet = match ipv4_packet_mut(eth_packet) {
+                Ok(ipv4_packet) => ipv4_packet,
+                Err(e) => {
+                    debug!("Int to Ext - {e}");
                     return false;
-                };
-                ipv4_packet.set_source(ipv4.ip());
-
-                let src_ip = ipv4_packet.get_source();
-                let dest_ip = ipv4_packet.get_destination();
-
-                match ipv4_packet.get_next_level_protocol() {
-                    IpNextHeaderProtocols::Tcp => {
-                        if let Some(mut tcp_packet) =
-                            MutableTcpPacket::new(ipv4_packet.payload_mut())
-                        {
-                            // Recalculate TCP checksum
-                            let checksum =
-                                tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dest_ip);
-                            tcp_packet.set_checksum(checksum);
-                        }
+                }
+            };
+            // Modify source IP
+            let IpNetwork::V4(ipv4) = ext_iface_ip else {
+                error!("Not an IPv4 address");
+                return false;
+            };
+            ipv4_packet.set_source(ipv4.ip());
+
+            let src_ip = ipv4_packet.get_source();
+            let dest_ip = ipv4_packet.get_destination();
+
+            match ipv4_packet.get_next_level_protocol() {
+                IpNextHeaderProtocols::Tcp => {
+                    if let Some(mut tcp_packet) = MutableTcpPacket::new(ipv4_packet.payload_mut()) {
+                        // Recalculate TCP checksum
+                        let checksum =
+                            tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dest_ip);
+                        tcp_packet.set_checksum(checksum);
                     }
-                    IpNextHeaderProtocols::Udp => {
-                        if let Some(mut udp_packet) =
-                            MutableUdpPacket::new(ipv4_packet.payload_mut())
-                        {
-                            udp_packet.set_checksum(0);
-
-                            // Recalculate UDP checksum
-                            let checksum =
-                                udp::ipv4_checksum(&udp_packet.to_immutable(), &src_ip, &dest_ip);
-                            udp_packet.set_checksum(checksum);
-                        }
+                }
+                IpNextHeaderProtocols::Udp => {
+                    if let Some(mut udp_packet) = MutableUdpPacket::new(ipv4_packet.payload_mut()) {
+                        udp_packet.set_checksum(0);
+
+                        // Recalculate UDP checksum
+                        let checksum =
+                            udp::ipv4_checksum(&udp_packet.to_immutable(), &src_ip, &dest_ip);
+                        udp_packet.set_checksum(checksum);
                     }
-
-                    _ => return false,
                 }
-                // Recalculate IPv4 checksum
-                ipv4_packet.set_checksum(0); // Clear existing checksum
 
-                match calculate_ipv4_checksum(ipv4_packet.packet()) {
-                    Ok(checksum) => {
-                        ipv4_packet.set_checksum(checksum);
-                        debug!("Int to Ext - ipv4_packet: {ipv4_packet:?}, checksum:{checksum:?}");
-                    }
-                    Err(e) => {
-                        error!("{e}");
-                        return false;
-                    }
+                _ => return false,
+            }
+            // Recalculate IPv4 checksum
+            ipv4_packet.set_checksum(0); // Clear existing checksum
+
+            match calculate_ipv4_checksum(ipv4_packet.packet()) {
+                Ok(checksum) => {
+                    ipv4_packet.set_checksum(checksum);
+                    debug!("Int to Ext - ipv4_packet: {ipv4_packet:?}, checksum:{checksum:?}");
+                }
+                Err(e) => {
+                    error!("{e}");
+                    return false;
                 }
             }
         } else {
@@ -654,53 +763,72 @@ pub mod forward {
 
         if eth_packet.get_ethertype() == EtherTypes::Ipv4 {
             // Parse the IPv4 packet
-            if let Some(mut ipv4_packet) =
-                MutableIpv4Packet::new(&mut eth_packet.packet_mut()[14..])
-            {
-                // Extract source and destination IPs before modifying the packet
-                let src_ip = ipv4_packet.get_source();
-                let dest_ip = ipv4_packet.get_destination();
-
-                if !ipv4_packet.is_checksum_correct(&src_ip, &dest_ip) || 0 == ipv4_packet.get_ttl()
-                {
-                    debug!("ext to int - ipv4 checksum is not correct:{ipv4_packet:?}");
+            let mut ipv4_packet = match ipv4_packet_mut(eth_packet) {
+                Ok(ipv4_packet) => ipv4_packet,
+                Err(e) => {
+                    debug!("ext to int - {e}");
                     return false;
                 }
+            };
+            // Extract source and destination IPs before modifying the packet
+            let src_ip = ipv4_packet.get_source();
+            let dest_ip = ipv4_packet.get_destination();
 
-                let proto = ipv4_packet.get_next_level_protocol();
-                let mut dest_port = 0;
-                let mut src_port = 0;
-
-                match proto {
-                    IpNextHeaderProtocols::Udp => {
-                        if let Some(mut udp_packet) =
-                            MutableUdpPacket::new(ipv4_packet.payload_mut())
-                        {
-                            if !udp_packet.is_checksum_correct(&src_ip, &dest_ip) {
-                                debug!("ext to int - udp checksum is not correct:{ipv4_packet:?}");
-                                return false;
-                            }
+            if !ipv4_packet.is_checksum_correct(&src_ip, &dest_ip) || 0 == ipv4_packet.get_ttl() {
+                debug!("ext to int - ipv4 checksum is not correct:{ipv4_packet:?}");
+                return false;
+            }
+
+            let proto = ipv4_packet.get_next_level_protocol();
+            let mut dest_port = 0;
+            let mut src_port = 0;
 
-                            dest_port = udp_packet.get_destination();
-                            src_port = udp_packet.get_source();
+            match proto {
+                IpNextHeaderProtocols::Udp => {
+                    if let Some(mut udp_packet) = MutableUdpPacket::new(ipv4_packet.payload_mut()) {
+                        if !udp_packet.is_checksum_correct(&src_ip, &dest_ip) {
+                            debug!("ext to int - udp checksum is not correct:{ipv4_packet:?}");
+                            return false;
                         }
+
+                        dest_port = udp_packet.get_destination();
+                        src_port = udp_packet.get_source();
                     }
+                }
 
-                    _ => {
-                        debug!("ext to int- unimplemented protocol handling");
+                IpNextHeaderProtocols::Tcp => {
+                    let Some(mut tcp_packet) = MutableTcpPacket::new(ipv4_packet.payload_mut())
+                    else {
+                        debug!("ext to int - tcp header truncated:{ipv4_packet:?}");
+                        return false;
+                    };
+                    if !tcp_packet.is_checksum_correct(&src_ip, &dest_ip) {
+                        debug!("ext to int - tcp checksum is not correct:{ipv4_packet:?}");
                         return false;
                     }
+                    if !is_tcp_state_valid(&tcp_packet.to_immutable()) {
+                        debug!("ext to int - tcp segment refused:{ipv4_packet:?}");
+                        return false;
+                    }
+
+                    dest_port = tcp_packet.get_destination();
+                    src_port = tcp_packet.get_source();
                 }
-                let security = Arc::clone(&SECURITY);
 
-                if !security
-                    .is_packet_secure(src_ip, proto, src_port, dest_port)
-                    .await
-                {
-                    warn!("packet is not safe");
+                _ => {
+                    debug!("ext to int- unimplemented protocol handling");
                     return false;
                 }
             }
+            let security = Arc::clone(&SECURITY);
+
+            if !security
+                .is_packet_secure(src_ip, proto, src_port, dest_port)
+                .await
+            {
+                warn!("packet is not safe");
+                return false;
+            }
         } else {
             return false;
         }
@@ -708,6 +836,24 @@ pub mod forward {
         true
     }
 
+    /// Checks that an inbound TCP segment is well-formed and belongs to a connection
+    /// opened from the internal network: connections are never accepted from outside,
+    /// and flag combinations no stack sends (scans, fingerprinting) are refused.
+    fn is_tcp_state_valid(tcp_packet: &TcpPacket<'_>) -> bool {
+        let header_len = usize::from(tcp_packet.get_data_offset()) * 4;
+        if header_len < TcpPacket::minimum_packet_size() || header_len > tcp_packet.packet().len() {
+            return false;
+        }
+        let flags = tcp_packet.get_flags();
+        let has = |flag| flags & flag != 0;
+        if has(TcpFlags::SYN) {
+            // Only the SYN-ACK answering our own SYN is expected
+            return has(TcpFlags::ACK) && !has(TcpFlags::FIN) && !has(TcpFlags::RST);
+        }
+        // Apart from a bare reset, every segment of a connection acknowledges
+        has(TcpFlags::ACK) || flags == TcpFlags::RST
+    }
+
     fn int_to_ext_is_packet_safe(_eth_packet: &mut MutableEthernetPacket<'_>) -> bool {
         //loopback check should be here
         //rate limiting should be here
@@ -743,6 +889,28 @@ pub mod forward {
     }
 
     // Implement the trait for TCP packets.
+    impl ChecksummablePacket for MutableTcpPacket<'_> {
+        fn is_checksum_correct(&mut self, src_ip: &Ipv4Addr, dest_ip: &Ipv4Addr) -> bool {
+            let current_checksum = self.get_checksum();
+            // Recalculate TCP checksum
+            self.set_checksum(0);
+
+            let expected_checksum = tcp::ipv4_checksum(&self.to_immutable(), src_ip, dest_ip);
+
+            if current_checksum != expected_checksum {
+                warn!(
+                    "Wrong tcp checksum, current:{current_checksum}, expected:{expected_checksum}"
+                );
+                return false;
+            }
+
+            self.set_checksum(expected_checksum);
+
+            true
+        }
+    }
+
+    // Implement the trait for IPv4 packets.
     impl ChecksummablePacket for MutableIpv4Packet<'_> {
         fn is_checksum_correct(&mut self, _src_ip: &Ipv4Addr, _dest_ip: &Ipv4Addr) -> bool {
             let current_ipv4_packet_checksum = self.get_checksum();
@@ -783,7 +951,52 @@ pub mod forward {
         src_ip: &Ipv4Addr,
         dest_ip: &Ipv4Addr,
     ) -> bool {
-        udp_packet.is_checksum_correct(src_ip, &dest_ip)
+        udp_packet.is_checksum_correct(src_ip, dest_ip)
+    }
+
+    #[cfg(test)]
+    pub fn is_checksum_correct_tcp_test(
+        tcp_packet: &mut MutableTcpPacket<'_>,
+        src_ip: &Ipv4Addr,
+        dest_ip: &Ipv4Addr,
+    ) -> bool {
+        tcp_packet.is_checksum_correct(src_ip, dest_ip)
+    }
+
+    #[cfg(test)]
+    pub async fn ext_to_int_is_packet_safe_test(
+        eth_packet: &mut MutableEthernetPacket<'_>,
+    ) -> bool {
+        ext_to_int_is_packet_safe(eth_packet).await
+    }
+
+    #[cfg(test)]
+    pub fn modify_ext_to_int_packet_test(
+        eth_packet: &mut MutableEthernetPacket,
+        src_mac: MacAddr,
+        dest_mac: MacAddr,
+        dest_ip: IpNetwork,
+    ) -> bool {
+        modify_ext_to_int_packet(eth_packet, src_mac, dest_mac, dest_ip)
+    }
+
+    #[cfg(test)]
+    pub fn modify_int_to_ext_packet_test(
+        eth_packet: &mut MutableEthernetPacket,
+        ext_iface_mac: &MacAddr,
+        ext_iface_ip: &IpNetwork,
+    ) -> bool {
+        modify_int_to_ext_packet(eth_packet, ext_iface_mac, ext_iface_ip)
+    }
+
+    #[cfg(test)]
+    pub fn calculate_ipv4_checksum_test(packet: &[u8]) -> Option<u16> {
+        calculate_ipv4_checksum(packet).ok()
+    }
+
+    #[cfg(test)]
+    pub fn is_tcp_state_valid_test(tcp_packet: &TcpPacket<'_>) -> bool {
+        is_tcp_state_valid(tcp_packet)
     }
 
     #[cfg(test)]
@@ -792,19 +1005,20 @@ pub mod forward {
         src_ip: &Ipv4Addr,
         dest_ip: &Ipv4Addr,
     ) -> bool {
-        ipv4_packet.is_checksum_correct(src_ip, &dest_ip)
+        ipv4_packet.is_checksum_correct(src_ip, dest_ip)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::forward;
+    use crate::forward_impl::forward;
     use pnet::datalink::NetworkInterface;
     use pnet::ipnetwork::IpNetwork;
     use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
     use pnet::packet::ip::IpNextHeaderProtocols;
     use pnet::packet::ipv4::MutableIpv4Packet;
     use pnet::packet::ipv6::MutableIpv6Packet;
+    use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
     use pnet::packet::udp::MutableUdpPacket;
     use pnet::packet::{MutablePacket, Packet};
     use std::net::Ipv4Addr; // Import the `forward` module
@@ -1053,4 +1267,192 @@ mod tests {
             &Ipv4Addr::new(0, 0, 0, 0)
         ));
     }
+
+    fn tcp_segment(flags: u8) -> [u8; 20] {
+        let mut buffer = [0u8; 20];
+        let mut tcp_packet = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp_packet.set_source(8009);
+        tcp_packet.set_destination(40000);
+        tcp_packet.set_data_offset(5);
+        tcp_packet.set_flags(flags);
+        tcp_packet.set_window(64240);
+        buffer
+    }
+
+    #[test]
+    fn test_tcp_checksum() {
+        let src_ip = Ipv4Addr::new(192, 168, 1, 50);
+        let dest_ip = Ipv4Addr::new(192, 168, 1, 3);
+        let mut buffer = tcp_segment(TcpFlags::ACK);
+        let mut tcp_packet = MutableTcpPacket::new(&mut buffer).unwrap();
+        let checksum = tcp::ipv4_checksum(&tcp_packet.to_immutable(), &src_ip, &dest_ip);
+
+        tcp_packet.set_checksum(checksum ^ 1);
+        assert!(!forward::is_checksum_correct_tcp_test(
+            &mut tcp_packet,
+            &src_ip,
+            &dest_ip
+        ));
+        tcp_packet.set_checksum(checksum);
+        assert!(forward::is_checksum_correct_tcp_test(
+            &mut tcp_packet,
+            &src_ip,
+            &dest_ip
+        ));
+        assert_eq!(tcp_packet.get_checksum(), checksum);
+    }
+
+    #[test]
+    fn test_tcp_state_checks() {
+        let valid =
+            |flags| forward::is_tcp_state_valid_test(&TcpPacket::new(&tcp_segment(flags)).unwrap());
+
+        // Answers on a connection opened from the internal network
+        assert!(valid(TcpFlags::SYN | TcpFlags::ACK));
+        assert!(valid(TcpFlags::ACK));
+        assert!(valid(TcpFlags::PSH | TcpFlags::ACK));
+        assert!(valid(TcpFlags::FIN | TcpFlags::ACK));
+        assert!(valid(TcpFlags::RST));
+
+        // Connection attempts from outside, and scans
+        assert!(!valid(TcpFlags::SYN));
+        assert!(!valid(TcpFlags::SYN | TcpFlags::FIN | TcpFlags::ACK));
+        assert!(!valid(TcpFlags::SYN | TcpFlags::RST | TcpFlags::ACK));
+        assert!(!valid(0));
+        assert!(!valid(TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG));
+
+        // Data offset beyond the segment
+        let mut buffer = tcp_segment(TcpFlags::ACK);
+        MutableTcpPacket::new(&mut buffer)
+            .unwrap()
+            .set_data_offset(6);
+        assert!(!forward::is_tcp_state_valid_test(
+            &TcpPacket::new(&buffer).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_tcp_header_refused() {
+        // IPv4 packet carrying only 12 bytes of a TCP header, padded to the minimum frame
+        let mut frame = vec![0u8; 64];
+        let mut eth_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth_packet.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4_packet = MutableIpv4Packet::new(eth_packet.payload_mut()).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(5);
+        ipv4_packet.set_total_length(32);
+        ipv4_packet.set_ttl(64);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ipv4_packet.set_source(Ipv4Addr::new(192, 168, 1, 50));
+        ipv4_packet.set_destination(Ipv4Addr::new(192, 168, 1, 3));
+        let checksum = pnet::packet::ipv4::checksum(&ipv4_packet.to_immutable());
+        ipv4_packet.set_checksum(checksum);
+
+        assert!(
+            !forward::ext_to_int_is_packet_safe_test(
+                &mut MutableEthernetPacket::new(&mut frame).unwrap()
+            )
+            .await
+        );
+    }
+
+    /// UDP frame of 14 + 20 + 8 + 6 bytes from 192.168.1.50 to 192.168.1.3
+    fn udp_frame() -> Vec<u8> {
+        let mut frame = vec![0u8; 48];
+        let mut eth_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth_packet.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4_packet = MutableIpv4Packet::new(eth_packet.payload_mut()).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(5);
+        ipv4_packet.set_total_length(34);
+        ipv4_packet.set_ttl(64);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4_packet.set_source(Ipv4Addr::new(192, 168, 1, 50));
+        ipv4_packet.set_destination(Ipv4Addr::new(192, 168, 1, 3));
+        let mut udp_packet = MutableUdpPacket::new(ipv4_packet.payload_mut()).unwrap();
+        udp_packet.set_source(8009);
+        udp_packet.set_destination(40000);
+        udp_packet.set_length(14);
+        udp_packet.set_payload(b"Hello!");
+        frame
+    }
+
+    fn modify_both_ways(frame: &mut [u8]) -> (bool, bool) {
+        let mac = [2, 0, 0, 0, 0, 1].into();
+        let ip: IpNetwork = "192.168.100.2/24".parse().unwrap();
+        let mut copy = frame.to_vec();
+        let ext_to_int = forward::modify_ext_to_int_packet_test(
+            &mut MutableEthernetPacket::new(frame).unwrap(),
+            mac,
+            mac,
+            ip,
+        );
+        let int_to_ext = forward::modify_int_to_ext_packet_test(
+            &mut MutableEthernetPacket::new(&mut copy).unwrap(),
+            &mac,
+            &ip,
+        );
+        (ext_to_int, int_to_ext)
+    }
+
+    #[test]
+    fn test_truncated_frames_refused() {
+        let frame = udp_frame();
+        assert_eq!(modify_both_ways(&mut frame.clone()), (true, true));
+
+        // Cut anywhere in the IPv4 packet
+        for len in 14..frame.len() {
+            assert_eq!(
+                modify_both_ways(&mut frame[..len].to_vec()),
+                (false, false),
+                "frame cut to {len} bytes"
+            );
+        }
+
+        // Ethernet padding after the packet is fine
+        let mut padded = frame.clone();
+        padded.resize(64, 0);
+        assert_eq!(modify_both_ways(&mut padded), (true, true));
+    }
+
+    #[test]
+    fn test_bogus_ipv4_lengths_refused() {
+        let set = |update: &dyn Fn(&mut MutableIpv4Packet)| {
+            let mut frame = udp_frame();
+            update(&mut MutableIpv4Packet::new(&mut frame[14..]).unwrap());
+            modify_both_ways(&mut frame)
+        };
+        // Header shorter than its fixed part, or longer than the packet
+        assert_eq!(set(&|ipv4| ipv4.set_header_length(4)), (false, false));
+        assert_eq!(set(&|ipv4| ipv4.set_header_length(15)), (false, false));
+        assert_eq!(set(&|ipv4| ipv4.set_total_length(19)), (false, false));
+        // Total length past the end of the frame
+        assert_eq!(set(&|ipv4| ipv4.set_total_length(35)), (false, false));
+    }
+
+    #[test]
+    fn test_ipv4_checksum_with_options() {
+        let mut buffer = [0u8; 32];
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(6);
+        ipv4_packet.set_total_length(32);
+        ipv4_packet.set_ttl(1);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+        ipv4_packet.set_source(Ipv4Addr::new(192, 168, 1, 50));
+        ipv4_packet.set_destination(Ipv4Addr::new(224, 0, 0, 22));
+        // Router alert
+        buffer[20..24].copy_from_slice(&[0x94, 0x04, 0x00, 0x00]);
+
+        let expected = pnet::packet::ipv4::checksum(
+            &MutableIpv4Packet::new(&mut buffer).unwrap().to_immutable(),
+        );
+        assert_eq!(
+            forward::calculate_ipv4_checksum_test(&buffer),
+            Some(expected)
+        );
+        // The header length points past the packet
+        assert_eq!(forward::calculate_ipv4_checksum_test(&buffer[..22]), None);
+        assert_eq!(forward::calculate_ipv4_checksum_test(&[0x44; 20]), None);
+    }
 }
@@ -0,0 +1,593 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # DHCP relay
+//!
+//! Lets internal VMs lease addresses from the DHCP server of the external network. Client
+//! messages from the internal interface are broadcast on the external interface with the
+//! broadcast flag set, so the server's replies reach the forwarder even though the
+//! client's hardware address is not on the external link. Replies to a transaction a
+//! client started are sent back to that client on the internal interface.
+//!
+//! Acknowledged leases are kept in a table, so the filters can tell which VM holds an
+//! address leased through the relay.
+use crate::forward_impl::forward::Ifaces;
+use log::{debug, error, info, warn};
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::dhcp::{DhcpOperations, DhcpPacket, MutableDhcpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const BROADCAST_FLAG: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_END: u8 = 255;
+
+/// Time a server has to answer a client message
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Transactions in flight at the same time
+const MAX_TRANSACTIONS: usize = 64;
+/// Lease time assumed when the server does not send one
+const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(3600);
+
+/// DHCP message types, option 53
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+}
+
+impl MessageType {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// An address leased by the external DHCP server to an internal client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lease {
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    pub expires: Instant,
+}
+
+/// A client message waiting for its reply
+#[derive(Debug, Clone, Copy)]
+struct Transaction {
+    client: MacAddr,
+    broadcast: bool,
+    expires: Instant,
+}
+
+pub struct DhcpRelay {
+    enabled: bool,
+    transactions: Mutex<HashMap<u32, Transaction>>,
+    leases: Mutex<HashMap<MacAddr, Lease>>,
+}
+
+impl DhcpRelay {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            transactions: Mutex::new(HashMap::new()),
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether `eth_packet` is a DHCP client message to relay.
+    pub fn is_client_message(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        self.enabled && ports(eth_packet) == Some((CLIENT_PORT, SERVER_PORT))
+    }
+
+    /// Returns whether `eth_packet` is a DHCP server message that may answer a relayed one.
+    pub fn is_server_message(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        self.enabled && ports(eth_packet) == Some((SERVER_PORT, CLIENT_PORT))
+    }
+
+    /// Relays a client message from the internal network to the external DHCP server.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the message was sent to the external network.
+    pub async fn relay_to_server(
+        &self,
+        tx: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> bool {
+        self.rewrite_request(eth_packet, ifaces).await && send(tx, eth_packet).await
+    }
+
+    /// Relays a reply of the external DHCP server to the internal client that asked for it.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the message was sent to the internal network.
+    pub async fn relay_to_client(
+        &self,
+        tx: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> bool {
+        self.rewrite_reply(eth_packet, ifaces).await && send(tx, eth_packet).await
+    }
+
+    /// Returns the client holding a lease on `ip`, if it was leased through the relay.
+    pub async fn lease_holder(&self, ip: Ipv4Addr) -> Option<MacAddr> {
+        let now = Instant::now();
+        self.leases
+            .lock()
+            .await
+            .values()
+            .find(|lease| lease.ip == ip && lease.expires > now)
+            .map(|lease| lease.mac)
+    }
+
+    #[cfg(test)]
+    pub async fn insert_lease(&self, lease: Lease) {
+        self.leases.lock().await.insert(lease.mac, lease);
+    }
+
+    /// Returns whether `eth_packet` uses, or claims in ARP, an address leased through the
+    /// relay to another client than the one sending it.
+    pub async fn is_spoofed(&self, eth_packet: &EthernetPacket<'_>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let src_ip = match eth_packet.get_ethertype() {
+            EtherTypes::Ipv4 => Ipv4Packet::new(eth_packet.payload()).map(|p| p.get_source()),
+            EtherTypes::Arp => {
+                ArpPacket::new(eth_packet.payload()).map(|p| p.get_sender_proto_addr())
+            }
+            _ => None,
+        };
+        let Some(src_ip) = src_ip else {
+            return false;
+        };
+        match self.lease_holder(src_ip).await {
+            Some(holder) if holder != eth_packet.get_source() => {
+                warn!(
+                    "DHCP relay - {src_ip} is leased to {holder}, not to {}",
+                    eth_packet.get_source()
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Broadcasts a client message from the external interface, asking for broadcast replies.
+    async fn rewrite_request(
+        &self,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> bool {
+        let Some((xid, client, broadcast, message)) = parse(&eth_packet.to_immutable(), true)
+        else {
+            return false;
+        };
+        if client != eth_packet.get_source() {
+            warn!(
+                "DHCP relay - {message:?} for {client} sent by {}, dropped",
+                eth_packet.get_source()
+            );
+            return false;
+        }
+
+        let now = Instant::now();
+        if matches!(message, MessageType::Release | MessageType::Decline) {
+            if let Some(lease) = self.leases.lock().await.remove(&client) {
+                info!("DHCP relay - {client} gave up {}", lease.ip);
+            }
+        } else {
+            let mut transactions = self.transactions.lock().await;
+            if transactions.len() >= MAX_TRANSACTIONS {
+                transactions.retain(|_, t| t.expires > now);
+            }
+            if transactions.len() >= MAX_TRANSACTIONS {
+                warn!(
+                    "DHCP relay - too many transactions in flight, {message:?} from {client} dropped"
+                );
+                return false;
+            }
+            transactions.insert(
+                xid,
+                Transaction {
+                    client,
+                    broadcast,
+                    expires: now + TRANSACTION_TIMEOUT,
+                },
+            );
+        }
+
+        eth_packet.set_source(ifaces.ext_mac);
+        eth_packet.set_destination(MacAddr::broadcast());
+        debug!("DHCP relay - {message:?} from {client}, xid {xid:#x}");
+        rewrite(eth_packet, Ipv4Addr::BROADCAST, |dhcp| {
+            dhcp.set_flags(dhcp.get_flags() | BROADCAST_FLAG);
+        })
+    }
+
+    /// Addresses a server reply to the client of its transaction, recording acknowledged leases.
+    async fn rewrite_reply(
+        &self,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> bool {
+        let Some((xid, client, _, message)) = parse(&eth_packet.to_immutable(), false) else {
+            return false;
+        };
+        let now = Instant::now();
+        let transaction = {
+            let mut transactions = self.transactions.lock().await;
+            let Some(transaction) = transactions
+                .get(&xid)
+                .copied()
+                .filter(|t| t.client == client && t.expires > now)
+            else {
+                debug!("DHCP relay - {message:?} for unknown transaction {xid:#x}, dropped");
+                return false;
+            };
+            if matches!(message, MessageType::Ack | MessageType::Nak) {
+                transactions.remove(&xid);
+            }
+            transaction
+        };
+
+        let Some(dhcp) = dhcp_payload(&eth_packet.to_immutable()) else {
+            return false;
+        };
+        let yiaddr = dhcp.get_yiaddr();
+        match message {
+            MessageType::Ack if !yiaddr.is_unspecified() => {
+                let lease_time = option(dhcp.payload(), OPTION_LEASE_TIME)
+                    .and_then(|v| v.try_into().ok())
+                    .map_or(DEFAULT_LEASE_TIME, |v| {
+                        Duration::from_secs(u32::from_be_bytes(v).into())
+                    });
+                info!(
+                    "DHCP relay - {yiaddr} leased to {client} for {}s",
+                    lease_time.as_secs()
+                );
+                self.leases.lock().await.insert(
+                    client,
+                    Lease {
+                        mac: client,
+                        ip: yiaddr,
+                        expires: now + lease_time,
+                    },
+                );
+            }
+            MessageType::Nak => {
+                if let Some(lease) = self.leases.lock().await.remove(&client) {
+                    info!("DHCP relay - lease of {} to {client} refused", lease.ip);
+                }
+            }
+            _ => {}
+        }
+
+        // Clients without an address yet only hear broadcasts, unless they said otherwise
+        let (dest_mac, dest_ip) = if transaction.broadcast || yiaddr.is_unspecified() {
+            (MacAddr::broadcast(), Ipv4Addr::BROADCAST)
+        } else {
+            (client, yiaddr)
+        };
+        eth_packet.set_source(ifaces.int_mac);
+        eth_packet.set_destination(dest_mac);
+        debug!("DHCP relay - {message:?} for {client}, xid {xid:#x}");
+        rewrite(eth_packet, dest_ip, |dhcp| {
+            // Restore the client's own choice
+            if !transaction.broadcast {
+                dhcp.set_flags(dhcp.get_flags() & !BROADCAST_FLAG);
+            }
+        })
+    }
+}
+
+/// UDP source and destination ports of an IPv4 packet
+fn ports(eth_packet: &EthernetPacket<'_>) -> Option<(u16, u16)> {
+    if eth_packet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp_packet = UdpPacket::new(ipv4_packet.payload())?;
+    Some((udp_packet.get_source(), udp_packet.get_destination()))
+}
+
+/// The DHCP message carried by `eth_packet`
+fn dhcp_payload(eth_packet: &EthernetPacket<'_>) -> Option<DhcpPacket<'static>> {
+    let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+    let udp_packet = UdpPacket::new(ipv4_packet.payload())?;
+    // Copy out of the nested views, the options are small
+    DhcpPacket::owned(udp_packet.payload().to_vec())
+}
+
+/// Transaction id, client hardware address, broadcast flag and type of a DHCP message,
+/// if it is a client (`request`) or server message for an Ethernet client.
+fn parse(
+    eth_packet: &EthernetPacket<'_>,
+    request: bool,
+) -> Option<(u32, MacAddr, bool, MessageType)> {
+    let dhcp = dhcp_payload(eth_packet)?;
+    let op = if request {
+        DhcpOperations::Request
+    } else {
+        DhcpOperations::Reply
+    };
+    if dhcp.get_op() != op || dhcp.get_hlen() != 6 {
+        return None;
+    }
+    let message = option(dhcp.payload(), OPTION_MESSAGE_TYPE)
+        .and_then(|v| v.first().copied())
+        .and_then(MessageType::from_code)?;
+    let is_request = matches!(
+        message,
+        MessageType::Discover
+            | MessageType::Request
+            | MessageType::Decline
+            | MessageType::Release
+            | MessageType::Inform
+    );
+    if is_request != request {
+        return None;
+    }
+    Some((
+        dhcp.get_xid(),
+        dhcp.get_chaddr(),
+        dhcp.get_flags() & BROADCAST_FLAG != 0,
+        message,
+    ))
+}
+
+/// Value of the DHCP option `code`, if present in `options`.
+fn option(options: &[u8], code: u8) -> Option<&[u8]> {
+    let mut rest = options.strip_prefix(&MAGIC_COOKIE)?;
+    while let [tag, tail @ ..] = rest {
+        match *tag {
+            OPTION_PAD => rest = tail,
+            OPTION_END => break,
+            tag => {
+                let (&len, tail) = tail.split_first()?;
+                let (value, tail) = tail.split_at_checked(len.into())?;
+                if tag == code {
+                    return Some(value);
+                }
+                rest = tail;
+            }
+        }
+    }
+    None
+}
+
+/// Sets the IPv4 destination of a DHCP message, applies `update` to it and recalculates
+/// the checksums.
+fn rewrite(
+    eth_packet: &mut MutableEthernetPacket<'_>,
+    dest_ip: Ipv4Addr,
+    update: impl FnOnce(&mut MutableDhcpPacket),
+) -> bool {
+    let Some(mut ipv4_packet) = MutableIpv4Packet::new(eth_packet.payload_mut()) else {
+        return false;
+    };
+    ipv4_packet.set_destination(dest_ip);
+    let (src_ip, dest_ip) = (ipv4_packet.get_source(), ipv4_packet.get_destination());
+    {
+        let Some(mut udp_packet) = MutableUdpPacket::new(ipv4_packet.payload_mut()) else {
+            return false;
+        };
+        let Some(mut dhcp) = MutableDhcpPacket::new(udp_packet.payload_mut()) else {
+            return false;
+        };
+        update(&mut dhcp);
+        udp_packet.set_checksum(0);
+        let checksum = udp::ipv4_checksum(&udp_packet.to_immutable(), &src_ip, &dest_ip);
+        udp_packet.set_checksum(checksum);
+    }
+    ipv4_packet.set_checksum(0);
+    let checksum = ipv4::checksum(&ipv4_packet.to_immutable());
+    ipv4_packet.set_checksum(checksum);
+    true
+}
+
+async fn send(
+    tx: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+    eth_packet: &MutableEthernetPacket<'_>,
+) -> bool {
+    match tx.lock().await.send_to(eth_packet.packet(), None) {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            error!("DHCP relay - Error sending packet: {e}");
+            false
+        }
+        None => {
+            error!("DHCP relay - Send failed, no destination address.");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::ipnetwork::IpNetwork;
+    use pnet::packet::arp::MutableArpPacket;
+
+    const CLIENT: MacAddr = MacAddr(2, 0, 0, 0, 0, 7);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const LEASED: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 77);
+
+    fn ifaces() -> Ifaces {
+        Ifaces {
+            ext_ip: IpNetwork::new(Ipv4Addr::new(192, 168, 1, 10).into(), 24).unwrap(),
+            ext_mac: MacAddr(2, 0, 0, 0, 0, 1),
+            int_ip: IpNetwork::new(Ipv4Addr::new(192, 168, 100, 1).into(), 24).unwrap(),
+            int_mac: MacAddr(2, 0, 0, 0, 0, 2),
+        }
+    }
+
+    /// A DHCP message of `message_type` in an Ethernet frame
+    fn dhcp_frame(request: bool, message_type: u8, yiaddr: Ipv4Addr) -> Vec<u8> {
+        let options = [
+            &MAGIC_COOKIE[..],
+            &[OPTION_MESSAGE_TYPE, 1, message_type],
+            &[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10],
+            &[OPTION_END],
+        ]
+        .concat();
+        let dhcp_len = 236 + options.len();
+        let mut frame = vec![0u8; 14 + 20 + 8 + dhcp_len];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        eth.set_source(if request {
+            CLIENT
+        } else {
+            MacAddr(2, 0, 0, 0, 0, 9)
+        });
+        eth.set_destination(MacAddr::broadcast());
+        let mut ip = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + 8 + dhcp_len) as u16);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(if request {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            SERVER
+        });
+        ip.set_destination(Ipv4Addr::BROADCAST);
+        let mut udp = MutableUdpPacket::new(ip.payload_mut()).unwrap();
+        let (src, dest) = if request {
+            (CLIENT_PORT, SERVER_PORT)
+        } else {
+            (SERVER_PORT, CLIENT_PORT)
+        };
+        udp.set_source(src);
+        udp.set_destination(dest);
+        udp.set_length((8 + dhcp_len) as u16);
+        let mut dhcp = MutableDhcpPacket::new(udp.payload_mut()).unwrap();
+        dhcp.set_op(if request {
+            DhcpOperations::Request
+        } else {
+            DhcpOperations::Reply
+        });
+        dhcp.set_hlen(6);
+        dhcp.set_xid(0x1234);
+        dhcp.set_yiaddr(yiaddr);
+        dhcp.set_chaddr(CLIENT);
+        dhcp.set_options(&options);
+        frame
+    }
+
+    fn arp_claim(mac: MacAddr, ip: Ipv4Addr) -> EthernetPacket<'static> {
+        let mut frame = vec![0u8; 42];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Arp);
+        eth.set_source(mac);
+        let mut arp = MutableArpPacket::new(eth.payload_mut()).unwrap();
+        arp.set_sender_hw_addr(mac);
+        arp.set_sender_proto_addr(ip);
+        EthernetPacket::owned(frame).unwrap()
+    }
+
+    fn flags(frame: &[u8]) -> u16 {
+        dhcp_payload(&EthernetPacket::new(frame).unwrap())
+            .unwrap()
+            .get_flags()
+    }
+
+    #[test]
+    fn test_option_parsing() {
+        let options = [&MAGIC_COOKIE[..], &[OPTION_PAD, 53, 1, 5, 51, 4, 1]].concat();
+        assert_eq!(option(&options, OPTION_MESSAGE_TYPE), Some(&[5][..]));
+        // Truncated value
+        assert_eq!(option(&options, OPTION_LEASE_TIME), None);
+        assert_eq!(option(&[1, 2, 3, 4], OPTION_MESSAGE_TYPE), None);
+    }
+
+    #[tokio::test]
+    async fn test_lease_through_relay() {
+        let relay = DhcpRelay::new(true);
+        let ifaces = ifaces();
+
+        let mut discover = dhcp_frame(true, 1, Ipv4Addr::UNSPECIFIED);
+        assert!(relay.is_client_message(&EthernetPacket::new(&discover).unwrap()));
+        let mut eth = MutableEthernetPacket::new(&mut discover).unwrap();
+        assert!(relay.rewrite_request(&mut eth, &ifaces).await);
+        assert_eq!(eth.get_source(), ifaces.ext_mac);
+        assert_ne!(flags(&discover) & BROADCAST_FLAG, 0);
+
+        let mut ack = dhcp_frame(false, 5, LEASED);
+        assert!(relay.is_server_message(&EthernetPacket::new(&ack).unwrap()));
+        let mut eth = MutableEthernetPacket::new(&mut ack).unwrap();
+        assert!(relay.rewrite_reply(&mut eth, &ifaces).await);
+        assert_eq!(eth.get_source(), ifaces.int_mac);
+        assert_eq!(eth.get_destination(), CLIENT);
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_destination(), LEASED);
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        assert_eq!(flags(&ack) & BROADCAST_FLAG, 0);
+
+        assert_eq!(relay.lease_holder(LEASED).await, Some(CLIENT));
+        // Only the holder may claim the address in ARP
+        assert!(!relay.is_spoofed(&arp_claim(CLIENT, LEASED)).await);
+        assert!(
+            relay
+                .is_spoofed(&arp_claim(MacAddr(2, 0, 0, 0, 0, 8), LEASED))
+                .await
+        );
+
+        // The transaction is complete
+        let mut ack = dhcp_frame(false, 5, LEASED);
+        let mut eth = MutableEthernetPacket::new(&mut ack).unwrap();
+        assert!(!relay.rewrite_reply(&mut eth, &ifaces).await);
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_reply_dropped() {
+        let relay = DhcpRelay::new(true);
+        let mut offer = dhcp_frame(false, 2, LEASED);
+        let mut eth = MutableEthernetPacket::new(&mut offer).unwrap();
+        assert!(!relay.rewrite_reply(&mut eth, &ifaces()).await);
+        assert_eq!(relay.lease_holder(LEASED).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let relay = DhcpRelay::new(false);
+        let discover = dhcp_frame(true, 1, Ipv4Addr::UNSPECIFIED);
+        assert!(!relay.is_client_message(&EthernetPacket::new(&discover).unwrap()));
+    }
+}
@@ -0,0 +1,780 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Port translation
+//!
+//! Lets the internal VMs share the external address for traffic of their own, beyond the
+//! discovery and casting flows the filters forward. Each TCP or UDP flow an internal
+//! client opens to a unicast address outside gets an external port from a configured
+//! range, so clients picking the same source port towards the same remote end up on
+//! different external ports. Replies from the flow's remote to that port are translated
+//! back and sent to the client.
+//!
+//! Flows forwarded by the filters keep their ports. The range must thus lie outside the
+//! ephemeral ports of the internal VMs and of the host (32768-60999 on Linux). The host's
+//! own stack must not answer on it either, or it would reset the translated connections:
+//! an nftables table dropping the range on input is installed at startup, and the
+//! forwarder refuses to start without it. Packet capture sees the packets before the
+//! input hook, so they are still translated.
+//!
+//! A mapping expires after a period without packets, short for UDP, long for TCP until
+//! either side closes or resets the connection.
+use crate::filter::conntrack::Endpoints;
+use crate::forward_impl::forward::{self, Ifaces};
+use log::{debug, info, trace};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::tcp::{MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::udp::MutableUdpPacket;
+use pnet::packet::{MutablePacket, Packet};
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Time a TCP connection stays mapped once either side closed or reset it
+const CLOSING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// nftables table keeping the host's own stack off the translated ports
+const NFT_TABLE: &str = "nw_pckt_fwd_napt";
+
+/// Port of a segment to translate
+#[derive(Debug, Clone, Copy)]
+enum Port {
+    Source,
+    Destination,
+}
+
+/// A flow opened by an internal client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Flow {
+    proto: IpNextHeaderProtocol,
+    client: Ipv4Addr,
+    client_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+}
+
+/// External port of a flow
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    flow: Flow,
+    client_mac: MacAddr,
+    expires: Instant,
+    closing: bool,
+}
+
+/// Mappings by flow and by external port, the port being unique per protocol
+#[derive(Default)]
+struct Table {
+    ports: HashMap<Flow, u16>,
+    mappings: HashMap<(IpNextHeaderProtocol, u16), Mapping>,
+}
+
+impl Table {
+    fn remove(&mut self, proto: IpNextHeaderProtocol, port: u16) {
+        if let Some(mapping) = self.mappings.remove(&(proto, port)) {
+            self.ports.remove(&mapping.flow);
+            trace!("NAPT - released port {port} of {:?}", mapping.flow);
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|(_, m)| m.expires <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (proto, port) in expired {
+            self.remove(proto, port);
+        }
+    }
+}
+
+/// Transport endpoints of an IPv4 packet, with its TCP flags
+struct Segment {
+    proto: IpNextHeaderProtocol,
+    src: Ipv4Addr,
+    src_port: u16,
+    dest: Ipv4Addr,
+    dest_port: u16,
+    /// TCP flags, 0 for UDP
+    flags: u8,
+}
+
+impl Segment {
+    fn parse(eth_packet: &EthernetPacket<'_>) -> Option<Self> {
+        let Endpoints {
+            proto,
+            src,
+            src_port,
+            dest,
+            dest_port,
+        } = Endpoints::parse(eth_packet)?;
+        let flags = if proto == IpNextHeaderProtocols::Tcp {
+            let ipv4_packet = Ipv4Packet::new(eth_packet.payload())?;
+            TcpPacket::new(ipv4_packet.payload())?.get_flags()
+        } else {
+            0
+        };
+        Some(Self {
+            proto,
+            src,
+            src_port,
+            dest,
+            dest_port,
+            flags,
+        })
+    }
+
+    fn closes(&self) -> bool {
+        self.flags & (TcpFlags::FIN | TcpFlags::RST) != 0
+    }
+
+    fn opens(&self) -> bool {
+        self.flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN
+    }
+}
+
+pub struct Napt {
+    ports: Option<RangeInclusive<u16>>,
+    udp_timeout: Duration,
+    tcp_timeout: Duration,
+    max_flows: usize,
+    table: Mutex<Table>,
+}
+
+impl Napt {
+    /// Creates a translation table allocating external ports from `ports`, disabled
+    /// without, and holding up to `max_flows` flows.
+    pub fn new(
+        ports: Option<RangeInclusive<u16>>,
+        udp_timeout: Duration,
+        tcp_timeout: Duration,
+        max_flows: usize,
+    ) -> Self {
+        Self {
+            ports: ports.filter(|p| !p.is_empty()),
+            udp_timeout,
+            tcp_timeout,
+            max_flows: max_flows.max(1),
+            table: Mutex::new(Table::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ports.is_some()
+    }
+
+    /// Installs the nftables table dropping the translated ports on input to the host,
+    /// replacing the one a previous run may have left behind.
+    pub fn shield_host_ports(&self) -> io::Result<()> {
+        let Some(ports) = &self.ports else {
+            return Ok(());
+        };
+        nft(&shield_ruleset(ports))?;
+        info!(
+            "NAPT - ports {}-{} closed to the host's own stack",
+            ports.start(),
+            ports.end()
+        );
+        Ok(())
+    }
+
+    /// Removes the table installed by [`Napt::shield_host_ports`].
+    pub fn unshield_host_ports(&self) -> io::Result<()> {
+        if self.ports.is_none() {
+            return Ok(());
+        }
+        nft(&format!("delete table inet {NFT_TABLE}\n"))
+    }
+
+    /// Translates a packet an internal client sends to a unicast address outside and
+    /// sends it to the external network.
+    ///
+    /// # Returns
+    /// A `bool` indicating whether the packet was sent to the external network.
+    pub async fn translate_outbound(
+        &self,
+        tx: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> bool {
+        let Some(segment) = Segment::parse(&eth_packet.to_immutable()) else {
+            return false;
+        };
+        if !ifaces.int_ip.contains(segment.src.into())
+            || ifaces.int_ip.contains(segment.dest.into())
+            || segment.dest.is_multicast()
+            || segment.dest.is_broadcast()
+        {
+            return false;
+        }
+        let flow = Flow {
+            proto: segment.proto,
+            client: segment.src,
+            client_port: segment.src_port,
+            remote: segment.dest,
+            remote_port: segment.dest_port,
+        };
+        let Some(port) = self
+            .map(flow, eth_packet.get_source(), &segment, Instant::now())
+            .await
+        else {
+            debug!("NAPT - no external port left for {flow:?}");
+            return false;
+        };
+        rewrite_port(eth_packet, segment.proto, Port::Source, port)
+            && forward::internal_to_external_process_packet(tx, eth_packet, ifaces).await
+    }
+
+    /// Checks whether a packet from the external network is addressed to a translated
+    /// port, and thus answers a translated flow or none at all.
+    pub fn is_inbound(&self, eth_packet: &EthernetPacket<'_>, ifaces: &Ifaces) -> bool {
+        let Some(ports) = &self.ports else {
+            return false;
+        };
+        Segment::parse(eth_packet).is_some_and(|segment| {
+            ifaces.ext_ip.ip() == IpAddr::from(segment.dest) && ports.contains(&segment.dest_port)
+        })
+    }
+
+    /// Translates a packet from the external network back to the internal client whose
+    /// flow it answers. The safety checks are up to the caller, on the packet as received.
+    ///
+    /// # Returns
+    /// The MAC and address of the client, `None` when the packet answers no translated
+    /// flow and was left untouched.
+    pub async fn translate_inbound(
+        &self,
+        eth_packet: &mut MutableEthernetPacket<'_>,
+        ifaces: &Ifaces,
+    ) -> Option<(MacAddr, IpNetwork)> {
+        if !self.is_inbound(&eth_packet.to_immutable(), ifaces) {
+            return None;
+        }
+        let segment = Segment::parse(&eth_packet.to_immutable())?;
+        let mapping = self.lookup(&segment, Instant::now()).await?;
+        if !rewrite_port(
+            eth_packet,
+            segment.proto,
+            Port::Destination,
+            mapping.flow.client_port,
+        ) {
+            return None;
+        }
+        let client = IpNetwork::new(mapping.flow.client.into(), ifaces.int_ip.prefix()).ok()?;
+        Some((mapping.client_mac, client))
+    }
+
+    /// Returns the external port of `flow`, allocating one for a new flow.
+    async fn map(
+        &self,
+        flow: Flow,
+        client_mac: MacAddr,
+        segment: &Segment,
+        now: Instant,
+    ) -> Option<u16> {
+        let ports = self.ports.as_ref()?;
+        let mut table = self.table.lock().await;
+        let port = match table.ports.get(&flow) {
+            Some(&port) => port,
+            None => {
+                if table.ports.len() >= self.max_flows {
+                    table.remove_expired(now);
+                }
+                if table.ports.len() >= self.max_flows
+                    && let Some((proto, port)) = table
+                        .mappings
+                        .iter()
+                        .min_by_key(|(_, m)| m.expires)
+                        .map(|(key, _)| *key)
+                {
+                    debug!("NAPT table full, evicting port {port}");
+                    table.remove(proto, port);
+                }
+                let port = allocate(&mut table, ports, &flow, now)?;
+                table.ports.insert(flow, port);
+                debug!("NAPT - {flow:?} mapped to port {port}");
+                port
+            }
+        };
+        let mapping = table.mappings.entry((flow.proto, port)).or_insert(Mapping {
+            flow,
+            client_mac,
+            expires: now,
+            closing: false,
+        });
+        mapping.client_mac = client_mac;
+        self.refresh(mapping, segment, now);
+        Some(port)
+    }
+
+    /// Returns the mapping `segment` answers, which must come from the flow's remote.
+    async fn lookup(&self, segment: &Segment, now: Instant) -> Option<Mapping> {
+        let mut table = self.table.lock().await;
+        let mapping = table
+            .mappings
+            .get_mut(&(segment.proto, segment.dest_port))
+            .filter(|m| m.expires > now)
+            .filter(|m| (m.flow.remote, m.flow.remote_port) == (segment.src, segment.src_port));
+        let Some(mapping) = mapping else {
+            debug!(
+                "NAPT - no flow for {}:{} -> port {}",
+                segment.src, segment.src_port, segment.dest_port
+            );
+            return None;
+        };
+        self.refresh(mapping, segment, now);
+        Some(*mapping)
+    }
+
+    fn refresh(&self, mapping: &mut Mapping, segment: &Segment, now: Instant) {
+        if segment.proto != IpNextHeaderProtocols::Tcp {
+            mapping.expires = now + self.udp_timeout;
+            return;
+        }
+        // A new connection may reuse the ports of a closed one
+        mapping.closing = (mapping.closing || segment.closes()) && !segment.opens();
+        mapping.expires = now
+            + if mapping.closing {
+                CLOSING_TIMEOUT
+            } else {
+                self.tcp_timeout
+            };
+    }
+}
+
+/// Picks a free external port for `flow`, the client's own port when in range, otherwise
+/// one derived from the flow, and the next free one on collisions.
+fn allocate(
+    table: &mut Table,
+    ports: &RangeInclusive<u16>,
+    flow: &Flow,
+    now: Instant,
+) -> Option<u16> {
+    let first = u32::from(*ports.start());
+    let count = u32::from(*ports.end()) - first + 1;
+    let preferred = if ports.contains(&flow.client_port) {
+        u32::from(flow.client_port) - first
+    } else {
+        let mut hasher = DefaultHasher::new();
+        flow.hash(&mut hasher);
+        (hasher.finish() % u64::from(count)) as u32
+    };
+    for offset in 0..count {
+        let port = (first + (preferred + offset) % count) as u16;
+        match table.mappings.get(&(flow.proto, port)) {
+            None => return Some(port),
+            Some(m) if m.expires <= now => {
+                table.remove(flow.proto, port);
+                return Some(port);
+            }
+            Some(_) => {}
+        }
+    }
+    None
+}
+
+/// Replaces the `which` port of the segment, adjusting its checksum rather than
+/// recomputing it, so that a corrupted packet still fails the safety checks.
+fn rewrite_port(
+    eth_packet: &mut MutableEthernetPacket<'_>,
+    proto: IpNextHeaderProtocol,
+    which: Port,
+    port: u16,
+) -> bool {
+    let mut ipv4_packet = match forward::ipv4_packet_mut(eth_packet) {
+        Ok(ipv4_packet) => ipv4_packet,
+        Err(e) => {
+            debug!("NAPT - {e}");
+            return false;
+        }
+    };
+    match proto {
+        IpNextHeaderProtocols::Tcp => {
+            let Some(mut tcp_packet) = MutableTcpPacket::new(ipv4_packet.payload_mut()) else {
+                return false;
+            };
+            let old = match which {
+                Port::Source => tcp_packet.get_source(),
+                Port::Destination => tcp_packet.get_destination(),
+            };
+            match which {
+                Port::Source => tcp_packet.set_source(port),
+                Port::Destination => tcp_packet.set_destination(port),
+            }
+            let checksum = adjust_checksum(tcp_packet.get_checksum(), old, port);
+            tcp_packet.set_checksum(checksum);
+        }
+        IpNextHeaderProtocols::Udp => {
+            let Some(mut udp_packet) = MutableUdpPacket::new(ipv4_packet.payload_mut()) else {
+                return false;
+            };
+            let old = match which {
+                Port::Source => udp_packet.get_source(),
+                Port::Destination => udp_packet.get_destination(),
+            };
+            match which {
+                Port::Source => udp_packet.set_source(port),
+                Port::Destination => udp_packet.set_destination(port),
+            }
+            // A UDP checksum of 0 means none, and is sent as 0xffff when computed as 0
+            let checksum = match udp_packet.get_checksum() {
+                0 => 0,
+                checksum => match adjust_checksum(checksum, old, port) {
+                    0 => 0xffff,
+                    adjusted => adjusted,
+                },
+            };
+            udp_packet.set_checksum(checksum);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Internet checksum after a 16-bit word of the data changed from `old` to `new`
+/// (RFC 1624)
+fn adjust_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+/// Ruleset dropping `ports` on input to the host. Declaring the table before deleting
+/// it makes the deletion succeed whether a previous table exists or not.
+fn shield_ruleset(ports: &RangeInclusive<u16>) -> String {
+    format!(
+        "table inet {NFT_TABLE}\n\
+         delete table inet {NFT_TABLE}\n\
+         table inet {NFT_TABLE} {{\n\
+         \tchain input {{\n\
+         \t\ttype filter hook input priority filter - 10; policy accept;\n\
+         \t\tmeta l4proto {{ tcp, udp }} th dport {}-{} drop\n\
+         \t}}\n\
+         }}\n",
+        ports.start(),
+        ports.end()
+    )
+}
+
+/// Applies `ruleset` atomically with `nft -f -`.
+fn nft(ruleset: &str) -> io::Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(ruleset.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Parses an external port range, e.g. `61000-65535`, or a single port.
+pub fn parse_ports(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let port = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|&p| p != 0)
+            .ok_or_else(|| format!("invalid port `{p}`"))
+    };
+    let ports = port(first)?..=port(last)?;
+    if ports.is_empty() {
+        return Err(format!("empty port range `{s}`"));
+    }
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::EtherTypes;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp;
+    use pnet::packet::udp::{self, UdpPacket};
+
+    const CLIENT_A: Ipv4Addr = Ipv4Addr::new(192, 168, 100, 2);
+    const CLIENT_B: Ipv4Addr = Ipv4Addr::new(192, 168, 100, 3);
+    const MAC_A: MacAddr = MacAddr(2, 0, 0, 0, 2, 2);
+    const MAC_B: MacAddr = MacAddr(2, 0, 0, 0, 2, 3);
+    const EXT: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+    const OTHER: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 35);
+
+    fn ifaces() -> Ifaces {
+        Ifaces {
+            ext_ip: IpNetwork::new(EXT.into(), 24).unwrap(),
+            ext_mac: MacAddr(2, 0, 0, 0, 1, 1),
+            int_ip: IpNetwork::new(Ipv4Addr::new(192, 168, 100, 1).into(), 24).unwrap(),
+            int_mac: MacAddr(2, 0, 0, 0, 2, 1),
+        }
+    }
+
+    fn napt(ports: RangeInclusive<u16>, max_flows: usize) -> Napt {
+        Napt::new(
+            Some(ports),
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            max_flows,
+        )
+    }
+
+    /// A TCP or UDP packet with valid checksums
+    fn frame(
+        proto: IpNextHeaderProtocol,
+        (src, src_port): (Ipv4Addr, u16),
+        (dest, dest_port): (Ipv4Addr, u16),
+        flags: u8,
+    ) -> Vec<u8> {
+        let transport_len = if proto == IpNextHeaderProtocols::Tcp {
+            20
+        } else {
+            8
+        } + 4;
+        let mut buffer = vec![0u8; 14 + 20 + transport_len];
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buffer[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length((20 + transport_len) as u16);
+        ipv4.set_next_level_protocol(proto);
+        ipv4.set_source(src);
+        ipv4.set_destination(dest);
+        if proto == IpNextHeaderProtocols::Tcp {
+            let mut tcp = MutableTcpPacket::new(&mut buffer[34..]).unwrap();
+            tcp.set_source(src_port);
+            tcp.set_destination(dest_port);
+            tcp.set_data_offset(5);
+            tcp.set_flags(flags);
+            tcp.set_payload(b"ping");
+            let checksum = tcp::ipv4_checksum(&tcp.to_immutable(), &src, &dest);
+            tcp.set_checksum(checksum);
+        } else {
+            let mut udp = MutableUdpPacket::new(&mut buffer[34..]).unwrap();
+            udp.set_source(src_port);
+            udp.set_destination(dest_port);
+            udp.set_length(transport_len as u16);
+            udp.set_payload(b"ping");
+            let checksum = udp::ipv4_checksum(&udp.to_immutable(), &src, &dest);
+            udp.set_checksum(checksum);
+        }
+        buffer
+    }
+
+    fn udp(src: (Ipv4Addr, u16), dest: (Ipv4Addr, u16)) -> Vec<u8> {
+        frame(IpNextHeaderProtocols::Udp, src, dest, 0)
+    }
+
+    async fn outbound(napt: &Napt, frame: &[u8], mac: MacAddr) -> Option<u16> {
+        let eth = EthernetPacket::new(frame).unwrap();
+        let segment = Segment::parse(&eth).unwrap();
+        let flow = Flow {
+            proto: segment.proto,
+            client: segment.src,
+            client_port: segment.src_port,
+            remote: segment.dest,
+            remote_port: segment.dest_port,
+        };
+        napt.map(flow, mac, &segment, Instant::now()).await
+    }
+
+    async fn inbound(napt: &Napt, frame: &mut [u8]) -> Option<(MacAddr, IpNetwork)> {
+        let mut eth = MutableEthernetPacket::new(frame).unwrap();
+        napt.translate_inbound(&mut eth, &ifaces()).await
+    }
+
+    #[test]
+    fn test_shield_ruleset() {
+        let ruleset = shield_ruleset(&(61000..=65535));
+        assert!(ruleset.starts_with("table inet nw_pckt_fwd_napt\ndelete table inet"));
+        assert!(ruleset.contains("type filter hook input"));
+        assert!(ruleset.contains("meta l4proto { tcp, udp } th dport 61000-65535 drop\n"));
+    }
+
+    #[test]
+    fn test_parse_ports() {
+        assert_eq!(parse_ports("61000-65535"), Ok(61000..=65535));
+        assert_eq!(parse_ports("61000"), Ok(61000..=61000));
+        assert!(parse_ports("65535-61000").is_err());
+        assert!(parse_ports("0-100").is_err());
+        assert!(parse_ports("61000-x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_colliding_clients_get_own_ports() {
+        let napt = napt(61000..=61009, 16);
+        let a = udp((CLIENT_A, 61005), (REMOTE, 443));
+        let b = udp((CLIENT_B, 61005), (REMOTE, 443));
+
+        // The client's port is kept when free, the next free one is taken otherwise
+        assert_eq!(outbound(&napt, &a, MAC_A).await, Some(61005));
+        assert_eq!(outbound(&napt, &b, MAC_B).await, Some(61006));
+        assert_eq!(outbound(&napt, &a, MAC_A).await, Some(61005));
+
+        let mut reply_a = udp((REMOTE, 443), (EXT, 61005));
+        let mut reply_b = udp((REMOTE, 443), (EXT, 61006));
+        let client_a = IpNetwork::new(CLIENT_A.into(), 24).unwrap();
+        let client_b = IpNetwork::new(CLIENT_B.into(), 24).unwrap();
+        assert_eq!(inbound(&napt, &mut reply_a).await, Some((MAC_A, client_a)));
+        assert_eq!(inbound(&napt, &mut reply_b).await, Some((MAC_B, client_b)));
+        // Both replies go back to the client's own port
+        for reply in [&reply_a, &reply_b] {
+            let ipv4 = Ipv4Packet::new(&reply[14..]).unwrap();
+            assert_eq!(
+                UdpPacket::new(ipv4.payload()).unwrap().get_destination(),
+                61005
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_the_remote_is_answered() {
+        let napt = napt(61000..=61009, 16);
+        let port = outbound(&napt, &udp((CLIENT_A, 40000), (REMOTE, 53)), MAC_A)
+            .await
+            .unwrap();
+        assert!((61000..=61009).contains(&port));
+
+        for (src, dest) in [
+            ((OTHER, 53), (EXT, port)),
+            ((REMOTE, 54), (EXT, port)),
+            ((REMOTE, 53), (OTHER, port)),
+        ] {
+            let mut reply = udp(src, dest);
+            let original = reply.clone();
+            assert_eq!(inbound(&napt, &mut reply).await, None);
+            assert_eq!(reply, original);
+        }
+        let mut tcp_reply = frame(
+            IpNextHeaderProtocols::Tcp,
+            (REMOTE, 53),
+            (EXT, port),
+            TcpFlags::ACK,
+        );
+        assert_eq!(inbound(&napt, &mut tcp_reply).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry() {
+        let napt = napt(61000..=61000, 16);
+        let a = udp((CLIENT_A, 40000), (REMOTE, 53));
+        let b = udp((CLIENT_B, 40000), (REMOTE, 53));
+        assert_eq!(outbound(&napt, &a, MAC_A).await, Some(61000));
+        // The only port is taken until the flow times out
+        assert_eq!(outbound(&napt, &b, MAC_B).await, None);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(outbound(&napt, &b, MAC_B).await, Some(61000));
+        let mut reply = udp((REMOTE, 53), (EXT, 61000));
+        assert_eq!(inbound(&napt, &mut reply).await.unwrap().0, MAC_B);
+
+        // Closed connections are released early, a new one reopens them
+        let tcp = |flags| {
+            frame(
+                IpNextHeaderProtocols::Tcp,
+                (CLIENT_A, 40000),
+                (REMOTE, 443),
+                flags,
+            )
+        };
+        assert!(outbound(&napt, &tcp(TcpFlags::SYN), MAC_A).await.is_some());
+        assert!(
+            outbound(&napt, &tcp(TcpFlags::FIN | TcpFlags::ACK), MAC_A)
+                .await
+                .is_some()
+        );
+        tokio::time::advance(CLOSING_TIMEOUT / 2).await;
+        assert!(outbound(&napt, &tcp(TcpFlags::SYN), MAC_A).await.is_some());
+        tokio::time::advance(CLOSING_TIMEOUT).await;
+        let mut reply = frame(
+            IpNextHeaderProtocols::Tcp,
+            (REMOTE, 443),
+            (EXT, 61000),
+            TcpFlags::ACK,
+        );
+        assert!(inbound(&napt, &mut reply).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_table_evicts_oldest() {
+        let napt = napt(61000..=61009, 2);
+        let flow = |port| udp((CLIENT_A, port), (REMOTE, 53));
+        assert_eq!(outbound(&napt, &flow(61000), MAC_A).await, Some(61000));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(outbound(&napt, &flow(61001), MAC_A).await, Some(61001));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(outbound(&napt, &flow(61002), MAC_A).await, Some(61002));
+
+        let mut reply = udp((REMOTE, 53), (EXT, 61000));
+        assert_eq!(inbound(&napt, &mut reply).await, None);
+        let mut reply = udp((REMOTE, 53), (EXT, 61001));
+        assert!(inbound(&napt, &mut reply).await.is_some());
+    }
+
+    #[test]
+    fn test_rewrite_keeps_checksums_valid() {
+        for proto in [IpNextHeaderProtocols::Tcp, IpNextHeaderProtocols::Udp] {
+            let mut buffer = frame(proto, (REMOTE, 443), (EXT, 61234), TcpFlags::ACK);
+            let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+            assert!(rewrite_port(&mut eth, proto, Port::Destination, 40000));
+
+            let ipv4 = Ipv4Packet::new(&buffer[14..]).unwrap();
+            if proto == IpNextHeaderProtocols::Tcp {
+                let tcp = TcpPacket::new(ipv4.payload()).unwrap();
+                assert_eq!(tcp.get_destination(), 40000);
+                assert_eq!(tcp.get_checksum(), tcp::ipv4_checksum(&tcp, &REMOTE, &EXT));
+            } else {
+                let udp = UdpPacket::new(ipv4.payload()).unwrap();
+                assert_eq!(udp.get_destination(), 40000);
+                assert_eq!(udp.get_checksum(), udp::ipv4_checksum(&udp, &REMOTE, &EXT));
+            }
+        }
+
+        // No checksum stays none
+        let mut buffer = udp((REMOTE, 443), (EXT, 61234));
+        MutableUdpPacket::new(&mut buffer[34..])
+            .unwrap()
+            .set_checksum(0);
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        assert!(rewrite_port(
+            &mut eth,
+            IpNextHeaderProtocols::Udp,
+            Port::Destination,
+            40000
+        ));
+        assert_eq!(UdpPacket::new(&buffer[34..]).unwrap().get_checksum(), 0);
+
+        // Truncated headers are left alone
+        let mut buffer = udp((REMOTE, 443), (EXT, 61234));
+        buffer.truncate(14 + 20 + 4);
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        assert!(!rewrite_port(
+            &mut eth,
+            IpNextHeaderProtocols::Udp,
+            Port::Destination,
+            40000
+        ));
+    }
+}
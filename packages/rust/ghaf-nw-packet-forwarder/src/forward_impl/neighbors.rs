@@ -0,0 +1,420 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Neighbor table
+//!
+//! Maps the addresses of internal clients to the MACs they were last seen with, learned
+//! from their traffic on the internal interface: ARP requests and replies, NDP neighbor
+//! solicitations and advertisements, and the source of any IPv4 or IPv6 packet. A VM that
+//! comes back with a new MAC keeps receiving traffic without restarting the forwarder.
+//!
+//! Any internal VM can claim any address, so a learned MAC never replaces the one given
+//! on the command line on its own: frames only go to it when the DHCP relay leased the
+//! address to that MAC. IPv6 addresses are not leased through the relay, their clients
+//! are always reached at the configured MAC and other MACs claiming them are only
+//! logged. Learning is disabled by default.
+//!
+//! IPv4 entries are only learned for addresses of the internal network or leased through
+//! the relay, IPv6 entries for addresses of the internal prefix or link-local ones.
+//! Entries expire when their client was silent for the configured time, and the table
+//! holds a bounded number of them, the least recently seen being evicted first.
+use crate::forward_impl::dhcp_relay::DhcpRelay;
+use log::{debug, info};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::Packet;
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Clients remembered at most
+const MAX_NEIGHBORS: usize = 256;
+/// NDP options carrying the link-layer address of the sender, or of the target
+const NDP_SOURCE_LL_ADDR: u8 = 1;
+const NDP_TARGET_LL_ADDR: u8 = 2;
+/// Offset of the options in neighbor solicitations and advertisements
+const NDP_OPTIONS_OFFSET: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    mac: MacAddr,
+    seen: Instant,
+}
+
+pub struct Neighbors {
+    table: Mutex<HashMap<IpAddr, Entry>>,
+    ttl: Duration,
+}
+
+impl Neighbors {
+    /// Creates a table whose entries expire `ttl` after their client was last seen;
+    /// learning is disabled with a zero `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            table: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Learns the sender of a frame received on the internal interface, whose addresses
+    /// are `internal_ips`.
+    pub async fn learn(
+        &self,
+        eth_packet: &EthernetPacket<'_>,
+        internal_ips: &[IpNetwork],
+        dhcp_relay: &DhcpRelay,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some((ip, mac)) = observed(eth_packet) else {
+            return;
+        };
+        let own = internal_ips.iter().any(|net| net.ip() == ip);
+        let internal = internal_ips.iter().any(|net| net.contains(ip));
+        let in_scope = match ip {
+            IpAddr::V4(ipv4) => {
+                (internal || dhcp_relay.lease_holder(ipv4).await == Some(mac))
+                    && !ipv4.is_broadcast()
+                    && !ipv4.is_multicast()
+            }
+            IpAddr::V6(ipv6) => internal || ipv6.is_unicast_link_local(),
+        };
+        if !in_scope || own || ip.is_unspecified() || !mac.is_unicast() || mac == MacAddr::zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut table = self.table.lock().await;
+        if !table.contains_key(&ip) && table.len() >= MAX_NEIGHBORS {
+            let ttl = self.ttl;
+            table.retain(|_, entry| now < entry.seen + ttl);
+            if table.len() >= MAX_NEIGHBORS
+                && let Some(oldest) = table.iter().min_by_key(|(_, e)| e.seen).map(|(k, _)| *k)
+            {
+                debug!("Neighbor table full, evicting {oldest}");
+                table.remove(&oldest);
+            }
+        }
+        match table.insert(ip, Entry { mac, seen: now }) {
+            Some(previous) if previous.mac != mac => {
+                info!(
+                    "Neighbor {ip} seen at {mac}, previously at {}",
+                    previous.mac
+                );
+            }
+            Some(_) => {}
+            None => debug!("Neighbor {ip} learned at {mac}"),
+        }
+    }
+
+    /// Returns the MAC to reach the internal client at `ip` with: the one it was last
+    /// seen with if `dhcp_relay` leased `ip` to it, `configured` otherwise. Group MACs are
+    /// kept as they are.
+    pub async fn resolve(
+        &self,
+        configured: MacAddr,
+        ip: IpAddr,
+        dhcp_relay: &DhcpRelay,
+    ) -> MacAddr {
+        if !self.is_enabled() || !configured.is_unicast() {
+            return configured;
+        }
+        let now = Instant::now();
+        let learned = self
+            .table
+            .lock()
+            .await
+            .get(&ip)
+            .filter(|entry| now < entry.seen + self.ttl)
+            .map(|entry| entry.mac);
+        let Some(learned) = learned.filter(|&mac| mac != configured) else {
+            return configured;
+        };
+        match ip {
+            IpAddr::V4(ipv4) if dhcp_relay.lease_holder(ipv4).await == Some(learned) => learned,
+            _ => {
+                debug!("Neighbor {ip} seen at {learned} without a lease, sent to {configured}");
+                configured
+            }
+        }
+    }
+}
+
+/// Address and MAC of the sender of `eth_packet`, as announced by it.
+fn observed(eth_packet: &EthernetPacket<'_>) -> Option<(IpAddr, MacAddr)> {
+    match eth_packet.get_ethertype() {
+        EtherTypes::Arp => {
+            let arp = ArpPacket::new(eth_packet.payload())?;
+            Some((arp.get_sender_proto_addr().into(), arp.get_sender_hw_addr()))
+        }
+        EtherTypes::Ipv4 => {
+            let ipv4 = Ipv4Packet::new(eth_packet.payload())?;
+            Some((ipv4.get_source().into(), eth_packet.get_source()))
+        }
+        EtherTypes::Ipv6 => {
+            let ipv6 = Ipv6Packet::new(eth_packet.payload())?;
+            let sender = (ipv6.get_source(), eth_packet.get_source());
+            let (ip, mac) = if ipv6.get_next_header() == IpNextHeaderProtocols::Icmpv6 {
+                observed_ndp(ipv6.payload(), sender).unwrap_or(sender)
+            } else {
+                sender
+            };
+            Some((ip.into(), mac))
+        }
+        _ => None,
+    }
+}
+
+/// Address and MAC announced by an NDP neighbor solicitation or advertisement from
+/// `sender`, whose own address and MAC stand in for what the message leaves out.
+fn observed_ndp(icmpv6: &[u8], sender: (Ipv6Addr, MacAddr)) -> Option<(Ipv6Addr, MacAddr)> {
+    let icmpv6_type = Icmpv6Packet::new(icmpv6)?.get_icmpv6_type();
+    let options = icmpv6.get(NDP_OPTIONS_OFFSET..)?;
+    let (ip, mac) = sender;
+    if icmpv6_type == Icmpv6Types::NeighborAdvert {
+        let target: [u8; 16] = icmpv6.get(8..NDP_OPTIONS_OFFSET)?.try_into().ok()?;
+        let mac = link_layer_option(options, NDP_TARGET_LL_ADDR).unwrap_or(mac);
+        Some((target.into(), mac))
+    } else if icmpv6_type == Icmpv6Types::NeighborSolicit {
+        // Duplicate address detection solicits from the unspecified address, not learned
+        Some((
+            ip,
+            link_layer_option(options, NDP_SOURCE_LL_ADDR).unwrap_or(mac),
+        ))
+    } else {
+        None
+    }
+}
+
+/// The Ethernet address carried in the NDP option `kind`, if any.
+fn link_layer_option(mut options: &[u8], kind: u8) -> Option<MacAddr> {
+    // Each option gives its type and its length in units of 8 bytes
+    while let [option_type, len, ..] = *options {
+        let len = usize::from(len) * 8;
+        if len == 0 {
+            return None;
+        }
+        let (option, rest) = options.split_at_checked(len)?;
+        if option_type == kind {
+            let mac: [u8; 6] = option.get(2..8)?.try_into().ok()?;
+            return Some(mac.into());
+        }
+        options = rest;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward_impl::dhcp_relay::Lease;
+    use pnet::packet::MutablePacket;
+    use pnet::packet::arp::{ArpOperations, MutableArpPacket};
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::ipv6::MutableIpv6Packet;
+    use std::net::Ipv4Addr;
+
+    const VM: Ipv4Addr = Ipv4Addr::new(192, 168, 100, 2);
+    const CONFIGURED: MacAddr = MacAddr(2, 0, 0, 0, 0, 2);
+    const NEW_MAC: MacAddr = MacAddr(2, 0, 0, 0, 0, 9);
+
+    fn internal_net() -> IpNetwork {
+        "192.168.100.1/24".parse().unwrap()
+    }
+
+    fn arp_frame(mac: MacAddr, ip: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0u8; 42];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Arp);
+        eth.set_source(mac);
+        eth.set_destination(MacAddr::broadcast());
+        let mut arp = MutableArpPacket::new(eth.payload_mut()).unwrap();
+        arp.set_operation(ArpOperations::Request);
+        arp.set_sender_hw_addr(mac);
+        arp.set_sender_proto_addr(ip);
+        arp.set_target_proto_addr(Ipv4Addr::new(192, 168, 100, 1));
+        frame
+    }
+
+    fn ipv4_frame(mac: MacAddr, ip: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0u8; 34];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        eth.set_source(mac);
+        let mut ipv4 = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length(20);
+        ipv4.set_source(ip);
+        frame
+    }
+
+    /// Neighbor advertisement for `target` sent from `mac`, announcing `ll_addr`
+    fn advert_frame(mac: MacAddr, target: Ipv6Addr, ll_addr: MacAddr) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 40 + 32];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv6);
+        eth.set_source(mac);
+        let mut ipv6 = MutableIpv6Packet::new(eth.payload_mut()).unwrap();
+        ipv6.set_version(6);
+        ipv6.set_payload_length(32);
+        ipv6.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        ipv6.set_source(target);
+        let icmpv6 = ipv6.payload_mut();
+        icmpv6[0] = Icmpv6Types::NeighborAdvert.0;
+        icmpv6[8..24].copy_from_slice(&target.octets());
+        icmpv6[24..26].copy_from_slice(&[NDP_TARGET_LL_ADDR, 1]);
+        icmpv6[26..32].copy_from_slice(&<[u8; 6]>::from(ll_addr));
+        frame
+    }
+
+    async fn learn(neighbors: &Neighbors, relay: &DhcpRelay, frame: &[u8]) {
+        neighbors
+            .learn(
+                &EthernetPacket::new(frame).unwrap(),
+                &[internal_net()],
+                relay,
+            )
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mac_change_followed() {
+        let neighbors = Neighbors::new(Duration::from_secs(300));
+        let relay = DhcpRelay::new(true);
+        let resolve = async |ip: Ipv4Addr| neighbors.resolve(CONFIGURED, ip.into(), &relay).await;
+        assert_eq!(resolve(VM).await, CONFIGURED);
+
+        learn(&neighbors, &relay, &arp_frame(CONFIGURED, VM)).await;
+        assert_eq!(resolve(VM).await, CONFIGURED);
+
+        // The VM came back with another MAC and leased its address again
+        learn(&neighbors, &relay, &ipv4_frame(NEW_MAC, VM)).await;
+        assert_eq!(resolve(VM).await, CONFIGURED);
+        relay
+            .insert_lease(Lease {
+                mac: NEW_MAC,
+                ip: VM,
+                expires: Instant::now() + Duration::from_secs(3600),
+            })
+            .await;
+        assert_eq!(resolve(VM).await, NEW_MAC);
+
+        // Group destinations are left alone
+        let group = MacAddr(0x01, 0, 0x5e, 0, 0, 0xfb);
+        assert_eq!(neighbors.resolve(group, VM.into(), &relay).await, group);
+
+        // Silent clients fall back to the configured MAC
+        tokio::time::advance(Duration::from_secs(301)).await;
+        assert_eq!(resolve(VM).await, CONFIGURED);
+    }
+
+    #[tokio::test]
+    async fn test_claimed_address_ignored() {
+        let neighbors = Neighbors::new(Duration::from_secs(300));
+        let relay = DhcpRelay::new(true);
+        // Another VM claims the address leased to the configured client
+        relay
+            .insert_lease(Lease {
+                mac: CONFIGURED,
+                ip: VM,
+                expires: Instant::now() + Duration::from_secs(3600),
+            })
+            .await;
+        learn(&neighbors, &relay, &arp_frame(NEW_MAC, VM)).await;
+        assert_eq!(
+            neighbors.resolve(CONFIGURED, VM.into(), &relay).await,
+            CONFIGURED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_learning_scope() {
+        let neighbors = Neighbors::new(Duration::from_secs(300));
+        let relay = DhcpRelay::new(true);
+        let outside = Ipv4Addr::new(192, 168, 1, 50);
+        learn(&neighbors, &relay, &ipv4_frame(NEW_MAC, outside)).await;
+        learn(
+            &neighbors,
+            &relay,
+            &arp_frame(NEW_MAC, Ipv4Addr::UNSPECIFIED),
+        )
+        .await;
+        learn(
+            &neighbors,
+            &relay,
+            &ipv4_frame(NEW_MAC, Ipv4Addr::new(192, 168, 100, 1)),
+        )
+        .await;
+        learn(&neighbors, &relay, &ipv4_frame(MacAddr::broadcast(), VM)).await;
+        let global: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        learn(&neighbors, &relay, &advert_frame(NEW_MAC, global, NEW_MAC)).await;
+        assert!(neighbors.table.lock().await.is_empty());
+
+        // Addresses leased through the relay are learned from their holder only
+        relay
+            .insert_lease(Lease {
+                mac: NEW_MAC,
+                ip: outside,
+                expires: Instant::now() + Duration::from_secs(3600),
+            })
+            .await;
+        learn(&neighbors, &relay, &ipv4_frame(CONFIGURED, outside)).await;
+        assert!(neighbors.table.lock().await.is_empty());
+        learn(&neighbors, &relay, &ipv4_frame(NEW_MAC, outside)).await;
+        assert_eq!(
+            neighbors.resolve(CONFIGURED, outside.into(), &relay).await,
+            NEW_MAC
+        );
+
+        let disabled = Neighbors::new(Duration::ZERO);
+        learn(&disabled, &relay, &ipv4_frame(NEW_MAC, VM)).await;
+        assert_eq!(
+            disabled.resolve(CONFIGURED, VM.into(), &relay).await,
+            CONFIGURED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ndp_learning() {
+        let neighbors = Neighbors::new(Duration::from_secs(300));
+        let relay = DhcpRelay::new(true);
+        let vm: Ipv6Addr = "fe80::2".parse().unwrap();
+        learn(&neighbors, &relay, &advert_frame(CONFIGURED, vm, NEW_MAC)).await;
+        assert_eq!(neighbors.table.lock().await[&IpAddr::V6(vm)].mac, NEW_MAC);
+        // Without a lease to confirm it, the configured MAC is kept
+        assert_eq!(
+            neighbors.resolve(CONFIGURED, vm.into(), &relay).await,
+            CONFIGURED
+        );
+    }
+
+    #[test]
+    fn test_link_layer_option() {
+        let mut options = vec![3, 4, 0, 0, 0, 0, 0, 0];
+        options.extend_from_slice(&[0; 24]);
+        options.extend_from_slice(&[NDP_SOURCE_LL_ADDR, 1, 2, 0, 0, 0, 0, 9]);
+        assert_eq!(
+            link_layer_option(&options, NDP_SOURCE_LL_ADDR),
+            Some(NEW_MAC)
+        );
+        assert_eq!(link_layer_option(&options, NDP_TARGET_LL_ADDR), None);
+        // Zero length, and an option running past the end
+        assert_eq!(link_layer_option(&[1, 0, 2, 0, 0, 0, 0, 9], 1), None);
+        assert_eq!(link_layer_option(&[1, 2, 2, 0, 0, 0, 0, 9], 1), None);
+    }
+}
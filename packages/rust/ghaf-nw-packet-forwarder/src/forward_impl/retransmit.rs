@@ -0,0 +1,208 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Discovery response retransmission
+//!
+//! A discovery response lost on a congested Wi-Fi link leaves the guest without the
+//! device until its next announcement, which can take minutes. Forwarded mDNS responses
+//! and SSDP search replies are therefore sent to the guest a configurable number of
+//! extra times, a little apart.
+//!
+//! Responses repeated by the network itself (e.g. several reflectors, or a device
+//! answering every query of a burst) would multiply with the copies, so a response the
+//! guest already got within the retransmission burst is not forwarded again.
+use crate::filter::chromecast::{MDNS_PORT, SSDP_PORT};
+use log::{debug, error};
+use pnet::datalink::DataLinkSender;
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant, sleep};
+
+/// Responses remembered for deduplication
+const MAX_RECENT: usize = 256;
+/// DNS header flag of a response
+const DNS_QR: u8 = 0x80;
+const SSDP_REPLY: &[u8] = b"HTTP/1.1 200";
+
+pub struct Retransmitter {
+    count: u32,
+    spacing: Duration,
+    recent: Mutex<HashMap<u64, Instant>>,
+}
+
+impl Retransmitter {
+    /// Sends each response `count` more times, `spacing` apart; disabled with 0.
+    pub fn new(count: u32, spacing: Duration) -> Self {
+        Self {
+            count,
+            spacing,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Time in which a copy of a response is still expected
+    fn burst(&self) -> Duration {
+        self.spacing * (self.count + 1)
+    }
+
+    /// Identifies `eth_packet` as a discovery response for the guest at `mac`, `None`
+    /// for other packets.
+    fn response_key(eth_packet: &EthernetPacket<'_>, mac: MacAddr) -> Option<u64> {
+        if eth_packet.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+        let ipv4 = Ipv4Packet::new(eth_packet.payload())?;
+        if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+        let udp = UdpPacket::new(ipv4.payload())?;
+        let payload = udp.payload();
+        let is_response = match udp.get_source() {
+            MDNS_PORT => payload.get(2).is_some_and(|flags| flags & DNS_QR != 0),
+            SSDP_PORT => payload.starts_with(SSDP_REPLY),
+            _ => false,
+        };
+        if !is_response {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (mac, ipv4.get_source(), udp.get_source(), payload).hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Returns whether `eth_packet` is a discovery response the guest at `mac` got a
+    /// copy of within the current burst, and records it otherwise.
+    pub async fn is_duplicate(&self, eth_packet: &EthernetPacket<'_>, mac: MacAddr) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let Some(key) = Self::response_key(eth_packet, mac) else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let mut recent = self.recent.lock().await;
+        if recent
+            .get(&key)
+            .is_some_and(|&sent| now < sent + self.burst())
+        {
+            return true;
+        }
+        if recent.len() >= MAX_RECENT {
+            let burst = self.burst();
+            recent.retain(|_, &mut sent| now < sent + burst);
+        }
+        if recent.len() < MAX_RECENT {
+            recent.insert(key, now);
+        }
+        false
+    }
+
+    /// Sends the extra copies of `frame`, already rewritten for the guest, in the
+    /// background if it is a discovery response.
+    pub fn repeat(&self, tx: &Arc<Mutex<Box<dyn DataLinkSender>>>, frame: &[u8]) {
+        if !self.is_enabled()
+            || EthernetPacket::new(frame)
+                .and_then(|e| Self::response_key(&e, e.get_destination()))
+                .is_none()
+        {
+            return;
+        }
+        let tx = Arc::clone(tx);
+        let frame = frame.to_vec();
+        let (count, spacing) = (self.count, self.spacing);
+        tokio::task::spawn(async move {
+            for copy in 1..=count {
+                sleep(spacing).await;
+                match tx.lock().await.send_to(&frame, None) {
+                    Some(Ok(())) => debug!("Ext to Int - discovery response copy {copy} sent"),
+                    Some(Err(e)) => error!("Error sending discovery response copy: {e}"),
+                    None => error!("Error: Send failed, no destination address."),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::udp::MutableUdpPacket;
+    use std::net::Ipv4Addr;
+
+    const GUEST: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+
+    fn udp_frame(src_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 8 + payload.len()];
+        let mut eth = MutableEthernetPacket::new(&mut frame).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(eth.payload_mut()).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_total_length((20 + 8 + payload.len()) as u16);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ipv4.set_source(Ipv4Addr::new(192, 168, 1, 20));
+        ipv4.set_destination(Ipv4Addr::new(224, 0, 0, 251));
+        let mut udp = MutableUdpPacket::new(ipv4.payload_mut()).unwrap();
+        udp.set_source(src_port);
+        udp.set_destination(MDNS_PORT);
+        udp.set_length((8 + payload.len()) as u16);
+        udp.set_payload(payload);
+        frame
+    }
+
+    #[test]
+    fn test_discovery_responses() {
+        let response = udp_frame(MDNS_PORT, &[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let query = udp_frame(MDNS_PORT, &[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let ssdp_reply = udp_frame(SSDP_PORT, b"HTTP/1.1 200 OK\r\n\r\n");
+        let ssdp_notify = udp_frame(SSDP_PORT, b"NOTIFY * HTTP/1.1\r\n\r\n");
+        let other = udp_frame(40000, &[0, 0, 0x84, 0]);
+        let is_response = |frame: &Vec<u8>| {
+            Retransmitter::response_key(&EthernetPacket::new(frame).unwrap(), GUEST).is_some()
+        };
+        assert!(is_response(&response));
+        assert!(!is_response(&query));
+        assert!(is_response(&ssdp_reply));
+        assert!(!is_response(&ssdp_notify));
+        assert!(!is_response(&other));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicates_within_burst() {
+        let retransmitter = Retransmitter::new(2, Duration::from_millis(100));
+        let frame = udp_frame(MDNS_PORT, &[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let packet = EthernetPacket::new(&frame).unwrap();
+        assert!(!retransmitter.is_duplicate(&packet, GUEST).await);
+        assert!(retransmitter.is_duplicate(&packet, GUEST).await);
+        // Another guest gets its own copies
+        assert!(
+            !retransmitter
+                .is_duplicate(&packet, MacAddr(2, 0, 0, 0, 0, 2))
+                .await
+        );
+
+        tokio::time::advance(Duration::from_millis(300)).await;
+        assert!(!retransmitter.is_duplicate(&packet, GUEST).await);
+
+        let disabled = Retransmitter::new(0, Duration::from_millis(100));
+        assert!(!disabled.is_duplicate(&packet, GUEST).await);
+        assert!(!disabled.is_duplicate(&packet, GUEST).await);
+    }
+}
@@ -0,0 +1,344 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Test packet injection
+//!
+//! The `inject` subcommand crafts one packet and runs it through the pipeline as if it
+//! was captured on the chosen interface, to check forwarding and filter rules in the
+//! field without a traffic generator. It takes the options of the service, so the same
+//! rules apply, and a forwarded packet really leaves through the other interface. For
+//! example an SSDP search of the chromecast VM, with the reason of a drop in the log:
+//!
+//! ```text
+//! nw-pckt-fwd <service options> --log-output stdout --log-level debug \
+//!     inject --ingress internal --src 192.168.100.2:40000 --dst 239.255.255.250:1900 \
+//!     --payload 'M-SEARCH * HTTP/1.1\r\nHOST: {dst}\r\nMAN: "ssdp:discover"\r\nMX: 1\r\nST: ssdp:all\r\n\r\n'
+//! ```
+//!
+//! The payload template understands the escapes `\r`, `\n`, `\t`, `\0`, `\\` and `\xNN`,
+//! and the placeholders `{src}`, `{dst}`, `{src_ip}`, `{dst_ip}`, `{src_port}` and
+//! `{dst_port}`.
+//!
+//! Every run starts with empty connection tracking and pins, like a service that was
+//! just started: external traffic only admitted as a reply is refused. Discovery
+//! retransmission and the pcap dump are off.
+use crate::traffic_stats::Snapshot;
+use clap::ValueEnum;
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, MutableIpv4Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags};
+use pnet::packet::udp::{self, MutableUdpPacket};
+use pnet::util::MacAddr;
+use std::net::SocketAddrV4;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_WINDOW: u16 = 64240;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Ingress {
+    Internal,
+    External,
+}
+
+#[derive(ValueEnum, Default, Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum TcpFlag {
+    Fin,
+    Syn,
+    Rst,
+    Psh,
+    Ack,
+    Urg,
+}
+
+impl TcpFlag {
+    fn bit(self) -> u8 {
+        match self {
+            TcpFlag::Fin => TcpFlags::FIN,
+            TcpFlag::Syn => TcpFlags::SYN,
+            TcpFlag::Rst => TcpFlags::RST,
+            TcpFlag::Psh => TcpFlags::PSH,
+            TcpFlag::Ack => TcpFlags::ACK,
+            TcpFlag::Urg => TcpFlags::URG,
+        }
+    }
+}
+
+/// Test packet of the `inject` subcommand
+#[derive(clap::Args, Debug, Clone)]
+pub struct Probe {
+    /// Interface the packet is handled as captured on, a forwarded packet leaves
+    /// through the other one
+    #[arg(long, value_enum)]
+    pub ingress: Ingress,
+
+    /// Transport protocol
+    #[arg(long, value_enum, default_value_t = Default::default())]
+    pub protocol: Protocol,
+
+    /// Source address and port
+    #[arg(long)]
+    pub src: SocketAddrV4,
+
+    /// Destination address and port
+    #[arg(long)]
+    pub dst: SocketAddrV4,
+
+    /// Source MAC address
+    #[arg(long, default_value = "02:00:00:00:00:01")]
+    pub src_mac: MacAddr,
+
+    /// Destination MAC address, by default the group MAC of a multicast destination,
+    /// the broadcast MAC or the MAC of the ingress interface
+    #[arg(long)]
+    pub dst_mac: Option<MacAddr>,
+
+    /// Comma-separated TCP flags
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "syn")]
+    pub tcp_flags: Vec<TcpFlag>,
+
+    /// Payload template
+    #[arg(long, default_value = "")]
+    pub payload: String,
+}
+
+impl Probe {
+    /// Payload with the placeholders filled in and the escapes resolved.
+    pub fn expand_payload(&self) -> Result<Vec<u8>, String> {
+        let text = self
+            .payload
+            .replace("{src}", &self.src.to_string())
+            .replace("{dst}", &self.dst.to_string())
+            .replace("{src_ip}", &self.src.ip().to_string())
+            .replace("{dst_ip}", &self.dst.ip().to_string())
+            .replace("{src_port}", &self.src.port().to_string())
+            .replace("{dst_port}", &self.dst.port().to_string());
+
+        let mut payload = Vec::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                payload.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                continue;
+            }
+            let byte = match chars.next() {
+                Some('r') => b'\r',
+                Some('n') => b'\n',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some('\\') => b'\\',
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    Some(&hex)
+                        .filter(|h| h.len() == 2 && h.chars().all(|c| c.is_ascii_hexdigit()))
+                        .and_then(|h| u8::from_str_radix(h, 16).ok())
+                        .ok_or_else(|| format!("Invalid escape \\x{hex} in payload"))?
+                }
+                Some(c) => return Err(format!("Unknown escape \\{c} in payload")),
+                None => return Err("Payload ends in a lone backslash".to_string()),
+            };
+            payload.push(byte);
+        }
+        Ok(payload)
+    }
+
+    /// Destination MAC, `ingress_mac` being the MAC of the ingress interface.
+    pub fn destination_mac(&self, ingress_mac: MacAddr) -> MacAddr {
+        let ip = self.dst.ip();
+        self.dst_mac.unwrap_or_else(|| {
+            if ip.is_multicast() {
+                let [_, b, c, d] = ip.octets();
+                MacAddr(0x01, 0x00, 0x5e, b & 0x7f, c, d)
+            } else if ip.is_broadcast() {
+                MacAddr::broadcast()
+            } else {
+                ingress_mac
+            }
+        })
+    }
+
+    /// Builds the Ethernet frame, `ingress_mac` being the MAC of the ingress interface.
+    pub fn frame(&self, ingress_mac: MacAddr) -> Result<Vec<u8>, String> {
+        let payload = self.expand_payload()?;
+        let (protocol, header_len) = match self.protocol {
+            Protocol::Udp => (IpNextHeaderProtocols::Udp, UDP_HEADER_LEN),
+            Protocol::Tcp => (IpNextHeaderProtocols::Tcp, TCP_HEADER_LEN),
+        };
+        let transport_len = header_len + payload.len();
+        let total_len = u16::try_from(IPV4_HEADER_LEN + transport_len)
+            .map_err(|_| format!("Payload of {} bytes too large", payload.len()))?;
+
+        let mut frame = vec![0u8; ETHERNET_HEADER_LEN + usize::from(total_len)];
+        let (src_ip, dst_ip) = (*self.src.ip(), *self.dst.ip());
+        let mut eth = MutableEthernetPacket::new(&mut frame).expect("Frame sized for its headers");
+        eth.set_source(self.src_mac);
+        eth.set_destination(self.destination_mac(ingress_mac));
+        eth.set_ethertype(EtherTypes::Ipv4);
+
+        let mut ip = MutableIpv4Packet::new(&mut frame[ETHERNET_HEADER_LEN..])
+            .expect("Frame sized for its headers");
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(total_len);
+        ip.set_ttl(if dst_ip.is_multicast() { 1 } else { 64 });
+        ip.set_next_level_protocol(protocol);
+        ip.set_source(src_ip);
+        ip.set_destination(dst_ip);
+        ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+
+        let transport = &mut frame[ETHERNET_HEADER_LEN + IPV4_HEADER_LEN..];
+        match self.protocol {
+            Protocol::Udp => {
+                let mut udp =
+                    MutableUdpPacket::new(transport).expect("Frame sized for its headers");
+                udp.set_source(self.src.port());
+                udp.set_destination(self.dst.port());
+                udp.set_length(transport_len as u16);
+                udp.set_payload(&payload);
+                udp.set_checksum(udp::ipv4_checksum(&udp.to_immutable(), &src_ip, &dst_ip));
+            }
+            Protocol::Tcp => {
+                let mut tcp =
+                    MutableTcpPacket::new(transport).expect("Frame sized for its headers");
+                tcp.set_source(self.src.port());
+                tcp.set_destination(self.dst.port());
+                tcp.set_sequence(1);
+                tcp.set_data_offset(5);
+                tcp.set_flags(self.tcp_flags.iter().fold(0, |flags, f| flags | f.bit()));
+                tcp.set_window(TCP_WINDOW);
+                tcp.set_payload(&payload);
+                tcp.set_checksum(tcp::ipv4_checksum(&tcp.to_immutable(), &src_ip, &dst_ip));
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Whether the pipeline forwarded the packet, going by the traffic counted.
+    pub fn forwarded(&self, traffic: &Snapshot) -> bool {
+        let stats = match self.ingress {
+            Ingress::Internal => &traffic.int_to_ext,
+            Ingress::External => &traffic.ext_to_int,
+        };
+        !stats.forwarded.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use pnet::packet::Packet;
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::tcp::TcpPacket;
+    use pnet::packet::udp::UdpPacket;
+
+    const INGRESS_MAC: MacAddr = MacAddr(2, 0, 0, 0, 2, 1);
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        probe: Probe,
+    }
+
+    fn probe(args: &[&str]) -> Probe {
+        Cli::try_parse_from(["inject"].iter().chain(args))
+            .expect("Valid probe")
+            .probe
+    }
+
+    #[test]
+    fn test_payload_template() {
+        let p = probe(&[
+            "--ingress=internal",
+            "--src=192.168.100.2:40000",
+            "--dst=239.255.255.250:1900",
+            "--payload=HOST: {dst}\\r\\nFROM: {src_ip} {src_port}\\x00\\\\",
+        ]);
+        assert_eq!(
+            p.expand_payload().unwrap(),
+            b"HOST: 239.255.255.250:1900\r\nFROM: 192.168.100.2 40000\x00\\"
+        );
+
+        for bad in ["\\q", "\\x4", "\\x+1", "trailing\\"] {
+            let p = Probe {
+                payload: bad.to_string(),
+                ..p.clone()
+            };
+            assert!(p.expand_payload().is_err(), "{bad} accepted");
+        }
+    }
+
+    #[test]
+    fn test_udp_frame() {
+        let p = probe(&[
+            "--ingress=internal",
+            "--src=192.168.100.2:40000",
+            "--dst=239.255.255.250:1900",
+            "--payload=M-SEARCH",
+        ]);
+        let frame = p.frame(INGRESS_MAC).unwrap();
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_source(), MacAddr(2, 0, 0, 0, 0, 1));
+        assert_eq!(eth.get_destination(), MacAddr(1, 0, 0x5e, 0x7f, 0xff, 0xfa));
+
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        assert_eq!(ip.get_ttl(), 1);
+        assert_eq!(usize::from(ip.get_total_length()), frame.len() - 14);
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!((udp.get_source(), udp.get_destination()), (40000, 1900));
+        assert_eq!(
+            udp.get_checksum(),
+            udp::ipv4_checksum(&udp, &ip.get_source(), &ip.get_destination())
+        );
+        assert_eq!(udp.payload(), b"M-SEARCH");
+    }
+
+    #[test]
+    fn test_tcp_frame() {
+        let p = probe(&[
+            "--ingress=external",
+            "--protocol=tcp",
+            "--src=192.168.1.50:8009",
+            "--dst=192.168.1.3:40000",
+            "--tcp-flags=psh,ack",
+            "--payload=data",
+        ]);
+        let frame = p.frame(INGRESS_MAC).unwrap();
+        let eth = EthernetPacket::new(&frame).unwrap();
+        // Unicast goes to the ingress interface
+        assert_eq!(eth.get_destination(), INGRESS_MAC);
+
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Tcp);
+        assert_eq!(ip.get_checksum(), ipv4::checksum(&ip));
+        let tcp = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!(tcp.get_flags(), TcpFlags::PSH | TcpFlags::ACK);
+        assert_eq!(
+            tcp.get_checksum(),
+            tcp::ipv4_checksum(&tcp, &ip.get_source(), &ip.get_destination())
+        );
+        assert_eq!(tcp.payload(), b"data");
+    }
+
+    #[test]
+    fn test_oversized_payload_refused() {
+        let p = Probe {
+            payload: "x".repeat(usize::from(u16::MAX)),
+            ..probe(&["--ingress=internal", "--src=1.2.3.4:1", "--dst=5.6.7.8:2"])
+        };
+        assert!(p.frame(INGRESS_MAC).is_err());
+    }
+}
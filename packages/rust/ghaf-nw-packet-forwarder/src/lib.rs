@@ -0,0 +1,17 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! Forwarder internals, shared by `nw-pckt-fwd` and the fuzz targets under `fuzz/`.
+pub mod capture;
+pub mod capture_stats;
+pub mod cli;
+pub mod control;
+pub mod drop_log;
+pub mod ext_iface;
+pub mod filter;
+pub mod forward_impl; // Declare the forward module
+pub mod inject;
+pub mod pcap_dump;
+pub mod pipeline;
+pub mod traffic_stats;
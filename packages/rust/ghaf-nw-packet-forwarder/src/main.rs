@@ -2,25 +2,28 @@
     SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
     SPDX-License-Identifier: Apache-2.0
 */
-mod cli;
-mod filter;
-mod forward_impl; // Declare the forward module
-
-use cli::LogOutput;
 use env_logger::Builder;
-use filter::Chromecast;
-use filter::chromecast::{ExternalOps, InternalOps};
-use forward_impl::forward::{self, get_ifaces};
-use log::{debug, error, info, trace, warn};
-use pnet::datalink::DataLinkReceiver;
+use log::{debug, error, info, warn};
+use nw_pckt_fwd::capture_stats::{self, CaptureStats};
+use nw_pckt_fwd::cli::{self, LogOutput};
+use nw_pckt_fwd::control::{self, Control};
+use nw_pckt_fwd::drop_log::DropLog;
+use nw_pckt_fwd::ext_iface::ExternalLink;
+use nw_pckt_fwd::filter::{Chromecast, MdnsReflector};
+use nw_pckt_fwd::forward_impl::forward::{self, get_ifaces};
+use nw_pckt_fwd::forward_impl::retransmit::Retransmitter;
+use nw_pckt_fwd::inject::Ingress;
+use nw_pckt_fwd::pcap_dump::PcapDump;
+use nw_pckt_fwd::traffic_stats::{self, TrafficStats};
+use nw_pckt_fwd::{capture, pipeline};
 use pnet::datalink::{self, Channel::Ethernet, Config};
-use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::util::MacAddr;
 use std::panic;
 use std::sync::Arc;
 use syslog::{BasicLogger, Facility, Formatter3164};
 use tokio::signal;
 use tokio::sync::Mutex;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, interval};
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
@@ -30,12 +33,15 @@ async fn main() {
     // Get the network interfaces inside the async block to ensure it lives long enough
     let interfaces = datalink::interfaces();
 
-    // Find the external interface
-    let external_iface = interfaces
-        .iter()
-        .find(|iface| iface.name == cli::get_ext_iface_name() && !iface.is_loopback())
-        .expect("No matching external interface found")
-        .clone(); // Clone the interface to avoid borrowing issues
+    // Pick the preferred external interface and open its capture channel
+    let external_link = match ExternalLink::open(cli::get_ext_iface_names()) {
+        Ok(link) => Arc::new(link),
+        Err(e) => {
+            error!("Failed to open external interface: {e}");
+            std::process::exit(1);
+        }
+    };
+    let external_iface = external_link.current();
 
     // Find the internal interface
     let internal_iface: datalink::NetworkInterface = interfaces
@@ -61,29 +67,34 @@ async fn main() {
 
     debug!("ifaces:{:?}", forward::get_ifaces());
 
-    // Create channels for both interfaces
-    let config = Config::default();
-    let (internal_tx_ch, internal_rx_ch) = match datalink::channel(&internal_iface, config) {
-        Ok(Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("Unhandled channel type"),
-        Err(e) => panic!(
-            "Failed to create datalink channel for {}: {}",
+    // Create the internal channel on a socket of our own, so its kernel
+    // statistics can be polled
+    let internal_stats = Arc::new(CaptureStats::new("internal"));
+    let internal_fd = capture_stats::open_socket().unwrap_or_else(|e| {
+        panic!(
+            "Failed to open capture socket for {}: {}",
             internal_iface.name, e
-        ),
+        )
+    });
+    let config = Config {
+        read_timeout: Some(capture::READ_TIMEOUT),
+        socket_fd: Some(internal_fd),
+        ..Default::default()
     };
-
-    let (external_tx_ch, external_rx_ch) = match datalink::channel(&external_iface, config) {
+    let (internal_tx_ch, internal_rx_ch) = match datalink::channel(&internal_iface, config) {
         Ok(Ethernet(tx, rx)) => (tx, rx),
         Ok(_) => panic!("Unhandled channel type"),
         Err(e) => panic!(
             "Failed to create datalink channel for {}: {}",
-            external_iface.name, e
+            internal_iface.name, e
         ),
     };
+    internal_stats.track(internal_fd);
 
-    // Wrap `external tx,rx` and `internal tx,rx` in Arc<Mutex<>> for thread-safe access
-    let external_tx_ch = Arc::new(Mutex::new(external_tx_ch));
-    let external_rx_ch = Arc::new(Mutex::new(external_rx_ch));
+    // Wrap `internal tx,rx` in Arc<Mutex<>> for thread-safe access, the external
+    // ones are owned by `external_link` so they can be swapped on link changes
+    let external_tx_ch = Arc::clone(&external_link.tx);
+    let external_rx_ch = Arc::clone(&external_link.rx);
     let internal_tx_ch = Arc::new(Mutex::new(internal_tx_ch));
     let internal_rx_ch = Arc::new(Mutex::new(internal_rx_ch));
 
@@ -100,16 +111,202 @@ async fn main() {
     // Lock only once here for internal_ops
     let chromecast_internal = chromecast.lock().await.get_internal_ops();
 
-    // Spawn an async thread for packet processing (capture loop) on internal interface
+    // Addresses for internal VMs from the external DHCP server
+    let dhcp_relay = Arc::new(cli::get_dhcp_relay());
+
+    // Flows the internal VMs open themselves share the external address
+    let napt = Arc::new(cli::get_napt());
+
+    // MACs of the internal clients, as last seen
+    let neighbors = Arc::new(cli::get_neighbors());
+
+    // Extra copies of discovery responses for the internal guests
+    let retransmitter = Arc::new(cli::get_retransmitter());
+
+    // Discovery reflection for other configured services
+    let reflector = Arc::new(MdnsReflector::new(cli::get_reflect_services()));
+
+    // Return traffic is only admitted for flows seen leaving the internal network
+    let conntrack = Arc::new(cli::get_conntrack());
+    // ... and, on established flows, only from devices that answered discovery
+    let pins = Arc::new(cli::get_device_pins());
+
+    // A test packet runs through the pipeline instead of captured traffic, see
+    // `nw_pckt_fwd::inject`. Nothing is captured and no background work is started
+    // for it, only the channels to send it are open
+    if let Some(probe) = cli::get_inject() {
+        let (ingress, egress) = match probe.ingress {
+            Ingress::Internal => (&internal_iface, &external_iface),
+            Ingress::External => (&external_iface, &internal_iface),
+        };
+        let mut frame = match probe.frame(ingress.mac.unwrap_or_else(MacAddr::zero)) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to build the test packet: {e}");
+                std::process::exit(2);
+            }
+        };
+        // Counted for the verdict only, the service's dump is left alone
+        let traffic = TrafficStats::new();
+        let pcap = PcapDump::new(None, 0, 0);
+        match probe.ingress {
+            Ingress::Internal => {
+                pipeline::process_internal_packets(
+                    &chromecast_internal,
+                    &reflector,
+                    &conntrack,
+                    &dhcp_relay,
+                    &napt,
+                    &neighbors,
+                    &traffic,
+                    &pcap,
+                    &external_tx_ch,
+                    &mut frame,
+                    &internal_iface,
+                    &get_ifaces(),
+                )
+                .await
+            }
+            Ingress::External => {
+                pipeline::process_external_packets(
+                    &chromecast_external,
+                    &reflector,
+                    &conntrack,
+                    &pins,
+                    &dhcp_relay,
+                    &napt,
+                    &neighbors,
+                    &Retransmitter::new(0, Duration::ZERO),
+                    &traffic,
+                    &pcap,
+                    &DropLog::new(1, None, None),
+                    &internal_tx_ch,
+                    &mut frame,
+                    &external_iface,
+                    &internal_iface,
+                )
+                .await
+            }
+        }
+        if probe.forwarded(&traffic.snapshot()) {
+            println!("Test packet forwarded out of {}", egress.name);
+            std::process::exit(0);
+        }
+        println!("Test packet dropped, see the debug log for the reason");
+        std::process::exit(1);
+    }
+    // Read both channels on dedicated capture threads
+    let mut internal_frames = capture::spawn("internal", internal_rx_ch, token.clone())
+        .unwrap_or_else(|e| panic!("Failed to start capture on {}: {e}", internal_iface.name));
+    let mut external_frames = capture::spawn("external", external_rx_ch, token.clone())
+        .unwrap_or_else(|e| panic!("Failed to start capture on {}: {e}", external_iface.name));
+
+    // Keep flows off chromecast VMs that stop responding
+    let health_check_task = tokio::task::spawn({
+        let balancer = chromecast.lock().await.get_balancer();
+        let cancel_token = token.clone();
+        async move {
+            if let Some((port, period)) = cli::get_chromecast_health_check() {
+                balancer.health_check(port, period, cancel_token).await;
+            }
+        }
+    });
+
+    // The host's own stack would reset the connections on the translated ports
+    if let Err(e) = napt.shield_host_ports() {
+        error!("Failed to close the translated ports to the host: {e}");
+        std::process::exit(1);
+    }
+
+    // Refused connection attempts, tagged with their origin
+    let drop_log = Arc::new(cli::get_drop_log());
+    // Handled frames written out for debugging
+    let pcap = Arc::new(cli::get_pcap_dump());
+
+    // Restore, persist and revalidate pinned devices
+    let pin_task = tokio::task::spawn({
+        let pins = Arc::clone(&pins);
+        let internal_iface = internal_iface.name.clone();
+        let cancel_token = token.clone();
+        async move {
+            pins.maintain(cli::get_pin_state_file(), &internal_iface, cancel_token)
+                .await;
+        }
+    });
+
+    // Packet and byte counters, served on the statistics socket
+    let traffic = Arc::new(TrafficStats::new());
+    let stats_socket_task = tokio::task::spawn({
+        let traffic = Arc::clone(&traffic);
+        let cancel_token = token.clone();
+        async move {
+            if let Some(path) = cli::get_stats_socket()
+                && let Err(e) = traffic_stats::serve(&traffic, path, cancel_token).await
+            {
+                error!("Failed to serve statistics on {}: {e}", path.display());
+            }
+        }
+    });
+
+    // Runtime control from the admin VM
+    let control_task = tokio::task::spawn({
+        let control = Arc::new(Control {
+            chromecast: Arc::clone(&chromecast),
+            reflector: Arc::clone(&reflector),
+            pins: Arc::clone(&pins),
+            traffic: Arc::clone(&traffic),
+        });
+        let cancel_token = token.clone();
+        async move {
+            if let Some((port, peer)) = cli::get_control_vsock()
+                && let Err(e) = control::serve(control, port, peer, cancel_token).await
+            {
+                error!("Failed to serve the control API on vsock port {port}: {e}");
+            }
+        }
+    });
+
+    // Follow link changes of the external interface candidates
+    let link_monitor_task = tokio::task::spawn({
+        let external_link = Arc::clone(&external_link);
+        let cancel_token = token.clone();
+        async move { external_link.monitor(cancel_token).await }
+    });
+
+    // Report frames dropped by the kernel on either capture socket
+    let capture_stats_task = tokio::task::spawn({
+        let stats = vec![internal_stats, Arc::clone(&external_link.stats)];
+        let traffic = Arc::clone(&traffic);
+        let cancel_token = token.clone();
+        async move {
+            if let Some(period) = cli::get_capture_stats_interval() {
+                capture_stats::monitor(
+                    stats,
+                    traffic,
+                    period,
+                    cli::get_capture_stats_file(),
+                    cancel_token,
+                )
+                .await;
+            }
+        }
+    });
+
+    // Spawn an async thread for packet processing on internal interface
     let internal_task = tokio::task::spawn({
         let cancel_token = token.clone();
         let internal_iface = internal_iface.clone();
-        let ifaces = get_ifaces();
+        let reflector = Arc::clone(&reflector);
+        let conntrack = Arc::clone(&conntrack);
+        let traffic = Arc::clone(&traffic);
+        let pcap = Arc::clone(&pcap);
+        let dhcp_relay = Arc::clone(&dhcp_relay);
+        let napt = Arc::clone(&napt);
+        let neighbors = Arc::clone(&neighbors);
         let mut last_err = String::new();
 
         async move {
             info!("Starting packet capture on {}...", internal_iface.name);
-            let internal_rx_ch = Arc::clone(&internal_rx_ch); // Clone for the async block
 
             loop {
                 tokio::select! {
@@ -119,24 +316,20 @@ async fn main() {
                         warn!("Cancellation token triggered, shutting down capture on {}...", internal_iface.name);
                         break;
                     }
-                    () = async {
-                        if forward::is_iface_running_up(&internal_iface.name) {
-                            match capture_next_packet(&internal_rx_ch).await {
-                                Ok(mut frame) => {
-                                    process_internal_packets(&chromecast_internal, &external_tx_ch, &mut frame, &internal_iface, &ifaces).await;
-                                }
-                                Err(e) => {
-                                    if last_err != e {
-                                        error!("Error receiving packet on {}: {}", internal_iface.name, e);
-                                        last_err = e;
-                                    }
-                                }
+                    frame = internal_frames.recv() => match frame {
+                        Some(Ok(mut frame)) => {
+                            // Re-read so a switched external interface is picked up
+                            let ifaces = get_ifaces();
+                            pipeline::process_internal_packets(&chromecast_internal, &reflector, &conntrack, &dhcp_relay, &napt, &neighbors, &traffic, &pcap, &external_tx_ch, &mut frame, &internal_iface, &ifaces).await;
+                        }
+                        Some(Err(e)) => {
+                            if last_err != e {
+                                error!("Error receiving packet on {}: {}", internal_iface.name, e);
+                                last_err = e;
                             }
-                        } else {
-                            // Sleep for 1 second asynchronously
-                            sleep(Duration::new(1, 0)).await;
                         }
-                    } => {}
+                        None => break,
+                    }
                 }
             }
 
@@ -144,16 +337,22 @@ async fn main() {
         }
     });
 
-    // Spawn a blocking thread for packet processing (capture loop) on external interface
+    // Spawn an async thread for packet processing on external interface
     let external_task = tokio::task::spawn({
         let internal_iface = internal_iface.clone();
         let cancel_token = token.clone();
+        let drop_log = Arc::clone(&drop_log);
+        let retransmitter = Arc::clone(&retransmitter);
+        let napt = Arc::clone(&napt);
         let mut last_err = String::new();
         async move {
             info!("Starting packet capture on {}...", external_iface.name);
             let chromecast_external = chromecast_external.clone(); // Clone Arc to give external task access
+            // Picks up address changes of the external interface, e.g. DHCP renewals
+            let mut iface_check = interval(Duration::from_secs(1));
 
             loop {
+                let external_iface = external_link.current();
                 tokio::select! {
                     // Check the cancellation token
                     () = cancel_token.cancelled() => {
@@ -161,28 +360,25 @@ async fn main() {
                         warn!("Cancellation token triggered, shutting down capture on {}...", external_iface.name);
                         break;
                     }
-                    () = async {
-                        if forward::is_iface_running_up(&external_iface.name) {
-                            match capture_next_packet(&external_rx_ch).await {
-                                Ok(mut frame) => {
-                                    process_external_packets(&chromecast_external, &internal_tx_ch, &mut frame, &external_iface, &internal_iface).await;
-                                }
-                                Err(e) => {
-                                    if last_err != e {
-                                        error!("Error receiving packet on {}: {}", external_iface.name, e);
-                                        last_err = e;
-                                    }
-                                }
+                    _ = iface_check.tick() => {
+                        forward::refresh_ext_ip(&external_iface.name);
+                    }
+                    frame = external_frames.recv() => match frame {
+                        Some(Ok(mut frame)) => {
+                            pipeline::process_external_packets(&chromecast_external, &reflector, &conntrack, &pins, &dhcp_relay, &napt, &neighbors, &retransmitter, &traffic, &pcap, &drop_log, &internal_tx_ch, &mut frame, &external_iface, &internal_iface).await;
+                        }
+                        Some(Err(e)) => {
+                            if last_err != e {
+                                error!("Error receiving packet on {}: {}", external_iface.name, e);
+                                last_err = e;
                             }
-                        } else {
-                            // Sleep for 1 second asynchronously
-                            sleep(Duration::new(1, 0)).await;
                         }
-                    } => {}
+                        None => break,
+                    }
                 }
             }
 
-            warn!("Task for {} is cleaning up", external_iface.name);
+            warn!("Task for {} is cleaning up", external_link.current().name);
         }
     });
 
@@ -196,7 +392,19 @@ async fn main() {
     token.cancel();
 
     // Wait for the tasks to finish
-    let _ = tokio::join!(external_task, internal_task);
+    let _ = tokio::join!(
+        external_task,
+        internal_task,
+        link_monitor_task,
+        capture_stats_task,
+        pin_task,
+        health_check_task,
+        stats_socket_task,
+        control_task
+    );
+    if let Err(e) = napt.unshield_host_ports() {
+        error!("Failed to reopen the translated ports to the host: {e}");
+    }
 }
 
 /// Initializes the logging system based on the selected feature and runtime configuration.
@@ -245,85 +453,3 @@ fn initialize_logger() {
         debug!("Logger initialized");
     }
 }
-
-async fn capture_next_packet(
-    rx_channel: &Arc<tokio::sync::Mutex<Box<dyn DataLinkReceiver>>>,
-) -> Result<Vec<u8>, String> {
-    let join_handle = tokio::task::spawn_blocking({
-        let rx_channel = Arc::clone(rx_channel);
-        move || {
-            let mut rx = rx_channel.blocking_lock();
-            rx.next().map(|frame| frame.to_owned())
-        }
-    })
-    .await
-    .map_err(|e| format!("Error in spawn_blocking: {e}"));
-
-    match join_handle {
-        Ok(Ok(frame)) => Ok(frame),
-        Ok(Err(e)) => Err(format!("Error receiving packet: {e}")),
-        Err(e) => Err(e.to_string()),
-    }
-}
-
-async fn process_internal_packets(
-    chromecast_internal: &Arc<InternalOps>,
-    external_tx_ch: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
-    frame: &mut [u8],
-    internal_iface: &datalink::NetworkInterface,
-    ifaces: &forward::Ifaces,
-) {
-    if let Some(mut eth_packet) = MutableEthernetPacket::new(frame) {
-        if chromecast_internal
-            .int_to_ext_filter_packets(&eth_packet.to_immutable())
-            .await
-        {
-            forward::internal_to_external_process_packet(external_tx_ch, &mut eth_packet, ifaces)
-                .await;
-
-            trace!(
-                "Received frame on {}: {}",
-                internal_iface.name,
-                forward::parse_packet(&eth_packet)
-            );
-        }
-    } else {
-        warn!(
-            "Invalid Ethernet packet received on {}",
-            internal_iface.name
-        );
-    }
-}
-
-async fn process_external_packets(
-    chromecast_external: &Arc<ExternalOps>,
-    internal_tx_ch: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
-    frame: &mut [u8],
-    external_iface: &datalink::NetworkInterface,
-    internal_iface: &datalink::NetworkInterface,
-) {
-    // Forward packet to internal interface channel
-    let internal_tx_ch_clone = Arc::clone(internal_tx_ch);
-
-    if let Some(mut eth_packet) = MutableEthernetPacket::new(frame) {
-        if let Some((mac, ip)) = chromecast_external
-            .is_ext_to_int_packet(&eth_packet.to_immutable())
-            .await
-        {
-            forward::external_to_internal_process_packet(
-                internal_tx_ch_clone,
-                &mut eth_packet,
-                &external_iface.ips,
-                internal_iface.mac.unwrap(),
-                mac,
-                ip,
-            )
-            .await;
-        }
-        trace!(
-            "Received frame on {}: {}",
-            external_iface.name,
-            forward::parse_packet(&eth_packet)
-        );
-    }
-}
@@ -0,0 +1,268 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Packet capture dump
+//!
+//! Debug aid writing every frame the forwarder handled, forwarded or dropped, to a pcap
+//! file that opens in Wireshark, so failing sessions can be analyzed without running
+//! tcpdump inside the VM. Forwarded frames are dumped as sent, i.e. rewritten for their
+//! destination.
+//!
+//! pcap has no room for per-frame comments, so a sidecar index next to the dump
+//! (`<path>.idx`) lists one line per frame: its number as shown by Wireshark, the
+//! direction and whether it was forwarded, or why it was dropped.
+//!
+//! When a dump reaches its size cap it is moved to `<path>.1` (and its index to
+//! `<path>.1.idx`), older ones shift up, and the oldest beyond the configured count is
+//! removed.
+use crate::drop_log::DropReason;
+use crate::traffic_stats::Direction;
+use log::{error, info};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::EthernetPacket;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+const GLOBAL_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    pcap: BufWriter<File>,
+    index: BufWriter<File>,
+    size: u64,
+    frames: u64,
+}
+
+impl Writer {
+    fn create(path: &Path, max_size: u64, keep: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            pcap: BufWriter::new(File::create(path)?),
+            index: BufWriter::new(File::create(index_path(path))?),
+            size: 0,
+            frames: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        // Native byte order, readers tell it from the magic number
+        self.pcap.write_all(&PCAP_MAGIC.to_ne_bytes())?;
+        self.pcap.write_all(&2u16.to_ne_bytes())?;
+        self.pcap.write_all(&4u16.to_ne_bytes())?;
+        self.pcap.write_all(&0i32.to_ne_bytes())?;
+        self.pcap.write_all(&0u32.to_ne_bytes())?;
+        self.pcap.write_all(&SNAPLEN.to_ne_bytes())?;
+        self.pcap.write_all(&LINKTYPE_ETHERNET.to_ne_bytes())?;
+        self.size = GLOBAL_HEADER_LEN;
+        self.frames = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &[u8], verdict: &str) -> io::Result<()> {
+        let frame = &frame[..frame.len().min(SNAPLEN as usize)];
+        let len = RECORD_HEADER_LEN + frame.len() as u64;
+        if self.frames > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = u32::try_from(now.as_secs()).unwrap_or(u32::MAX);
+        self.pcap.write_all(&secs.to_ne_bytes())?;
+        self.pcap.write_all(&now.subsec_micros().to_ne_bytes())?;
+        self.pcap.write_all(&(frame.len() as u32).to_ne_bytes())?;
+        self.pcap.write_all(&(frame.len() as u32).to_ne_bytes())?;
+        self.pcap.write_all(frame)?;
+        self.size += len;
+        self.frames += 1;
+        writeln!(
+            self.index,
+            "{} {}.{:06} {verdict}",
+            self.frames,
+            secs,
+            now.subsec_micros()
+        )?;
+        // Frames stay available to a reader even if the forwarder dies
+        self.pcap.flush()?;
+        self.index.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.pcap.flush()?;
+        self.index.flush()?;
+        // Renaming onto the oldest kept dump removes it
+        for n in (1..=self.keep).rev() {
+            let from = rotated_path(&self.path, n - 1);
+            let to = rotated_path(&self.path, n);
+            rename_existing(&from, &to)?;
+            rename_existing(&index_path(&from), &index_path(&to))?;
+        }
+        self.pcap = BufWriter::new(File::create(&self.path)?);
+        self.index = BufWriter::new(File::create(index_path(&self.path))?);
+        self.write_header()
+    }
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    name.into()
+}
+
+fn rename_existing(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Frames handled by the forwarder, written to rotating pcap files.
+pub struct PcapDump {
+    writer: Mutex<Option<Writer>>,
+}
+
+impl PcapDump {
+    /// Dumps to `path` when given, starting a new file when one reaches `max_size`
+    /// bytes and keeping `keep` previous ones.
+    pub fn new(path: Option<&Path>, max_size: u64, keep: u32) -> Self {
+        let writer = path.and_then(|path| match Writer::create(path, max_size, keep) {
+            Ok(writer) => {
+                info!("Dumping frames to {}", path.display());
+                Some(writer)
+            }
+            Err(e) => {
+                error!("Failed to create frame dump {}: {e}", path.display());
+                None
+            }
+        });
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Dumps a frame travelling in `direction`, with the `reason` it was dropped for if
+    /// known.
+    pub fn record(
+        &self,
+        direction: Direction,
+        forwarded: bool,
+        reason: Option<DropReason>,
+        eth_packet: &EthernetPacket<'_>,
+    ) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(w) = writer.as_mut() else {
+            return;
+        };
+        let direction = direction.label();
+        let verdict = match reason {
+            _ if forwarded => format!("{direction} forwarded"),
+            Some(reason) => format!("{direction} dropped:{reason}"),
+            None => format!("{direction} dropped"),
+        };
+        if let Err(e) = w.write(eth_packet.packet(), &verdict) {
+            error!(
+                "Failed to dump frame to {}, stopping: {e}",
+                w.path.display()
+            );
+            *writer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_rotation() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-pcap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.pcap");
+        let frame = [0xAAu8; 60];
+        let packet = EthernetPacket::new(&frame).unwrap();
+
+        // Room for the header and two frames per file, two old files kept
+        let dump = PcapDump::new(Some(&path), 24 + 2 * (16 + 60), 2);
+        dump.record(Direction::IntToExt, true, None, &packet);
+        dump.record(
+            Direction::ExtToInt,
+            false,
+            Some(DropReason::Untracked),
+            &packet,
+        );
+        dump.record(Direction::ExtToInt, false, None, &packet);
+        for _ in 0..3 {
+            dump.record(Direction::IntToExt, true, None, &packet);
+        }
+        let verdicts = |n| {
+            let index = fs::read_to_string(index_path(&rotated_path(&path, n))).unwrap();
+            index
+                .lines()
+                .map(|l| l.splitn(3, ' ').nth(2).unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let pcap = fs::read(&path).unwrap();
+        assert_eq!(pcap.len(), 24 + 2 * (16 + 60));
+        assert_eq!(pcap[..4], PCAP_MAGIC.to_ne_bytes());
+        assert_eq!(pcap[20..24], LINKTYPE_ETHERNET.to_ne_bytes());
+        assert_eq!(pcap[24 + 8..24 + 12], 60u32.to_ne_bytes());
+        assert_eq!(pcap[24 + 16..24 + 16 + 60], frame);
+
+        assert_eq!(
+            verdicts(2),
+            ["int_to_ext forwarded", "ext_to_int dropped:untracked"]
+        );
+        assert_eq!(verdicts(1), ["ext_to_int dropped", "int_to_ext forwarded"]);
+        assert_eq!(verdicts(0).len(), 2);
+        assert!(
+            fs::read_to_string(index_path(&path))
+                .unwrap()
+                .starts_with("1 ")
+        );
+
+        // The oldest dump goes on the next rotation
+        dump.record(Direction::IntToExt, true, None, &packet);
+        assert_eq!(verdicts(2), ["ext_to_int dropped", "int_to_ext forwarded"]);
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disabled_dump() {
+        let frame = [0u8; 60];
+        let dump = PcapDump::new(None, 1 << 20, 1);
+        dump.record(
+            Direction::IntToExt,
+            true,
+            None,
+            &EthernetPacket::new(&frame).unwrap(),
+        );
+        assert!(dump.writer.lock().unwrap().is_none());
+    }
+}
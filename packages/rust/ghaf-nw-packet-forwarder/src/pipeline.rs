@@ -0,0 +1,287 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Frame pipeline
+//!
+//! Classifies each captured frame, rewrites the ones to forward for the other side and
+//! sends them, with the bookkeeping of statistics, dumps and drop logs.
+use crate::drop_log::{DropLog, DropReason};
+use crate::filter::chromecast::{ExternalOps, InternalOps};
+use crate::filter::conntrack::Inbound;
+use crate::filter::{Conntrack, DevicePins, MdnsReflector};
+use crate::forward_impl::dhcp_relay::DhcpRelay;
+use crate::forward_impl::forward::{self, get_ifaces};
+use crate::forward_impl::napt::Napt;
+use crate::forward_impl::neighbors::Neighbors;
+use crate::forward_impl::retransmit::Retransmitter;
+use crate::pcap_dump::PcapDump;
+use crate::traffic_stats::{Direction, TrafficStats};
+use log::{debug, trace, warn};
+use pnet::datalink;
+use pnet::packet::Packet;
+use pnet::packet::ethernet::MutableEthernetPacket;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn process_internal_packets(
+    chromecast_internal: &Arc<InternalOps>,
+    reflector: &Arc<MdnsReflector>,
+    conntrack: &Arc<Conntrack>,
+    dhcp_relay: &Arc<DhcpRelay>,
+    napt: &Napt,
+    neighbors: &Neighbors,
+    traffic: &TrafficStats,
+    pcap: &PcapDump,
+    external_tx_ch: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+    frame: &mut [u8],
+    internal_iface: &datalink::NetworkInterface,
+    ifaces: &forward::Ifaces,
+) {
+    if let Some(mut eth_packet) = MutableEthernetPacket::new(frame) {
+        // The source is rewritten when forwarding
+        let client = eth_packet.get_source();
+        let mut forwarded = false;
+        // Clients using another's leased address are not learned either
+        let spoofed = dhcp_relay.is_spoofed(&eth_packet.to_immutable()).await;
+        if !spoofed {
+            neighbors
+                .learn(&eth_packet.to_immutable(), &internal_iface.ips, dhcp_relay)
+                .await;
+        }
+        if dhcp_relay.is_client_message(&eth_packet.to_immutable()) {
+            forwarded = dhcp_relay
+                .relay_to_server(external_tx_ch, &mut eth_packet, ifaces)
+                .await;
+        } else if spoofed {
+            debug!(
+                "Int to Ext - packet dropped {}",
+                forward::parse_packet(&eth_packet)
+            );
+        } else if chromecast_internal
+            .int_to_ext_filter_packets(&eth_packet.to_immutable())
+            .await
+            || reflector
+                .int_to_ext_filter_packets(&eth_packet.to_immutable())
+                .await
+        {
+            forwarded = forward::internal_to_external_process_packet(
+                external_tx_ch,
+                &mut eth_packet,
+                ifaces,
+            )
+            .await;
+            if forwarded {
+                conntrack.track_outbound(&eth_packet.to_immutable()).await;
+            }
+
+            trace!(
+                "Received frame on {}: {}",
+                internal_iface.name,
+                forward::parse_packet(&eth_packet)
+            );
+        } else if napt.is_enabled() {
+            forwarded = napt
+                .translate_outbound(external_tx_ch, &mut eth_packet, ifaces)
+                .await;
+        }
+        traffic.record(
+            Direction::IntToExt,
+            forwarded,
+            &eth_packet.to_immutable(),
+            Some(client),
+        );
+        pcap.record(
+            Direction::IntToExt,
+            forwarded,
+            None,
+            &eth_packet.to_immutable(),
+        );
+    } else {
+        warn!(
+            "Invalid Ethernet packet received on {}",
+            internal_iface.name
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn process_external_packets(
+    chromecast_external: &Arc<ExternalOps>,
+    reflector: &Arc<MdnsReflector>,
+    conntrack: &Arc<Conntrack>,
+    pins: &Arc<DevicePins>,
+    dhcp_relay: &Arc<DhcpRelay>,
+    napt: &Napt,
+    neighbors: &Neighbors,
+    retransmitter: &Retransmitter,
+    traffic: &TrafficStats,
+    pcap: &PcapDump,
+    drop_log: &DropLog,
+    internal_tx_ch: &Arc<Mutex<Box<dyn pnet::datalink::DataLinkSender>>>,
+    frame: &mut [u8],
+    external_iface: &datalink::NetworkInterface,
+    internal_iface: &datalink::NetworkInterface,
+) {
+    // Forward packet to internal interface channel
+    let internal_tx_ch_clone = Arc::clone(internal_tx_ch);
+
+    if let Some(mut eth_packet) = MutableEthernetPacket::new(frame) {
+        if dhcp_relay.is_server_message(&eth_packet.to_immutable()) {
+            let forwarded = dhcp_relay
+                .relay_to_client(&internal_tx_ch_clone, &mut eth_packet, &get_ifaces())
+                .await;
+            let client = eth_packet.get_destination();
+            traffic.record(
+                Direction::ExtToInt,
+                forwarded,
+                &eth_packet.to_immutable(),
+                (forwarded && client.is_unicast()).then_some(client),
+            );
+            pcap.record(
+                Direction::ExtToInt,
+                forwarded,
+                None,
+                &eth_packet.to_immutable(),
+            );
+            return;
+        }
+        // Replies to flows the internal VMs opened themselves, checked as received before
+        // their port is translated back
+        let ifaces = get_ifaces();
+        if napt.is_inbound(&eth_packet.to_immutable(), &ifaces) {
+            let client =
+                if !forward::is_ext_to_int_packet_allowed(&mut eth_packet, &external_iface.ips)
+                    .await
+                {
+                    Err(DropReason::Refused)
+                } else if let Some((mac, ip)) =
+                    napt.translate_inbound(&mut eth_packet, &ifaces).await
+                {
+                    let mac = neighbors.resolve(mac, ip.ip(), dhcp_relay).await;
+                    forward::external_to_internal_send_packet(
+                        internal_tx_ch_clone,
+                        &mut eth_packet,
+                        internal_iface.mac.unwrap(),
+                        mac,
+                        ip,
+                    )
+                    .await
+                    .then_some(mac)
+                    .ok_or(DropReason::Refused)
+                } else {
+                    Err(DropReason::Untracked)
+                };
+            let forwarded = client.is_ok();
+            let reason = client.err();
+            if let Some(reason) = reason {
+                drop_log.record(reason, &eth_packet.to_immutable());
+            }
+            traffic.record(
+                Direction::ExtToInt,
+                forwarded,
+                &eth_packet.to_immutable(),
+                client.ok(),
+            );
+            pcap.record(
+                Direction::ExtToInt,
+                forwarded,
+                reason,
+                &eth_packet.to_immutable(),
+            );
+            return;
+        }
+        let destination = match chromecast_external
+            .is_ext_to_int_packet(&eth_packet.to_immutable())
+            .await
+        {
+            Some(destination) => Some(destination),
+            None => {
+                reflector
+                    .is_ext_to_int_packet(&eth_packet.to_immutable())
+                    .await
+            }
+        };
+        let Some((mac, ip)) = destination else {
+            traffic.record(Direction::ExtToInt, false, &eth_packet.to_immutable(), None);
+            pcap.record(
+                Direction::ExtToInt,
+                false,
+                Some(DropReason::NoService),
+                &eth_packet.to_immutable(),
+            );
+            drop_log.record(DropReason::NoService, &eth_packet.to_immutable());
+            return;
+        };
+        // Internal clients are reached at the MAC they were last seen with
+        let mac = neighbors.resolve(mac, ip.ip(), dhcp_relay).await;
+        let refusal = match conntrack.classify_inbound(&eth_packet.to_immutable()).await {
+            // Answers to a discovery search of the chromecast VMs pin their sender, other
+            // group traffic pins nobody but may not come from a pinned address
+            Some(Inbound::Group | Inbound::GroupReply) => {
+                if chromecast_external
+                    .is_discovery_response(&eth_packet.to_immutable())
+                    .await
+                {
+                    (!pins.learn(&eth_packet.to_immutable()).await).then_some(DropReason::NotPinned)
+                } else {
+                    pins.is_spoofed(&eth_packet.to_immutable())
+                        .await
+                        .then_some(DropReason::NotPinned)
+                }
+            }
+            Some(Inbound::Established) => {
+                (!pins.is_pinned(&eth_packet.to_immutable()).await).then_some(DropReason::NotPinned)
+            }
+            None => Some(DropReason::Untracked),
+        };
+        // The guest already has this response, or is about to get a copy of it
+        if refusal.is_none()
+            && retransmitter
+                .is_duplicate(&eth_packet.to_immutable(), mac)
+                .await
+        {
+            debug!("Ext to Int - duplicate discovery response suppressed");
+            traffic.record(Direction::ExtToInt, false, &eth_packet.to_immutable(), None);
+            pcap.record(Direction::ExtToInt, false, None, &eth_packet.to_immutable());
+            return;
+        }
+        let forwarded = refusal.is_none()
+            && forward::external_to_internal_process_packet(
+                internal_tx_ch_clone,
+                &mut eth_packet,
+                &external_iface.ips,
+                internal_iface.mac.unwrap(),
+                mac,
+                ip,
+            )
+            .await;
+        if forwarded {
+            retransmitter.repeat(internal_tx_ch, eth_packet.packet());
+        }
+        let reason = (!forwarded).then(|| refusal.unwrap_or(DropReason::Refused));
+        if let Some(reason) = reason {
+            drop_log.record(reason, &eth_packet.to_immutable());
+        }
+        pcap.record(
+            Direction::ExtToInt,
+            forwarded,
+            reason,
+            &eth_packet.to_immutable(),
+        );
+        // Group destinations are no client of their own
+        let client = mac.is_unicast().then_some(mac);
+        traffic.record(
+            Direction::ExtToInt,
+            forwarded,
+            &eth_packet.to_immutable(),
+            client,
+        );
+        trace!(
+            "Received frame on {}: {}",
+            external_iface.name,
+            forward::parse_packet(&eth_packet)
+        );
+    }
+}
@@ -0,0 +1,379 @@
+/*
+    SPDX-FileCopyrightText: 2022-2026 TII (SSRC) and the Ghaf contributors
+    SPDX-License-Identifier: Apache-2.0
+*/
+//! # Traffic statistics
+//!
+//! Counts the packets and bytes the forwarder handles, per direction, verdict and
+//! protocol, and per internal client MAC. A snapshot is served as JSON to every client
+//! connecting to the statistics socket, e.g. `socat - UNIX-CONNECT:/run/nw-pckt-fwd.sock`,
+//! and is included in the Prometheus export of the capture statistics.
+use log::{debug, info, warn};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+use tokio_util::sync::CancellationToken;
+
+/// Internal clients tracked individually, further ones are only counted in the totals
+const MAX_CLIENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    IntToExt,
+    ExtToInt,
+}
+
+impl Direction {
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::IntToExt => "int_to_ext",
+            Direction::ExtToInt => "ext_to_int",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Arp,
+    Ipv6,
+    Other,
+}
+
+impl Protocol {
+    fn of(eth_packet: &EthernetPacket<'_>) -> Self {
+        match eth_packet.get_ethertype() {
+            EtherTypes::Ipv4 => match Ipv4Packet::new(eth_packet.payload())
+                .map(|ipv4| ipv4.get_next_level_protocol())
+            {
+                Some(IpNextHeaderProtocols::Tcp) => Protocol::Tcp,
+                Some(IpNextHeaderProtocols::Udp) => Protocol::Udp,
+                Some(IpNextHeaderProtocols::Icmp) => Protocol::Icmp,
+                _ => Protocol::Other,
+            },
+            EtherTypes::Arp => Protocol::Arp,
+            EtherTypes::Ipv6 => Protocol::Ipv6,
+            _ => Protocol::Other,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+            Protocol::Arp => "arp",
+            Protocol::Ipv6 => "ipv6",
+            Protocol::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Counters {
+    fn add(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Forwarded and dropped traffic of one direction, per protocol
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DirectionStats {
+    pub forwarded: BTreeMap<Protocol, Counters>,
+    pub dropped: BTreeMap<Protocol, Counters>,
+}
+
+/// Traffic of one internal client, keyed by its MAC
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ClientStats {
+    /// Forwarded from the client to the external network
+    pub sent: Counters,
+    /// Forwarded from the external network to the client
+    pub received: Counters,
+    /// Sent by the client, but not forwarded
+    pub dropped: Counters,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Snapshot {
+    pub int_to_ext: DirectionStats,
+    pub ext_to_int: DirectionStats,
+    pub clients: BTreeMap<String, ClientStats>,
+}
+
+#[derive(Default)]
+pub struct TrafficStats {
+    snapshot: Mutex<Snapshot>,
+}
+
+impl TrafficStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a captured frame, `client` being the internal MAC it was sent from or
+    /// forwarded to.
+    pub fn record(
+        &self,
+        direction: Direction,
+        forwarded: bool,
+        eth_packet: &EthernetPacket<'_>,
+        client: Option<MacAddr>,
+    ) {
+        let protocol = Protocol::of(eth_packet);
+        let bytes = eth_packet.packet().len();
+        let mut snapshot = self.snapshot.lock().expect("Failed to lock traffic stats");
+
+        let stats = match direction {
+            Direction::IntToExt => &mut snapshot.int_to_ext,
+            Direction::ExtToInt => &mut snapshot.ext_to_int,
+        };
+        let verdicts = if forwarded {
+            &mut stats.forwarded
+        } else {
+            &mut stats.dropped
+        };
+        verdicts.entry(protocol).or_default().add(bytes);
+
+        // Traffic from outside that was not forwarded has no internal client
+        let Some(client) = client.filter(|_| forwarded || direction == Direction::IntToExt) else {
+            return;
+        };
+        let key = client.to_string();
+        if !snapshot.clients.contains_key(&key) && snapshot.clients.len() >= MAX_CLIENTS {
+            debug!("Traffic stats client table full, not tracking {key}");
+            return;
+        }
+        let client = snapshot.clients.entry(key).or_default();
+        match (direction, forwarded) {
+            (Direction::IntToExt, true) => client.sent.add(bytes),
+            (Direction::IntToExt, false) => client.dropped.add(bytes),
+            (Direction::ExtToInt, _) => client.received.add(bytes),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot
+            .lock()
+            .expect("Failed to lock traffic stats")
+            .clone()
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        render(&self.snapshot())
+    }
+}
+
+fn render(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+    for unit in ["packets", "bytes"] {
+        let value = |c: &Counters| {
+            if unit == "packets" {
+                c.packets
+            } else {
+                c.bytes
+            }
+        };
+        let metric = format!("nw_pckt_fwd_{unit}_total");
+        let _ = writeln!(out, "# HELP {metric} Captured {unit} by verdict.");
+        let _ = writeln!(out, "# TYPE {metric} counter");
+        for (direction, stats) in [
+            (Direction::IntToExt, &snapshot.int_to_ext),
+            (Direction::ExtToInt, &snapshot.ext_to_int),
+        ] {
+            for (verdict, counters) in
+                [("forwarded", &stats.forwarded), ("dropped", &stats.dropped)]
+            {
+                for (protocol, c) in counters {
+                    let _ = writeln!(
+                        out,
+                        "{metric}{{direction=\"{}\",verdict=\"{verdict}\",protocol=\"{}\"}} {}",
+                        direction.label(),
+                        protocol.label(),
+                        value(c)
+                    );
+                }
+            }
+        }
+
+        let metric = format!("nw_pckt_fwd_client_{unit}_total");
+        let _ = writeln!(
+            out,
+            "# HELP {metric} Forwarded and dropped {unit} per internal client."
+        );
+        let _ = writeln!(out, "# TYPE {metric} counter");
+        for (mac, client) in &snapshot.clients {
+            for (kind, c) in [
+                ("sent", &client.sent),
+                ("received", &client.received),
+                ("dropped", &client.dropped),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "{metric}{{mac=\"{mac}\",kind=\"{kind}\"}} {}",
+                    value(c)
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Serves a JSON snapshot of `stats` to every client connecting to the Unix socket at
+/// `path`, until `cancel_token` is cancelled.
+pub async fn serve(
+    stats: &TrafficStats,
+    path: &Path,
+    cancel_token: CancellationToken,
+) -> io::Result<()> {
+    // A socket left behind by a previous instance would make binding fail
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Serving traffic statistics on {}", path.display());
+
+    loop {
+        let mut stream = tokio::select! {
+            () = cancel_token.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept statistics client: {e}");
+                    continue;
+                }
+            },
+        };
+        let mut json = serde_json::to_vec(&stats.snapshot())?;
+        json.push(b'\n');
+        if let Err(e) = stream.write_all(&json).await {
+            debug!("Failed to send traffic statistics: {e}");
+        }
+    }
+
+    let _ = tokio::fs::remove_file(path).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    const CLIENT: MacAddr = MacAddr(2, 0, 0, 0, 0, 1);
+
+    fn udp_frame() -> Vec<u8> {
+        let mut buffer = vec![0u8; 14 + 20 + 8];
+        let mut eth = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4 = MutableIpv4Packet::new(&mut buffer[14..]).unwrap();
+        ipv4.set_version(4);
+        ipv4.set_header_length(5);
+        ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        buffer
+    }
+
+    fn record(stats: &TrafficStats, direction: Direction, forwarded: bool, frame: &[u8]) {
+        let eth = EthernetPacket::new(frame).unwrap();
+        stats.record(direction, forwarded, &eth, Some(CLIENT));
+    }
+
+    #[test]
+    fn test_record() {
+        let stats = TrafficStats::new();
+        let frame = udp_frame();
+        record(&stats, Direction::IntToExt, true, &frame);
+        record(&stats, Direction::IntToExt, true, &frame);
+        record(&stats, Direction::IntToExt, false, &frame);
+        record(&stats, Direction::ExtToInt, true, &frame);
+        // Dropped inbound traffic is not attributed to the client
+        record(&stats, Direction::ExtToInt, false, &frame);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.int_to_ext.forwarded[&Protocol::Udp],
+            Counters {
+                packets: 2,
+                bytes: 84
+            }
+        );
+        assert_eq!(snapshot.int_to_ext.dropped[&Protocol::Udp].packets, 1);
+        assert_eq!(snapshot.ext_to_int.dropped[&Protocol::Udp].packets, 1);
+        let client = &snapshot.clients[&CLIENT.to_string()];
+        assert_eq!(client.sent.packets, 2);
+        assert_eq!(client.received.packets, 1);
+        assert_eq!(client.dropped.packets, 1);
+    }
+
+    #[test]
+    fn test_render() {
+        let stats = TrafficStats::new();
+        record(&stats, Direction::ExtToInt, true, &udp_frame());
+        let text = stats.render();
+        assert!(text.contains(
+            "nw_pckt_fwd_packets_total{direction=\"ext_to_int\",verdict=\"forwarded\",protocol=\"udp\"} 1\n"
+        ));
+        assert!(text.contains(
+            "nw_pckt_fwd_client_bytes_total{mac=\"02:00:00:00:00:01\",kind=\"received\"} 42\n"
+        ));
+        assert_eq!(text.matches("# TYPE").count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_serve_json() {
+        let dir = std::env::temp_dir().join(format!("nw-pckt-fwd-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stats.sock");
+        let stats = std::sync::Arc::new(TrafficStats::new());
+        record(&stats, Direction::IntToExt, true, &udp_frame());
+
+        let cancel_token = CancellationToken::new();
+        let server = tokio::spawn({
+            let stats = std::sync::Arc::clone(&stats);
+            let path = path.clone();
+            let cancel_token = cancel_token.clone();
+            async move { serve(&stats, &path, cancel_token).await }
+        });
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let mut json = String::new();
+        stream.read_to_string(&mut json).await.unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["int_to_ext"]["forwarded"]["udp"]["packets"], 1);
+        assert_eq!(value["clients"]["02:00:00:00:00:01"]["sent"]["bytes"], 42);
+
+        cancel_token.cancel();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}